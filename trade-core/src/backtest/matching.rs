@@ -0,0 +1,153 @@
+use crate::models::{
+    candle::Candle,
+    decimal::Decimal,
+    order::{Order, OrderType},
+    trade::Side,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+// Order resting at a simulated exchange, matched against candles by backtests and paper trading
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingOrder {
+    pub order: Order,
+    // Orders reach the simulated exchange after the latency
+    pub active_at: DateTime<Utc>,
+    // Stop price of a stop limit order was hit, it rests as limit order now
+    pub triggered: bool,
+}
+
+impl PendingOrder {
+    pub fn new(order: Order, active_at: DateTime<Utc>) -> Self {
+        PendingOrder {
+            order,
+            active_at,
+            triggered: false,
+        }
+    }
+
+    // Price the order fills at during `candle`, `None` while it does not fill. Market orders fill
+    // at the open and stop orders at their trigger price, both adjusted by `slipped`. Stop limit
+    // orders are triggered by the candle and rest as limit orders from the next one.
+    pub fn fill_price(
+        &mut self,
+        candle: &Candle,
+        slipped: impl FnOnce(Side, Decimal) -> Option<Decimal>,
+    ) -> Option<Decimal> {
+        let side = self.order.side;
+        match self.order.order_type {
+            OrderType::Market => slipped(side, candle.open),
+            OrderType::Limit { price } => limit_fill(side, price, candle),
+            OrderType::Stop { stop_price } => slipped(side, stop_fill(side, stop_price, candle)?),
+            OrderType::StopLimit { price, .. } if self.triggered => limit_fill(side, price, candle),
+            OrderType::StopLimit { stop_price, .. } => {
+                self.triggered = stop_fill(side, stop_price, candle).is_some();
+                None
+            }
+        }
+    }
+}
+
+// Price a limit order fills at during `candle`, at the open if that is better
+pub fn limit_fill(side: Side, limit: Decimal, candle: &Candle) -> Option<Decimal> {
+    match side {
+        Side::Buy => (candle.low <= limit).then(|| candle.open.min(limit)),
+        Side::Sell => (candle.high >= limit).then(|| candle.open.max(limit)),
+    }
+}
+
+// Price a stop order triggers at during `candle`, at the open if that is worse
+pub fn stop_fill(side: Side, stop: Decimal, candle: &Candle) -> Option<Decimal> {
+    match side {
+        Side::Buy => (candle.high >= stop).then(|| candle.open.max(stop)),
+        Side::Sell => (candle.low <= stop).then(|| candle.open.min(stop)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PendingOrder;
+    use crate::models::{
+        candle::Candle,
+        order::{Order, OrderType},
+        trade::Side,
+    };
+    use crate::test_data::{dec, minute, ohlc};
+
+    fn candle(open: &str, high: &str, low: &str) -> Candle {
+        ohlc(0, open, high, low, open)
+    }
+
+    fn pending(side: Side, order_type: OrderType) -> PendingOrder {
+        let order = Order::market("a".to_string(), "BTCUSDT".to_string(), side, dec("1"));
+        PendingOrder::new(
+            Order {
+                order_type,
+                ..order
+            },
+            minute(0),
+        )
+    }
+
+    #[test]
+    fn stop_limit_orders_rest_after_the_trigger() {
+        let mut order = pending(
+            Side::Buy,
+            OrderType::StopLimit {
+                stop_price: dec("105"),
+                price: dec("106"),
+            },
+        );
+        let unslipped = |_, price| Some(price);
+
+        assert_eq!(
+            None,
+            order.fill_price(&candle("100", "104", "99"), unslipped)
+        );
+        // Triggered, but fills from the next candle only
+        assert_eq!(
+            None,
+            order.fill_price(&candle("100", "107", "99"), unslipped)
+        );
+        assert!(order.triggered);
+        assert_eq!(
+            Some(dec("106")),
+            order.fill_price(&candle("108", "109", "105"), unslipped)
+        );
+    }
+
+    #[test]
+    fn market_and_stop_prices_are_slipped() {
+        let slipped = |side, price: crate::models::decimal::Decimal| {
+            assert_eq!(Side::Sell, side);
+            price.checked_sub(dec("1"))
+        };
+
+        let mut market = pending(Side::Sell, OrderType::Market);
+        assert_eq!(
+            Some(dec("99")),
+            market.fill_price(&candle("100", "101", "98"), slipped)
+        );
+        let mut stop = pending(
+            Side::Sell,
+            OrderType::Stop {
+                stop_price: dec("99"),
+            },
+        );
+        assert_eq!(
+            Some(dec("98")),
+            stop.fill_price(&candle("100", "101", "97"), slipped)
+        );
+        // Limit prices are never slipped
+        let mut limit = pending(
+            Side::Sell,
+            OrderType::Limit {
+                price: dec("100.5"),
+            },
+        );
+        assert_eq!(
+            Some(dec("100.5")),
+            limit.fill_price(&candle("100", "101", "97"), slipped)
+        );
+    }
+}
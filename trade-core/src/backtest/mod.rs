@@ -0,0 +1,739 @@
+pub mod matching;
+pub mod report;
+
+use crate::data::database::SharedStockDataCache;
+use crate::models::{
+    candle::Candle,
+    decimal::Decimal,
+    interval::Interval,
+    order::{Fill, Order, TimeInForce},
+    portfolio::Portfolio,
+    trade::Side,
+};
+use crate::Error;
+use chrono::{DateTime, Duration, Utc};
+use matching::PendingOrder;
+use report::{max_drawdown, sharpe_ratio, BacktestReport};
+use std::collections::HashMap;
+use std::ops::Range;
+
+const SECONDS_PER_YEAR: f64 = 365.25 * 24.0 * 60.0 * 60.0;
+
+// Trading logic driven by a backtest
+pub trait Strategy {
+    // Called once `candle` of `symbol` closed, candles of all symbols arrive in time order
+    fn on_candle(&mut self, context: &mut StrategyContext<'_>, symbol: &str, candle: &Candle);
+
+    fn on_fill(&mut self, _fill: &Fill) {}
+}
+
+// Replays cached candles through a `Strategy` in event time and simulates its orders.
+//
+// Strategies see a candle when it closes, so orders are filled against later candles only. An
+// order takes part from the first candle opening after its latency passed, latencies below the
+// interval therefore fill at the next open. Orders fill completely:
+// - market orders at the open
+// - limit orders once a candle trades at their price, at the open if that is better
+// - stop orders like market orders once a candle trades at their stop price, at the stop price or
+//   the open if that is worse
+// - stop limit orders rest as limit orders from the candle after the one hitting the stop price
+// Market and stop fills get slippage. IOC and FOK orders expire if the first candle they take
+// part in does not fill them. Buys need enough cash and sells an open position, otherwise the
+// order is rejected. Slippage and latency jitter are drawn from a generator seeded with `seed`,
+// so equal inputs always give equal reports.
+pub struct Backtest {
+    cache: SharedStockDataCache,
+    initial_cash: Decimal,
+    // Fraction of the notional, e.g. 0.001 for 0.1%
+    fee_rate: Decimal,
+    max_slippage_bps: u32,
+    latency: Duration,
+    latency_jitter: Duration,
+    seed: u64,
+}
+
+impl Backtest {
+    pub fn new(cache: SharedStockDataCache, initial_cash: Decimal) -> Self {
+        Backtest {
+            cache,
+            initial_cash,
+            fee_rate: Decimal::ZERO,
+            max_slippage_bps: 0,
+            latency: Duration::zero(),
+            latency_jitter: Duration::zero(),
+            seed: 0,
+        }
+    }
+
+    pub fn with_fee_rate(mut self, fee_rate: Decimal) -> Self {
+        self.fee_rate = fee_rate;
+        self
+    }
+
+    // Market and stop orders fill up to `max_slippage_bps` basis points worse
+    pub fn with_slippage(mut self, max_slippage_bps: u32) -> Self {
+        self.max_slippage_bps = max_slippage_bps;
+        self
+    }
+
+    // Every order is delayed by `latency` plus up to `jitter`
+    pub fn with_latency(mut self, latency: Duration, jitter: Duration) -> Self {
+        self.latency = latency;
+        self.latency_jitter = jitter;
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub async fn run<S: Strategy>(
+        &self,
+        strategy: &mut S,
+        symbols: &[String],
+        interval: Interval,
+        range: Range<DateTime<Utc>>,
+    ) -> Result<BacktestReport, Error> {
+        let mut events = Vec::new();
+        for (index, symbol) in symbols.iter().enumerate() {
+            let candles = self
+                .cache
+                .get_candles(symbol.clone(), interval, range.clone())
+                .await?;
+            events.extend(candles.into_iter().map(|candle| (index, candle)));
+        }
+        // Symbols closing at the same time are handled in the given order
+        events.sort_by_key(|(index, candle)| (candle.time, *index));
+
+        let mut account = Account::new(self.initial_cash, self.seed);
+        let mut equity_curve = Vec::new();
+        for (position, (index, candle)) in events.iter().enumerate() {
+            let symbol = &symbols[*index];
+            for fill in self.fill_orders(&mut account, symbol, candle) {
+                strategy.on_fill(&fill);
+            }
+            account.prices.insert(symbol.clone(), candle.close);
+            account.portfolio.mark_candle(symbol, candle);
+
+            let close_time = candle.time + interval.duration();
+            let mut context = StrategyContext {
+                backtest: self,
+                account: &mut account,
+                time: close_time,
+            };
+            strategy.on_candle(&mut context, symbol, candle);
+
+            // Equity is recorded once all symbols of a close time were handled
+            let next_time = events.get(position + 1).map(|(_, next)| next.time);
+            if next_time != Some(candle.time) {
+                equity_curve.push((close_time, account.equity()));
+            }
+        }
+
+        let equity: Vec<f64> = equity_curve
+            .iter()
+            .map(|(_, value)| value.to_f64())
+            .collect();
+        let final_equity = account.equity();
+        Ok(BacktestReport {
+            initial_equity: self.initial_cash,
+            final_equity,
+            pnl: final_equity - self.initial_cash,
+            total_fees: account.fills.iter().map(|fill| fill.fee).sum(),
+            sharpe: sharpe_ratio(&equity, SECONDS_PER_YEAR / interval.seconds() as f64),
+            max_drawdown: max_drawdown(&equity),
+            trades: account.fills,
+            equity_curve,
+            rejected_orders: account.rejected_orders,
+            expired_orders: account.expired_orders,
+            open_orders: account.pending.len(),
+        })
+    }
+
+    fn fill_orders(&self, account: &mut Account, symbol: &str, candle: &Candle) -> Vec<Fill> {
+        let mut fills = Vec::new();
+        let mut index = 0;
+        while index < account.pending.len() {
+            let pending = &mut account.pending[index];
+            if pending.order.symbol != symbol || pending.active_at > candle.time {
+                index += 1;
+                continue;
+            }
+            let rng = &mut account.rng;
+            let slipped = |side, price| self.slipped(rng, side, price);
+            let price = match pending.fill_price(candle, slipped) {
+                Some(price) => price,
+                None if pending.order.time_in_force == TimeInForce::Gtc => {
+                    index += 1;
+                    continue;
+                }
+                None => {
+                    account.pending.remove(index);
+                    account.expired_orders += 1;
+                    continue;
+                }
+            };
+
+            let pending = account.pending.remove(index);
+            match account.execute(pending.order, price, self.fee_rate, candle.time) {
+                Some(fill) => fills.push(fill),
+                None => account.rejected_orders += 1,
+            }
+        }
+        fills
+    }
+
+    fn slipped(&self, rng: &mut SplitMix64, side: Side, price: Decimal) -> Option<Decimal> {
+        let bps = rng.below(self.max_slippage_bps as u64 + 1) as i64;
+        let slippage = Decimal::new(bps, 4);
+        let factor = match side {
+            Side::Buy => Decimal::ONE + slippage,
+            Side::Sell => Decimal::ONE - slippage,
+        };
+        price.checked_mul(factor)
+    }
+}
+
+// Portfolio and orders of a running backtest
+struct Account {
+    portfolio: Portfolio,
+    // Last close of every symbol
+    prices: HashMap<String, Decimal>,
+    pending: Vec<PendingOrder>,
+    next_order_id: u64,
+    fills: Vec<Fill>,
+    rejected_orders: usize,
+    expired_orders: usize,
+    rng: SplitMix64,
+}
+
+impl Account {
+    fn new(cash: Decimal, seed: u64) -> Self {
+        Account {
+            portfolio: Portfolio::new(cash),
+            prices: HashMap::new(),
+            pending: Vec::new(),
+            next_order_id: 1,
+            fills: Vec::new(),
+            rejected_orders: 0,
+            expired_orders: 0,
+            rng: SplitMix64::new(seed),
+        }
+    }
+
+    fn equity(&self) -> Decimal {
+        self.portfolio.equity().expect("Equity overflow")
+    }
+
+    // `None` if the cash or the position does not cover the order
+    fn execute(
+        &mut self,
+        order: Order,
+        price: Decimal,
+        fee_rate: Decimal,
+        time: DateTime<Utc>,
+    ) -> Option<Fill> {
+        let notional = price.checked_mul(order.quantity)?;
+        let fee = notional.checked_mul(fee_rate)?;
+        let covered = match order.side {
+            Side::Buy => self.portfolio.cash() >= notional.checked_add(fee)?,
+            Side::Sell => self.portfolio.quantity(&order.symbol) >= order.quantity,
+        };
+        if !covered {
+            return None;
+        }
+
+        let fill = Fill {
+            client_order_id: order.client_order_id,
+            symbol: order.symbol,
+            side: order.side,
+            quantity: order.quantity,
+            price,
+            fee,
+            time,
+        };
+        self.portfolio.apply_fill(&fill)?;
+        self.fills.push(fill.clone());
+        Some(fill)
+    }
+}
+
+// View of the account handed to strategies
+pub struct StrategyContext<'a> {
+    backtest: &'a Backtest,
+    account: &'a mut Account,
+    time: DateTime<Utc>,
+}
+
+impl StrategyContext<'_> {
+    // Close time of the current candle
+    pub fn time(&self) -> DateTime<Utc> {
+        self.time
+    }
+
+    pub fn portfolio(&self) -> &Portfolio {
+        &self.account.portfolio
+    }
+
+    // Last close of `symbol`
+    pub fn price(&self, symbol: &str) -> Option<Decimal> {
+        self.account.prices.get(symbol).copied()
+    }
+
+    // Returns the client order id, a generated one if the order has none. Orders with a quantity
+    // that is not positive are ignored.
+    pub fn submit(&mut self, mut order: Order) -> Option<String> {
+        if !order.quantity.is_positive() {
+            return None;
+        }
+        if order.client_order_id.is_empty() {
+            order.client_order_id = format!("backtest-{}", self.account.next_order_id);
+        }
+        self.account.next_order_id += 1;
+
+        let jitter_ms = self.backtest.latency_jitter.num_milliseconds().max(0) as u64;
+        let jitter = Duration::milliseconds(self.account.rng.below(jitter_ms + 1) as i64);
+        let client_order_id = order.client_order_id.clone();
+        self.account.pending.push(PendingOrder::new(
+            order,
+            self.time + self.backtest.latency + jitter,
+        ));
+        Some(client_order_id)
+    }
+
+    // Returns whether the order was still open
+    pub fn cancel(&mut self, client_order_id: &str) -> bool {
+        let before = self.account.pending.len();
+        self.account
+            .pending
+            .retain(|pending| pending.order.client_order_id != client_order_id);
+        self.account.pending.len() < before
+    }
+
+    pub fn open_orders(&self) -> impl Iterator<Item = &Order> {
+        self.account.pending.iter().map(|pending| &pending.order)
+    }
+}
+
+// Small seedable generator, so reports stay reproducible across versions of external crates
+#[derive(Debug, Clone)]
+struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    fn new(seed: u64) -> Self {
+        SplitMix64 { state: seed }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // Uniform below `bound`, which has to be positive
+    fn below(&mut self, bound: u64) -> u64 {
+        if bound == 1 {
+            return 0;
+        }
+        // Rejects the values that would favour small results
+        let zone = u64::MAX - u64::MAX % bound;
+        loop {
+            let value = self.next_u64();
+            if value < zone {
+                return value % bound;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Backtest, SplitMix64, Strategy, StrategyContext};
+    use crate::data::database::{in_memory::InMemoryStockDataCache, StockDataCache};
+    use crate::models::{
+        candle::Candle,
+        decimal::Decimal,
+        interval::Interval,
+        order::{Fill, Order, OrderType, TimeInForce},
+        trade::Side,
+    };
+    use crate::test_data::{dec, minute};
+    use chrono::{DateTime, Duration, Utc};
+    use std::sync::Arc;
+
+    // Candles opening at the previous close
+    fn candles(closes: &[&str]) -> Vec<Candle> {
+        let mut open = dec(closes[0]);
+        closes
+            .iter()
+            .enumerate()
+            .map(|(index, close)| {
+                let close = dec(close);
+                let candle = Candle {
+                    open,
+                    high: open.max(close),
+                    low: open.min(close),
+                    close,
+                    volume: Decimal::from(10),
+                    time: minute(index as i64),
+                };
+                open = close;
+                candle
+            })
+            .collect()
+    }
+
+    async fn backtest(series: &[(&str, &[&str])]) -> Backtest {
+        let cache = InMemoryStockDataCache::new();
+        for (symbol, closes) in series {
+            cache
+                .write_candles(symbol.to_string(), Interval::OneMinute, candles(closes))
+                .await
+                .unwrap();
+        }
+        Backtest::new(Arc::new(cache), Decimal::from(1000))
+    }
+
+    // Submits the given orders at the given candle indices and records what it sees
+    #[derive(Default)]
+    struct Scripted {
+        orders: Vec<(usize, Order)>,
+        seen: usize,
+        fills: Vec<Fill>,
+        times: Vec<DateTime<Utc>>,
+    }
+
+    impl Scripted {
+        fn new(orders: Vec<(usize, Order)>) -> Self {
+            Scripted {
+                orders,
+                ..Default::default()
+            }
+        }
+    }
+
+    impl Strategy for Scripted {
+        fn on_candle(
+            &mut self,
+            context: &mut StrategyContext<'_>,
+            _symbol: &str,
+            _candle: &Candle,
+        ) {
+            for (at, order) in &self.orders {
+                if *at == self.seen {
+                    context.submit(order.clone());
+                }
+            }
+            self.times.push(context.time());
+            self.seen += 1;
+        }
+
+        fn on_fill(&mut self, fill: &Fill) {
+            self.fills.push(fill.clone());
+        }
+    }
+
+    fn order(side: Side, quantity: &str, order_type: OrderType) -> Order {
+        Order {
+            order_type,
+            ..Order::market(String::new(), "BTCUSDT".to_string(), side, dec(quantity))
+        }
+    }
+
+    fn symbols() -> Vec<String> {
+        vec!["BTCUSDT".to_string()]
+    }
+
+    #[tokio::test]
+    async fn market_orders_fill_at_the_next_open_with_fees() {
+        let backtest = backtest(&[("BTCUSDT", &["100", "110", "121", "110"])])
+            .await
+            .with_fee_rate(dec("0.001"));
+        let mut strategy = Scripted::new(vec![
+            (0, order(Side::Buy, "5", OrderType::Market)),
+            (2, order(Side::Sell, "5", OrderType::Market)),
+        ]);
+
+        let report = backtest
+            .run(
+                &mut strategy,
+                &symbols(),
+                Interval::OneMinute,
+                minute(0)..minute(4),
+            )
+            .await
+            .unwrap();
+
+        // Bought at 100 and sold at 121
+        assert_eq!(2, report.trades.len());
+        assert_eq!(
+            (dec("100"), minute(1)),
+            (report.trades[0].price, report.trades[0].time)
+        );
+        assert_eq!(
+            (dec("121"), minute(3)),
+            (report.trades[1].price, report.trades[1].time)
+        );
+        assert_eq!(dec("1.105"), report.total_fees);
+        assert_eq!(dec("103.895"), report.pnl);
+        assert_eq!(report.final_equity, report.equity_curve.last().unwrap().1);
+        assert_eq!(report.trades, strategy.fills);
+        // Strategies see candles at their close
+        assert_eq!(minute(1), strategy.times[0]);
+        // Peak of 1104.5 down to 1103.895 once the fee was paid
+        assert!(report.max_drawdown > 0.0);
+        assert!(report.sharpe.unwrap() > 0.0);
+    }
+
+    #[tokio::test]
+    async fn latency_delays_fills() {
+        let backtest = backtest(&[("BTCUSDT", &["100", "110", "121", "110"])])
+            .await
+            .with_latency(Duration::seconds(90), Duration::zero());
+        let mut strategy = Scripted::new(vec![(0, order(Side::Buy, "1", OrderType::Market))]);
+
+        let report = backtest
+            .run(
+                &mut strategy,
+                &symbols(),
+                Interval::OneMinute,
+                minute(0)..minute(4),
+            )
+            .await
+            .unwrap();
+
+        // Decided at minute 1, reaching the exchange at 2:30
+        assert_eq!(minute(3), report.trades[0].time);
+        assert_eq!(dec("121"), report.trades[0].price);
+    }
+
+    #[tokio::test]
+    async fn limit_orders_wait_for_their_price() {
+        let backtest = backtest(&[("BTCUSDT", &["100", "110", "121", "110", "95"])]).await;
+        let mut strategy = Scripted::new(vec![
+            (
+                0,
+                order(Side::Buy, "1", OrderType::Limit { price: dec("95") }),
+            ),
+            (
+                0,
+                order(Side::Sell, "1", OrderType::Limit { price: dec("130") }),
+            ),
+        ]);
+
+        let report = backtest
+            .run(
+                &mut strategy,
+                &symbols(),
+                Interval::OneMinute,
+                minute(0)..minute(5),
+            )
+            .await
+            .unwrap();
+
+        // The last candle is the first one trading at 95
+        assert_eq!(1, report.trades.len());
+        assert_eq!(
+            (dec("95"), minute(4)),
+            (report.trades[0].price, report.trades[0].time)
+        );
+        assert_eq!(1, report.open_orders);
+    }
+
+    #[tokio::test]
+    async fn stop_orders_trigger_at_their_price() {
+        let backtest = backtest(&[("BTCUSDT", &["100", "110", "121", "110", "95"])]).await;
+        let mut strategy = Scripted::new(vec![
+            (0, order(Side::Buy, "2", OrderType::Market)),
+            (
+                0,
+                order(
+                    Side::Buy,
+                    "1",
+                    OrderType::Stop {
+                        stop_price: dec("115"),
+                    },
+                ),
+            ),
+            (
+                2,
+                order(
+                    Side::Sell,
+                    "2",
+                    OrderType::StopLimit {
+                        stop_price: dec("112"),
+                        price: dec("100"),
+                    },
+                ),
+            ),
+        ]);
+
+        let report = backtest
+            .run(
+                &mut strategy,
+                &symbols(),
+                Interval::OneMinute,
+                minute(0)..minute(5),
+            )
+            .await
+            .unwrap();
+
+        let fills: Vec<_> = report
+            .trades
+            .iter()
+            .map(|fill| (fill.side, fill.price, fill.time))
+            .collect();
+        assert_eq!(
+            vec![
+                (Side::Buy, dec("100"), minute(1)),
+                // Candle from 110 to 121 crosses the stop
+                (Side::Buy, dec("115"), minute(2)),
+                // Triggered by the candle down to 110, rests as limit from the next one
+                (Side::Sell, dec("110"), minute(4)),
+            ],
+            fills
+        );
+        assert_eq!(0, report.open_orders);
+        // Ids are generated for orders without one
+        assert_eq!("backtest-2", report.trades[1].client_order_id);
+    }
+
+    #[tokio::test]
+    async fn immediate_orders_expire_unfilled() {
+        let backtest = backtest(&[("BTCUSDT", &["100", "110", "121", "110", "95"])]).await;
+        let mut strategy = Scripted::new(vec![
+            (
+                0,
+                order(Side::Buy, "1", OrderType::Limit { price: dec("95") })
+                    .with_time_in_force(TimeInForce::Ioc),
+            ),
+            (
+                0,
+                order(Side::Buy, "1", OrderType::Limit { price: dec("105") })
+                    .with_time_in_force(TimeInForce::Fok),
+            ),
+        ]);
+
+        let report = backtest
+            .run(
+                &mut strategy,
+                &symbols(),
+                Interval::OneMinute,
+                minute(0)..minute(5),
+            )
+            .await
+            .unwrap();
+
+        // Only the FOK order traded on the first candle it saw
+        assert_eq!(1, report.trades.len());
+        assert_eq!(dec("100"), report.trades[0].price);
+        assert_eq!(1, report.expired_orders);
+        assert_eq!(0, report.open_orders);
+    }
+
+    #[tokio::test]
+    async fn uncovered_orders_are_rejected() {
+        let backtest = backtest(&[("BTCUSDT", &["100", "110", "121"])]).await;
+        let mut strategy = Scripted::new(vec![
+            (0, order(Side::Buy, "100", OrderType::Market)),
+            (0, order(Side::Sell, "1", OrderType::Market)),
+        ]);
+
+        let report = backtest
+            .run(
+                &mut strategy,
+                &symbols(),
+                Interval::OneMinute,
+                minute(0)..minute(3),
+            )
+            .await
+            .unwrap();
+
+        assert!(report.trades.is_empty());
+        assert_eq!(2, report.rejected_orders);
+        assert_eq!(Decimal::ZERO, report.pnl);
+    }
+
+    #[tokio::test]
+    async fn symbols_are_replayed_in_event_time() {
+        let backtest = backtest(&[
+            ("BTCUSDT", &["100", "101", "102"]),
+            ("ETHUSDT", &["10", "11", "12"]),
+        ])
+        .await;
+        let mut strategy = Scripted::default();
+
+        let report = backtest
+            .run(
+                &mut strategy,
+                &["BTCUSDT".to_string(), "ETHUSDT".to_string()],
+                Interval::OneMinute,
+                minute(0)..minute(3),
+            )
+            .await
+            .unwrap();
+
+        assert!(strategy.times.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert_eq!(6, strategy.times.len());
+        // One equity point per close time
+        assert_eq!(3, report.equity_curve.len());
+    }
+
+    #[tokio::test]
+    async fn runs_are_deterministic_for_a_seed() {
+        let closes = ["100", "110", "121", "110", "100", "90", "100"];
+        let orders: Vec<_> = (0..6)
+            .map(|index| {
+                let side = if index % 2 == 0 {
+                    Side::Buy
+                } else {
+                    Side::Sell
+                };
+                (index, order(side, "1", OrderType::Market))
+            })
+            .collect();
+        let run = |seed: u64| {
+            let orders = orders.clone();
+            async move {
+                backtest(&[("BTCUSDT", &closes)])
+                    .await
+                    .with_slippage(50)
+                    .with_latency(Duration::seconds(30), Duration::seconds(60))
+                    .with_seed(seed)
+                    .run(
+                        &mut Scripted::new(orders),
+                        &symbols(),
+                        Interval::OneMinute,
+                        minute(0)..minute(7),
+                    )
+                    .await
+                    .unwrap()
+            }
+        };
+
+        let first = run(7).await;
+        assert_eq!(first, run(7).await);
+        assert_ne!(first.trades, run(8).await.trades);
+        // Slippage never helps
+        for trade in &first.trades {
+            let open = dec(closes[(trade.time - minute(0)).num_minutes() as usize - 1]);
+            match trade.side {
+                Side::Buy => assert!(trade.price >= open && trade.price <= open * dec("1.005")),
+                Side::Sell => assert!(trade.price <= open && trade.price >= open * dec("0.995")),
+            }
+        }
+    }
+
+    #[test]
+    fn generator_stays_below_the_bound() {
+        let mut rng = SplitMix64::new(1);
+
+        assert!((0..1000).all(|_| rng.below(7) < 7));
+        assert_eq!(0, rng.below(1));
+    }
+}
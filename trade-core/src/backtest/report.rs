@@ -0,0 +1,88 @@
+use crate::models::{decimal::Decimal, order::Fill};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BacktestReport {
+    pub initial_equity: Decimal,
+    pub final_equity: Decimal,
+    pub pnl: Decimal,
+    pub total_fees: Decimal,
+    // Annualized from the per candle returns without risk-free rate, `None` for flat equity
+    pub sharpe: Option<f64>,
+    // Largest loss from a previous peak as fraction of the peak
+    pub max_drawdown: f64,
+    pub trades: Vec<Fill>,
+    // Cash plus positions at their last close, after every candle close
+    pub equity_curve: Vec<(DateTime<Utc>, Decimal)>,
+    // Orders the cash or position did not cover
+    pub rejected_orders: usize,
+    // IOC and FOK orders that could not be filled
+    pub expired_orders: usize,
+    // Orders still open at the end
+    pub open_orders: usize,
+}
+
+impl BacktestReport {
+    // Relative to the initial equity
+    pub fn total_return(&self) -> f64 {
+        self.pnl.to_f64() / self.initial_equity.to_f64()
+    }
+}
+
+pub(crate) fn sharpe_ratio(equity: &[f64], periods_per_year: f64) -> Option<f64> {
+    let returns: Vec<f64> = equity
+        .windows(2)
+        .map(|pair| pair[1] / pair[0] - 1.0)
+        .collect();
+    if returns.len() < 2 {
+        return None;
+    }
+    let count = returns.len() as f64;
+    let mean = returns.iter().sum::<f64>() / count;
+    let variance = returns
+        .iter()
+        .map(|value| (value - mean).powi(2))
+        .sum::<f64>()
+        / (count - 1.0);
+    if variance <= 0.0 {
+        return None;
+    }
+    Some(mean / variance.sqrt() * periods_per_year.sqrt())
+}
+
+pub(crate) fn max_drawdown(equity: &[f64]) -> f64 {
+    let mut peak = f64::MIN;
+    let mut drawdown: f64 = 0.0;
+    for &value in equity {
+        peak = peak.max(value);
+        if peak > 0.0 {
+            drawdown = drawdown.max((peak - value) / peak);
+        }
+    }
+    drawdown
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{max_drawdown, sharpe_ratio};
+
+    #[test]
+    fn sharpe_matches_reference() {
+        // Returns of 10%, -5%, 8% and 2%
+        let equity = [100.0, 110.0, 104.5, 112.86, 115.1172];
+
+        let sharpe = sharpe_ratio(&equity, 252.0).unwrap();
+        assert!((sharpe - 8.8172).abs() < 1e-4, "{}", sharpe);
+        assert_eq!(None, sharpe_ratio(&[100.0, 100.0, 100.0], 252.0));
+        assert_eq!(None, sharpe_ratio(&[100.0, 110.0], 252.0));
+    }
+
+    #[test]
+    fn drawdown_is_measured_from_the_peak() {
+        let equity = [100.0, 120.0, 90.0, 130.0, 117.0];
+
+        assert!((max_drawdown(&equity) - 0.25).abs() < 1e-12);
+        assert_eq!(0.0, max_drawdown(&[100.0, 101.0, 102.0]));
+    }
+}
@@ -0,0 +1,824 @@
+use super::StockDataCache;
+use crate::models::{
+    candle::Candle,
+    decimal::{Decimal, MAX_SCALE},
+    interval::Interval,
+};
+use crate::Error;
+use async_trait::async_trait;
+use chrono::{DateTime, TimeZone, Utc};
+use std::collections::{BTreeMap, HashMap};
+use std::io::SeekFrom;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::Mutex;
+
+// "CND2"
+const BLOCK_MAGIC: u32 = 0x3244_4e43;
+// Magic, count, min time, max time
+const HEADER_SIZE: u64 = 4 + 4 + 8 + 8;
+const CHECKSUM_SIZE: u64 = 4;
+// Time and the units of open, high, low, close and volume, followed by their scales
+const CANDLE_SIZE: u64 = 6 * 8 + 5;
+// Most candles of a block written by a compaction
+const COMPACTED_BLOCK_CANDLES: usize = 4096;
+// Blocks a segment may have before it is compacted, at least
+const COMPACTION_MIN_BLOCKS: usize = 64;
+
+// Stores candles on local disk, one append-only segment file per (symbol, interval).
+//
+// Every write appends one block holding its candles column by column, followed by a CRC32 of the
+// block. The time range of every block is kept in memory as index, so range scans only read the
+// blocks they need. Blocks may overlap, the most recently written candle wins. Once a segment has
+// more than twice the blocks it needs, e.g. from streaming single candles, it is rewritten as
+// sorted blocks without overlaps. A torn block at the end of a segment, e.g. after a crash during
+// an append, is ignored and cut off by the next append. Reads never create or modify files.
+// Every segment has its own lock, so different series are read and written concurrently.
+pub struct DiskStockDataCache {
+    root: PathBuf,
+    series: Mutex<HashMap<(String, Interval), SharedSegment>>,
+}
+
+type SharedSegment = Arc<Mutex<Segment>>;
+
+impl DiskStockDataCache {
+    pub fn open<P: AsRef<Path>>(root: P) -> Result<Self, Error> {
+        std::fs::create_dir_all(root.as_ref())?;
+        Ok(DiskStockDataCache {
+            root: root.as_ref().to_path_buf(),
+            series: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // `None` if the series has no segment yet, unless `create` is set
+    async fn segment(
+        &self,
+        symbol: String,
+        interval: Interval,
+        create: bool,
+    ) -> Result<Option<SharedSegment>, Error> {
+        let key = (symbol, interval);
+        // Held while opening, so a segment is never opened twice
+        let mut series = self.series.lock().await;
+        if let Some(segment) = series.get(&key) {
+            return Ok(Some(Arc::clone(segment)));
+        }
+        let path = segment_path(&self.root, &key.0, interval);
+        let segment = if create {
+            Segment::create(path).await?
+        } else {
+            match Segment::open(path).await? {
+                Some(segment) => segment,
+                None => return Ok(None),
+            }
+        };
+        let segment = Arc::new(Mutex::new(segment));
+        series.insert(key, Arc::clone(&segment));
+        Ok(Some(segment))
+    }
+}
+
+#[async_trait]
+impl StockDataCache for DiskStockDataCache {
+    async fn write_candles(
+        &self,
+        symbol: String,
+        interval: Interval,
+        candles: Vec<Candle>,
+    ) -> Result<(), Error> {
+        if candles.is_empty() {
+            return Ok(());
+        }
+        let segment = self.segment(symbol, interval, true).await?;
+        let mut segment = segment
+            .as_ref()
+            .expect("Created segment is missing")
+            .lock()
+            .await;
+        segment.append(candles).await
+    }
+    async fn get_candles(
+        &self,
+        symbol: String,
+        interval: Interval,
+        range: Range<DateTime<Utc>>,
+    ) -> Result<Vec<Candle>, Error> {
+        Error::check_range(&range)?;
+        let start = nanoseconds(range.start)?;
+        let end = nanoseconds(range.end)?;
+        match self.segment(symbol, interval, false).await? {
+            Some(segment) => segment.lock().await.scan(start, end).await,
+            None => Ok(Vec::new()),
+        }
+    }
+    async fn get_first_candle(
+        &self,
+        symbol: String,
+        interval: Interval,
+    ) -> Result<Option<Candle>, Error> {
+        let segment = match self.segment(symbol, interval, false).await? {
+            Some(segment) => segment,
+            None => return Ok(None),
+        };
+        let mut segment = segment.lock().await;
+        match segment.index.iter().map(|block| block.min_time).min() {
+            Some(first) => Ok(segment.scan(first, first + 1).await?.into_iter().next()),
+            None => Ok(None),
+        }
+    }
+    async fn get_last_candle(
+        &self,
+        symbol: String,
+        interval: Interval,
+    ) -> Result<Option<Candle>, Error> {
+        let segment = match self.segment(symbol, interval, false).await? {
+            Some(segment) => segment,
+            None => return Ok(None),
+        };
+        let mut segment = segment.lock().await;
+        match segment.index.iter().map(|block| block.max_time).max() {
+            Some(last) => Ok(segment.scan(last, last + 1).await?.into_iter().next()),
+            None => Ok(None),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct BlockInfo {
+    offset: u64,
+    count: u32,
+    min_time: i64,
+    max_time: i64,
+}
+
+impl BlockInfo {
+    fn len(&self) -> u64 {
+        block_len(self.count)
+    }
+}
+
+struct Segment {
+    path: PathBuf,
+    // Read only, appends go through `writer`
+    file: File,
+    writer: Option<File>,
+    index: Vec<BlockInfo>,
+    // End of the last valid block
+    len: u64,
+}
+
+impl Segment {
+    // `None` if there is no segment at `path`
+    async fn open(path: PathBuf) -> Result<Option<Self>, Error> {
+        let mut file = match File::open(&path).await {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let file_len = file.metadata().await?.len();
+
+        let mut index = Vec::new();
+        let mut offset = 0;
+        while offset < file_len {
+            match read_block(&mut file, offset, file_len).await? {
+                BlockRead::Valid(info, _) => {
+                    offset += info.len();
+                    index.push(info);
+                }
+                // Left behind by an interrupted append, which is always the last block
+                BlockRead::Torn => break,
+                BlockRead::Corrupt => {
+                    return Err(Error::decode(format!(
+                        "Corrupted block at offset {} of {}",
+                        offset,
+                        path.display()
+                    )));
+                }
+            }
+        }
+
+        Ok(Some(Segment {
+            path,
+            file,
+            writer: None,
+            index,
+            len: offset,
+        }))
+    }
+
+    async fn create(path: PathBuf) -> Result<Self, Error> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .await?;
+        Self::open(path.clone())
+            .await?
+            .ok_or_else(|| Error::NotFound(path.display().to_string()))
+    }
+
+    async fn append(&mut self, candles: Vec<Candle>) -> Result<(), Error> {
+        let mut by_time = BTreeMap::new();
+        for candle in candles {
+            by_time.insert(nanoseconds(candle.time)?, candle);
+        }
+        let candles: Vec<(i64, Candle)> = by_time.into_iter().collect();
+        let (block, info) = encode_block(&candles, self.len);
+
+        if self.writer.is_none() {
+            let writer = OpenOptions::new().write(true).open(&self.path).await?;
+            // Cuts off a torn block
+            writer.set_len(self.len).await?;
+            self.writer = Some(writer);
+        }
+        let writer = self.writer.as_mut().unwrap();
+        writer.seek(SeekFrom::Start(self.len)).await?;
+        writer.write_all(&block).await?;
+        writer.sync_data().await?;
+        self.index.push(info);
+        self.len += block.len() as u64;
+
+        let stored: usize = self.index.iter().map(|block| block.count as usize).sum();
+        let needed = stored.div_ceil(COMPACTED_BLOCK_CANDLES);
+        if self.index.len() > COMPACTION_MIN_BLOCKS.max(2 * needed) {
+            self.compact().await?;
+        }
+        Ok(())
+    }
+
+    // Rewrites the segment as sorted blocks without overlaps. They are written next to the
+    // segment and renamed over it, so a crash leaves either version behind.
+    async fn compact(&mut self) -> Result<(), Error> {
+        let candles = self.scan(i64::MIN, i64::MAX).await?;
+        let candles = candles
+            .into_iter()
+            .map(|candle| Ok((nanoseconds(candle.time)?, candle)))
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let temporary = self.path.with_extension("seg.tmp");
+        let mut file = File::create(&temporary).await?;
+        let mut index = Vec::new();
+        let mut len = 0;
+        for chunk in candles.chunks(COMPACTED_BLOCK_CANDLES) {
+            let (block, info) = encode_block(chunk, len);
+            file.write_all(&block).await?;
+            index.push(info);
+            len += block.len() as u64;
+        }
+        file.sync_all().await?;
+        tokio::fs::rename(&temporary, &self.path).await?;
+
+        self.file = File::open(&self.path).await?;
+        self.writer = None;
+        self.index = index;
+        self.len = len;
+        Ok(())
+    }
+
+    // Candles with `start <= time < end` in nanoseconds
+    async fn scan(&mut self, start: i64, end: i64) -> Result<Vec<Candle>, Error> {
+        let blocks: Vec<BlockInfo> = self
+            .index
+            .iter()
+            .filter(|block| block.min_time < end && block.max_time >= start)
+            .copied()
+            .collect();
+
+        // Later blocks overwrite earlier ones
+        let mut by_time = BTreeMap::new();
+        for block in blocks {
+            let candles = match read_block(&mut self.file, block.offset, self.len).await? {
+                BlockRead::Valid(_, candles) => candles,
+                _ => return Err(Error::decode("Segment block changed on disk")),
+            };
+            for (time, candle) in candles {
+                if time >= start && time < end {
+                    by_time.insert(time, candle);
+                }
+            }
+        }
+        Ok(by_time.into_values().collect())
+    }
+}
+
+fn block_len(count: u32) -> u64 {
+    HEADER_SIZE + count as u64 * CANDLE_SIZE + CHECKSUM_SIZE
+}
+
+// Block at `offset` of candles sorted by their time in nanoseconds, at least one
+fn encode_block(candles: &[(i64, Candle)], offset: u64) -> (Vec<u8>, BlockInfo) {
+    let count = candles.len() as u32;
+    let min_time = candles[0].0;
+    let max_time = candles[candles.len() - 1].0;
+
+    let mut block = Vec::with_capacity(block_len(count) as usize);
+    block.extend_from_slice(&BLOCK_MAGIC.to_le_bytes());
+    block.extend_from_slice(&count.to_le_bytes());
+    block.extend_from_slice(&min_time.to_le_bytes());
+    block.extend_from_slice(&max_time.to_le_bytes());
+    for (time, _) in candles {
+        block.extend_from_slice(&time.to_le_bytes());
+    }
+    let columns: [fn(&Candle) -> Decimal; 5] = [
+        |candle| candle.open,
+        |candle| candle.high,
+        |candle| candle.low,
+        |candle| candle.close,
+        |candle| candle.volume,
+    ];
+    for column in columns {
+        for (_, candle) in candles {
+            block.extend_from_slice(&column(candle).units().to_le_bytes());
+        }
+    }
+    for column in columns {
+        block.extend(candles.iter().map(|(_, candle)| column(candle).scale()));
+    }
+    let checksum = crc32fast::hash(&block);
+    block.extend_from_slice(&checksum.to_le_bytes());
+
+    let info = BlockInfo {
+        offset,
+        count,
+        min_time,
+        max_time,
+    };
+    (block, info)
+}
+
+enum BlockRead {
+    Valid(BlockInfo, Vec<(i64, Candle)>),
+    // Incomplete or broken block at the end of the segment, no valid block follows it
+    Torn,
+    // Broken block followed by more data
+    Corrupt,
+}
+
+async fn read_block(file: &mut File, offset: u64, file_len: u64) -> Result<BlockRead, Error> {
+    if offset + HEADER_SIZE > file_len {
+        return Ok(BlockRead::Torn);
+    }
+    let mut header = [0u8; HEADER_SIZE as usize];
+    file.seek(SeekFrom::Start(offset)).await?;
+    file.read_exact(&mut header).await?;
+
+    let magic = u32::from_le_bytes(header[0..4].try_into().unwrap());
+    let count = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    let len = block_len(count);
+    if magic != BLOCK_MAGIC || count == 0 || offset + len > file_len {
+        // The header cannot be trusted, so neither can the length. An interrupted append
+        // leaves nothing valid behind it.
+        let mut rest = vec![0u8; (file_len - offset - 1) as usize];
+        file.seek(SeekFrom::Start(offset + 1)).await?;
+        file.read_exact(&mut rest).await?;
+        return Ok(if contains_valid_block(&rest) {
+            BlockRead::Corrupt
+        } else {
+            BlockRead::Torn
+        });
+    }
+
+    let mut body = vec![0u8; (len - HEADER_SIZE) as usize];
+    file.read_exact(&mut body).await?;
+    let (payload, checksum) = body.split_at(body.len() - CHECKSUM_SIZE as usize);
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&header);
+    hasher.update(payload);
+    if hasher.finalize() != u32::from_le_bytes(checksum.try_into().unwrap()) {
+        return Ok(if offset + len < file_len {
+            BlockRead::Corrupt
+        } else {
+            BlockRead::Torn
+        });
+    }
+
+    let count = count as usize;
+    let word = |column: usize, row: usize| -> [u8; 8] {
+        let start = (column * count + row) * 8;
+        payload[start..start + 8].try_into().unwrap()
+    };
+    let scales = &payload[6 * 8 * count..];
+    let value = |column: usize, row: usize| -> Result<Decimal, Error> {
+        let scale = scales[(column - 1) * count + row];
+        if scale > MAX_SCALE {
+            return Err(Error::decode(format!("Invalid scale {}", scale)));
+        }
+        Ok(Decimal::new(i64::from_le_bytes(word(column, row)), scale))
+    };
+    let mut candles = Vec::with_capacity(count);
+    for row in 0..count {
+        let time = i64::from_le_bytes(word(0, row));
+        candles.push((
+            time,
+            Candle {
+                open: value(1, row)?,
+                high: value(2, row)?,
+                low: value(3, row)?,
+                close: value(4, row)?,
+                volume: value(5, row)?,
+                time: Utc.timestamp_nanos(time),
+            },
+        ));
+    }
+
+    let info = BlockInfo {
+        offset,
+        count: count as u32,
+        min_time: i64::from_le_bytes(header[8..16].try_into().unwrap()),
+        max_time: i64::from_le_bytes(header[16..24].try_into().unwrap()),
+    };
+    Ok(BlockRead::Valid(info, candles))
+}
+
+// Whether a block with a matching checksum starts anywhere in `bytes`
+fn contains_valid_block(bytes: &[u8]) -> bool {
+    (0..bytes.len().saturating_sub(HEADER_SIZE as usize - 1)).any(|start| {
+        let word = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        let magic = word(start);
+        let count = word(start + 4);
+        if magic != BLOCK_MAGIC || count == 0 {
+            return false;
+        }
+        let len = block_len(count) as usize;
+        if start + len > bytes.len() {
+            return false;
+        }
+        let checksum_at = start + len - CHECKSUM_SIZE as usize;
+        crc32fast::hash(&bytes[start..checksum_at]) == word(checksum_at)
+    })
+}
+
+// Only times between the years 1677 and 2262 can be stored
+fn nanoseconds(time: DateTime<Utc>) -> Result<i64, Error> {
+    time.timestamp_nanos_opt()
+        .ok_or_else(|| Error::decode(format!("{} cannot be stored", time)))
+}
+
+// Symbols may contain characters that are not allowed in file names
+fn segment_path(root: &Path, symbol: &str, interval: Interval) -> PathBuf {
+    let directory: String = symbol
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c.to_string()
+            } else {
+                format!("%{:02X}", c as u32)
+            }
+        })
+        .collect();
+    root.join(directory).join(format!("{}.seg", interval))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{segment_path, DiskStockDataCache};
+    use crate::data::database::StockDataCache;
+    use crate::models::{candle::Candle, decimal::Decimal, interval::Interval};
+    use crate::test_data::{candle, minute};
+    use std::io::Write;
+    use std::path::Path;
+
+    async fn closes(cache: &DiskStockDataCache, range: std::ops::Range<i64>) -> Vec<f64> {
+        cache
+            .get_candles(
+                "BTCUSDT".to_string(),
+                Interval::OneMinute,
+                minute(range.start)..minute(range.end),
+            )
+            .await
+            .unwrap()
+            .iter()
+            .map(|candle| candle.close.to_f64())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn candles_survive_reopening() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let cache = DiskStockDataCache::open(dir.path()).unwrap();
+            cache
+                .write_candles(
+                    "BTCUSDT".to_string(),
+                    Interval::OneMinute,
+                    (0..10).map(|i| candle(i, i as f64)).collect(),
+                )
+                .await
+                .unwrap();
+            cache
+                .write_candles(
+                    "BTCUSDT".to_string(),
+                    Interval::OneMinute,
+                    (10..20).rev().map(|i| candle(i, i as f64)).collect(),
+                )
+                .await
+                .unwrap();
+        }
+
+        let cache = DiskStockDataCache::open(dir.path()).unwrap();
+        assert_eq!(
+            (5..15).map(|i| i as f64).collect::<Vec<_>>(),
+            closes(&cache, 5..15).await
+        );
+        let first = cache
+            .get_first_candle("BTCUSDT".to_string(), Interval::OneMinute)
+            .await
+            .unwrap();
+        let last = cache
+            .get_last_candle("BTCUSDT".to_string(), Interval::OneMinute)
+            .await
+            .unwrap();
+        assert_eq!(Some(candle(0, 0.0)), first);
+        assert_eq!(Some(candle(19, 19.0)), last);
+    }
+
+    #[tokio::test]
+    async fn later_writes_win() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = DiskStockDataCache::open(dir.path()).unwrap();
+        for close in [1.0, 2.0] {
+            cache
+                .write_candles(
+                    "BTCUSDT".to_string(),
+                    Interval::OneMinute,
+                    (0..3).map(|i| candle(i, close)).collect(),
+                )
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(vec![2.0, 2.0, 2.0], closes(&cache, 0..10).await);
+    }
+
+    #[tokio::test]
+    async fn series_are_separated() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = DiskStockDataCache::open(dir.path()).unwrap();
+        cache
+            .write_candles(
+                "BTCUSDT".to_string(),
+                Interval::OneHour,
+                vec![candle(0, 1.0)],
+            )
+            .await
+            .unwrap();
+        cache
+            .write_candles(
+                "BTC/USDT".to_string(),
+                Interval::OneMinute,
+                vec![candle(0, 2.0)],
+            )
+            .await
+            .unwrap();
+
+        assert!(closes(&cache, 0..10).await.is_empty());
+        let other = cache
+            .get_last_candle("BTC/USDT".to_string(), Interval::OneMinute)
+            .await
+            .unwrap();
+        assert_eq!(Some(2.0), other.map(|candle| candle.close.to_f64()));
+    }
+
+    #[tokio::test]
+    async fn decimals_are_stored_exactly() {
+        let dir = tempfile::tempdir().unwrap();
+        // More significant digits than an f64 holds
+        let close: Decimal = "123456789.12345678".parse().unwrap();
+        {
+            let cache = DiskStockDataCache::open(dir.path()).unwrap();
+            cache
+                .write_candles(
+                    "BTCUSDT".to_string(),
+                    Interval::OneMinute,
+                    vec![Candle {
+                        close,
+                        ..candle(0, 0.0)
+                    }],
+                )
+                .await
+                .unwrap();
+        }
+
+        let cache = DiskStockDataCache::open(dir.path()).unwrap();
+        let candles = cache
+            .get_candles(
+                "BTCUSDT".to_string(),
+                Interval::OneMinute,
+                minute(0)..minute(1),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            vec![Candle {
+                close,
+                ..candle(0, 0.0)
+            }],
+            candles
+        );
+    }
+
+    fn append_garbage(root: &Path, bytes: &[u8]) {
+        let path = segment_path(root, "BTCUSDT", Interval::OneMinute);
+        let mut file = std::fs::OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(bytes).unwrap();
+    }
+
+    #[tokio::test]
+    async fn torn_append_is_discarded() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let cache = DiskStockDataCache::open(dir.path()).unwrap();
+            cache
+                .write_candles(
+                    "BTCUSDT".to_string(),
+                    Interval::OneMinute,
+                    (0..5).map(|i| candle(i, i as f64)).collect(),
+                )
+                .await
+                .unwrap();
+        }
+        // Header of a block whose candles never made it to disk
+        let mut torn = Vec::new();
+        torn.extend_from_slice(&super::BLOCK_MAGIC.to_le_bytes());
+        torn.extend_from_slice(&3u32.to_le_bytes());
+        torn.extend_from_slice(&[0u8; 20]);
+        append_garbage(dir.path(), &torn);
+
+        let cache = DiskStockDataCache::open(dir.path()).unwrap();
+        assert_eq!(5, closes(&cache, 0..10).await.len());
+
+        // Appending after the recovery works as usual
+        cache
+            .write_candles(
+                "BTCUSDT".to_string(),
+                Interval::OneMinute,
+                vec![candle(5, 5.0)],
+            )
+            .await
+            .unwrap();
+        drop(cache);
+        let cache = DiskStockDataCache::open(dir.path()).unwrap();
+        assert_eq!(
+            vec![0.0, 1.0, 2.0, 3.0, 4.0, 5.0],
+            closes(&cache, 0..10).await
+        );
+    }
+
+    #[tokio::test]
+    async fn corruption_before_the_end_is_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let cache = DiskStockDataCache::open(dir.path()).unwrap();
+            for i in 0..2 {
+                cache
+                    .write_candles(
+                        "BTCUSDT".to_string(),
+                        Interval::OneMinute,
+                        vec![candle(i, i as f64)],
+                    )
+                    .await
+                    .unwrap();
+            }
+        }
+        let path = segment_path(dir.path(), "BTCUSDT", Interval::OneMinute);
+        let mut bytes = std::fs::read(&path).unwrap();
+        // Close price of the first block
+        bytes[super::HEADER_SIZE as usize + 8 * 4] ^= 0xff;
+        std::fs::write(&path, bytes).unwrap();
+
+        let cache = DiskStockDataCache::open(dir.path()).unwrap();
+        assert!(cache
+            .get_candles(
+                "BTCUSDT".to_string(),
+                Interval::OneMinute,
+                minute(0)..minute(10)
+            )
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn corrupted_header_before_the_end_is_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let cache = DiskStockDataCache::open(dir.path()).unwrap();
+            for i in 0..3 {
+                cache
+                    .write_candles(
+                        "BTCUSDT".to_string(),
+                        Interval::OneMinute,
+                        vec![candle(i, i as f64)],
+                    )
+                    .await
+                    .unwrap();
+            }
+        }
+        let path = segment_path(dir.path(), "BTCUSDT", Interval::OneMinute);
+        let original = std::fs::read(&path).unwrap();
+        let block_len = original.len() / 3;
+
+        // Magic, zero count and a count past the end of the file of the middle block
+        let damages: [(usize, u32); 3] = [(0, 0xdead_beef), (4, 0), (4, u32::MAX)];
+        for (field, value) in damages {
+            let mut bytes = original.clone();
+            bytes[block_len + field..block_len + field + 4].copy_from_slice(&value.to_le_bytes());
+            std::fs::write(&path, &bytes).unwrap();
+
+            let cache = DiskStockDataCache::open(dir.path()).unwrap();
+            assert!(cache
+                .get_candles(
+                    "BTCUSDT".to_string(),
+                    Interval::OneMinute,
+                    minute(0)..minute(10)
+                )
+                .await
+                .is_err());
+            drop(cache);
+            // The valid blocks behind it are kept
+            assert_eq!(bytes, std::fs::read(&path).unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn lookups_do_not_create_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = DiskStockDataCache::open(dir.path()).unwrap();
+
+        assert!(closes(&cache, 0..10).await.is_empty());
+        assert_eq!(
+            None,
+            cache
+                .get_last_candle("ETHUSDT".to_string(), Interval::OneHour)
+                .await
+                .unwrap()
+        );
+        assert_eq!(0, std::fs::read_dir(dir.path()).unwrap().count());
+
+        // The series is found once it is written
+        cache
+            .write_candles(
+                "BTCUSDT".to_string(),
+                Interval::OneMinute,
+                vec![candle(0, 1.0)],
+            )
+            .await
+            .unwrap();
+        assert_eq!(vec![1.0], closes(&cache, 0..10).await);
+    }
+
+    #[tokio::test]
+    async fn small_writes_are_compacted() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let cache = DiskStockDataCache::open(dir.path()).unwrap();
+            for i in 0..200 {
+                cache
+                    .write_candles(
+                        "BTCUSDT".to_string(),
+                        Interval::OneMinute,
+                        vec![candle(i, i as f64)],
+                    )
+                    .await
+                    .unwrap();
+            }
+            // Overwrites a candle of a compacted block
+            cache
+                .write_candles(
+                    "BTCUSDT".to_string(),
+                    Interval::OneMinute,
+                    vec![candle(0, 1000.0)],
+                )
+                .await
+                .unwrap();
+        }
+
+        let path = segment_path(dir.path(), "BTCUSDT", Interval::OneMinute);
+        assert!(std::fs::metadata(&path).unwrap().len() < 200 * super::block_len(1));
+        let cache = DiskStockDataCache::open(dir.path()).unwrap();
+        let mut expected: Vec<f64> = (0..200).map(|i| i as f64).collect();
+        expected[0] = 1000.0;
+        assert_eq!(expected, closes(&cache, 0..200).await);
+    }
+
+    #[tokio::test]
+    async fn empty_series_has_no_candles() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = DiskStockDataCache::open(dir.path()).unwrap();
+
+        assert!(closes(&cache, 0..10).await.is_empty());
+        assert_eq!(
+            None,
+            cache
+                .get_first_candle("BTCUSDT".to_string(), Interval::OneMinute)
+                .await
+                .unwrap()
+        );
+    }
+}
@@ -1,18 +1,236 @@
 use super::StockDataCache;
-use crate::models::candle::Candle;
+use crate::models::{candle::Candle, interval::Interval};
+use crate::Error;
 use async_trait::async_trait;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, Utc};
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
 use std::ops::Range;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+// Candles ordered by time without duplicates, writes replace candles with the same time
+#[derive(Default)]
+struct Series {
+    candles: Vec<Candle>,
+    last_access: AtomicU64,
+    // Candles before this time were dropped by the capacity or retention limit
+    dropped_before: Option<DateTime<Utc>>,
+}
+
+impl Series {
+    fn upsert(&mut self, candles: Vec<Candle>) {
+        for candle in candles {
+            // Appending is by far the most common case
+            match self.candles.last() {
+                Some(last) if last.time < candle.time => self.candles.push(candle),
+                None => self.candles.push(candle),
+                _ => match self
+                    .candles
+                    .binary_search_by_key(&candle.time, |existing| existing.time)
+                {
+                    Ok(index) => self.candles[index] = candle,
+                    Err(index) => self.candles.insert(index, candle),
+                },
+            }
+        }
+    }
+
+    // Like `upsert`, but keeps the candles that are already there
+    fn insert_missing(&mut self, candles: Vec<Candle>) {
+        for candle in candles {
+            if let Err(index) = self
+                .candles
+                .binary_search_by_key(&candle.time, |existing| existing.time)
+            {
+                self.candles.insert(index, candle);
+            }
+        }
+    }
+
+    fn range(&self, range: Range<DateTime<Utc>>) -> &[Candle] {
+        let start = self
+            .candles
+            .partition_point(|candle| candle.time < range.start);
+        let end = self
+            .candles
+            .partition_point(|candle| candle.time < range.end);
+        &self.candles[start..end.max(start)]
+    }
+
+    fn drop_before(&mut self, time: DateTime<Utc>) {
+        let count = self.candles.partition_point(|candle| candle.time < time);
+        if count > 0 {
+            self.candles.drain(..count);
+            self.dropped_before = self.dropped_before.max(Some(time));
+        }
+    }
+}
+
+// Amount of independently locked parts, series in different shards never contend
+const SHARDS: usize = 16;
+
+type SeriesKey = (String, Interval);
+type Shard = RwLock<HashMap<SeriesKey, Series>>;
 
 pub struct InMemoryStockDataCache {
-    candles: HashMap<String, Vec<Candle>>,
+    shards: Vec<Shard>,
+    // Maximum amount of candles over all series
+    capacity: Option<usize>,
+    // Maximum age of a candle relative to the newest one of its series
+    retention: Option<Duration>,
+    len: AtomicUsize,
+    access_counter: AtomicU64,
+}
+
+impl Default for InMemoryStockDataCache {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl InMemoryStockDataCache {
     pub fn new() -> Self {
         InMemoryStockDataCache {
-            candles: HashMap::new(),
+            shards: (0..SHARDS).map(|_| RwLock::default()).collect(),
+            capacity: None,
+            retention: None,
+            len: AtomicUsize::new(0),
+            access_counter: AtomicU64::new(0),
+        }
+    }
+
+    // Evicts the least recently used series once more than `capacity` candles are cached
+    pub fn with_capacity_limit(mut self, capacity: usize) -> Self {
+        self.capacity = Some(capacity);
+        self
+    }
+
+    pub fn with_retention(mut self, retention: Duration) -> Self {
+        self.retention = Some(retention);
+        self
+    }
+
+    // Adds the candles whose times are not cached yet. Used for candles loaded from a slower
+    // store, which must not replace candles written in the meantime.
+    pub fn fill_candles(&self, symbol: String, interval: Interval, candles: Vec<Candle>) {
+        self.write((symbol, interval), candles, Series::insert_missing);
+    }
+
+    // Amount of cached candles over all series
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains_series(&self, symbol: &str, interval: Interval) -> bool {
+        let key = (symbol.to_string(), interval);
+        self.read_shard(&key).contains_key(&key)
+    }
+
+    // Set once older candles of the series were dropped to stay within the limits
+    pub fn dropped_before(&self, symbol: &str, interval: Interval) -> Option<DateTime<Utc>> {
+        let key = (symbol.to_string(), interval);
+        self.read_shard(&key)
+            .get(&key)
+            .and_then(|series| series.dropped_before)
+    }
+
+    fn shard(&self, key: &SeriesKey) -> &Shard {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % SHARDS]
+    }
+
+    // A panic while holding a lock leaves the series in a consistent state, so poisoning is ignored
+    fn read_shard(&self, key: &SeriesKey) -> RwLockReadGuard<'_, HashMap<SeriesKey, Series>> {
+        self.shard(key)
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write_shard(&self, key: &SeriesKey) -> RwLockWriteGuard<'_, HashMap<SeriesKey, Series>> {
+        self.shard(key)
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Runs `f` on the series, if present, and marks it as recently used
+    fn with_series<T>(&self, key: &SeriesKey, f: impl FnOnce(&Series) -> T) -> Option<T> {
+        self.read_shard(key).get(key).map(|series| {
+            self.touch(series);
+            f(series)
+        })
+    }
+
+    fn touch(&self, series: &Series) {
+        let counter = self.access_counter.fetch_add(1, Ordering::Relaxed) + 1;
+        series.last_access.store(counter, Ordering::Relaxed);
+    }
+
+    // Adds `candles` with `insert` under the lock of the series, then applies the limits
+    fn write(&self, key: SeriesKey, candles: Vec<Candle>, insert: fn(&mut Series, Vec<Candle>)) {
+        {
+            let mut shard = self.write_shard(&key);
+            let series = shard.entry(key.clone()).or_default();
+            let before = series.candles.len();
+            insert(series, candles);
+            if let (Some(retention), Some(newest)) = (self.retention, series.candles.last()) {
+                series.drop_before(newest.time - retention);
+            }
+            self.len.fetch_add(series.candles.len(), Ordering::Relaxed);
+            self.len.fetch_sub(before, Ordering::Relaxed);
+            self.touch(series);
+        }
+        self.enforce_capacity(&key);
+    }
+
+    fn enforce_capacity(&self, written: &SeriesKey) {
+        let capacity = match self.capacity {
+            Some(capacity) => capacity,
+            None => return,
+        };
+        while self.len() > capacity {
+            let least_recent = self
+                .shards
+                .iter()
+                .filter_map(|shard| {
+                    shard
+                        .read()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .iter()
+                        .filter(|(key, _)| *key != written)
+                        .map(|(key, series)| {
+                            (series.last_access.load(Ordering::Relaxed), key.clone())
+                        })
+                        .min_by_key(|(last_access, _)| *last_access)
+                })
+                .min_by_key(|(last_access, _)| *last_access)
+                .map(|(_, key)| key);
+            match least_recent {
+                Some(key) => {
+                    // Another writer might have evicted it in the meantime
+                    if let Some(series) = self.write_shard(&key).remove(&key) {
+                        self.len.fetch_sub(series.candles.len(), Ordering::Relaxed);
+                    }
+                }
+                None => {
+                    // Only the written series is left, keep its newest candles
+                    let mut shard = self.write_shard(written);
+                    let series = match shard.get_mut(written) {
+                        Some(series) if !series.candles.is_empty() => series,
+                        _ => return,
+                    };
+                    let excess = (self.len() - capacity).min(series.candles.len());
+                    let boundary = series.candles[excess - 1].time + Duration::nanoseconds(1);
+                    series.drop_before(boundary);
+                    self.len.fetch_sub(excess, Ordering::Relaxed);
+                }
+            }
         }
     }
 }
@@ -20,60 +238,210 @@ impl InMemoryStockDataCache {
 #[async_trait]
 impl StockDataCache for InMemoryStockDataCache {
     async fn write_candles(
-        &mut self,
+        &self,
         symbol: String,
+        interval: Interval,
         candles: Vec<Candle>,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        if let Some(candle_vec) = self.candles.get_mut(&symbol) {
-            for candle in candles {
-                if !candle_vec.contains(&candle) {
-                    candle_vec.push(candle);
-                }
-            }
-        } else {
-            self.candles.insert(symbol, candles);
-        }
+    ) -> Result<(), Error> {
+        self.write((symbol, interval), candles, Series::upsert);
         Ok(())
     }
     async fn get_candles(
-        &mut self,
+        &self,
         symbol: String,
+        interval: Interval,
         range: Range<DateTime<Utc>>,
-    ) -> Result<Vec<Candle>, Box<dyn std::error::Error>> {
-        if let Some(candle_vec) = self.candles.get(&symbol) {
-            let mut result_vec: Vec<_> = candle_vec
-                .iter()
-                .filter(|&item| range.contains(&item.time))
-                .cloned()
-                .collect();
-            result_vec.sort_by(|a, b| a.time.cmp(&b.time));
-            Ok(result_vec)
-        } else {
-            Ok(vec![])
-        }
+    ) -> Result<Vec<Candle>, Error> {
+        Error::check_range(&range)?;
+        Ok(self
+            .with_series(&(symbol, interval), |series| series.range(range).to_vec())
+            .unwrap_or_default())
     }
     async fn get_first_candle(
-        &mut self,
+        &self,
         symbol: String,
-    ) -> Result<Option<Candle>, Box<dyn std::error::Error + Send + Sync>> {
-        if let Some(candle_vec) = self.candles.get(&symbol) {
-            let mut result_vec: Vec<_> = candle_vec.iter().collect();
-            result_vec.sort_by(|a, b| a.time.cmp(&b.time));
-            Ok(result_vec.first().cloned().cloned())
-        } else {
-            Ok(None)
-        }
+        interval: Interval,
+    ) -> Result<Option<Candle>, Error> {
+        Ok(self
+            .with_series(&(symbol, interval), |series| {
+                series.candles.first().copied()
+            })
+            .flatten())
     }
     async fn get_last_candle(
-        &mut self,
+        &self,
         symbol: String,
-    ) -> Result<Option<Candle>, Box<dyn std::error::Error + Send + Sync>> {
-        if let Some(candle_vec) = self.candles.get(&symbol) {
-            let mut result_vec: Vec<_> = candle_vec.iter().collect();
-            result_vec.sort_by(|a, b| a.time.cmp(&b.time));
-            Ok(result_vec.last().cloned().cloned())
-        } else {
-            Ok(None)
+        interval: Interval,
+    ) -> Result<Option<Candle>, Error> {
+        Ok(self
+            .with_series(&(symbol, interval), |series| series.candles.last().copied())
+            .flatten())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::InMemoryStockDataCache;
+    use crate::data::database::StockDataCache;
+    use crate::models::{candle::Candle, interval::Interval};
+    use crate::test_data::{candle, minute};
+    use crate::Error;
+    use chrono::Duration;
+    use std::sync::Arc;
+
+    async fn write(cache: &InMemoryStockDataCache, symbol: &str, candles: Vec<Candle>) {
+        cache
+            .write_candles(symbol.to_string(), Interval::OneMinute, candles)
+            .await
+            .unwrap();
+    }
+
+    async fn closes(cache: &InMemoryStockDataCache, symbol: &str) -> Vec<f64> {
+        cache
+            .get_candles(
+                symbol.to_string(),
+                Interval::OneMinute,
+                minute(-1000)..minute(1000),
+            )
+            .await
+            .unwrap()
+            .iter()
+            .map(|candle| candle.close.to_f64())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn writes_are_sorted_and_upserted() {
+        let cache = InMemoryStockDataCache::new();
+        write(&cache, "BTCUSDT", vec![candle(2, 2.0), candle(0, 0.0)]).await;
+        write(
+            &cache,
+            "BTCUSDT",
+            vec![candle(1, 1.0), candle(2, 2.5), candle(3, 3.0)],
+        )
+        .await;
+
+        assert_eq!(vec![0.0, 1.0, 2.5, 3.0], closes(&cache, "BTCUSDT").await);
+        assert_eq!(4, cache.len());
+        let first = cache
+            .get_first_candle("BTCUSDT".to_string(), Interval::OneMinute)
+            .await
+            .unwrap();
+        let last = cache
+            .get_last_candle("BTCUSDT".to_string(), Interval::OneMinute)
+            .await
+            .unwrap();
+        assert_eq!(Some(candle(0, 0.0)), first);
+        assert_eq!(Some(candle(3, 3.0)), last);
+    }
+
+    #[tokio::test]
+    async fn fills_keep_cached_candles() {
+        let cache = InMemoryStockDataCache::new();
+        write(&cache, "BTCUSDT", vec![candle(1, 1.5)]).await;
+        cache.fill_candles(
+            "BTCUSDT".to_string(),
+            Interval::OneMinute,
+            vec![candle(2, 2.0), candle(0, 0.0), candle(1, 1.0)],
+        );
+
+        assert_eq!(vec![0.0, 1.5, 2.0], closes(&cache, "BTCUSDT").await);
+        assert_eq!(3, cache.len());
+    }
+
+    #[tokio::test]
+    async fn range_is_half_open() {
+        let cache = InMemoryStockDataCache::new();
+        write(
+            &cache,
+            "BTCUSDT",
+            (0..10).map(|i| candle(i, i as f64)).collect(),
+        )
+        .await;
+
+        let candles = cache
+            .get_candles(
+                "BTCUSDT".to_string(),
+                Interval::OneMinute,
+                minute(3)..minute(6),
+            )
+            .await
+            .unwrap();
+        let inverted = cache
+            .get_candles(
+                "BTCUSDT".to_string(),
+                Interval::OneMinute,
+                minute(6)..minute(3),
+            )
+            .await;
+
+        assert_eq!(
+            vec![minute(3), minute(4), minute(5)],
+            candles.iter().map(|candle| candle.time).collect::<Vec<_>>()
+        );
+        assert!(matches!(inverted, Err(Error::InvalidRange { .. })));
+    }
+
+    #[tokio::test]
+    async fn capacity_evicts_least_recently_used_series() {
+        let cache = InMemoryStockDataCache::new().with_capacity_limit(10);
+        write(&cache, "A", (0..4).map(|i| candle(i, 1.0)).collect()).await;
+        write(&cache, "B", (0..4).map(|i| candle(i, 2.0)).collect()).await;
+        // Makes B the least recently used series
+        closes(&cache, "A").await;
+        write(&cache, "C", (0..4).map(|i| candle(i, 3.0)).collect()).await;
+
+        assert!(cache.contains_series("A", Interval::OneMinute));
+        assert!(!cache.contains_series("B", Interval::OneMinute));
+        assert!(cache.contains_series("C", Interval::OneMinute));
+        assert_eq!(8, cache.len());
+    }
+
+    #[tokio::test]
+    async fn capacity_trims_a_single_series() {
+        let cache = InMemoryStockDataCache::new().with_capacity_limit(3);
+        write(&cache, "A", (0..5).map(|i| candle(i, i as f64)).collect()).await;
+
+        assert_eq!(vec![2.0, 3.0, 4.0], closes(&cache, "A").await);
+        assert_eq!(3, cache.len());
+        assert!(cache
+            .dropped_before("A", Interval::OneMinute)
+            .is_some_and(|time| time > minute(1) && time <= minute(2)));
+    }
+
+    #[tokio::test]
+    async fn retention_drops_old_candles() {
+        let cache = InMemoryStockDataCache::new().with_retention(Duration::minutes(2));
+        write(&cache, "A", (0..3).map(|i| candle(i, i as f64)).collect()).await;
+        write(&cache, "A", vec![candle(5, 5.0)]).await;
+
+        assert_eq!(vec![5.0], closes(&cache, "A").await);
+        assert_eq!(1, cache.len());
+        assert_eq!(
+            Some(minute(3)),
+            cache.dropped_before("A", Interval::OneMinute)
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_access_to_a_shared_cache() {
+        let cache = Arc::new(InMemoryStockDataCache::new());
+        let tasks: Vec<_> = (0..8)
+            .map(|task| {
+                let cache = Arc::clone(&cache);
+                tokio::spawn(async move {
+                    let symbol = format!("S{}", task);
+                    for i in 0..50 {
+                        write(&cache, &symbol, vec![candle(i, i as f64)]).await;
+                        assert_eq!(i as usize + 1, closes(&cache, &symbol).await.len());
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
         }
+
+        assert_eq!(400, cache.len());
     }
 }
@@ -1,116 +1,329 @@
-use crate::models::candle::Candle;
+use crate::models::{candle::Candle, decimal::Decimal, interval::Interval};
+use crate::Error;
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
+use futures::stream::{self, StreamExt};
 use influxdb::InfluxDbWriteable;
-use influxdb::{Client, ReadQuery};
+use influxdb::{Client, Query, ReadQuery};
 use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::{Duration, Instant};
 
+use super::influx_query::{CandleQuery, Order, CANDLE_MEASUREMENT};
 use super::StockDataCache;
 
+// Reads and writes are independent HTTP requests, so concurrent calls run in parallel
 pub struct InfluxStockDataCache {
-    client: Arc<Mutex<Client>>,
+    client: Client,
+    writer: InfluxLineWriter,
 }
 
 impl InfluxStockDataCache {
-    pub fn new(client: Arc<Mutex<Client>>) -> Self {
-        InfluxStockDataCache { client }
+    // Reads go through `client`, writes through `writer`
+    pub fn new(client: Client, writer: InfluxLineWriter) -> Self {
+        InfluxStockDataCache { client, writer }
+    }
+
+    pub fn metrics(&self) -> Arc<InfluxWriteMetrics> {
+        Arc::clone(&self.writer.metrics)
+    }
+
+    // Candles of the stored `interval` series downsampled to `target` by the database, e.g. for
+    // charts spanning years of 1m candles
+    pub async fn get_aggregated_candles(
+        &self,
+        symbol: String,
+        interval: Interval,
+        target: Interval,
+        session_offset: chrono::Duration,
+        range: Range<DateTime<Utc>>,
+    ) -> Result<Vec<Candle>, Error> {
+        self.read(
+            CandleQuery::new(&symbol, interval)
+                .range(range)
+                .aggregate(target, session_offset),
+        )
+        .await
+    }
+
+    async fn read(&self, query: CandleQuery) -> Result<Vec<Candle>, Error> {
+        let mut query_result = self
+            .client
+            .json_query(ReadQuery::new(query.build()))
+            .await
+            .map_err(influx_error)?;
+        Ok(query_result
+            .deserialize_next::<Candle>()
+            .map_err(influx_error)?
+            .series
+            .into_iter()
+            .flat_map(|series| series.values)
+            .collect())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct InfluxWriteOptions {
+    // Lines per HTTP request
+    pub batch_size: usize,
+    pub max_retries: u32,
+    // Doubled after every failed attempt
+    pub retry_delay: Duration,
+    // Requests sent concurrently
+    pub max_in_flight: usize,
+}
+
+impl Default for InfluxWriteOptions {
+    fn default() -> Self {
+        InfluxWriteOptions {
+            batch_size: 5000,
+            max_retries: 3,
+            retry_delay: Duration::from_millis(500),
+            max_in_flight: 4,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct InfluxWriteMetrics {
+    candles_written: AtomicU64,
+    batches_written: AtomicU64,
+    batches_failed: AtomicU64,
+    retries: AtomicU64,
+    write_micros: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InfluxWriteStats {
+    pub candles_written: u64,
+    pub batches_written: u64,
+    pub batches_failed: u64,
+    pub retries: u64,
+    // Summed duration of all successful requests
+    pub write_time: Duration,
+}
+
+impl InfluxWriteStats {
+    pub fn candles_per_second(&self) -> f64 {
+        if self.write_time.is_zero() {
+            0.0
+        } else {
+            self.candles_written as f64 / self.write_time.as_secs_f64()
+        }
+    }
+}
+
+impl InfluxWriteMetrics {
+    pub fn snapshot(&self) -> InfluxWriteStats {
+        InfluxWriteStats {
+            candles_written: self.candles_written.load(Ordering::Relaxed),
+            batches_written: self.batches_written.load(Ordering::Relaxed),
+            batches_failed: self.batches_failed.load(Ordering::Relaxed),
+            retries: self.retries.load(Ordering::Relaxed),
+            write_time: Duration::from_micros(self.write_micros.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+// Writes line protocol batches to the `/write` endpoint, the influxdb client sends every query on
+// its own and treats server errors as success
+pub struct InfluxLineWriter {
+    http: reqwest::Client,
+    url: String,
+    database: String,
+    credentials: Option<(String, String)>,
+    options: InfluxWriteOptions,
+    metrics: Arc<InfluxWriteMetrics>,
+}
+
+impl InfluxLineWriter {
+    pub fn new<S1: Into<String>, S2: Into<String>>(url: S1, database: S2) -> Self {
+        InfluxLineWriter {
+            http: reqwest::Client::new(),
+            url: url.into(),
+            database: database.into(),
+            credentials: None,
+            options: InfluxWriteOptions::default(),
+            metrics: Arc::new(InfluxWriteMetrics::default()),
+        }
+    }
+
+    pub fn with_auth<S1: Into<String>, S2: Into<String>>(
+        mut self,
+        username: S1,
+        password: S2,
+    ) -> Self {
+        self.credentials = Some((username.into(), password.into()));
+        self
+    }
+
+    pub fn with_options(mut self, options: InfluxWriteOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub async fn write_lines(&self, lines: Vec<String>) -> Result<(), Error> {
+        let batch_size = self.options.batch_size.max(1);
+        let batches: Vec<(usize, String)> = lines
+            .chunks(batch_size)
+            .map(|batch| (batch.len(), batch.join("\n")))
+            .collect();
+        let results: Vec<_> = stream::iter(batches)
+            .map(|(count, body)| self.write_batch(count, body))
+            .buffer_unordered(self.options.max_in_flight.max(1))
+            .collect()
+            .await;
+        results.into_iter().collect()
+    }
+
+    async fn write_batch(&self, count: usize, body: String) -> Result<(), Error> {
+        let mut attempt = 0;
+        loop {
+            let started = Instant::now();
+            match self.send(body.clone()).await {
+                Ok(()) => {
+                    let metrics = &self.metrics;
+                    metrics
+                        .candles_written
+                        .fetch_add(count as u64, Ordering::Relaxed);
+                    metrics.batches_written.fetch_add(1, Ordering::Relaxed);
+                    metrics
+                        .write_micros
+                        .fetch_add(started.elapsed().as_micros() as u64, Ordering::Relaxed);
+                    return Ok(());
+                }
+                Err(WriteError::Transient(_)) if attempt < self.options.max_retries => {
+                    self.metrics.retries.fetch_add(1, Ordering::Relaxed);
+                    tokio::time::sleep(self.options.retry_delay * 2u32.pow(attempt)).await;
+                    attempt += 1;
+                }
+                Err(WriteError::Transient(err)) => {
+                    self.metrics.batches_failed.fetch_add(1, Ordering::Relaxed);
+                    return Err(Error::backend(err));
+                }
+                Err(WriteError::Permanent(err)) => {
+                    self.metrics.batches_failed.fetch_add(1, Ordering::Relaxed);
+                    return Err(Error::rejected(err));
+                }
+            }
+        }
+    }
+
+    async fn send(&self, body: String) -> Result<(), WriteError> {
+        let mut request = self
+            .http
+            .post(format!("{}/write", self.url))
+            .query(&[("db", self.database.as_str()), ("precision", "ns")]);
+        if let Some((username, password)) = &self.credentials {
+            request = request.query(&[("u", username), ("p", password)]);
+        }
+
+        let response = request
+            .body(body)
+            .send()
+            .await
+            .map_err(|err| WriteError::Transient(err.to_string()))?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+
+        let message = format!(
+            "InfluxDB write failed with {}: {}",
+            status,
+            response.text().await.unwrap_or_default()
+        );
+        // Rejected lines will be rejected again
+        if status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            Err(WriteError::Transient(message))
+        } else {
+            Err(WriteError::Permanent(message))
+        }
+    }
+}
+
+fn influx_error(err: influxdb::Error) -> Error {
+    match err {
+        influxdb::Error::DeserializationError { error } => Error::Decode(error),
+        err => Error::backend(err),
     }
 }
 
+enum WriteError {
+    Transient(String),
+    Permanent(String),
+}
+
 #[async_trait]
 impl StockDataCache for InfluxStockDataCache {
     async fn write_candles(
-        &mut self,
+        &self,
         symbol: String,
+        interval: Interval,
         candles: Vec<Candle>,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        let influx_candles = candles
+    ) -> Result<(), Error> {
+        let lines = candles
             .iter()
-            .map(|candle| InfluxCandle::from_candle(symbol.clone(), candle))
-            .map(|influx_candle| influx_candle.into_query("candle"));
+            .map(|candle| {
+                InfluxCandle::from_candle(symbol.clone(), interval, candle)
+                    .into_query(CANDLE_MEASUREMENT)
+                    .build()
+                    .map(|query| query.get())
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(influx_error)?;
 
-        let client = self.client.lock().await;
-        for query in influx_candles {
-            client.query(query).await?;
-        }
-        Ok(())
+        self.writer.write_lines(lines).await
     }
     async fn get_candles(
-        &mut self,
+        &self,
         symbol: String,
+        interval: Interval,
         range: Range<DateTime<Utc>>,
-    ) -> Result<Vec<Candle>, Box<dyn std::error::Error>> {
-        let client = self.client.lock().await;
-        let query = ReadQuery::new(format!(
-            "SELECT * FROM {} WHERE time >= {} AND time < {} AND symbol = \"{}\"",
-            "candle",
-            range.start.to_rfc3339(),
-            range.end.to_rfc3339(),
-            symbol
-        ));
-        let mut query_result = client.json_query(query).await?;
-        let queries: Vec<Candle> = query_result
-            .deserialize_next::<Candle>()?
-            .series
-            .iter()
-            .flat_map(|series| series.values.clone())
-            .collect();
-        Ok(queries)
+    ) -> Result<Vec<Candle>, Error> {
+        Error::check_range(&range)?;
+        self.read(CandleQuery::new(&symbol, interval).range(range))
+            .await
     }
     async fn get_first_candle(
-        &mut self,
+        &self,
         symbol: String,
-    ) -> Result<Option<Candle>, Box<dyn std::error::Error + Send + Sync>> {
-        let client = self.client.lock().await;
-        let query = ReadQuery::new(format!(
-            "SELECT * FROM {} WHERE symbol = \"{}\" LIMIT 1",
-            "candle", symbol
-        ));
-        let mut query_result = client.json_query(query).await?;
-        let queries: Option<Candle> = query_result
-            .deserialize_next::<Candle>()?
-            .series
-            .iter()
-            .flat_map(|series| series.values.clone())
-            .next();
-        Ok(queries)
+        interval: Interval,
+    ) -> Result<Option<Candle>, Error> {
+        let query = CandleQuery::new(&symbol, interval)
+            .order(Order::Ascending)
+            .limit(1);
+        Ok(self.read(query).await?.into_iter().next())
     }
     async fn get_last_candle(
-        &mut self,
+        &self,
         symbol: String,
-    ) -> Result<Option<Candle>, Box<dyn std::error::Error + Send + Sync>> {
-        let client = self.client.lock().await;
-        let query = ReadQuery::new(format!(
-            "SELECT * FROM {} WHERE symbol = \"{}\" ORDER BY time DESC LIMIT 1",
-            "candle", symbol
-        ));
-        let mut query_result = client.json_query(query).await?;
-        let queries: Option<Candle> = query_result
-            .deserialize_next::<Candle>()?
-            .series
-            .iter()
-            .flat_map(|series| series.values.clone())
-            .next();
-        Ok(queries)
+        interval: Interval,
+    ) -> Result<Option<Candle>, Error> {
+        let query = CandleQuery::new(&symbol, interval)
+            .order(Order::Descending)
+            .limit(1);
+        Ok(self.read(query).await?.into_iter().next())
     }
 }
 
 #[derive(Debug, Clone, InfluxDbWriteable)]
 struct InfluxCandle {
     time: DateTime<Utc>,
-    open: f64,
-    high: f64,
-    low: f64,
-    close: f64,
-    volume: f64,
+    open: Decimal,
+    high: Decimal,
+    low: Decimal,
+    close: Decimal,
+    volume: Decimal,
     #[influxdb(tag)]
     symbol: String,
+    #[influxdb(tag)]
+    interval: String,
 }
 
 impl InfluxCandle {
-    const fn from_candle(symbol: String, candle: &Candle) -> InfluxCandle {
+    fn from_candle(symbol: String, interval: Interval, candle: &Candle) -> InfluxCandle {
         InfluxCandle {
             time: candle.time,
             open: candle.open,
@@ -119,6 +332,186 @@ impl InfluxCandle {
             close: candle.close,
             volume: candle.volume,
             symbol,
+            interval: interval.to_string(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{InfluxLineWriter, InfluxStockDataCache, InfluxWriteOptions};
+    use crate::data::database::influx_query::{CandleQuery, Order};
+    use crate::data::database::StockDataCache;
+    use crate::models::{candle::Candle, decimal::Decimal, interval::Interval};
+    use chrono::{Duration, TimeZone, Utc};
+    use influxdb::Client;
+    use serde_json::json;
+    use wiremock::matchers::{method, path, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn candles(count: i64) -> Vec<Candle> {
+        let start = Utc.with_ymd_and_hms(2022, 1, 1, 0, 0, 0).unwrap();
+        (0..count)
+            .map(|minute| Candle {
+                open: Decimal::from(1),
+                high: Decimal::from(2),
+                low: Decimal::new(5, 1),
+                close: Decimal::new(15, 1),
+                volume: Decimal::from(10),
+                time: start + Duration::minutes(minute),
+            })
+            .collect()
+    }
+
+    fn cache(server: &MockServer, batch_size: usize, max_retries: u32) -> InfluxStockDataCache {
+        let client = Client::new(server.uri(), "test");
+        let writer = InfluxLineWriter::new(server.uri(), "test")
+            .with_auth("user", "secret")
+            .with_options(InfluxWriteOptions {
+                batch_size,
+                max_retries,
+                retry_delay: std::time::Duration::from_millis(1),
+                max_in_flight: 2,
+            });
+        InfluxStockDataCache::new(client, writer)
+    }
+
+    #[tokio::test]
+    async fn writes_are_batched() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/write"))
+            .and(query_param("db", "test"))
+            .and(query_param("precision", "ns"))
+            .and(query_param("u", "user"))
+            .respond_with(ResponseTemplate::new(204))
+            .mount(&server)
+            .await;
+        let cache = cache(&server, 1000, 0);
+
+        cache
+            .write_candles("BTCUSDT".to_string(), Interval::OneMinute, candles(2500))
+            .await
+            .expect("Failed to write candles");
+
+        let requests = server.received_requests().await.unwrap();
+        let mut line_counts: Vec<_> = requests
+            .iter()
+            .map(|request| String::from_utf8_lossy(&request.body).lines().count())
+            .collect();
+        line_counts.sort_unstable();
+        assert_eq!(vec![500, 1000, 1000], line_counts);
+
+        let body = String::from_utf8_lossy(&requests[0].body).to_string();
+        let line = body.lines().next().unwrap();
+        assert!(line.starts_with("candle,"));
+        assert!(line.contains("symbol=BTCUSDT"));
+        assert!(line.contains("interval=1m"));
+        assert!(line.contains("close=1.5"));
+
+        let stats = cache.metrics().snapshot();
+        assert_eq!(2500, stats.candles_written);
+        assert_eq!(3, stats.batches_written);
+        assert_eq!(0, stats.retries);
+    }
+
+    #[tokio::test]
+    async fn transient_errors_are_retried() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/write"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(2)
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/write"))
+            .respond_with(ResponseTemplate::new(204))
+            .mount(&server)
+            .await;
+        let cache = cache(&server, 1000, 3);
+
+        cache
+            .write_candles("BTCUSDT".to_string(), Interval::OneMinute, candles(10))
+            .await
+            .expect("Failed to write candles");
+
+        assert_eq!(3, server.received_requests().await.unwrap().len());
+        let stats = cache.metrics().snapshot();
+        assert_eq!(2, stats.retries);
+        assert_eq!(1, stats.batches_written);
+        assert_eq!(10, stats.candles_written);
+    }
+
+    #[tokio::test]
+    async fn retries_are_bounded() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/write"))
+            .respond_with(ResponseTemplate::new(503))
+            .mount(&server)
+            .await;
+        let cache = cache(&server, 1000, 2);
+
+        let result = cache
+            .write_candles("BTCUSDT".to_string(), Interval::OneMinute, candles(10))
+            .await;
+
+        assert!(result.unwrap_err().is_transient());
+        assert_eq!(3, server.received_requests().await.unwrap().len());
+        assert_eq!(1, cache.metrics().snapshot().batches_failed);
+    }
+
+    #[tokio::test]
+    async fn rejected_lines_are_not_retried() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/write"))
+            .respond_with(ResponseTemplate::new(400).set_body_string("{\"error\":\"bad line\"}"))
+            .mount(&server)
+            .await;
+        let cache = cache(&server, 1000, 3);
+
+        let result = cache
+            .write_candles("BTCUSDT".to_string(), Interval::OneMinute, candles(10))
+            .await;
+
+        let err = result.unwrap_err();
+        assert!(err.to_string().contains("bad line"));
+        assert!(!err.is_transient());
+        assert_eq!(1, server.received_requests().await.unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn reads_use_escaped_queries() {
+        let server = MockServer::start().await;
+        let expected = CandleQuery::new("BTC'USDT", Interval::OneMinute)
+            .order(Order::Ascending)
+            .limit(1)
+            .build();
+        Mock::given(method("GET"))
+            .and(path("/query"))
+            .and(query_param("q", expected.as_str()))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "results": [{
+                    "statement_id": 0,
+                    "series": [{
+                        "name": "candle",
+                        "columns": ["time", "open", "high", "low", "close", "volume"],
+                        "values": [["2022-01-01T00:00:00Z", 1.0, 2.0, 0.5, 1.5, 10.0]]
+                    }]
+                }]
+            })))
+            .mount(&server)
+            .await;
+        let cache = cache(&server, 1000, 0);
+
+        let first = cache
+            .get_first_candle("BTC'USDT".to_string(), Interval::OneMinute)
+            .await
+            .expect("Failed to read candle");
+
+        assert_eq!(Some(candles(1)[0]), first);
+    }
+}
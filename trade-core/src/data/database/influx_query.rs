@@ -0,0 +1,223 @@
+use crate::models::interval::Interval;
+use chrono::{DateTime, Duration, Utc};
+use std::ops::Range;
+
+pub const CANDLE_MEASUREMENT: &str = "candle";
+
+// Double quoted InfluxQL identifier
+pub fn escape_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// Single quoted InfluxQL string literal
+pub fn escape_string(value: &str) -> String {
+    format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Order {
+    Ascending,
+    Descending,
+}
+
+// SELECT over the candles of one series, all user input ends up escaped
+#[derive(Debug, Clone)]
+pub struct CandleQuery {
+    symbol: String,
+    interval: Interval,
+    range: Option<Range<DateTime<Utc>>>,
+    order: Order,
+    limit: Option<usize>,
+    aggregation: Option<(Interval, Duration)>,
+}
+
+impl CandleQuery {
+    pub fn new(symbol: &str, interval: Interval) -> Self {
+        CandleQuery {
+            symbol: symbol.to_string(),
+            interval,
+            range: None,
+            order: Order::Ascending,
+            limit: None,
+            aggregation: None,
+        }
+    }
+
+    pub fn range(mut self, range: Range<DateTime<Utc>>) -> Self {
+        self.range = Some(range);
+        self
+    }
+
+    pub fn order(mut self, order: Order) -> Self {
+        self.order = order;
+        self
+    }
+
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    // Downsamples to `interval` inside the database. Buckets are shifted by `session_offset`
+    // like the `Resampler` does.
+    pub fn aggregate(mut self, interval: Interval, session_offset: Duration) -> Self {
+        self.aggregation = Some((interval, session_offset));
+        self
+    }
+
+    pub fn build(&self) -> String {
+        let fields = match self.aggregation {
+            Some(_) => [
+                ("FIRST", "open"),
+                ("MAX", "high"),
+                ("MIN", "low"),
+                ("LAST", "close"),
+                ("SUM", "volume"),
+            ]
+            .iter()
+            .map(|(function, field)| {
+                format!(
+                    "{}({}) AS {}",
+                    function,
+                    escape_identifier(field),
+                    escape_identifier(field)
+                )
+            })
+            .collect::<Vec<_>>(),
+            None => ["open", "high", "low", "close", "volume"]
+                .iter()
+                .map(|field| escape_identifier(field))
+                .collect(),
+        };
+
+        let mut conditions = vec![
+            format!(
+                "{} = {}",
+                escape_identifier("symbol"),
+                escape_string(&self.symbol)
+            ),
+            format!(
+                "{} = {}",
+                escape_identifier("interval"),
+                escape_string(self.interval.as_str())
+            ),
+        ];
+        if let Some(range) = &self.range {
+            conditions.push(format!("time >= {}", nanoseconds(range.start)));
+            conditions.push(format!("time < {}", nanoseconds(range.end)));
+        }
+
+        let mut query = format!(
+            "SELECT {} FROM {} WHERE {}",
+            fields.join(", "),
+            escape_identifier(CANDLE_MEASUREMENT),
+            conditions.join(" AND ")
+        );
+
+        if let Some((interval, session_offset)) = self.aggregation {
+            // InfluxDB aligns buckets to the unix epoch, a Thursday
+            let week_offset = match interval {
+                Interval::OneWeek => Duration::days(4),
+                _ => Duration::zero(),
+            };
+            let offset = (week_offset + session_offset).num_seconds();
+            query.push_str(&format!(
+                " GROUP BY time({}s, {}s) fill(none)",
+                interval.seconds(),
+                offset.rem_euclid(interval.seconds())
+            ));
+        }
+
+        query.push_str(match self.order {
+            Order::Ascending => " ORDER BY time ASC",
+            Order::Descending => " ORDER BY time DESC",
+        });
+        if let Some(limit) = self.limit {
+            query.push_str(&format!(" LIMIT {}", limit));
+        }
+        query
+    }
+}
+
+fn nanoseconds(time: DateTime<Utc>) -> i64 {
+    // Out of range for nanoseconds only beyond the years 1677 and 2262
+    time.timestamp_nanos_opt()
+        .unwrap_or(if time.timestamp() < 0 {
+            i64::MIN
+        } else {
+            i64::MAX
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{escape_identifier, escape_string, CandleQuery, Order};
+    use crate::models::interval::Interval;
+    use chrono::{Duration, TimeZone, Utc};
+
+    #[test]
+    fn strings_are_escaped() {
+        assert_eq!("'BTCUSDT'", escape_string("BTCUSDT"));
+        assert_eq!(r"'x\' OR symbol = \'y'", escape_string("x' OR symbol = 'y"));
+        assert_eq!(r"'a\\\'b'", escape_string(r"a\'b"));
+    }
+
+    #[test]
+    fn identifiers_are_escaped() {
+        assert_eq!("\"close\"", escape_identifier("close"));
+        assert_eq!(r#""a\"b\\c""#, escape_identifier(r#"a"b\c"#));
+    }
+
+    #[test]
+    fn injected_symbol_stays_a_literal() {
+        let query =
+            CandleQuery::new("BTC'; DROP MEASUREMENT candle; --", Interval::OneMinute).build();
+
+        assert_eq!(
+            r#"SELECT "open", "high", "low", "close", "volume" FROM "candle" WHERE "symbol" = 'BTC\'; DROP MEASUREMENT candle; --' AND "interval" = '1m' ORDER BY time ASC"#,
+            query
+        );
+    }
+
+    #[test]
+    fn range_order_and_limit() {
+        let start = Utc.with_ymd_and_hms(2022, 1, 1, 0, 0, 0).unwrap();
+        let query = CandleQuery::new("BTCUSDT", Interval::OneHour)
+            .range(start..start + Duration::hours(1))
+            .order(Order::Descending)
+            .limit(1)
+            .build();
+
+        assert_eq!(
+            r#"SELECT "open", "high", "low", "close", "volume" FROM "candle" WHERE "symbol" = 'BTCUSDT' AND "interval" = '1h' AND time >= 1640995200000000000 AND time < 1640998800000000000 ORDER BY time DESC LIMIT 1"#,
+            query
+        );
+    }
+
+    #[test]
+    fn aggregation_groups_by_time() {
+        let start = Utc.with_ymd_and_hms(2022, 1, 1, 0, 0, 0).unwrap();
+        let query = CandleQuery::new("BTCUSDT", Interval::OneMinute)
+            .range(start..start + Duration::days(1))
+            .aggregate(Interval::FourHours, Duration::zero())
+            .build();
+
+        assert_eq!(
+            r#"SELECT FIRST("open") AS "open", MAX("high") AS "high", MIN("low") AS "low", LAST("close") AS "close", SUM("volume") AS "volume" FROM "candle" WHERE "symbol" = 'BTCUSDT' AND "interval" = '1m' AND time >= 1640995200000000000 AND time < 1641081600000000000 GROUP BY time(14400s, 0s) fill(none) ORDER BY time ASC"#,
+            query
+        );
+    }
+
+    #[test]
+    fn aggregation_aligns_weeks_and_sessions() {
+        let weekly = CandleQuery::new("BTCUSDT", Interval::OneDay)
+            .aggregate(Interval::OneWeek, Duration::zero())
+            .build();
+        let session = CandleQuery::new("SPY", Interval::OneMinute)
+            .aggregate(Interval::OneDay, Duration::minutes(14 * 60 + 30))
+            .build();
+
+        assert!(weekly.contains("GROUP BY time(604800s, 345600s)"));
+        assert!(session.contains("GROUP BY time(86400s, 52200s)"));
+    }
+}
@@ -1,29 +1,42 @@
+pub mod disk;
 pub mod in_memory;
 pub mod influx;
+pub mod influx_query;
+pub mod tiered;
 
-use crate::models::candle::Candle;
+use crate::models::{candle::Candle, interval::Interval};
+use crate::Error;
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
 use std::ops::Range;
+use std::sync::Arc;
 
+pub type SharedStockDataCache = Arc<dyn StockDataCache + Send + Sync>;
+
+// Candle series are keyed by symbol and interval, so e.g. 1m and 1d candles never mix.
+// Implementations handle concurrent calls themselves and are shared as `SharedStockDataCache`.
 #[async_trait]
 pub trait StockDataCache {
     async fn write_candles(
-        &mut self,
+        &self,
         symbol: String,
+        interval: Interval,
         candles: Vec<Candle>,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+    ) -> Result<(), Error>;
     async fn get_candles(
-        &mut self,
+        &self,
         symbol: String,
+        interval: Interval,
         range: Range<DateTime<Utc>>,
-    ) -> Result<Vec<Candle>, Box<dyn std::error::Error>>;
+    ) -> Result<Vec<Candle>, Error>;
     async fn get_first_candle(
-        &mut self,
+        &self,
         symbol: String,
-    ) -> Result<Option<Candle>, Box<dyn std::error::Error + Send + Sync>>;
+        interval: Interval,
+    ) -> Result<Option<Candle>, Error>;
     async fn get_last_candle(
-        &mut self,
+        &self,
         symbol: String,
-    ) -> Result<Option<Candle>, Box<dyn std::error::Error + Send + Sync>>;
+        interval: Interval,
+    ) -> Result<Option<Candle>, Error>;
 }
@@ -0,0 +1,698 @@
+use super::in_memory::InMemoryStockDataCache;
+use super::{SharedStockDataCache, StockDataCache};
+use crate::models::{candle::Candle, interval::Interval};
+use crate::Error;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::future;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use tokio::sync::{mpsc, oneshot};
+
+// Disjoint, sorted time ranges whose candles are completely held in memory
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Coverage {
+    ranges: Vec<Range<DateTime<Utc>>>,
+}
+
+impl Coverage {
+    // Parts of `range` that are not covered yet
+    pub fn missing(&self, range: Range<DateTime<Utc>>) -> Vec<Range<DateTime<Utc>>> {
+        let mut missing = Vec::new();
+        let mut start = range.start;
+        for covered in &self.ranges {
+            if start >= range.end {
+                break;
+            }
+            if covered.end <= start {
+                continue;
+            }
+            if covered.start >= range.end {
+                break;
+            }
+            if covered.start > start {
+                missing.push(start..covered.start);
+            }
+            start = start.max(covered.end);
+        }
+        if start < range.end {
+            missing.push(start..range.end);
+        }
+        missing
+    }
+
+    pub fn insert(&mut self, range: Range<DateTime<Utc>>) {
+        if range.start >= range.end {
+            return;
+        }
+        let mut merged = range;
+        let mut ranges = Vec::with_capacity(self.ranges.len() + 1);
+        for covered in self.ranges.drain(..) {
+            // Adjacent ranges are merged as well
+            if covered.end < merged.start || covered.start > merged.end {
+                ranges.push(covered);
+            } else {
+                merged = merged.start.min(covered.start)..merged.end.max(covered.end);
+            }
+        }
+        ranges.push(merged);
+        ranges.sort_by_key(|covered| covered.start);
+        self.ranges = ranges;
+    }
+
+    // Forgets everything before `time`
+    pub fn clip_before(&mut self, time: DateTime<Utc>) {
+        self.ranges.retain(|covered| covered.end > time);
+        if let Some(first) = self.ranges.first_mut() {
+            first.start = first.start.max(time);
+        }
+    }
+}
+
+enum BackgroundTask {
+    Write(String, Interval, Vec<Candle>),
+    // Answered once every write queued before is done, or with an error while the backend
+    // fails them
+    Flush(oneshot::Sender<Result<(), Error>>),
+}
+
+type SeriesKey = (String, Interval);
+
+// Delay before retrying a failed background write, doubled up to the maximum while it keeps
+// failing
+const RETRY_DELAY: std::time::Duration = std::time::Duration::from_millis(100);
+const MAX_RETRY_DELAY: std::time::Duration = std::time::Duration::from_secs(30);
+
+#[derive(Debug, Default)]
+pub struct WriteBehindMetrics {
+    batches_written: AtomicU64,
+    // Failed attempts of batches that are retried
+    retries: AtomicU64,
+    // Batches the backend refused for good, their candles only exist in memory
+    batches_dropped: AtomicU64,
+    last_error: Mutex<Option<String>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WriteBehindStats {
+    pub batches_written: u64,
+    pub retries: u64,
+    pub batches_dropped: u64,
+    pub last_error: Option<String>,
+}
+
+impl WriteBehindMetrics {
+    pub fn snapshot(&self) -> WriteBehindStats {
+        WriteBehindStats {
+            batches_written: self.batches_written.load(Ordering::Relaxed),
+            retries: self.retries.load(Ordering::Relaxed),
+            batches_dropped: self.batches_dropped.load(Ordering::Relaxed),
+            last_error: lock(&self.last_error).clone(),
+        }
+    }
+
+    fn record_error(&self, counter: &AtomicU64, err: &Error) {
+        counter.fetch_add(1, Ordering::Relaxed);
+        *lock(&self.last_error) = Some(err.to_string());
+    }
+}
+
+// Layers an in-memory cache over a persistent backend.
+//
+// Reads are answered from memory as far as the requested range is covered, only the missing parts
+// are fetched from the backend. Writes go to memory right away and are written to the backend in
+// the background, in order. Writes failing transiently are retried until they succeed, holding
+// back the ones queued after them.
+pub struct TieredStockDataCache {
+    memory: InMemoryStockDataCache,
+    backend: SharedStockDataCache,
+    coverage: Mutex<HashMap<SeriesKey, Coverage>>,
+    // First and last candle of the backend including the queued writes, `None` if empty
+    first: Mutex<HashMap<SeriesKey, Option<Candle>>>,
+    last: Mutex<HashMap<SeriesKey, Option<Candle>>>,
+    background: mpsc::UnboundedSender<BackgroundTask>,
+    metrics: Arc<WriteBehindMetrics>,
+}
+
+impl TieredStockDataCache {
+    // Needs to be called within a tokio runtime, writes are flushed by a spawned task
+    pub fn new(memory: InMemoryStockDataCache, backend: SharedStockDataCache) -> Self {
+        let (background, receiver) = mpsc::unbounded_channel();
+        let metrics = Arc::new(WriteBehindMetrics::default());
+        tokio::spawn(write_behind(
+            Arc::clone(&backend),
+            receiver,
+            Arc::clone(&metrics),
+        ));
+        TieredStockDataCache {
+            memory,
+            backend,
+            coverage: Mutex::default(),
+            first: Mutex::default(),
+            last: Mutex::default(),
+            background,
+            metrics,
+        }
+    }
+
+    // Waits until all writes queued so far are in the backend. Fails while the backend fails
+    // them, they stay queued and are retried.
+    pub async fn flush(&self) -> Result<(), Error> {
+        let (sender, receiver) = oneshot::channel();
+        self.background
+            .send(BackgroundTask::Flush(sender))
+            .map_err(|_| stopped())?;
+        receiver.await.map_err(|_| stopped())?
+    }
+
+    pub fn metrics(&self) -> Arc<WriteBehindMetrics> {
+        Arc::clone(&self.metrics)
+    }
+
+    pub fn coverage(&self, symbol: &str, interval: Interval) -> Option<Coverage> {
+        lock(&self.coverage)
+            .get(&(symbol.to_string(), interval))
+            .cloned()
+    }
+
+    // Evicted or trimmed series are no longer covered
+    fn sync_coverage(&self) {
+        let memory = &self.memory;
+        let mut coverage = lock(&self.coverage);
+        coverage.retain(|(symbol, interval), _| memory.contains_series(symbol, *interval));
+        for ((symbol, interval), coverage) in coverage.iter_mut() {
+            if let Some(dropped_before) = memory.dropped_before(symbol, *interval) {
+                coverage.clip_before(dropped_before);
+            }
+        }
+    }
+}
+
+// The maps stay consistent even if a holder panicked, so poisoning is ignored
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+async fn write_behind(
+    backend: SharedStockDataCache,
+    mut receiver: mpsc::UnboundedReceiver<BackgroundTask>,
+    metrics: Arc<WriteBehindMetrics>,
+) {
+    let mut queue = VecDeque::new();
+    let mut retry_delay = RETRY_DELAY;
+    loop {
+        if queue.is_empty() {
+            match receiver.recv().await {
+                Some(task) => queue.push_back(task),
+                None => return,
+            }
+        }
+        while let Ok(task) = receiver.try_recv() {
+            queue.push_back(task);
+        }
+
+        match queue.pop_front() {
+            Some(BackgroundTask::Write(symbol, interval, candles)) => {
+                match backend
+                    .write_candles(symbol.clone(), interval, candles.clone())
+                    .await
+                {
+                    Ok(()) => {
+                        metrics.batches_written.fetch_add(1, Ordering::Relaxed);
+                        retry_delay = RETRY_DELAY;
+                    }
+                    Err(err) if err.is_transient() => {
+                        metrics.record_error(&metrics.retries, &err);
+                        // Flushes waiting behind the batch learn that the backend is behind
+                        queue = std::mem::take(&mut queue)
+                            .into_iter()
+                            .filter_map(|task| match task {
+                                BackgroundTask::Flush(sender) => {
+                                    sender.send(Err(behind(&err))).ok();
+                                    None
+                                }
+                                write => Some(write),
+                            })
+                            .collect();
+                        queue.push_front(BackgroundTask::Write(symbol, interval, candles));
+                        tokio::time::sleep(retry_delay).await;
+                        retry_delay = (retry_delay * 2).min(MAX_RETRY_DELAY);
+                    }
+                    Err(err) => metrics.record_error(&metrics.batches_dropped, &err),
+                }
+            }
+            Some(BackgroundTask::Flush(sender)) => {
+                // The waiting side might be gone already
+                sender.send(Ok(())).ok();
+            }
+            None => {}
+        }
+    }
+}
+
+fn behind(err: &Error) -> Error {
+    Error::backend(format!("Background writes are pending: {}", err))
+}
+
+fn stopped() -> Error {
+    Error::backend("Write-behind task stopped")
+}
+
+#[async_trait]
+impl StockDataCache for TieredStockDataCache {
+    async fn write_candles(
+        &self,
+        symbol: String,
+        interval: Interval,
+        candles: Vec<Candle>,
+    ) -> Result<(), Error> {
+        if candles.is_empty() {
+            return Ok(());
+        }
+        let key = (symbol.clone(), interval);
+        // Bounds that were never looked up stay unknown
+        let earliest = *candles.iter().min_by_key(|candle| candle.time).unwrap();
+        let latest = *candles.iter().max_by_key(|candle| candle.time).unwrap();
+        if let Some(first) = lock(&self.first).get_mut(&key) {
+            if first.is_none_or(|first| earliest.time <= first.time) {
+                *first = Some(earliest);
+            }
+        }
+        if let Some(last) = lock(&self.last).get_mut(&key) {
+            if last.is_none_or(|last| latest.time >= last.time) {
+                *last = Some(latest);
+            }
+        }
+
+        self.memory
+            .write_candles(symbol.clone(), interval, candles.clone())
+            .await?;
+        self.sync_coverage();
+
+        self.background
+            .send(BackgroundTask::Write(symbol, interval, candles))
+            .map_err(|_| stopped())?;
+        Ok(())
+    }
+    async fn get_candles(
+        &self,
+        symbol: String,
+        interval: Interval,
+        range: Range<DateTime<Utc>>,
+    ) -> Result<Vec<Candle>, Error> {
+        Error::check_range(&range)?;
+        let key = (symbol.clone(), interval);
+        let missing = lock(&self.coverage)
+            .get(&key)
+            .map(|coverage| coverage.missing(range.clone()))
+            .unwrap_or_else(|| vec![range.clone()]);
+
+        if missing.is_empty() {
+            return self.memory.get_candles(symbol, interval, range).await;
+        }
+
+        // The backend has to include the queued writes before it is asked
+        self.flush().await?;
+        let fetched: Vec<Candle> = future::try_join_all(missing.iter().map(|part| {
+            self.backend
+                .get_candles(symbol.clone(), interval, part.clone())
+        }))
+        .await?
+        .into_iter()
+        .flatten()
+        .collect();
+
+        // Merged before the fetched candles go to memory, they might not all fit in there
+        let mut merged: BTreeMap<DateTime<Utc>, Candle> = fetched
+            .iter()
+            .map(|candle| (candle.time, *candle))
+            .collect();
+        for candle in self
+            .memory
+            .get_candles(symbol.clone(), interval, range)
+            .await?
+        {
+            merged.insert(candle.time, candle);
+        }
+
+        // Candles written since the fetch are newer than the fetched ones
+        self.memory.fill_candles(symbol.clone(), interval, fetched);
+        {
+            let mut coverage = lock(&self.coverage);
+            let coverage = coverage.entry(key).or_default();
+            for part in missing {
+                coverage.insert(part);
+            }
+        }
+        self.sync_coverage();
+
+        Ok(merged.into_values().collect())
+    }
+    async fn get_first_candle(
+        &self,
+        symbol: String,
+        interval: Interval,
+    ) -> Result<Option<Candle>, Error> {
+        let key = (symbol.clone(), interval);
+        if let Some(first) = lock(&self.first).get(&key) {
+            return Ok(*first);
+        }
+        self.flush().await?;
+        let first = self.backend.get_first_candle(symbol, interval).await?;
+        // A write in the meantime already knows better
+        Ok(*lock(&self.first).entry(key).or_insert(first))
+    }
+    async fn get_last_candle(
+        &self,
+        symbol: String,
+        interval: Interval,
+    ) -> Result<Option<Candle>, Error> {
+        let key = (symbol.clone(), interval);
+        if let Some(last) = lock(&self.last).get(&key) {
+            return Ok(*last);
+        }
+        self.flush().await?;
+        let last = self.backend.get_last_candle(symbol, interval).await?;
+        Ok(*lock(&self.last).entry(key).or_insert(last))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Coverage, TieredStockDataCache};
+    use crate::data::database::{in_memory::InMemoryStockDataCache, StockDataCache};
+    use crate::models::{candle::Candle, interval::Interval};
+    use crate::test_data::{candle, minute};
+    use crate::Error;
+    use async_trait::async_trait;
+    use chrono::{DateTime, Utc};
+    use std::collections::VecDeque;
+    use std::ops::Range;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    fn minutes(range: Range<i64>) -> Range<DateTime<Utc>> {
+        minute(range.start)..minute(range.end)
+    }
+
+    // Records the ranges queried from the backend
+    #[derive(Default)]
+    struct RecordingCache {
+        inner: InMemoryStockDataCache,
+        queries: Mutex<Vec<Range<DateTime<Utc>>>>,
+        bound_queries: AtomicUsize,
+        // Held by tests to delay the answers of range reads
+        read_gate: tokio::sync::Mutex<()>,
+        // Returned by the next writes instead of writing
+        write_failures: Mutex<VecDeque<Error>>,
+    }
+
+    #[async_trait]
+    impl StockDataCache for RecordingCache {
+        async fn write_candles(
+            &self,
+            symbol: String,
+            interval: Interval,
+            candles: Vec<Candle>,
+        ) -> Result<(), Error> {
+            if let Some(err) = self.write_failures.lock().unwrap().pop_front() {
+                return Err(err);
+            }
+            self.inner.write_candles(symbol, interval, candles).await
+        }
+        async fn get_candles(
+            &self,
+            symbol: String,
+            interval: Interval,
+            range: Range<DateTime<Utc>>,
+        ) -> Result<Vec<Candle>, Error> {
+            self.queries.lock().unwrap().push(range.clone());
+            let candles = self.inner.get_candles(symbol, interval, range).await;
+            // Answered late, with what was stored when asked
+            let _gate = self.read_gate.lock().await;
+            candles
+        }
+        async fn get_first_candle(
+            &self,
+            symbol: String,
+            interval: Interval,
+        ) -> Result<Option<Candle>, Error> {
+            self.bound_queries.fetch_add(1, Ordering::Relaxed);
+            self.inner.get_first_candle(symbol, interval).await
+        }
+        async fn get_last_candle(
+            &self,
+            symbol: String,
+            interval: Interval,
+        ) -> Result<Option<Candle>, Error> {
+            self.bound_queries.fetch_add(1, Ordering::Relaxed);
+            self.inner.get_last_candle(symbol, interval).await
+        }
+    }
+
+    async fn tiered(
+        memory: InMemoryStockDataCache,
+        stored: Vec<Candle>,
+    ) -> (TieredStockDataCache, Arc<RecordingCache>) {
+        let backend = RecordingCache::default();
+        backend
+            .write_candles("BTCUSDT".to_string(), Interval::OneMinute, stored)
+            .await
+            .unwrap();
+        let backend = Arc::new(backend);
+        let tiered = TieredStockDataCache::new(memory, backend.clone());
+        (tiered, backend)
+    }
+
+    async fn read(cache: &TieredStockDataCache, range: Range<i64>) -> Vec<f64> {
+        cache
+            .get_candles("BTCUSDT".to_string(), Interval::OneMinute, minutes(range))
+            .await
+            .unwrap()
+            .iter()
+            .map(|candle| candle.close.to_f64())
+            .collect()
+    }
+
+    #[test]
+    fn coverage_reports_missing_parts() {
+        let mut coverage = Coverage::default();
+        coverage.insert(minutes(10..20));
+        coverage.insert(minutes(30..40));
+
+        assert_eq!(
+            vec![minutes(0..10), minutes(20..30), minutes(40..50)],
+            coverage.missing(minutes(0..50))
+        );
+        assert!(coverage.missing(minutes(12..18)).is_empty());
+        assert_eq!(vec![minutes(20..25)], coverage.missing(minutes(15..25)));
+
+        coverage.insert(minutes(20..30));
+        assert!(coverage.missing(minutes(10..40)).is_empty());
+
+        coverage.clip_before(minute(35));
+        assert_eq!(vec![minutes(30..35)], coverage.missing(minutes(30..40)));
+    }
+
+    #[tokio::test]
+    async fn repeated_reads_are_served_from_memory() {
+        let stored = (0..100).map(|i| candle(i, i as f64)).collect();
+        let (cache, backend) = tiered(InMemoryStockDataCache::new(), stored).await;
+
+        assert_eq!(10, read(&cache, 10..20).await.len());
+        assert_eq!(5, read(&cache, 12..17).await.len());
+        assert_eq!(20, read(&cache, 5..25).await.len());
+
+        assert_eq!(
+            vec![minutes(10..20), minutes(5..10), minutes(20..25)],
+            *backend.queries.lock().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn writes_are_flushed_in_the_background() {
+        let (cache, backend) = tiered(InMemoryStockDataCache::new(), Vec::new()).await;
+        cache
+            .write_candles(
+                "BTCUSDT".to_string(),
+                Interval::OneMinute,
+                (0..5).map(|i| candle(i, i as f64)).collect(),
+            )
+            .await
+            .unwrap();
+        cache.flush().await.unwrap();
+
+        let stored = backend
+            .inner
+            .get_candles("BTCUSDT".to_string(), Interval::OneMinute, minutes(0..10))
+            .await
+            .unwrap();
+        assert_eq!(5, stored.len());
+    }
+
+    #[tokio::test]
+    async fn failed_writes_are_retried_in_order() {
+        let (cache, backend) = tiered(InMemoryStockDataCache::new(), Vec::new()).await;
+        backend.write_failures.lock().unwrap().extend([
+            Error::backend("connection refused"),
+            Error::backend("connection refused"),
+        ]);
+        for close in [1.0, 2.0] {
+            cache
+                .write_candles(
+                    "BTCUSDT".to_string(),
+                    Interval::OneMinute,
+                    vec![candle(0, close)],
+                )
+                .await
+                .unwrap();
+        }
+
+        // Flushes report the backend as behind until the batch made it
+        let mut failed_flushes = 0;
+        while cache.flush().await.is_err() {
+            failed_flushes += 1;
+        }
+        assert!(failed_flushes >= 1);
+
+        let stored = backend
+            .inner
+            .get_candles("BTCUSDT".to_string(), Interval::OneMinute, minutes(0..10))
+            .await
+            .unwrap();
+        assert_eq!(vec![candle(0, 2.0)], stored);
+        let stats = cache.metrics().snapshot();
+        assert_eq!(2, stats.retries);
+        assert_eq!(2, stats.batches_written);
+        assert_eq!(0, stats.batches_dropped);
+        assert!(stats.last_error.unwrap().contains("connection refused"));
+    }
+
+    #[tokio::test]
+    async fn rejected_writes_are_dropped() {
+        let (cache, backend) = tiered(InMemoryStockDataCache::new(), Vec::new()).await;
+        backend
+            .write_failures
+            .lock()
+            .unwrap()
+            .push_back(Error::rejected("bad line"));
+        for minute_offset in 0..2 {
+            cache
+                .write_candles(
+                    "BTCUSDT".to_string(),
+                    Interval::OneMinute,
+                    vec![candle(minute_offset, 1.0)],
+                )
+                .await
+                .unwrap();
+        }
+
+        // Not reported to readers, only in the metrics
+        cache.flush().await.unwrap();
+        assert_eq!(vec![1.0, 1.0], read(&cache, 0..10).await);
+        let stats = cache.metrics().snapshot();
+        assert_eq!(1, stats.batches_dropped);
+        assert_eq!(1, stats.batches_written);
+        assert_eq!(0, stats.retries);
+    }
+
+    #[tokio::test]
+    async fn pending_writes_win_over_backend() {
+        let stored = (0..5).map(|i| candle(i, 1.0)).collect();
+        let (cache, _backend) = tiered(InMemoryStockDataCache::new(), stored).await;
+        cache
+            .write_candles(
+                "BTCUSDT".to_string(),
+                Interval::OneMinute,
+                vec![candle(2, 9.0)],
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(vec![1.0, 1.0, 9.0, 1.0, 1.0], read(&cache, 0..10).await);
+    }
+
+    #[tokio::test]
+    async fn writes_during_a_fetch_are_kept() {
+        let stored = (0..10).map(|i| candle(i, i as f64)).collect();
+        let (cache, backend) = tiered(InMemoryStockDataCache::new(), stored).await;
+        let cache = Arc::new(cache);
+
+        let gate = backend.read_gate.lock().await;
+        let reader = tokio::spawn({
+            let cache = Arc::clone(&cache);
+            async move { read(&cache, 0..10).await }
+        });
+        while backend.queries.lock().unwrap().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        // Lands in memory after the backend answered with the old candle
+        cache
+            .write_candles(
+                "BTCUSDT".to_string(),
+                Interval::OneMinute,
+                vec![candle(5, 55.0)],
+            )
+            .await
+            .unwrap();
+        drop(gate);
+        assert_eq!(10, reader.await.unwrap().len());
+
+        assert_eq!(vec![55.0], read(&cache, 5..6).await);
+        assert_eq!(1, backend.queries.lock().unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn bounds_are_tracked_locally() {
+        let stored = (10..20).map(|i| candle(i, i as f64)).collect();
+        let (cache, backend) = tiered(InMemoryStockDataCache::new(), stored).await;
+
+        let first = cache
+            .get_first_candle("BTCUSDT".to_string(), Interval::OneMinute)
+            .await
+            .unwrap();
+        let last = cache
+            .get_last_candle("BTCUSDT".to_string(), Interval::OneMinute)
+            .await
+            .unwrap();
+        cache
+            .write_candles(
+                "BTCUSDT".to_string(),
+                Interval::OneMinute,
+                vec![candle(20, 20.0)],
+            )
+            .await
+            .unwrap();
+        let new_last = cache
+            .get_last_candle("BTCUSDT".to_string(), Interval::OneMinute)
+            .await
+            .unwrap();
+
+        assert_eq!(Some(candle(10, 10.0)), first);
+        assert_eq!(Some(candle(19, 19.0)), last);
+        assert_eq!(Some(candle(20, 20.0)), new_last);
+        assert_eq!(2, backend.bound_queries.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn evicted_candles_are_fetched_again() {
+        let stored = (0..100).map(|i| candle(i, i as f64)).collect();
+        let (cache, backend) = tiered(
+            InMemoryStockDataCache::new().with_capacity_limit(20),
+            stored,
+        )
+        .await;
+
+        assert_eq!(20, read(&cache, 0..20).await.len());
+        // Pushes the oldest candles out of memory
+        assert_eq!(10, read(&cache, 20..30).await.len());
+        assert_eq!(20, read(&cache, 0..20).await.len());
+
+        let queries = backend.queries.lock().unwrap().clone();
+        assert_eq!(minutes(0..20), queries[0]);
+        assert_eq!(minutes(20..30), queries[1]);
+        assert_eq!(3, queries.len());
+    }
+}
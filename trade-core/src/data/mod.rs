@@ -1,2 +1,4 @@
 pub mod database;
+pub mod order_book;
 pub mod providers;
+pub mod resample;
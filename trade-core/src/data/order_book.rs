@@ -0,0 +1,317 @@
+use crate::data::providers::OrderBookProvider;
+use crate::models::order_book::{OrderBook, OrderBookDiff};
+use crate::Error;
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+// Depth diffs kept while waiting for a snapshot
+const DEFAULT_MAX_PENDING: usize = 1000;
+// Levels per side requested for snapshots
+const DEFAULT_SNAPSHOT_DEPTH: u16 = 1000;
+
+// Outcome of feeding a depth diff to a `LocalOrderBook`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DepthUpdate {
+    Applied,
+    // Already contained in the book, e.g. buffered before the snapshot
+    Stale,
+    // Kept until a snapshot arrives
+    Buffered,
+    // Updates were missed, the book needs a new snapshot
+    Gap,
+}
+
+// Order book kept in sync with Binance style depth diffs.
+//
+// Diffs are buffered until a snapshot is applied. Afterwards every diff has to continue where the
+// book ends, i.e. `first_update_id <= last_update_id + 1 <= final_update_id`, otherwise the book
+// is dropped and diffs are buffered again until the next snapshot.
+pub struct LocalOrderBook {
+    book: Option<OrderBook>,
+    pending: VecDeque<OrderBookDiff>,
+    max_pending: usize,
+}
+
+impl Default for LocalOrderBook {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LocalOrderBook {
+    pub fn new() -> Self {
+        LocalOrderBook {
+            book: None,
+            pending: VecDeque::new(),
+            max_pending: DEFAULT_MAX_PENDING,
+        }
+    }
+
+    // Oldest diffs are dropped once more are pending
+    pub fn with_max_pending(mut self, max_pending: usize) -> Self {
+        self.max_pending = max_pending.max(1);
+        self
+    }
+
+    // `None` while out of sync
+    pub fn book(&self) -> Option<&OrderBook> {
+        self.book.as_ref()
+    }
+
+    pub fn is_synced(&self) -> bool {
+        self.book.is_some()
+    }
+
+    pub fn apply_diff(&mut self, diff: OrderBookDiff) -> DepthUpdate {
+        let book = match &mut self.book {
+            Some(book) => book,
+            None => {
+                if self.pending.len() >= self.max_pending {
+                    self.pending.pop_front();
+                }
+                self.pending.push_back(diff);
+                return DepthUpdate::Buffered;
+            }
+        };
+
+        if diff.final_update_id <= book.last_update_id {
+            return DepthUpdate::Stale;
+        }
+        if diff.first_update_id > book.last_update_id + 1 {
+            self.book = None;
+            self.pending.clear();
+            self.pending.push_back(diff);
+            return DepthUpdate::Gap;
+        }
+        book.apply(&diff);
+        DepthUpdate::Applied
+    }
+
+    // Replaces the book and replays the buffered diffs on top. Returns `Gap` if the snapshot is
+    // older than the buffered diffs, the book then stays out of sync.
+    pub fn apply_snapshot(&mut self, snapshot: OrderBook) -> DepthUpdate {
+        let mut pending = std::mem::take(&mut self.pending);
+        self.book = Some(snapshot);
+        while let Some(diff) = pending.pop_front() {
+            if self.apply_diff(diff) == DepthUpdate::Gap {
+                // Keeps the rest for the next snapshot
+                self.pending.extend(pending);
+                return DepthUpdate::Gap;
+            }
+        }
+        DepthUpdate::Applied
+    }
+}
+
+// Keeps a `LocalOrderBook` of one symbol in sync, fetching a new snapshot whenever it is not
+pub struct OrderBookSync {
+    symbol: String,
+    depth: u16,
+    provider: Arc<dyn OrderBookProvider>,
+    local: LocalOrderBook,
+    resyncs: u64,
+}
+
+impl OrderBookSync {
+    pub fn new(symbol: String, provider: Arc<dyn OrderBookProvider>) -> Self {
+        OrderBookSync {
+            symbol,
+            depth: DEFAULT_SNAPSHOT_DEPTH,
+            provider,
+            local: LocalOrderBook::new(),
+            resyncs: 0,
+        }
+    }
+
+    pub fn with_depth(mut self, depth: u16) -> Self {
+        self.depth = depth;
+        self
+    }
+
+    pub fn book(&self) -> Option<&OrderBook> {
+        self.local.book()
+    }
+
+    // Amount of snapshots fetched so far
+    pub fn resyncs(&self) -> u64 {
+        self.resyncs
+    }
+
+    // Returns the book if it is in sync after the diff
+    pub async fn handle_diff(&mut self, diff: OrderBookDiff) -> Result<Option<&OrderBook>, Error> {
+        match self.local.apply_diff(diff) {
+            DepthUpdate::Applied | DepthUpdate::Stale => {}
+            DepthUpdate::Buffered | DepthUpdate::Gap => {
+                let snapshot = self
+                    .provider
+                    .get_order_book(self.symbol.clone(), self.depth)
+                    .await?;
+                self.resyncs += 1;
+                // A snapshot older than the buffered diffs is retried with the next diff
+                self.local.apply_snapshot(snapshot);
+            }
+        }
+        Ok(self.local.book())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DepthUpdate, LocalOrderBook, OrderBookSync};
+    use crate::data::providers::OrderBookProvider;
+    use crate::models::decimal::Decimal;
+    use crate::models::order_book::{OrderBook, OrderBookDiff, PriceLevel};
+    use crate::Error;
+    use async_trait::async_trait;
+    use chrono::{TimeZone, Utc};
+    use std::sync::{Arc, Mutex};
+
+    fn level(price: f64, quantity: f64) -> PriceLevel {
+        PriceLevel {
+            price: Decimal::from_f64(price).unwrap(),
+            quantity: Decimal::from_f64(quantity).unwrap(),
+        }
+    }
+
+    fn diff(first: u64, last: u64, bids: Vec<PriceLevel>, asks: Vec<PriceLevel>) -> OrderBookDiff {
+        OrderBookDiff {
+            first_update_id: first,
+            final_update_id: last,
+            bids,
+            asks,
+            time: Utc.with_ymd_and_hms(2022, 1, 1, 0, 0, 0).unwrap(),
+        }
+    }
+
+    fn snapshot(last_update_id: u64) -> OrderBook {
+        OrderBook::new(
+            last_update_id,
+            vec![level(99.0, 1.0), level(100.0, 2.0)],
+            vec![level(102.0, 1.0), level(101.0, 3.0)],
+        )
+    }
+
+    #[test]
+    fn snapshot_levels_are_sorted() {
+        let book = snapshot(1);
+
+        assert_eq!(Some(level(100.0, 2.0)), book.best_bid());
+        assert_eq!(Some(level(101.0, 3.0)), book.best_ask());
+        assert_eq!(Some(Decimal::from(1)), book.spread());
+        assert_eq!(Some(Decimal::new(1005, 1)), book.mid_price());
+    }
+
+    #[test]
+    fn buffered_diffs_are_replayed_after_the_snapshot() {
+        let mut local = LocalOrderBook::new();
+        assert_eq!(
+            DepthUpdate::Buffered,
+            local.apply_diff(diff(5, 8, vec![level(100.0, 9.0)], Vec::new()))
+        );
+        assert_eq!(
+            DepthUpdate::Buffered,
+            local.apply_diff(diff(
+                9,
+                12,
+                vec![level(100.5, 1.0)],
+                vec![level(101.0, 0.0)]
+            ))
+        );
+
+        assert_eq!(DepthUpdate::Applied, local.apply_snapshot(snapshot(10)));
+
+        let book = local.book().unwrap();
+        assert_eq!(12, book.last_update_id);
+        // The first diff is older than the snapshot
+        assert_eq!(
+            vec![level(100.5, 1.0), level(100.0, 2.0), level(99.0, 1.0)],
+            book.bids
+        );
+        assert_eq!(vec![level(102.0, 1.0)], book.asks);
+    }
+
+    #[test]
+    fn gaps_drop_the_book() {
+        let mut local = LocalOrderBook::new();
+        local.apply_snapshot(snapshot(10));
+
+        assert_eq!(
+            DepthUpdate::Applied,
+            local.apply_diff(diff(11, 15, Vec::new(), Vec::new()))
+        );
+        assert_eq!(
+            DepthUpdate::Stale,
+            local.apply_diff(diff(14, 15, Vec::new(), Vec::new()))
+        );
+        assert_eq!(
+            DepthUpdate::Gap,
+            local.apply_diff(diff(17, 20, Vec::new(), Vec::new()))
+        );
+        assert!(!local.is_synced());
+        assert_eq!(
+            DepthUpdate::Buffered,
+            local.apply_diff(diff(21, 22, Vec::new(), Vec::new()))
+        );
+
+        // Still older than the buffered diffs
+        assert_eq!(DepthUpdate::Gap, local.apply_snapshot(snapshot(15)));
+        assert!(!local.is_synced());
+        assert_eq!(DepthUpdate::Applied, local.apply_snapshot(snapshot(18)));
+        assert_eq!(22, local.book().unwrap().last_update_id);
+    }
+
+    #[test]
+    fn pending_diffs_are_bounded() {
+        let mut local = LocalOrderBook::new().with_max_pending(2);
+        for id in 1..=3 {
+            local.apply_diff(diff(id, id, Vec::new(), Vec::new()));
+        }
+
+        // The first diff was dropped, so the snapshot has to cover it
+        assert_eq!(DepthUpdate::Gap, local.apply_snapshot(snapshot(0)));
+        assert_eq!(DepthUpdate::Applied, local.apply_snapshot(snapshot(1)));
+        assert_eq!(3, local.book().unwrap().last_update_id);
+    }
+
+    // Hands out the given snapshots in order
+    struct SnapshotQueue {
+        snapshots: Mutex<Vec<OrderBook>>,
+    }
+
+    #[async_trait]
+    impl OrderBookProvider for SnapshotQueue {
+        async fn get_order_book(&self, symbol: String, _depth: u16) -> Result<OrderBook, Error> {
+            let mut snapshots = self.snapshots.lock().unwrap();
+            if snapshots.is_empty() {
+                return Err(Error::NotFound(symbol));
+            }
+            Ok(snapshots.remove(0))
+        }
+    }
+
+    #[tokio::test]
+    async fn sync_resyncs_after_a_gap() {
+        let provider = Arc::new(SnapshotQueue {
+            snapshots: Mutex::new(vec![snapshot(10), snapshot(30)]),
+        });
+        let mut sync = OrderBookSync::new("BTCUSDT".to_string(), provider);
+
+        let book = sync
+            .handle_diff(diff(9, 11, Vec::new(), Vec::new()))
+            .await
+            .unwrap();
+        assert_eq!(Some(11), book.map(|book| book.last_update_id));
+
+        let book = sync
+            .handle_diff(diff(25, 31, Vec::new(), Vec::new()))
+            .await
+            .unwrap();
+        assert_eq!(Some(31), book.map(|book| book.last_update_id));
+        assert_eq!(2, sync.resyncs());
+
+        assert!(sync
+            .handle_diff(diff(40, 41, Vec::new(), Vec::new()))
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,468 @@
+use super::{CandleStream, MarketDataProvider, OrderBookProvider};
+use crate::models::{
+    candle::Candle,
+    interval::Interval,
+    order_book::{OrderBook, OrderBookDiff, PriceLevel},
+    symbol::SymbolInfo,
+    trade::{Side, Trade},
+};
+use crate::Error;
+use async_trait::async_trait;
+use binance::api::Binance;
+use binance::config::Config;
+use binance::general::General;
+use binance::market::Market;
+use binance::util::build_request;
+use binance::websockets::{kline_stream, WebSockets};
+use binance::ws_model::TradeEvent;
+use chrono::{DateTime, TimeZone, Utc};
+use futures::channel::mpsc;
+use serde::de::IgnoredAny;
+use serde::Deserialize;
+use std::ops::Range;
+use std::sync::atomic::AtomicBool;
+
+// Maximum amount of klines Binance returns per request
+const KLINE_LIMIT: u16 = 1000;
+
+const EXCHANGE_INFO_ENDPOINT: &str = "/api/v3/exchangeInfo";
+const KLINES_ENDPOINT: &str = "/api/v3/klines";
+const DEPTH_ENDPOINT: &str = "/api/v3/depth";
+
+pub struct BinanceMarketDataProvider {
+    general: General,
+    market: Market,
+    config: Config,
+}
+
+impl BinanceMarketDataProvider {
+    pub fn new(api_key: Option<String>, secret_key: Option<String>) -> Self {
+        Self::new_with_config(api_key, secret_key, Config::default())
+    }
+
+    pub fn new_with_config(
+        api_key: Option<String>,
+        secret_key: Option<String>,
+        config: Config,
+    ) -> Self {
+        BinanceMarketDataProvider {
+            general: General::new_with_config(api_key.clone(), secret_key.clone(), &config),
+            market: Market::new_with_config(api_key, secret_key, &config),
+            config,
+        }
+    }
+}
+
+#[async_trait]
+impl MarketDataProvider for BinanceMarketDataProvider {
+    async fn get_symbols(&self) -> Result<Vec<SymbolInfo>, Error> {
+        let exchange_info: ExchangeInfo = self
+            .general
+            .client
+            .get(EXCHANGE_INFO_ENDPOINT, None)
+            .await
+            .map_err(binance_error)?;
+        exchange_info
+            .symbols
+            .into_iter()
+            .map(|symbol| {
+                let tick_size = symbol
+                    .filters
+                    .iter()
+                    .find_map(|filter| match filter {
+                        Filter::Price { tick_size } => Some(tick_size.parse()),
+                        _ => None,
+                    })
+                    .transpose()?
+                    .unwrap_or_default();
+                let (lot_size, min_quantity) = symbol
+                    .filters
+                    .iter()
+                    .find_map(|filter| match filter {
+                        Filter::LotSize { step_size, min_qty } => Some((step_size, min_qty)),
+                        _ => None,
+                    })
+                    .map(|(step_size, min_qty)| {
+                        Ok::<_, Error>((step_size.parse()?, min_qty.parse()?))
+                    })
+                    .transpose()?
+                    .unwrap_or_default();
+                Ok(SymbolInfo {
+                    symbol: symbol.symbol,
+                    base_asset: symbol.base_asset,
+                    quote_asset: symbol.quote_asset,
+                    tick_size,
+                    lot_size,
+                    min_quantity,
+                })
+            })
+            .collect()
+    }
+
+    async fn get_candles(
+        &self,
+        symbol: String,
+        interval: Interval,
+        range: Range<DateTime<Utc>>,
+    ) -> Result<Vec<Candle>, Error> {
+        let mut candles = Vec::new();
+        let mut start = range.start.timestamp_millis();
+        // Binance treats `endTime` as inclusive
+        let end = range.end.timestamp_millis() - 1;
+
+        while start <= end {
+            let request = build_request([
+                ("symbol", symbol.clone()),
+                ("interval", interval.as_str().to_string()),
+                ("limit", KLINE_LIMIT.to_string()),
+                ("startTime", start.to_string()),
+                ("endTime", end.to_string()),
+            ]);
+            let klines: Vec<RestKline> = self
+                .market
+                .client
+                .get(KLINES_ENDPOINT, Some(&request))
+                .await
+                .map_err(binance_error)?;
+
+            let received = klines.len();
+            match klines.last() {
+                Some(last) => start = last.0 + 1,
+                None => break,
+            }
+            for kline in &klines {
+                candles.push(candle_from_kline(kline)?);
+            }
+
+            if received < KLINE_LIMIT as usize {
+                break;
+            }
+        }
+
+        Ok(candles)
+    }
+
+    // The callback's error type is given by binance-rs-async
+    #[allow(clippy::result_large_err)]
+    async fn stream_candles(
+        &self,
+        symbol: String,
+        interval: Interval,
+    ) -> Result<CandleStream, Error> {
+        let (sender, receiver) = mpsc::unbounded();
+        let endpoint = kline_stream(&symbol.to_lowercase(), interval.as_str());
+
+        let callback_sender = sender.clone();
+        let mut web_socket: WebSockets<'static, MarketEvent> = WebSockets::new_with_options(
+            move |event| {
+                if let MarketEvent::Kline(event) = event {
+                    if event.kline.is_final_bar {
+                        // Stop the event loop once the stream has been dropped
+                        callback_sender
+                            .unbounded_send(candle_from_ws_kline(&event.kline))
+                            .map_err(|_| binance::errors::Error::Msg("Receiver dropped".into()))?;
+                    }
+                }
+                Ok(())
+            },
+            self.config.clone(),
+        );
+        web_socket.connect(&endpoint).await.map_err(binance_error)?;
+
+        tokio::spawn(async move {
+            let running = AtomicBool::new(true);
+            if let Err(err) = web_socket.event_loop(&running).await {
+                sender.unbounded_send(Err(binance_error(err))).ok();
+            }
+        });
+
+        Ok(Box::pin(receiver))
+    }
+}
+
+#[async_trait]
+impl OrderBookProvider for BinanceMarketDataProvider {
+    async fn get_order_book(&self, symbol: String, depth: u16) -> Result<OrderBook, Error> {
+        let request = build_request([("symbol", symbol), ("limit", depth.to_string())]);
+        let book: Depth = self
+            .market
+            .client
+            .get(DEPTH_ENDPOINT, Some(&request))
+            .await
+            .map_err(binance_error)?;
+        Ok(OrderBook::new(
+            book.last_update_id,
+            price_levels(&book.bids)?,
+            price_levels(&book.asks)?,
+        ))
+    }
+}
+
+// Binance error code for unknown symbols
+const INVALID_SYMBOL: i32 = -1121;
+// Binance error codes of server and network issues, all others are about the request itself
+const SERVER_ERRORS: std::ops::RangeInclusive<i32> = -1099..=-1000;
+
+fn binance_error(err: binance::errors::Error) -> Error {
+    use binance::errors::Error as BinanceError;
+    match err {
+        BinanceError::UnknownSymbol(symbol) => Error::NotFound(symbol),
+        BinanceError::BinanceError { response } if response.code == INVALID_SYMBOL => {
+            Error::NotFound(response.msg)
+        }
+        BinanceError::BinanceError { response } if !SERVER_ERRORS.contains(&response.code) => {
+            Error::rejected(response)
+        }
+        err @ (BinanceError::InvalidPrice
+        | BinanceError::InvalidOrderError { .. }
+        | BinanceError::InvalidPeriod(_)
+        | BinanceError::InvalidListenKey(_)
+        | BinanceError::Unauthorized) => Error::rejected(err),
+        BinanceError::ReqError(err) if err.is_decode() => Error::decode(err),
+        BinanceError::Json(err) => Error::decode(err),
+        BinanceError::ParseFloatError(err) => Error::decode(err),
+        err => Error::backend(err),
+    }
+}
+
+fn timestamp(unix_ms: i64) -> DateTime<Utc> {
+    Utc.timestamp_millis_opt(unix_ms).unwrap()
+}
+
+// binance-rs-async decodes klines, depth levels and filters into f64, so they are read with the
+// models below instead. Binance sends those values as strings, which are parsed straight into
+// `Decimal` without losing digits.
+
+#[derive(Deserialize)]
+struct ExchangeInfo {
+    symbols: Vec<SymbolFilters>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SymbolFilters {
+    symbol: String,
+    base_asset: String,
+    quote_asset: String,
+    filters: Vec<Filter>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "filterType")]
+enum Filter {
+    #[serde(rename = "PRICE_FILTER", rename_all = "camelCase")]
+    Price { tick_size: String },
+    #[serde(rename = "LOT_SIZE", rename_all = "camelCase")]
+    LotSize { step_size: String, min_qty: String },
+    #[serde(other)]
+    Other,
+}
+
+// Open time, open, high, low, close and volume, followed by fields that are not needed
+#[derive(Deserialize)]
+struct RestKline(
+    i64,
+    String,
+    String,
+    String,
+    String,
+    String,
+    IgnoredAny,
+    IgnoredAny,
+    IgnoredAny,
+    IgnoredAny,
+    IgnoredAny,
+    IgnoredAny,
+);
+
+// Price and quantity
+type Level = (String, String);
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Depth {
+    last_update_id: u64,
+    bids: Vec<Level>,
+    asks: Vec<Level>,
+}
+
+// Events of the market data streams
+#[derive(Debug, Deserialize)]
+#[serde(tag = "e")]
+pub enum MarketEvent {
+    #[serde(rename = "kline")]
+    Kline(KlineEvent),
+    #[serde(rename = "trade")]
+    Trade(TradeEvent),
+    #[serde(rename = "depthUpdate")]
+    Depth(DepthEvent),
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct KlineEvent {
+    #[serde(rename = "s")]
+    pub symbol: String,
+    #[serde(rename = "k")]
+    pub kline: Kline,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Kline {
+    #[serde(rename = "t")]
+    pub start_time: i64,
+    #[serde(rename = "o")]
+    pub open: String,
+    #[serde(rename = "h")]
+    pub high: String,
+    #[serde(rename = "l")]
+    pub low: String,
+    #[serde(rename = "c")]
+    pub close: String,
+    #[serde(rename = "v")]
+    pub volume: String,
+    #[serde(rename = "x")]
+    pub is_final_bar: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DepthEvent {
+    #[serde(rename = "E")]
+    pub event_time: u64,
+    #[serde(rename = "U")]
+    pub first_update_id: u64,
+    #[serde(rename = "u")]
+    pub final_update_id: u64,
+    #[serde(rename = "b")]
+    pub bids: Vec<Level>,
+    #[serde(rename = "a")]
+    pub asks: Vec<Level>,
+}
+
+pub fn candle_from_ws_kline(kline: &Kline) -> Result<Candle, Error> {
+    Ok(Candle {
+        open: kline.open.parse()?,
+        high: kline.high.parse()?,
+        low: kline.low.parse()?,
+        close: kline.close.parse()?,
+        volume: kline.volume.parse()?,
+        time: timestamp(kline.start_time),
+    })
+}
+
+fn candle_from_kline(kline: &RestKline) -> Result<Candle, Error> {
+    Ok(Candle {
+        open: kline.1.parse()?,
+        high: kline.2.parse()?,
+        low: kline.3.parse()?,
+        close: kline.4.parse()?,
+        volume: kline.5.parse()?,
+        time: timestamp(kline.0),
+    })
+}
+
+fn price_levels(levels: &[Level]) -> Result<Vec<PriceLevel>, Error> {
+    levels
+        .iter()
+        .map(|(price, quantity)| {
+            Ok(PriceLevel {
+                price: price.parse()?,
+                quantity: quantity.parse()?,
+            })
+        })
+        .collect()
+}
+
+pub fn order_book_diff_from_ws_depth(event: &DepthEvent) -> Result<OrderBookDiff, Error> {
+    Ok(OrderBookDiff {
+        first_update_id: event.first_update_id,
+        final_update_id: event.final_update_id,
+        bids: price_levels(&event.bids)?,
+        asks: price_levels(&event.asks)?,
+        time: timestamp(event.event_time as i64),
+    })
+}
+
+pub fn trade_from_ws_trade(event: &TradeEvent) -> Result<Trade, Error> {
+    Ok(Trade {
+        id: event.trade_id,
+        price: event.price.parse()?,
+        quantity: event.qty.parse()?,
+        // The maker is the passive side, so the taker sold into the bid
+        side: if event.is_buyer_maker {
+            Side::Sell
+        } else {
+            Side::Buy
+        },
+        time: timestamp(event.trade_order_time as i64),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_data::{dec, minute};
+
+    // More significant digits than an f64 holds
+    const PRICE: &str = "20000.12345678901234";
+    const VOLUME: &str = "123456789.1234567891";
+
+    #[test]
+    fn rest_klines_keep_every_digit() {
+        let kline: RestKline = serde_json::from_str(&format!(
+            r#"[1640995200000, "{PRICE}", "{PRICE}", "{PRICE}", "{PRICE}", "{VOLUME}",
+                1640995259999, "0", 3, "0", "0", "0"]"#
+        ))
+        .unwrap();
+        let candle = candle_from_kline(&kline).unwrap();
+
+        assert_eq!(candle.open, dec(PRICE));
+        assert_eq!(candle.close, dec(PRICE));
+        assert_eq!(candle.volume, dec(VOLUME));
+        assert_eq!(candle.time, minute(0));
+    }
+
+    #[test]
+    fn stream_klines_keep_every_digit() {
+        let event: MarketEvent = serde_json::from_str(&format!(
+            r#"{{"e": "kline", "E": 1640995260000, "s": "BTCUSDT", "k": {{
+                "t": 1640995200000, "T": 1640995259999, "s": "BTCUSDT", "i": "1m",
+                "o": "{PRICE}", "h": "{PRICE}", "l": "{PRICE}", "c": "{PRICE}",
+                "v": "{VOLUME}", "n": 3, "x": true
+            }}}}"#
+        ))
+        .unwrap();
+        let event = match event {
+            MarketEvent::Kline(event) => event,
+            event => panic!("Unexpected event {:?}", event),
+        };
+        let candle = candle_from_ws_kline(&event.kline).unwrap();
+
+        assert_eq!(event.symbol, "BTCUSDT");
+        assert_eq!(candle.high, dec(PRICE));
+        assert_eq!(candle.low, dec(PRICE));
+        assert_eq!(candle.volume, dec(VOLUME));
+        assert_eq!(candle.time, minute(0));
+    }
+
+    #[test]
+    fn depth_levels_keep_every_digit() {
+        let event: MarketEvent = serde_json::from_str(&format!(
+            r#"{{"e": "depthUpdate", "E": 1640995200000, "s": "BTCUSDT", "U": 5, "u": 7,
+                "b": [["{PRICE}", "{VOLUME}"]], "a": []}}"#
+        ))
+        .unwrap();
+        let event = match event {
+            MarketEvent::Depth(event) => event,
+            event => panic!("Unexpected event {:?}", event),
+        };
+        let diff = order_book_diff_from_ws_depth(&event).unwrap();
+
+        assert_eq!(diff.first_update_id, 5);
+        assert_eq!(diff.final_update_id, 7);
+        assert_eq!(diff.bids[0].price, dec(PRICE));
+        assert_eq!(diff.bids[0].quantity, dec(VOLUME));
+        assert!(diff.asks.is_empty());
+    }
+}
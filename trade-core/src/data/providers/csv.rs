@@ -0,0 +1,322 @@
+use super::{CandleStream, MarketDataProvider};
+use crate::models::{candle::Candle, decimal::Decimal, interval::Interval, symbol::SymbolInfo};
+use crate::Error;
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use futures::stream;
+use std::collections::HashMap;
+use std::io::Read;
+use std::ops::Range;
+use std::path::Path;
+use std::time::Duration;
+
+// Replays candles from CSV files, e.g. for backtests or development without exchange access.
+//
+// The files need a header row with the columns `time` (or `date`/`timestamp`), `open`, `high`,
+// `low`, `close` and `volume`; other columns are ignored. Times are RFC 3339, `%Y-%m-%d %H:%M:%S`,
+// `%Y-%m-%d` (UTC) or unix milliseconds.
+#[derive(Default)]
+pub struct CsvMarketDataProvider {
+    symbols: Vec<SymbolInfo>,
+    series: HashMap<(String, Interval), Vec<Candle>>,
+    // Multiple of real time, `None` replays without delay
+    replay_speed: Option<f64>,
+}
+
+impl CsvMarketDataProvider {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Rejects speeds that are not positive and finite, they have no delay between candles
+    pub fn with_replay_speed(mut self, replay_speed: f64) -> Result<Self, Error> {
+        if !(replay_speed.is_finite() && replay_speed > 0.0) {
+            return Err(Error::rejected(format!(
+                "Invalid replay speed {}",
+                replay_speed
+            )));
+        }
+        self.replay_speed = Some(replay_speed);
+        Ok(self)
+    }
+
+    pub fn add_symbol(&mut self, info: SymbolInfo) {
+        self.symbols.retain(|symbol| symbol.symbol != info.symbol);
+        self.symbols.push(info);
+    }
+
+    // Returns the amount of candles read
+    pub fn read_candles<R: Read>(
+        &mut self,
+        symbol: String,
+        interval: Interval,
+        reader: R,
+    ) -> Result<usize, Error> {
+        let mut csv_reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(reader);
+        let columns = Columns::from_headers(csv_reader.headers().map_err(csv_error)?)?;
+
+        let mut candles = Vec::new();
+        for record in csv_reader.records() {
+            candles.push(columns.parse(&record.map_err(csv_error)?)?);
+        }
+        let count = candles.len();
+
+        if !self.symbols.iter().any(|info| info.symbol == symbol) {
+            self.symbols.push(SymbolInfo {
+                symbol: symbol.clone(),
+                base_asset: symbol.clone(),
+                quote_asset: String::new(),
+                tick_size: Decimal::ZERO,
+                lot_size: Decimal::ZERO,
+                min_quantity: Decimal::ZERO,
+            });
+        }
+
+        let series = self.series.entry((symbol, interval)).or_default();
+        series.extend(candles);
+        series.sort_by_key(|candle| candle.time);
+        series.dedup_by_key(|candle| candle.time);
+        Ok(count)
+    }
+
+    pub async fn load_file<P: AsRef<Path>>(
+        &mut self,
+        symbol: String,
+        interval: Interval,
+        path: P,
+    ) -> Result<usize, Error> {
+        let data = tokio::fs::read(path).await?;
+        self.read_candles(symbol, interval, data.as_slice())
+    }
+}
+
+#[async_trait]
+impl MarketDataProvider for CsvMarketDataProvider {
+    async fn get_symbols(&self) -> Result<Vec<SymbolInfo>, Error> {
+        Ok(self.symbols.clone())
+    }
+
+    async fn get_candles(
+        &self,
+        symbol: String,
+        interval: Interval,
+        range: Range<DateTime<Utc>>,
+    ) -> Result<Vec<Candle>, Error> {
+        Ok(self
+            .series
+            .get(&(symbol, interval))
+            .map(|series| {
+                series
+                    .iter()
+                    .filter(|candle| range.contains(&candle.time))
+                    .copied()
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    async fn stream_candles(
+        &self,
+        symbol: String,
+        interval: Interval,
+    ) -> Result<CandleStream, Error> {
+        let candles = self
+            .series
+            .get(&(symbol, interval))
+            .cloned()
+            .unwrap_or_default();
+        let delay = self
+            .replay_speed
+            .map(|speed| Duration::from_secs_f64(interval.seconds() as f64 / speed));
+
+        Ok(Box::pin(stream::unfold(
+            candles.into_iter(),
+            move |mut candles| async move {
+                let candle = candles.next()?;
+                if let Some(delay) = delay {
+                    tokio::time::sleep(delay).await;
+                }
+                Some((Ok(candle), candles))
+            },
+        )))
+    }
+}
+
+struct Columns {
+    time: usize,
+    open: usize,
+    high: usize,
+    low: usize,
+    close: usize,
+    volume: usize,
+}
+
+impl Columns {
+    fn from_headers(headers: &csv::StringRecord) -> Result<Self, Error> {
+        let find = |names: &[&str]| {
+            headers
+                .iter()
+                .position(|header| names.iter().any(|name| header.eq_ignore_ascii_case(name)))
+                .ok_or_else(|| Error::decode(format!("Missing column: {}", names[0])))
+        };
+        Ok(Columns {
+            time: find(&["time", "date", "timestamp"])?,
+            open: find(&["open"])?,
+            high: find(&["high"])?,
+            low: find(&["low"])?,
+            close: find(&["close"])?,
+            volume: find(&["volume"])?,
+        })
+    }
+
+    fn parse(&self, record: &csv::StringRecord) -> Result<Candle, Error> {
+        let field = |index: usize| record.get(index).unwrap_or_default();
+        let number = |index: usize| -> Result<Decimal, Error> {
+            // Thousands separators, e.g. "7,380,500"
+            field(index)
+                .replace(',', "")
+                .parse()
+                .map_err(|_| Error::decode(format!("Invalid number: {}", field(index))))
+        };
+        Ok(Candle {
+            open: number(self.open)?,
+            high: number(self.high)?,
+            low: number(self.low)?,
+            close: number(self.close)?,
+            volume: number(self.volume)?,
+            time: parse_time(field(self.time))?,
+        })
+    }
+}
+
+fn parse_time(value: &str) -> Result<DateTime<Utc>, Error> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    if let Ok(time) = NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S") {
+        return Ok(Utc.from_utc_datetime(&time));
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return Ok(Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0).unwrap()));
+    }
+    if let Ok(unix_ms) = value.parse::<i64>() {
+        if let Some(time) = Utc.timestamp_millis_opt(unix_ms).single() {
+            return Ok(time);
+        }
+    }
+    Err(Error::decode(format!("Invalid time: {}", value)))
+}
+
+fn csv_error(err: csv::Error) -> Error {
+    if err.is_io_error() {
+        Error::backend(err)
+    } else {
+        Error::decode(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CsvMarketDataProvider;
+    use crate::data::providers::MarketDataProvider;
+    use crate::models::{decimal::Decimal, interval::Interval};
+    use chrono::{TimeZone, Utc};
+    use futures::StreamExt;
+
+    const CSV: &str = "Date,Open,High,Low,Close,Adj Close,Volume
+2001-01-03,1283.27,1347.76,1274.61,1347.56,1347.56,1880700000
+2001-01-02,1320.28,1320.28,1276.05,1283.27,1283.27,\"1,129,400,000\"
+2001-01-04,1347.56,1350.24,1329.14,1333.34,1333.34,2131000000
+";
+
+    fn provider() -> CsvMarketDataProvider {
+        let mut provider = CsvMarketDataProvider::new();
+        let count = provider
+            .read_candles("GSPC".to_string(), Interval::OneDay, CSV.as_bytes())
+            .expect("Failed to read csv");
+        assert_eq!(3, count);
+        provider
+    }
+
+    #[tokio::test]
+    async fn range_query_works() {
+        let provider = provider();
+        let candles = provider
+            .get_candles(
+                "GSPC".to_string(),
+                Interval::OneDay,
+                Utc.with_ymd_and_hms(2001, 1, 2, 0, 0, 0).unwrap()
+                    ..Utc.with_ymd_and_hms(2001, 1, 4, 0, 0, 0).unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(2, candles.len());
+        assert_eq!(
+            Utc.with_ymd_and_hms(2001, 1, 2, 0, 0, 0).unwrap(),
+            candles[0].time
+        );
+        assert_eq!(Decimal::from(1_129_400_000), candles[0].volume);
+        assert_eq!("1347.56".parse::<Decimal>().unwrap(), candles[1].close);
+    }
+
+    #[tokio::test]
+    async fn unknown_interval_is_empty() {
+        let provider = provider();
+        let candles = provider
+            .get_candles(
+                "GSPC".to_string(),
+                Interval::OneHour,
+                Utc.with_ymd_and_hms(2001, 1, 1, 0, 0, 0).unwrap()
+                    ..Utc.with_ymd_and_hms(2002, 1, 1, 0, 0, 0).unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert!(candles.is_empty());
+    }
+
+    #[tokio::test]
+    async fn replay_is_ordered() {
+        let provider = provider();
+        let times: Vec<_> = provider
+            .stream_candles("GSPC".to_string(), Interval::OneDay)
+            .await
+            .unwrap()
+            .map(|candle| candle.unwrap().time)
+            .collect()
+            .await;
+
+        assert_eq!(3, times.len());
+        assert!(times.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[tokio::test]
+    async fn replay_speed_is_validated() {
+        for speed in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            assert!(CsvMarketDataProvider::new()
+                .with_replay_speed(speed)
+                .is_err());
+        }
+
+        let provider = provider().with_replay_speed(1e9).unwrap();
+        let candles: Vec<_> = provider
+            .stream_candles("GSPC".to_string(), Interval::OneDay)
+            .await
+            .unwrap()
+            .collect()
+            .await;
+        assert_eq!(3, candles.len());
+    }
+
+    #[tokio::test]
+    async fn symbols_are_registered() {
+        let provider = provider();
+        let symbols = provider.get_symbols().await.unwrap();
+
+        assert_eq!(1, symbols.len());
+        assert_eq!("GSPC", symbols[0].symbol);
+    }
+}
@@ -0,0 +1,57 @@
+pub mod binance;
+pub mod csv;
+
+use crate::data::database::StockDataCache;
+use crate::models::{
+    candle::Candle, interval::Interval, order_book::OrderBook, symbol::SymbolInfo,
+};
+use crate::Error;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::stream::Stream;
+use std::ops::Range;
+use std::pin::Pin;
+
+pub type CandleStream = Pin<Box<dyn Stream<Item = Result<Candle, Error>> + Send>>;
+
+#[async_trait]
+pub trait MarketDataProvider: Send + Sync {
+    async fn get_symbols(&self) -> Result<Vec<SymbolInfo>, Error>;
+
+    // Closed candles whose open time lies within `range`, ordered by time
+    async fn get_candles(
+        &self,
+        symbol: String,
+        interval: Interval,
+        range: Range<DateTime<Utc>>,
+    ) -> Result<Vec<Candle>, Error>;
+
+    // Candles as they close, starting with the next one
+    async fn stream_candles(
+        &self,
+        symbol: String,
+        interval: Interval,
+    ) -> Result<CandleStream, Error>;
+
+    // Returns the amount of candles written
+    async fn fill_cache(
+        &self,
+        cache: &(dyn StockDataCache + Send + Sync),
+        symbol: String,
+        interval: Interval,
+        range: Range<DateTime<Utc>>,
+    ) -> Result<usize, Error> {
+        let candles = self.get_candles(symbol.clone(), interval, range).await?;
+        let count = candles.len();
+        if count > 0 {
+            cache.write_candles(symbol, interval, candles).await?;
+        }
+        Ok(count)
+    }
+}
+
+#[async_trait]
+pub trait OrderBookProvider: Send + Sync {
+    // Snapshot of the best `depth` levels per side
+    async fn get_order_book(&self, symbol: String, depth: u16) -> Result<OrderBook, Error>;
+}
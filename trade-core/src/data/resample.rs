@@ -0,0 +1,186 @@
+use crate::models::{candle::Candle, interval::Interval};
+use chrono::{DateTime, Duration, Utc};
+
+// Aggregates finer candles into candles of a coarser interval.
+//
+// Buckets start at multiples of the interval shifted by the session offset, e.g. an offset of
+// 14h30m aligns daily candles to the NYSE open instead of midnight UTC. Empty buckets are
+// skipped rather than filled with flat candles.
+#[derive(Debug, Clone)]
+pub struct Resampler {
+    interval: Interval,
+    session_offset: Duration,
+    current: Option<Candle>,
+}
+
+impl Resampler {
+    pub fn new(interval: Interval) -> Self {
+        Resampler {
+            interval,
+            session_offset: Duration::zero(),
+            current: None,
+        }
+    }
+
+    pub fn with_session_offset(mut self, session_offset: Duration) -> Self {
+        self.session_offset = session_offset;
+        self
+    }
+
+    pub fn interval(&self) -> Interval {
+        self.interval
+    }
+
+    // Open time of the bucket containing `time`
+    pub fn bucket_start(&self, time: DateTime<Utc>) -> DateTime<Utc> {
+        self.interval.floor(time - self.session_offset) + self.session_offset
+    }
+
+    // Adds the next candle in time order, returns the previous bucket once a candle of a later
+    // bucket arrives. Candles older than the current bucket are ignored.
+    pub fn push(&mut self, candle: Candle) -> Option<Candle> {
+        let bucket = self.bucket_start(candle.time);
+        match self.current.as_mut() {
+            Some(current) if current.time == bucket => {
+                current.high = current.high.max(candle.high);
+                current.low = current.low.min(candle.low);
+                current.close = candle.close;
+                current.volume += candle.volume;
+                None
+            }
+            Some(current) if current.time > bucket => None,
+            _ => self.current.replace(Candle {
+                time: bucket,
+                ..candle
+            }),
+        }
+    }
+
+    // Returns the current, possibly incomplete bucket
+    pub fn flush(&mut self) -> Option<Candle> {
+        self.current.take()
+    }
+
+    // Resamples a whole series, the last candle may be incomplete
+    pub fn resample(&self, candles: &[Candle]) -> Vec<Candle> {
+        let mut sorted = candles.to_vec();
+        sorted.sort_by_key(|candle| candle.time);
+
+        let mut resampler = Resampler::new(self.interval).with_session_offset(self.session_offset);
+        let mut resampled: Vec<Candle> = sorted
+            .into_iter()
+            .filter_map(|candle| resampler.push(candle))
+            .collect();
+        resampled.extend(resampler.flush());
+        resampled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Resampler;
+    use crate::models::{candle::Candle, decimal::Decimal, interval::Interval};
+    use crate::test_data::{dec, minute, ohlc};
+    use chrono::{Duration, TimeZone, Utc};
+
+    #[test]
+    fn aggregates_ohlcv() {
+        let candles = vec![
+            ohlc(0, "10", "12", "9", "11"),
+            ohlc(1, "11", "15", "10", "14"),
+            ohlc(2, "14", "14.5", "8", "9"),
+            ohlc(5, "9", "9.5", "8.5", "9.2"),
+        ];
+
+        let resampled = Resampler::new(Interval::FiveMinutes).resample(&candles);
+
+        assert_eq!(
+            vec![
+                Candle {
+                    open: dec("10"),
+                    high: dec("15"),
+                    low: dec("8"),
+                    close: dec("9"),
+                    volume: dec("3"),
+                    time: minute(0),
+                },
+                Candle {
+                    open: dec("9"),
+                    high: dec("9.5"),
+                    low: dec("8.5"),
+                    close: dec("9.2"),
+                    volume: dec("1"),
+                    time: minute(5),
+                },
+            ],
+            resampled
+        );
+    }
+
+    #[test]
+    fn unordered_input_is_sorted() {
+        let candles = vec![
+            ohlc(2, "3", "3", "3", "3"),
+            ohlc(0, "1", "1", "1", "1"),
+            ohlc(1, "2", "2", "2", "2"),
+        ];
+
+        let resampled = Resampler::new(Interval::FiveMinutes).resample(&candles);
+
+        assert_eq!(1, resampled.len());
+        assert_eq!(dec("1"), resampled[0].open);
+        assert_eq!(dec("3"), resampled[0].close);
+    }
+
+    #[test]
+    fn empty_buckets_are_skipped() {
+        let candles = vec![ohlc(0, "1", "1", "1", "1"), ohlc(50, "2", "2", "2", "2")];
+
+        let resampled = Resampler::new(Interval::FifteenMinutes).resample(&candles);
+
+        let times: Vec<_> = resampled.iter().map(|candle| candle.time).collect();
+        assert_eq!(vec![minute(0), minute(45)], times);
+    }
+
+    #[test]
+    fn session_offset_aligns_buckets() {
+        // Hourly candles of two NYSE sessions, opening at 14:30 UTC
+        let session_open = Utc.with_ymd_and_hms(2022, 6, 15, 14, 30, 0).unwrap();
+        let candles: Vec<_> = (0..7)
+            .chain(24..31)
+            .map(|hour| Candle {
+                time: session_open + Duration::hours(hour),
+                ..ohlc(0, "1", "2", "0.5", "1.5")
+            })
+            .collect();
+
+        let resampled = Resampler::new(Interval::OneDay)
+            .with_session_offset(Duration::minutes(14 * 60 + 30))
+            .resample(&candles);
+
+        let times: Vec<_> = resampled.iter().map(|candle| candle.time).collect();
+        assert_eq!(vec![session_open, session_open + Duration::days(1)], times);
+        assert!(resampled
+            .iter()
+            .all(|candle| candle.volume == Decimal::from(7)));
+    }
+
+    #[test]
+    fn streaming_emits_completed_buckets() {
+        let mut resampler = Resampler::new(Interval::FiveMinutes);
+
+        assert_eq!(None, resampler.push(ohlc(3, "1", "1", "1", "1")));
+        assert_eq!(None, resampler.push(ohlc(4, "2", "2", "2", "2")));
+        let completed = resampler
+            .push(ohlc(5, "3", "3", "3", "3"))
+            .expect("Bucket not completed");
+        // Late candle of an already emitted bucket
+        assert_eq!(None, resampler.push(ohlc(4, "9", "9", "9", "9")));
+
+        assert_eq!(minute(0), completed.time);
+        assert_eq!(dec("2"), completed.close);
+        assert_eq!(dec("2"), completed.volume);
+        assert_eq!(Some(minute(5)), resampler.flush().map(|candle| candle.time));
+        assert_eq!(None, resampler.flush());
+    }
+}
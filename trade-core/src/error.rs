@@ -0,0 +1,108 @@
+use chrono::{DateTime, Utc};
+use std::fmt;
+use std::ops::Range;
+
+// Errors of the candle caches, market data providers and stocks
+#[derive(Debug)]
+pub enum Error {
+    // Storage or upstream API failed, e.g. I/O or network errors
+    Backend(Box<dyn std::error::Error + Send + Sync>),
+    // Stored or received data cannot be decoded
+    Decode(String),
+    // Unknown symbol or series
+    NotFound(String),
+    // Request refused by the backend, it fails the same way when retried
+    Rejected(String),
+    InvalidRange {
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    },
+}
+
+impl Error {
+    pub fn backend<E: Into<Box<dyn std::error::Error + Send + Sync>>>(err: E) -> Self {
+        Error::Backend(err.into())
+    }
+
+    pub fn decode<M: fmt::Display>(message: M) -> Self {
+        Error::Decode(message.to_string())
+    }
+
+    pub fn rejected<M: fmt::Display>(message: M) -> Self {
+        Error::Rejected(message.to_string())
+    }
+
+    // Only backend failures may go away when retried
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Error::Backend(_))
+    }
+
+    // Empty ranges are fine, inverted ones are rejected
+    pub fn check_range(range: &Range<DateTime<Utc>>) -> Result<(), Error> {
+        if range.start > range.end {
+            return Err(Error::InvalidRange {
+                start: range.start,
+                end: range.end,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Backend(err) => write!(f, "Backend error: {}", err),
+            Error::Decode(message) => write!(f, "Cannot decode data: {}", message),
+            Error::NotFound(name) => write!(f, "Not found: {}", name),
+            Error::Rejected(message) => write!(f, "Rejected: {}", message),
+            Error::InvalidRange { start, end } => {
+                write!(f, "Invalid range: {} is after {}", start, end)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Backend(err) => Some(err.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Backend(Box::new(err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Error;
+    use chrono::{Duration, TimeZone, Utc};
+
+    #[test]
+    fn inverted_ranges_are_rejected() {
+        let start = Utc.with_ymd_and_hms(2022, 1, 1, 0, 0, 0).unwrap();
+        let end = start + Duration::minutes(1);
+
+        assert!(Error::check_range(&(start..end)).is_ok());
+        assert!(Error::check_range(&(start..start)).is_ok());
+        assert!(matches!(
+            Error::check_range(&(end..start)),
+            Err(Error::InvalidRange { .. })
+        ));
+    }
+
+    #[test]
+    fn only_backend_errors_are_transient() {
+        let io = std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out");
+
+        assert!(Error::from(io).is_transient());
+        assert!(!Error::decode("bad block").is_transient());
+        assert!(!Error::NotFound("BTCUSDT".to_string()).is_transient());
+        assert!(!Error::rejected("bad line").is_transient());
+    }
+}
@@ -0,0 +1,88 @@
+use super::Indicator;
+use crate::models::candle::Candle;
+
+// Average true range with Wilder's smoothing. The true range of the first candle is its high-low
+// range, as there is no previous close.
+#[derive(Debug, Clone)]
+pub struct Atr {
+    period: usize,
+    previous_close: Option<f64>,
+    ranges: usize,
+    value: f64,
+}
+
+impl Atr {
+    pub fn new(period: usize) -> Self {
+        Atr {
+            period: period.max(1),
+            previous_close: None,
+            ranges: 0,
+            value: 0.0,
+        }
+    }
+
+    pub fn period(&self) -> usize {
+        self.period
+    }
+}
+
+impl Indicator for Atr {
+    type Output = f64;
+
+    fn update(&mut self, candle: &Candle) -> Option<f64> {
+        let (high, low, close) = (
+            candle.high.to_f64(),
+            candle.low.to_f64(),
+            candle.close.to_f64(),
+        );
+        let true_range = match self.previous_close.replace(close) {
+            Some(previous) => (high - low)
+                .max((high - previous).abs())
+                .max((low - previous).abs()),
+            None => high - low,
+        };
+        let period = self.period as f64;
+
+        self.ranges += 1;
+        if self.ranges <= self.period {
+            // The first average is a simple one
+            self.value += true_range / period;
+            return (self.ranges == self.period).then_some(self.value);
+        }
+        self.value = (self.value * (period - 1.0) + true_range) / period;
+        Some(self.value)
+    }
+
+    fn reset(&mut self) {
+        *self = Atr::new(self.period);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Atr;
+    use crate::indicators::test_data::{assert_close, candles, HLCV};
+    use crate::indicators::Indicator;
+
+    #[test]
+    fn atr_matches_reference() {
+        let mut atr = Atr::new(5);
+
+        let mut expected = vec![None; 4];
+        expected.extend(
+            [
+                0.616, 0.5748, 0.5118, 0.5075, 0.526, 0.4848, 0.5738, 0.6111, 0.5788, 0.5551,
+            ]
+            .map(Some),
+        );
+        assert_close(&expected, &atr.batch(&candles(&HLCV)), 4);
+    }
+
+    #[test]
+    fn gaps_count_into_the_true_range() {
+        let mut atr = Atr::new(1);
+        let rows = [(10.0, 9.0, 10.0, 1.0), (13.0, 12.0, 12.5, 1.0)];
+
+        assert_eq!(vec![Some(1.0), Some(3.0)], atr.batch(&candles(&rows)));
+    }
+}
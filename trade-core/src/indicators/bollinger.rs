@@ -0,0 +1,149 @@
+use super::Indicator;
+use crate::models::candle::Candle;
+use std::collections::VecDeque;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BollingerBands {
+    pub upper: f64,
+    // Simple moving average
+    pub middle: f64,
+    pub lower: f64,
+}
+
+// Bands `deviations` population standard deviations around the simple moving average of the
+// closes
+#[derive(Debug, Clone)]
+pub struct Bollinger {
+    period: usize,
+    deviations: f64,
+    window: VecDeque<f64>,
+    sum: f64,
+    sum_of_squares: f64,
+}
+
+impl Default for Bollinger {
+    // 20 periods, two standard deviations
+    fn default() -> Self {
+        Self::new(20, 2.0)
+    }
+}
+
+impl Bollinger {
+    pub fn new(period: usize, deviations: f64) -> Self {
+        let period = period.max(1);
+        Bollinger {
+            period,
+            deviations,
+            window: VecDeque::with_capacity(period),
+            sum: 0.0,
+            sum_of_squares: 0.0,
+        }
+    }
+
+    pub fn push(&mut self, value: f64) -> Option<BollingerBands> {
+        if self.window.len() == self.period {
+            let oldest = self.window.pop_front().unwrap();
+            self.sum -= oldest;
+            self.sum_of_squares -= oldest * oldest;
+        }
+        self.window.push_back(value);
+        self.sum += value;
+        self.sum_of_squares += value * value;
+        if self.window.len() < self.period {
+            return None;
+        }
+
+        let period = self.period as f64;
+        let mean = self.sum / period;
+        // Rounding errors may leave a tiny negative variance for flat prices
+        let variance = (self.sum_of_squares / period - mean * mean).max(0.0);
+        let width = self.deviations * variance.sqrt();
+        Some(BollingerBands {
+            upper: mean + width,
+            middle: mean,
+            lower: mean - width,
+        })
+    }
+}
+
+impl Indicator for Bollinger {
+    type Output = BollingerBands;
+
+    fn update(&mut self, candle: &Candle) -> Option<BollingerBands> {
+        self.push(candle.close.to_f64())
+    }
+
+    fn reset(&mut self) {
+        *self = Bollinger::new(self.period, self.deviations);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Bollinger, BollingerBands};
+    use crate::indicators::test_data::{assert_close, closes};
+    use crate::indicators::Indicator;
+
+    const CLOSES: [f64; 30] = [
+        22.27, 22.19, 22.08, 22.17, 22.18, 22.13, 22.23, 22.43, 22.24, 22.29, 22.15, 22.39, 22.38,
+        22.61, 23.36, 24.05, 23.75, 23.83, 23.95, 23.63, 23.82, 23.87, 23.65, 23.19, 23.10, 23.33,
+        22.68, 23.10, 22.40, 22.17,
+    ];
+
+    #[test]
+    fn bands_match_reference() {
+        let outputs = Bollinger::new(10, 2.0).batch(&closes(&CLOSES));
+
+        // (upper, middle, lower)
+        let reference = [
+            (22.4051, 22.221, 22.0369),
+            (22.3944, 22.209, 22.0236),
+            (22.4428, 22.229, 22.0152),
+            (22.4648, 22.259, 22.0532),
+            (22.5871, 22.303, 22.0189),
+            (23.1036, 22.421, 21.7384),
+            (23.7732, 22.613, 21.4528),
+            (24.0734, 22.765, 21.4566),
+            (24.3341, 22.905, 21.4759),
+            (24.5543, 23.076, 21.5977),
+            (24.6204, 23.21, 21.7996),
+            (24.6328, 23.377, 22.1212),
+            (24.6191, 23.525, 22.4309),
+            (24.4358, 23.652, 22.8682),
+            (24.2119, 23.71, 23.2081),
+            (24.2748, 23.684, 23.0932),
+            (24.182, 23.612, 23.042),
+            (24.2917, 23.505, 22.7183),
+            (24.22, 23.432, 22.644),
+            (24.1954, 23.277, 22.3586),
+            (24.2258, 23.131, 22.0362),
+        ];
+        let expected = |field: fn(&(f64, f64, f64)) -> f64| -> Vec<Option<f64>> {
+            let mut values = vec![None; 9];
+            values.extend(reference.iter().map(|row| Some(field(row))));
+            values
+        };
+        let actual = |field: fn(&BollingerBands) -> f64| -> Vec<Option<f64>> {
+            outputs
+                .iter()
+                .map(|output| output.as_ref().map(field))
+                .collect()
+        };
+
+        assert_close(&expected(|row| row.0), &actual(|bands| bands.upper), 4);
+        assert_close(&expected(|row| row.1), &actual(|bands| bands.middle), 4);
+        assert_close(&expected(|row| row.2), &actual(|bands| bands.lower), 4);
+    }
+
+    #[test]
+    fn flat_prices_collapse_the_bands() {
+        let mut bollinger = Bollinger::new(3, 2.0);
+
+        for _ in 0..5 {
+            bollinger.push(0.1);
+        }
+        let bands = bollinger.push(0.1).unwrap();
+        assert!((bands.upper - 0.1).abs() < 1e-6);
+        assert!((bands.lower - 0.1).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,132 @@
+use super::moving_average::Ema;
+use super::Indicator;
+use crate::models::candle::Candle;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MacdOutput {
+    // Difference of the fast and the slow EMA
+    pub macd: f64,
+    // EMA of `macd`
+    pub signal: f64,
+    pub histogram: f64,
+}
+
+// Moving average convergence divergence of the closes. Outputs start once the signal line is
+// known, after `slow + signal - 1` values.
+#[derive(Debug, Clone)]
+pub struct Macd {
+    fast: Ema,
+    slow: Ema,
+    signal: Ema,
+}
+
+impl Default for Macd {
+    // The common 12/26/9 setup
+    fn default() -> Self {
+        Self::new(12, 26, 9)
+    }
+}
+
+impl Macd {
+    pub fn new(fast: usize, slow: usize, signal: usize) -> Self {
+        Macd {
+            fast: Ema::new(fast),
+            slow: Ema::new(slow),
+            signal: Ema::new(signal),
+        }
+    }
+
+    pub fn push(&mut self, value: f64) -> Option<MacdOutput> {
+        // Both averages need every value
+        let fast = self.fast.push(value);
+        let slow = self.slow.push(value);
+        let macd = fast? - slow?;
+        let signal = self.signal.push(macd)?;
+        Some(MacdOutput {
+            macd,
+            signal,
+            histogram: macd - signal,
+        })
+    }
+}
+
+impl Indicator for Macd {
+    type Output = MacdOutput;
+
+    fn update(&mut self, candle: &Candle) -> Option<MacdOutput> {
+        self.push(candle.close.to_f64())
+    }
+
+    fn reset(&mut self) {
+        *self = Macd::new(self.fast.period(), self.slow.period(), self.signal.period());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Macd;
+    use crate::indicators::test_data::{assert_close, closes};
+    use crate::indicators::Indicator;
+
+    const CLOSES: [f64; 30] = [
+        22.27, 22.19, 22.08, 22.17, 22.18, 22.13, 22.23, 22.43, 22.24, 22.29, 22.15, 22.39, 22.38,
+        22.61, 23.36, 24.05, 23.75, 23.83, 23.95, 23.63, 23.82, 23.87, 23.65, 23.19, 23.10, 23.33,
+        22.68, 23.10, 22.40, 22.17,
+    ];
+
+    #[test]
+    fn macd_matches_reference() {
+        let outputs = Macd::new(5, 10, 4).batch(&closes(&CLOSES));
+
+        // (macd, signal, histogram)
+        let reference = [
+            (0.0487, 0.0396, 0.0091),
+            (0.0845, 0.0576, 0.0269),
+            (0.2126, 0.1196, 0.093),
+            (0.3741, 0.2214, 0.1527),
+            (0.3941, 0.2904, 0.1036),
+            (0.3932, 0.3315, 0.0616),
+            (0.3871, 0.3538, 0.0333),
+            (0.3118, 0.337, -0.0252),
+            (0.2806, 0.3144, -0.0338),
+            (0.2542, 0.2903, -0.0361),
+            (0.191, 0.2506, -0.0596),
+            (0.0753, 0.1805, -0.1052),
+            (-0.006, 0.1059, -0.1119),
+            (-0.0152, 0.0575, -0.0726),
+            (-0.1177, -0.0126, -0.1051),
+            (-0.1029, -0.0487, -0.0542),
+            (-0.1946, -0.1071, -0.0875),
+            (-0.2677, -0.1713, -0.0964),
+        ];
+        let expected = |field: fn(&(f64, f64, f64)) -> f64| -> Vec<Option<f64>> {
+            let mut values = vec![None; 12];
+            values.extend(reference.iter().map(|row| Some(field(row))));
+            values
+        };
+        let actual = |field: fn(&super::MacdOutput) -> f64| -> Vec<Option<f64>> {
+            outputs
+                .iter()
+                .map(|output| output.as_ref().map(field))
+                .collect()
+        };
+
+        assert_close(&expected(|row| row.0), &actual(|output| output.macd), 4);
+        assert_close(&expected(|row| row.1), &actual(|output| output.signal), 4);
+        assert_close(
+            &expected(|row| row.2),
+            &actual(|output| output.histogram),
+            4,
+        );
+    }
+
+    #[test]
+    fn default_warms_up_for_33_values() {
+        let mut macd = Macd::default();
+        let candles = closes(&[CLOSES, CLOSES].concat());
+
+        let outputs = macd.batch(&candles);
+        assert!(outputs[..33].iter().all(Option::is_none));
+        assert!(outputs[33..].iter().all(Option::is_some));
+    }
+}
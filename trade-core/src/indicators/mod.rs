@@ -0,0 +1,88 @@
+pub mod atr;
+pub mod bollinger;
+pub mod macd;
+pub mod moving_average;
+pub mod rsi;
+pub mod volume;
+
+use crate::models::candle::Candle;
+
+// Technical indicator fed one candle at a time, every update takes constant time.
+//
+// Indicators compute in f64, they are features and signals rather than amounts of money.
+pub trait Indicator {
+    type Output;
+
+    // Feeds the next candle, `None` while there are not enough candles yet
+    fn update(&mut self, candle: &Candle) -> Option<Self::Output>;
+
+    // Forgets all candles fed so far
+    fn reset(&mut self);
+
+    // Feeds a whole series, the output at index `i` belongs to `candles[i]`
+    fn batch(&mut self, candles: &[Candle]) -> Vec<Option<Self::Output>> {
+        candles.iter().map(|candle| self.update(candle)).collect()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test_data {
+    use crate::models::{candle::Candle, decimal::Decimal};
+    use chrono::{Duration, TimeZone, Utc};
+
+    // High, low, close and volume of 14 days
+    pub const HLCV: [(f64, f64, f64, f64); 14] = [
+        (48.70, 47.79, 48.16, 1200.0),
+        (48.72, 48.14, 48.61, 1500.0),
+        (48.90, 48.39, 48.75, 1100.0),
+        (48.87, 48.37, 48.63, 900.0),
+        (48.82, 48.24, 48.74, 1300.0),
+        (49.05, 48.64, 49.03, 1700.0),
+        (49.20, 48.94, 49.07, 1600.0),
+        (49.35, 48.86, 49.32, 1400.0),
+        (49.92, 49.50, 49.91, 2200.0),
+        (50.19, 49.87, 50.13, 2100.0),
+        (50.12, 49.20, 49.53, 1800.0),
+        (49.66, 48.90, 49.50, 1000.0),
+        (49.88, 49.43, 49.75, 1250.0),
+        (50.19, 49.73, 50.03, 1900.0),
+    ];
+
+    // Daily candles with the given high, low, close and volume
+    pub fn candles(rows: &[(f64, f64, f64, f64)]) -> Vec<Candle> {
+        let start = Utc.with_ymd_and_hms(2022, 1, 3, 0, 0, 0).unwrap();
+        rows.iter()
+            .enumerate()
+            .map(|(day, &(high, low, close, volume))| Candle {
+                open: Decimal::from_f64(close).unwrap(),
+                high: Decimal::from_f64(high).unwrap(),
+                low: Decimal::from_f64(low).unwrap(),
+                close: Decimal::from_f64(close).unwrap(),
+                volume: Decimal::from_f64(volume).unwrap(),
+                time: start + Duration::days(day as i64),
+            })
+            .collect()
+    }
+
+    pub fn closes(closes: &[f64]) -> Vec<Candle> {
+        let rows: Vec<_> = closes
+            .iter()
+            .map(|&close| (close, close, close, 1.0))
+            .collect();
+        candles(&rows)
+    }
+
+    // Compares with reference values rounded to `places`, `None` marks the warm up
+    pub fn assert_close(expected: &[Option<f64>], actual: &[Option<f64>], places: i32) {
+        let tolerance = 0.5 * 10f64.powi(-places) + 1e-9;
+        assert_eq!(expected.len(), actual.len());
+        for (index, (expected, actual)) in expected.iter().zip(actual).enumerate() {
+            let matches = match (expected, actual) {
+                (Some(expected), Some(actual)) => (expected - actual).abs() <= tolerance,
+                (None, None) => true,
+                _ => false,
+            };
+            assert!(matches, "{:?} != {:?} at {}", expected, actual, index);
+        }
+    }
+}
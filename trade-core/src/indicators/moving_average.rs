@@ -0,0 +1,164 @@
+use super::Indicator;
+use crate::models::candle::Candle;
+use std::collections::VecDeque;
+
+// Simple moving average of the closes
+#[derive(Debug, Clone)]
+pub struct Sma {
+    period: usize,
+    window: VecDeque<f64>,
+    sum: f64,
+}
+
+impl Sma {
+    pub fn new(period: usize) -> Self {
+        let period = period.max(1);
+        Sma {
+            period,
+            window: VecDeque::with_capacity(period),
+            sum: 0.0,
+        }
+    }
+
+    pub fn period(&self) -> usize {
+        self.period
+    }
+
+    // Adds the next value, the average is known once `period` values were added
+    pub fn push(&mut self, value: f64) -> Option<f64> {
+        if self.window.len() == self.period {
+            self.sum -= self.window.pop_front().unwrap();
+        }
+        self.window.push_back(value);
+        self.sum += value;
+
+        (self.window.len() == self.period).then(|| self.sum / self.period as f64)
+    }
+}
+
+impl Indicator for Sma {
+    type Output = f64;
+
+    fn update(&mut self, candle: &Candle) -> Option<f64> {
+        self.push(candle.close.to_f64())
+    }
+
+    fn reset(&mut self) {
+        *self = Sma::new(self.period);
+    }
+}
+
+// Exponential moving average of the closes with `alpha = 2 / (period + 1)`, seeded with the
+// simple average of the first `period` values
+#[derive(Debug, Clone)]
+pub struct Ema {
+    alpha: f64,
+    seed: Sma,
+    value: Option<f64>,
+}
+
+impl Ema {
+    pub fn new(period: usize) -> Self {
+        let seed = Sma::new(period);
+        Ema {
+            alpha: 2.0 / (seed.period() as f64 + 1.0),
+            seed,
+            value: None,
+        }
+    }
+
+    pub fn period(&self) -> usize {
+        self.seed.period()
+    }
+
+    pub fn push(&mut self, value: f64) -> Option<f64> {
+        let average = match self.value {
+            Some(average) => average + self.alpha * (value - average),
+            None => self.seed.push(value)?,
+        };
+        self.value = Some(average);
+        self.value
+    }
+}
+
+impl Indicator for Ema {
+    type Output = f64;
+
+    fn update(&mut self, candle: &Candle) -> Option<f64> {
+        self.push(candle.close.to_f64())
+    }
+
+    fn reset(&mut self) {
+        *self = Ema::new(self.period());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Ema, Sma};
+    use crate::indicators::test_data::{assert_close, closes};
+    use crate::indicators::Indicator;
+
+    // 10 day EMA example of StockCharts
+    const CLOSES: [f64; 30] = [
+        22.27, 22.19, 22.08, 22.17, 22.18, 22.13, 22.23, 22.43, 22.24, 22.29, 22.15, 22.39, 22.38,
+        22.61, 23.36, 24.05, 23.75, 23.83, 23.95, 23.63, 23.82, 23.87, 23.65, 23.19, 23.10, 23.33,
+        22.68, 23.10, 22.40, 22.17,
+    ];
+
+    fn warm_up(period: usize, values: &[f64]) -> Vec<Option<f64>> {
+        let mut expected = vec![None; period - 1];
+        expected.extend(values.iter().copied().map(Some));
+        expected
+    }
+
+    #[test]
+    fn sma_matches_reference() {
+        let mut sma = Sma::new(10);
+
+        let expected = warm_up(
+            10,
+            &[
+                22.221, 22.209, 22.229, 22.259, 22.303, 22.421, 22.613, 22.765, 22.905, 23.076,
+                23.21, 23.377, 23.525, 23.652, 23.71, 23.684, 23.612, 23.505, 23.432, 23.277,
+                23.131,
+            ],
+        );
+        assert_close(&expected, &sma.batch(&closes(&CLOSES)), 4);
+    }
+
+    #[test]
+    fn ema_matches_reference() {
+        let mut ema = Ema::new(10);
+
+        let expected = warm_up(
+            10,
+            &[
+                22.221, 22.2081, 22.2412, 22.2664, 22.3289, 22.5164, 22.7952, 22.9688, 23.1254,
+                23.2753, 23.3398, 23.4271, 23.5076, 23.5335, 23.4711, 23.4036, 23.3902, 23.2611,
+                23.2318, 23.0806, 22.915,
+            ],
+        );
+        assert_close(&expected, &ema.batch(&closes(&CLOSES)), 4);
+    }
+
+    #[test]
+    fn streaming_matches_batch_after_reset() {
+        let candles = closes(&CLOSES);
+        let mut ema = Ema::new(5);
+        let batch = ema.batch(&candles);
+
+        ema.reset();
+        let streamed: Vec<_> = candles.iter().map(|candle| ema.update(candle)).collect();
+        assert_eq!(batch, streamed);
+    }
+
+    #[test]
+    fn period_of_one_follows_the_values() {
+        let mut sma = Sma::new(0);
+
+        assert_eq!(1, sma.period());
+        assert_eq!(Some(3.0), sma.push(3.0));
+        assert_eq!(Some(4.0), sma.push(4.0));
+    }
+}
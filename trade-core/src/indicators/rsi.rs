@@ -0,0 +1,116 @@
+use super::Indicator;
+use crate::models::candle::Candle;
+
+// Relative strength index of the closes with Wilder's smoothing, between 0 and 100
+#[derive(Debug, Clone)]
+pub struct Rsi {
+    period: usize,
+    previous: Option<f64>,
+    changes: usize,
+    average_gain: f64,
+    average_loss: f64,
+}
+
+impl Rsi {
+    pub fn new(period: usize) -> Self {
+        Rsi {
+            period: period.max(1),
+            previous: None,
+            changes: 0,
+            average_gain: 0.0,
+            average_loss: 0.0,
+        }
+    }
+
+    pub fn period(&self) -> usize {
+        self.period
+    }
+
+    // Known once `period` changes, i.e. `period + 1` values, were added
+    pub fn push(&mut self, value: f64) -> Option<f64> {
+        let change = value - self.previous.replace(value)?;
+        let (gain, loss) = (change.max(0.0), (-change).max(0.0));
+        let period = self.period as f64;
+
+        self.changes += 1;
+        if self.changes <= self.period {
+            // The first averages are simple ones
+            self.average_gain += gain / period;
+            self.average_loss += loss / period;
+            if self.changes < self.period {
+                return None;
+            }
+        } else {
+            self.average_gain = (self.average_gain * (period - 1.0) + gain) / period;
+            self.average_loss = (self.average_loss * (period - 1.0) + loss) / period;
+        }
+
+        Some(if self.average_loss == 0.0 {
+            // Flat prices are neutral
+            if self.average_gain == 0.0 {
+                50.0
+            } else {
+                100.0
+            }
+        } else {
+            100.0 - 100.0 / (1.0 + self.average_gain / self.average_loss)
+        })
+    }
+}
+
+impl Indicator for Rsi {
+    type Output = f64;
+
+    fn update(&mut self, candle: &Candle) -> Option<f64> {
+        self.push(candle.close.to_f64())
+    }
+
+    fn reset(&mut self) {
+        *self = Rsi::new(self.period);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Rsi;
+    use crate::indicators::test_data::{assert_close, closes};
+    use crate::indicators::Indicator;
+
+    // 14 day RSI example of StockCharts
+    const CLOSES: [f64; 30] = [
+        44.34, 44.09, 44.15, 43.61, 44.33, 44.83, 45.10, 45.42, 45.84, 46.08, 45.89, 46.03, 45.61,
+        46.28, 46.28, 46.00, 46.03, 46.41, 46.22, 45.64, 46.21, 46.25, 45.71, 46.45, 45.78, 45.35,
+        44.03, 44.38, 44.02, 43.39,
+    ];
+
+    #[test]
+    fn rsi_matches_reference() {
+        let mut rsi = Rsi::new(14);
+
+        let mut expected = vec![None; 14];
+        expected.extend(
+            [
+                70.4641, 66.2496, 66.4809, 69.3469, 66.2947, 57.915, 62.8807, 63.2088, 56.0116,
+                62.3399, 54.671, 50.3868, 40.0194, 43.3479, 40.8377, 36.8193,
+            ]
+            .map(Some),
+        );
+        assert_close(&expected, &rsi.batch(&closes(&CLOSES)), 4);
+    }
+
+    #[test]
+    fn one_sided_moves_hit_the_bounds() {
+        let mut rising = Rsi::new(3);
+        let mut falling = Rsi::new(3);
+        let mut flat = Rsi::new(3);
+
+        for value in 1..=4 {
+            rising.push(value as f64);
+            falling.push(-value as f64);
+            flat.push(1.0);
+        }
+        assert_eq!(Some(100.0), rising.push(5.0));
+        assert_eq!(Some(0.0), falling.push(-5.0));
+        assert_eq!(Some(50.0), flat.push(1.0));
+    }
+}
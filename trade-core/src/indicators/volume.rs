@@ -0,0 +1,143 @@
+use super::Indicator;
+use crate::models::{candle::Candle, interval::Interval};
+use chrono::{DateTime, Utc};
+
+// Volume weighted average of the typical price `(high + low + close) / 3`, accumulated since the
+// first candle or since the start of the current session
+#[derive(Debug, Clone, Default)]
+pub struct Vwap {
+    session: Option<Interval>,
+    session_start: Option<DateTime<Utc>>,
+    volume: f64,
+    price_volume: f64,
+}
+
+impl Vwap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Starts over with every session, e.g. `Interval::OneDay` for a daily VWAP
+    pub fn with_session(mut self, session: Interval) -> Self {
+        self.session = Some(session);
+        self
+    }
+}
+
+impl Indicator for Vwap {
+    type Output = f64;
+
+    // `None` until there was some volume
+    fn update(&mut self, candle: &Candle) -> Option<f64> {
+        if let Some(session) = self.session {
+            let start = session.floor(candle.time);
+            if self.session_start != Some(start) {
+                self.session_start = Some(start);
+                self.volume = 0.0;
+                self.price_volume = 0.0;
+            }
+        }
+
+        let typical = (candle.high.to_f64() + candle.low.to_f64() + candle.close.to_f64()) / 3.0;
+        let volume = candle.volume.to_f64();
+        self.volume += volume;
+        self.price_volume += typical * volume;
+
+        (self.volume > 0.0).then(|| self.price_volume / self.volume)
+    }
+
+    fn reset(&mut self) {
+        *self = Vwap {
+            session: self.session,
+            ..Vwap::default()
+        };
+    }
+}
+
+// On-balance volume, starting at zero with the first candle
+#[derive(Debug, Clone, Default)]
+pub struct Obv {
+    previous_close: Option<f64>,
+    value: f64,
+}
+
+impl Obv {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Indicator for Obv {
+    type Output = f64;
+
+    fn update(&mut self, candle: &Candle) -> Option<f64> {
+        let close = candle.close.to_f64();
+        if let Some(previous) = self.previous_close.replace(close) {
+            if close > previous {
+                self.value += candle.volume.to_f64();
+            } else if close < previous {
+                self.value -= candle.volume.to_f64();
+            }
+        }
+        Some(self.value)
+    }
+
+    fn reset(&mut self) {
+        *self = Obv::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Obv, Vwap};
+    use crate::indicators::test_data::{assert_close, candles, HLCV};
+    use crate::indicators::Indicator;
+    use crate::models::{candle::Candle, decimal::Decimal, interval::Interval};
+    use chrono::Duration;
+
+    #[test]
+    fn vwap_matches_reference() {
+        let expected = [
+            48.2167, 48.3685, 48.4587, 48.4902, 48.514, 48.6007, 48.6814, 48.7462, 48.922, 49.0818,
+            49.1391, 49.1511, 49.1862, 49.2585,
+        ]
+        .map(Some);
+
+        assert_close(&expected, &Vwap::new().batch(&candles(&HLCV)), 4);
+    }
+
+    #[test]
+    fn vwap_starts_over_with_every_session() {
+        let mut candles = candles(&HLCV[..3]);
+        candles[1].time = candles[0].time + Duration::hours(1);
+        let mut vwap = Vwap::new().with_session(Interval::OneDay);
+
+        // The third candle is the first of the next day
+        assert_close(
+            &[Some(48.2167), Some(48.3685), Some(48.68)],
+            &vwap.batch(&candles),
+            4,
+        );
+    }
+
+    #[test]
+    fn vwap_needs_volume() {
+        let candle = Candle {
+            volume: Decimal::ZERO,
+            ..candles(&HLCV)[0]
+        };
+
+        assert_eq!(None, Vwap::new().update(&candle));
+    }
+
+    #[test]
+    fn obv_matches_reference() {
+        let expected = [
+            0.0, 1500.0, 2600.0, 1700.0, 3000.0, 4700.0, 6300.0, 7700.0, 9900.0, 12000.0, 10200.0,
+            9200.0, 10450.0, 12350.0,
+        ]
+        .map(Some);
+
+        assert_eq!(expected.to_vec(), Obv::new().batch(&candles(&HLCV)));
+    }
+}
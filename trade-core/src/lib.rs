@@ -1,3 +1,47 @@
+pub mod backtest;
 pub mod data;
+pub mod error;
+pub mod indicators;
 pub mod models;
 pub mod stock;
+
+pub use error::Error;
+
+#[cfg(test)]
+pub(crate) mod test_data {
+    use crate::models::{candle::Candle, decimal::Decimal};
+    use chrono::{DateTime, Duration, TimeZone, Utc};
+
+    pub fn dec(value: &str) -> Decimal {
+        value.parse().unwrap()
+    }
+
+    // Start of minute `minute` since 2022-01-01
+    pub fn minute(minute: i64) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2022, 1, 1, 0, 0, 0).unwrap() + Duration::minutes(minute)
+    }
+
+    // Candle of minute `minute` that only differs from others in its close
+    pub fn candle(minute: i64, close: f64) -> Candle {
+        Candle {
+            open: Decimal::from(1),
+            high: Decimal::from(2),
+            low: Decimal::new(5, 1),
+            close: Decimal::from_f64(close).unwrap(),
+            volume: Decimal::from(10),
+            time: self::minute(minute),
+        }
+    }
+
+    // Candle of minute `minute` with a volume of one
+    pub fn ohlc(minute: i64, open: &str, high: &str, low: &str, close: &str) -> Candle {
+        Candle {
+            open: dec(open),
+            high: dec(high),
+            low: dec(low),
+            close: dec(close),
+            volume: Decimal::ONE,
+            time: self::minute(minute),
+        }
+    }
+}
@@ -1,13 +1,14 @@
+use super::decimal::Decimal;
 use chrono::{DateTime, Utc};
 use influxdb::InfluxDbWriteable;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, PartialOrd, InfluxDbWriteable)]
 pub struct Candle {
-    pub open: f64,
-    pub high: f64,
-    pub low: f64,
-    pub close: f64,
-    pub volume: f64,
+    pub open: Decimal,
+    pub high: Decimal,
+    pub low: Decimal,
+    pub close: Decimal,
+    pub volume: Decimal,
     pub time: DateTime<Utc>,
 }
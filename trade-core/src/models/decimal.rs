@@ -0,0 +1,593 @@
+use crate::Error;
+use influxdb::Type;
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::cmp::Ordering;
+use std::fmt;
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Mul, Neg, Sub, SubAssign};
+use std::str::FromStr;
+
+// Most decimal places a `Decimal` can hold
+pub const MAX_SCALE: u8 = 18;
+
+// Fixed-point decimal number with the value `units * 10^-scale`, used for prices and quantities.
+//
+// Trailing zeros are always stripped, so every value has exactly one representation and
+// equality and hashing compare the fields. Additions and subtractions are exact, products are
+// rounded to `MAX_SCALE` places and quotients to the requested scale. The checked operations
+// return `None` on overflow, the operators panic instead.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Decimal {
+    units: i64,
+    scale: u8,
+}
+
+// How to drop the places that do not fit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rounding {
+    // Towards negative infinity
+    Floor,
+    // Towards positive infinity
+    Ceil,
+    // Halves are rounded away from zero
+    Nearest,
+}
+
+impl Decimal {
+    pub const ZERO: Decimal = Decimal { units: 0, scale: 0 };
+    pub const ONE: Decimal = Decimal { units: 1, scale: 0 };
+
+    // Panics if `scale` is above `MAX_SCALE`
+    pub fn new(units: i64, scale: u8) -> Self {
+        assert!(scale <= MAX_SCALE, "Scale {} is above {}", scale, MAX_SCALE);
+        Self::from_parts(units as i128, scale as u32).unwrap()
+    }
+
+    pub fn units(&self) -> i64 {
+        self.units
+    }
+
+    // Decimal places needed for the value
+    pub fn scale(&self) -> u8 {
+        self.scale
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.units == 0
+    }
+
+    pub fn is_positive(&self) -> bool {
+        self.units > 0
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.units < 0
+    }
+
+    pub fn abs(&self) -> Decimal {
+        Decimal {
+            units: self.units.abs(),
+            scale: self.scale,
+        }
+    }
+
+    // Converts via the shortest representation that reads back as the same `f64`, so values
+    // parsed from decimal strings with up to 15 significant digits convert back exactly
+    pub fn from_f64(value: f64) -> Option<Decimal> {
+        if !value.is_finite() {
+            return None;
+        }
+        value.to_string().parse().ok()
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        self.units as f64 / 10f64.powi(self.scale as i32)
+    }
+
+    // Drops the places after `scale`
+    pub fn round(&self, scale: u8, rounding: Rounding) -> Decimal {
+        if self.scale <= scale {
+            return *self;
+        }
+        let divisor = pow10(u32::from(self.scale - scale)).unwrap();
+        let units = div_round(self.units as i128, divisor, rounding);
+        // Rounding away places cannot overflow
+        Self::from_parts(units, scale as u32).unwrap()
+    }
+
+    // Rounds to a multiple of `step`, e.g. a tick or lot size. `None` if `step` is not positive
+    // or the result overflows.
+    pub fn round_to_step(&self, step: Decimal, rounding: Rounding) -> Option<Decimal> {
+        if !step.is_positive() {
+            return None;
+        }
+        let (value, step_units, scale) = align(self, &step)?;
+        let steps = div_round(value, step_units, rounding);
+        Self::from_parts(steps.checked_mul(step_units)?, scale)
+    }
+
+    pub fn is_multiple_of(&self, step: Decimal) -> bool {
+        self.round_to_step(step, Rounding::Floor) == Some(*self)
+    }
+
+    pub fn checked_add(&self, other: Decimal) -> Option<Decimal> {
+        let (left, right, scale) = align(self, &other)?;
+        Self::from_parts(left.checked_add(right)?, scale)
+    }
+
+    pub fn checked_sub(&self, other: Decimal) -> Option<Decimal> {
+        let (left, right, scale) = align(self, &other)?;
+        Self::from_parts(left.checked_sub(right)?, scale)
+    }
+
+    pub fn checked_mul(&self, other: Decimal) -> Option<Decimal> {
+        // Two i64 always fit into an i128
+        let units = self.units as i128 * other.units as i128;
+        let scale = u32::from(self.scale) + u32::from(other.scale);
+        if scale <= MAX_SCALE as u32 {
+            return Self::from_parts(units, scale);
+        }
+        let divisor = pow10(scale - MAX_SCALE as u32)?;
+        Self::from_parts(
+            div_round(units, divisor, Rounding::Nearest),
+            MAX_SCALE as u32,
+        )
+    }
+
+    // Quotient with at most `scale` places. `None` on division by zero or overflow.
+    pub fn checked_div(&self, other: Decimal, scale: u8, rounding: Rounding) -> Option<Decimal> {
+        if other.is_zero() || scale > MAX_SCALE {
+            return None;
+        }
+        // units / 10^self.scale / (other.units / 10^other.scale) * 10^scale
+        let exponent = i32::from(other.scale) + i32::from(scale) - i32::from(self.scale);
+        let mut numerator = self.units as i128;
+        let mut denominator = other.units as i128;
+        if exponent >= 0 {
+            numerator = numerator.checked_mul(pow10(exponent as u32)?)?;
+        } else {
+            denominator = denominator.checked_mul(pow10(exponent.unsigned_abs())?)?;
+        }
+        Self::from_parts(div_round(numerator, denominator, rounding), scale as u32)
+    }
+
+    // Quotient with as many places as fit, rounded to the nearest value
+    pub fn checked_div_precise(&self, other: Decimal) -> Option<Decimal> {
+        (0..=MAX_SCALE)
+            .rev()
+            .find_map(|scale| self.checked_div(other, scale, Rounding::Nearest))
+    }
+
+    pub fn checked_neg(&self) -> Option<Decimal> {
+        Some(Decimal {
+            units: self.units.checked_neg()?,
+            scale: self.scale,
+        })
+    }
+
+    fn from_parts(mut units: i128, mut scale: u32) -> Option<Decimal> {
+        while scale > 0 && units % 10 == 0 {
+            units /= 10;
+            scale -= 1;
+        }
+        if scale > MAX_SCALE as u32 {
+            return None;
+        }
+        Some(Decimal {
+            units: i64::try_from(units).ok()?,
+            scale: scale as u8,
+        })
+    }
+}
+
+fn pow10(exponent: u32) -> Option<i128> {
+    10i128.checked_pow(exponent)
+}
+
+// Units of both values at the larger scale
+fn align(left: &Decimal, right: &Decimal) -> Option<(i128, i128, u32)> {
+    let scale = left.scale.max(right.scale);
+    let left_units = left.units as i128 * pow10(u32::from(scale - left.scale))?;
+    let right_units = right.units as i128 * pow10(u32::from(scale - right.scale))?;
+    Some((left_units, right_units, scale as u32))
+}
+
+fn div_round(numerator: i128, denominator: i128, rounding: Rounding) -> i128 {
+    let quotient = numerator / denominator;
+    let remainder = numerator % denominator;
+    if remainder == 0 {
+        return quotient;
+    }
+    let negative = (numerator < 0) != (denominator < 0);
+    let away_from_zero = match rounding {
+        Rounding::Floor => negative,
+        Rounding::Ceil => !negative,
+        Rounding::Nearest => remainder.unsigned_abs() * 2 >= denominator.unsigned_abs(),
+    };
+    match (away_from_zero, negative) {
+        (false, _) => quotient,
+        (true, false) => quotient + 1,
+        (true, true) => quotient - 1,
+    }
+}
+
+impl PartialOrd for Decimal {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Decimal {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // At most 18 places, so aligning never overflows
+        let (left, right, _) = align(self, other).unwrap();
+        left.cmp(&right)
+    }
+}
+
+impl fmt::Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // `{:.2}` rounds to and pads two places
+        let (value, places) = match f.precision() {
+            Some(precision) => {
+                let places = precision.min(MAX_SCALE as usize) as u8;
+                (self.round(places, Rounding::Nearest), places)
+            }
+            None => (*self, self.scale),
+        };
+        if value.is_negative() {
+            f.write_str("-")?;
+        }
+        let divisor = 10u64.pow(value.scale as u32);
+        let units = value.units.unsigned_abs();
+        write!(f, "{}", units / divisor)?;
+        if places > 0 {
+            let fraction = (units % divisor) * 10u64.pow((places - value.scale) as u32);
+            write!(f, ".{:0width$}", fraction, width = places as usize)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+// Plain decimal notation like "-12.345" as sent by Binance, without exponent
+impl FromStr for Decimal {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::decode(format!("Invalid decimal '{}'", value));
+
+        let (negative, digits) = match value.trim().as_bytes() {
+            [b'-', rest @ ..] => (true, rest),
+            [b'+', rest @ ..] => (false, rest),
+            digits => (false, digits),
+        };
+        let (integer, fraction) = match digits.iter().position(|&c| c == b'.') {
+            Some(index) => (&digits[..index], &digits[index + 1..]),
+            None => (digits, &digits[..0]),
+        };
+        if integer.is_empty() && fraction.is_empty() {
+            return Err(invalid());
+        }
+        // Places beyond `MAX_SCALE` are fine as long as they are zero
+        let fraction = match fraction.iter().rposition(|&c| c != b'0') {
+            Some(last) => &fraction[..=last],
+            None => &fraction[..0],
+        };
+        if fraction.len() > MAX_SCALE as usize {
+            return Err(invalid());
+        }
+
+        let mut units: i128 = 0;
+        for &digit in integer.iter().chain(fraction) {
+            if !digit.is_ascii_digit() {
+                return Err(invalid());
+            }
+            units = units
+                .checked_mul(10)
+                .and_then(|units| units.checked_add(i128::from(digit - b'0')))
+                .ok_or_else(invalid)?;
+        }
+        if negative {
+            units = -units;
+        }
+        Self::from_parts(units, fraction.len() as u32).ok_or_else(invalid)
+    }
+}
+
+impl TryFrom<f64> for Decimal {
+    type Error = Error;
+
+    fn try_from(value: f64) -> Result<Self, Self::Error> {
+        Decimal::from_f64(value)
+            .ok_or_else(|| Error::decode(format!("{} is not a supported decimal", value)))
+    }
+}
+
+impl From<i64> for Decimal {
+    fn from(value: i64) -> Self {
+        Decimal::new(value, 0)
+    }
+}
+
+impl From<i32> for Decimal {
+    fn from(value: i32) -> Self {
+        Decimal::new(value.into(), 0)
+    }
+}
+
+impl From<u32> for Decimal {
+    fn from(value: u32) -> Self {
+        Decimal::new(value.into(), 0)
+    }
+}
+
+impl Add for Decimal {
+    type Output = Decimal;
+
+    fn add(self, other: Decimal) -> Decimal {
+        self.checked_add(other).expect("Decimal overflow")
+    }
+}
+
+impl AddAssign for Decimal {
+    fn add_assign(&mut self, other: Decimal) {
+        *self = *self + other;
+    }
+}
+
+impl Sub for Decimal {
+    type Output = Decimal;
+
+    fn sub(self, other: Decimal) -> Decimal {
+        self.checked_sub(other).expect("Decimal overflow")
+    }
+}
+
+impl SubAssign for Decimal {
+    fn sub_assign(&mut self, other: Decimal) {
+        *self = *self - other;
+    }
+}
+
+impl Mul for Decimal {
+    type Output = Decimal;
+
+    fn mul(self, other: Decimal) -> Decimal {
+        self.checked_mul(other).expect("Decimal overflow")
+    }
+}
+
+impl Neg for Decimal {
+    type Output = Decimal;
+
+    fn neg(self) -> Decimal {
+        self.checked_neg().expect("Decimal overflow")
+    }
+}
+
+impl Sum for Decimal {
+    fn sum<I: Iterator<Item = Decimal>>(iter: I) -> Self {
+        iter.fold(Decimal::ZERO, |sum, value| sum + value)
+    }
+}
+
+// InfluxDB has no decimal type, values are stored as float and converted back via `from_f64`
+impl From<Decimal> for Type {
+    fn from(value: Decimal) -> Self {
+        Type::Float(value.to_f64())
+    }
+}
+
+// Strings for human readable formats, so JSON keeps every place. Binary formats get the units
+// and scale.
+impl Serialize for Decimal {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.collect_str(self)
+        } else {
+            (self.units, self.scale).serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Decimal {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            return deserializer.deserialize_any(DecimalVisitor);
+        }
+        let (units, scale) = <(i64, u8)>::deserialize(deserializer)?;
+        if scale > MAX_SCALE {
+            return Err(de::Error::custom(format!(
+                "Scale {} is above {}",
+                scale, MAX_SCALE
+            )));
+        }
+        Ok(Decimal::new(units, scale))
+    }
+}
+
+// Accepts strings and numbers, InfluxDB returns fields as JSON numbers
+struct DecimalVisitor;
+
+impl<'de> Visitor<'de> for DecimalVisitor {
+    type Value = Decimal;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a decimal number or string")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Decimal, E> {
+        value.parse().map_err(de::Error::custom)
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<Decimal, E> {
+        Ok(Decimal::from(value))
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<Decimal, E> {
+        i64::try_from(value)
+            .map(Decimal::from)
+            .map_err(de::Error::custom)
+    }
+
+    fn visit_f64<E: de::Error>(self, value: f64) -> Result<Decimal, E> {
+        Decimal::try_from(value).map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Decimal, Rounding, MAX_SCALE};
+    use crate::test_data::dec;
+    use proptest::prelude::*;
+
+    #[test]
+    fn parsing_strips_trailing_zeros() {
+        assert_eq!(Decimal::new(1, 2), dec("0.01000000"));
+        assert_eq!(Decimal::new(-12345, 3), dec("-12.345"));
+        assert_eq!(Decimal::from(20_000), dec("20000.00000000"));
+        assert_eq!(Decimal::new(5, 1), dec(".5"));
+        assert_eq!(Decimal::ZERO, dec("-0.000"));
+
+        for invalid in ["", ".", "-", "1.2.3", "1e5", "abc", "0.0000000000000000001"] {
+            assert!(invalid.parse::<Decimal>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn display_keeps_every_place() {
+        assert_eq!("0.01", dec("0.0100").to_string());
+        assert_eq!("-0.5", dec("-0.5").to_string());
+        assert_eq!("20000", dec("20000.0").to_string());
+        assert_eq!("1.20", format!("{:.2}", dec("1.2")));
+        assert_eq!("1.24", format!("{:.2}", dec("1.235")));
+        assert_eq!("-2", format!("{:.0}", dec("-1.5")));
+    }
+
+    #[test]
+    fn values_compare_across_scales() {
+        assert!(dec("1.5") > dec("1.25"));
+        assert!(dec("-1.5") < dec("-1.25"));
+        assert_eq!(
+            Some(dec("100")),
+            [dec("99.99"), dec("100")].into_iter().max()
+        );
+    }
+
+    #[test]
+    fn arithmetic_is_exact() {
+        assert_eq!(dec("0.3"), dec("0.1") + dec("0.2"));
+        assert_eq!(dec("-0.15"), dec("0.1") - dec("0.25"));
+        assert_eq!(dec("5000.125"), dec("20000.5") * dec("0.25"));
+        assert_eq!(
+            Some(dec("0.3333")),
+            dec("1").checked_div(dec("3"), 4, Rounding::Nearest)
+        );
+        assert_eq!(
+            Some(dec("0.6667")),
+            dec("2").checked_div(dec("3"), 4, Rounding::Nearest)
+        );
+        assert_eq!(
+            Some(dec("400")),
+            dec("100").checked_div(dec("0.25"), 0, Rounding::Floor)
+        );
+        assert_eq!(
+            None,
+            dec("1").checked_div(Decimal::ZERO, 2, Rounding::Floor)
+        );
+    }
+
+    #[test]
+    fn overflows_are_reported() {
+        let max = Decimal::new(i64::MAX, 0);
+
+        assert_eq!(None, max.checked_add(Decimal::ONE));
+        assert_eq!(None, max.checked_mul(dec("2")));
+        assert_eq!(None, Decimal::new(i64::MIN, 0).checked_neg());
+        assert_eq!(Some(max), max.checked_sub(Decimal::ZERO));
+    }
+
+    #[test]
+    fn products_are_rounded_to_max_scale() {
+        let tiny = Decimal::new(1, MAX_SCALE);
+
+        assert_eq!(Decimal::ZERO, tiny * dec("0.4"));
+        assert_eq!(tiny, tiny * dec("0.5"));
+    }
+
+    #[test]
+    fn values_round_to_steps() {
+        let tick = dec("0.05");
+
+        assert_eq!(
+            Some(dec("1.2")),
+            dec("1.23").round_to_step(tick, Rounding::Floor)
+        );
+        assert_eq!(
+            Some(dec("1.25")),
+            dec("1.23").round_to_step(tick, Rounding::Ceil)
+        );
+        assert_eq!(
+            Some(dec("1.25")),
+            dec("1.225").round_to_step(tick, Rounding::Nearest)
+        );
+        assert_eq!(
+            Some(dec("-1.25")),
+            dec("-1.23").round_to_step(tick, Rounding::Floor)
+        );
+        assert_eq!(
+            None,
+            dec("1.23").round_to_step(Decimal::ZERO, Rounding::Floor)
+        );
+        assert!(dec("1.25").is_multiple_of(tick));
+        assert!(!dec("1.26").is_multiple_of(tick));
+
+        assert_eq!(dec("1.23"), dec("1.234").round(2, Rounding::Nearest));
+        assert_eq!(dec("-1.24"), dec("-1.234").round(2, Rounding::Floor));
+        assert_eq!(dec("1.5"), dec("1.5").round(4, Rounding::Floor));
+    }
+
+    #[test]
+    fn serde_keeps_every_place() {
+        let value = dec("0.12345678");
+
+        assert_eq!("\"0.12345678\"", serde_json::to_string(&value).unwrap());
+        assert_eq!(value, serde_json::from_str("\"0.12345678\"").unwrap());
+        // InfluxDB returns numbers
+        assert_eq!(value, serde_json::from_str("0.12345678").unwrap());
+        assert_eq!(dec("42"), serde_json::from_str("42").unwrap());
+    }
+
+    proptest! {
+        #[test]
+        fn strings_round_trip(units in any::<i64>(), scale in 0..=MAX_SCALE) {
+            let value = Decimal::new(units, scale);
+
+            prop_assert_eq!(value, value.to_string().parse::<Decimal>().unwrap());
+        }
+
+        // Holds for every value with at most 15 significant digits
+        #[test]
+        fn floats_round_trip(units in -999_999_999_999_999i64..=999_999_999_999_999, scale in 0..=MAX_SCALE) {
+            let value = Decimal::new(units, scale);
+
+            prop_assert_eq!(Some(value), Decimal::from_f64(value.to_f64()));
+        }
+
+        #[test]
+        fn addition_reverts_subtraction(a in any::<i32>(), b in any::<i32>(), scale in 0..=8u8) {
+            let a = Decimal::new(a.into(), scale);
+            let b = Decimal::new(b.into(), 8 - scale);
+
+            prop_assert_eq!(a, (a + b) - b);
+            prop_assert_eq!(a.cmp(&b), a.to_f64().partial_cmp(&b.to_f64()).unwrap());
+        }
+    }
+}
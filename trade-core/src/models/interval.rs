@@ -0,0 +1,172 @@
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Interval {
+    OneMinute,
+    ThreeMinutes,
+    FiveMinutes,
+    FifteenMinutes,
+    ThirtyMinutes,
+    OneHour,
+    TwoHours,
+    FourHours,
+    SixHours,
+    EightHours,
+    TwelveHours,
+    OneDay,
+    ThreeDays,
+    OneWeek,
+}
+
+impl Interval {
+    pub const ALL: [Interval; 14] = [
+        Interval::OneMinute,
+        Interval::ThreeMinutes,
+        Interval::FiveMinutes,
+        Interval::FifteenMinutes,
+        Interval::ThirtyMinutes,
+        Interval::OneHour,
+        Interval::TwoHours,
+        Interval::FourHours,
+        Interval::SixHours,
+        Interval::EightHours,
+        Interval::TwelveHours,
+        Interval::OneDay,
+        Interval::ThreeDays,
+        Interval::OneWeek,
+    ];
+
+    // Same notation as the Binance API ("1m", "4h", "1d", ...)
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Interval::OneMinute => "1m",
+            Interval::ThreeMinutes => "3m",
+            Interval::FiveMinutes => "5m",
+            Interval::FifteenMinutes => "15m",
+            Interval::ThirtyMinutes => "30m",
+            Interval::OneHour => "1h",
+            Interval::TwoHours => "2h",
+            Interval::FourHours => "4h",
+            Interval::SixHours => "6h",
+            Interval::EightHours => "8h",
+            Interval::TwelveHours => "12h",
+            Interval::OneDay => "1d",
+            Interval::ThreeDays => "3d",
+            Interval::OneWeek => "1w",
+        }
+    }
+
+    pub const fn seconds(&self) -> i64 {
+        match self {
+            Interval::OneMinute => 60,
+            Interval::ThreeMinutes => 3 * 60,
+            Interval::FiveMinutes => 5 * 60,
+            Interval::FifteenMinutes => 15 * 60,
+            Interval::ThirtyMinutes => 30 * 60,
+            Interval::OneHour => 60 * 60,
+            Interval::TwoHours => 2 * 60 * 60,
+            Interval::FourHours => 4 * 60 * 60,
+            Interval::SixHours => 6 * 60 * 60,
+            Interval::EightHours => 8 * 60 * 60,
+            Interval::TwelveHours => 12 * 60 * 60,
+            Interval::OneDay => 24 * 60 * 60,
+            Interval::ThreeDays => 3 * 24 * 60 * 60,
+            Interval::OneWeek => 7 * 24 * 60 * 60,
+        }
+    }
+
+    pub fn duration(&self) -> Duration {
+        Duration::seconds(self.seconds())
+    }
+
+    // Open time of the interval containing `time`
+    pub fn floor(&self, time: DateTime<Utc>) -> DateTime<Utc> {
+        // Weeks start on Monday, the unix epoch was a Thursday
+        let offset = match self {
+            Interval::OneWeek => 4 * 24 * 60 * 60,
+            _ => 0,
+        };
+        let seconds = time.timestamp() - offset;
+        let floored = seconds - seconds.rem_euclid(self.seconds()) + offset;
+        Utc.timestamp_opt(floored, 0).unwrap()
+    }
+}
+
+impl Display for Interval {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseIntervalError(pub String);
+
+impl Display for ParseIntervalError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unknown interval: {}", self.0)
+    }
+}
+
+impl std::error::Error for ParseIntervalError {}
+
+impl FromStr for Interval {
+    type Err = ParseIntervalError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Interval::ALL
+            .iter()
+            .find(|interval| interval.as_str() == s)
+            .copied()
+            .ok_or_else(|| ParseIntervalError(s.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Interval;
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn parsing_roundtrips() {
+        for interval in Interval::ALL {
+            assert_eq!(Ok(interval), interval.as_str().parse());
+        }
+    }
+
+    #[test]
+    fn parsing_unknown_fails() {
+        assert!("2m".parse::<Interval>().is_err());
+    }
+
+    #[test]
+    fn intervals_are_ordered_by_duration() {
+        for pair in Interval::ALL.windows(2) {
+            assert!(pair[0] < pair[1]);
+            assert!(pair[0].duration() < pair[1].duration());
+        }
+    }
+
+    #[test]
+    fn floor_works() {
+        let time = Utc.with_ymd_and_hms(2022, 6, 15, 13, 47, 12).unwrap();
+        assert_eq!(
+            Utc.with_ymd_and_hms(2022, 6, 15, 13, 45, 0).unwrap(),
+            Interval::FifteenMinutes.floor(time)
+        );
+        assert_eq!(
+            Utc.with_ymd_and_hms(2022, 6, 15, 12, 0, 0).unwrap(),
+            Interval::FourHours.floor(time)
+        );
+        assert_eq!(
+            Utc.with_ymd_and_hms(2022, 6, 15, 0, 0, 0).unwrap(),
+            Interval::OneDay.floor(time)
+        );
+        // Monday
+        assert_eq!(
+            Utc.with_ymd_and_hms(2022, 6, 13, 0, 0, 0).unwrap(),
+            Interval::OneWeek.floor(time)
+        );
+    }
+}
@@ -1 +1,8 @@
 pub mod candle;
+pub mod decimal;
+pub mod interval;
+pub mod order;
+pub mod order_book;
+pub mod portfolio;
+pub mod symbol;
+pub mod trade;
@@ -0,0 +1,199 @@
+use super::decimal::Decimal;
+use super::trade::Side;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum OrderType {
+    Market,
+    Limit { price: Decimal },
+    // Becomes a market order once the stop price trades
+    Stop { stop_price: Decimal },
+    // Becomes a limit order once the stop price trades
+    StopLimit { stop_price: Decimal, price: Decimal },
+}
+
+// How long an order stays on the book
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash, Default)]
+pub enum TimeInForce {
+    // Good till cancelled
+    #[default]
+    Gtc,
+    // Immediate or cancel, unfilled parts are cancelled
+    Ioc,
+    // Fill or kill, filled completely or not at all
+    Fok,
+}
+
+impl TimeInForce {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TimeInForce::Gtc => "GTC",
+            TimeInForce::Ioc => "IOC",
+            TimeInForce::Fok => "FOK",
+        }
+    }
+}
+
+// An order as decided by a strategy, identified by the id chosen by its client
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Order {
+    pub client_order_id: String,
+    pub symbol: String,
+    pub side: Side,
+    pub quantity: Decimal,
+    pub order_type: OrderType,
+    pub time_in_force: TimeInForce,
+}
+
+impl Order {
+    pub fn market(client_order_id: String, symbol: String, side: Side, quantity: Decimal) -> Self {
+        Order {
+            client_order_id,
+            symbol,
+            side,
+            quantity,
+            order_type: OrderType::Market,
+            time_in_force: TimeInForce::Gtc,
+        }
+    }
+
+    pub fn limit(
+        client_order_id: String,
+        symbol: String,
+        side: Side,
+        quantity: Decimal,
+        price: Decimal,
+    ) -> Self {
+        Order {
+            order_type: OrderType::Limit { price },
+            ..Self::market(client_order_id, symbol, side, quantity)
+        }
+    }
+
+    pub fn stop(
+        client_order_id: String,
+        symbol: String,
+        side: Side,
+        quantity: Decimal,
+        stop_price: Decimal,
+    ) -> Self {
+        Order {
+            order_type: OrderType::Stop { stop_price },
+            ..Self::market(client_order_id, symbol, side, quantity)
+        }
+    }
+
+    pub fn with_time_in_force(mut self, time_in_force: TimeInForce) -> Self {
+        self.time_in_force = time_in_force;
+        self
+    }
+
+    // Worst price the order may fill at, `None` for market and stop orders
+    pub fn limit_price(&self) -> Option<Decimal> {
+        match self.order_type {
+            OrderType::Limit { price } | OrderType::StopLimit { price, .. } => Some(price),
+            OrderType::Market | OrderType::Stop { .. } => None,
+        }
+    }
+
+    pub fn stop_price(&self) -> Option<Decimal> {
+        match self.order_type {
+            OrderType::Stop { stop_price } | OrderType::StopLimit { stop_price, .. } => {
+                Some(stop_price)
+            }
+            OrderType::Market | OrderType::Limit { .. } => None,
+        }
+    }
+
+    // Quantity signed by side, sells are negative
+    pub fn signed_quantity(&self) -> Decimal {
+        signed(self.side, self.quantity)
+    }
+}
+
+// An execution of (a part of) an order
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Fill {
+    pub client_order_id: String,
+    pub symbol: String,
+    pub side: Side,
+    pub quantity: Decimal,
+    pub price: Decimal,
+    // In the quote asset
+    pub fee: Decimal,
+    pub time: DateTime<Utc>,
+}
+
+impl Fill {
+    // `None` on overflow
+    pub fn notional(&self) -> Option<Decimal> {
+        self.price.checked_mul(self.quantity)
+    }
+
+    // Quantity signed by side, sells are negative
+    pub fn signed_quantity(&self) -> Decimal {
+        signed(self.side, self.quantity)
+    }
+}
+
+fn signed(side: Side, quantity: Decimal) -> Decimal {
+    match side {
+        Side::Buy => quantity,
+        Side::Sell => -quantity,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Order, OrderType, TimeInForce};
+    use crate::models::trade::Side;
+    use crate::test_data::dec;
+
+    #[test]
+    fn prices_depend_on_the_type() {
+        let limit = Order::limit(
+            "a".to_string(),
+            "BTCUSDT".to_string(),
+            Side::Sell,
+            dec("0.5"),
+            dec("20000"),
+        )
+        .with_time_in_force(TimeInForce::Ioc);
+        let stop_limit = Order {
+            order_type: OrderType::StopLimit {
+                stop_price: dec("19000"),
+                price: dec("18900"),
+            },
+            ..limit.clone()
+        };
+
+        assert_eq!(Some(dec("20000")), limit.limit_price());
+        assert_eq!(None, limit.stop_price());
+        assert_eq!(dec("-0.5"), limit.signed_quantity());
+        assert_eq!("IOC", limit.time_in_force.as_str());
+        assert_eq!(Some(dec("18900")), stop_limit.limit_price());
+        assert_eq!(Some(dec("19000")), stop_limit.stop_price());
+    }
+
+    #[test]
+    fn orders_round_trip_through_serde() {
+        let order = Order::stop(
+            "b".to_string(),
+            "BTCUSDT".to_string(),
+            Side::Buy,
+            dec("1"),
+            dec("21000.5"),
+        );
+        let json = serde_json::to_string(&order).unwrap();
+
+        assert_eq!(order, serde_json::from_str(&json).unwrap());
+        assert_eq!(order, bincode_round_trip(&order));
+    }
+
+    fn bincode_round_trip(order: &Order) -> Order {
+        // Nodes send orders to the host with bincode
+        let bytes = bincode::serialize(order).unwrap();
+        bincode::deserialize(&bytes).unwrap()
+    }
+}
@@ -0,0 +1,91 @@
+use super::decimal::{Decimal, Rounding, MAX_SCALE};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub struct PriceLevel {
+    pub price: Decimal,
+    pub quantity: Decimal,
+}
+
+// L2 state of a book, bids are ordered from the highest and asks from the lowest price
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct OrderBook {
+    // Sequence number of the last update contained in the book
+    pub last_update_id: u64,
+    pub bids: Vec<PriceLevel>,
+    pub asks: Vec<PriceLevel>,
+}
+
+// Levels changed by the updates `first_update_id..=final_update_id`, a quantity of zero removes
+// the level
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct OrderBookDiff {
+    pub first_update_id: u64,
+    pub final_update_id: u64,
+    pub bids: Vec<PriceLevel>,
+    pub asks: Vec<PriceLevel>,
+    pub time: DateTime<Utc>,
+}
+
+impl OrderBook {
+    // Sorts the levels and drops empty ones
+    pub fn new(last_update_id: u64, bids: Vec<PriceLevel>, asks: Vec<PriceLevel>) -> Self {
+        let mut book = OrderBook {
+            last_update_id,
+            bids: Vec::with_capacity(bids.len()),
+            asks: Vec::with_capacity(asks.len()),
+        };
+        update_levels(&mut book.bids, &bids, true);
+        update_levels(&mut book.asks, &asks, false);
+        book
+    }
+
+    pub fn best_bid(&self) -> Option<PriceLevel> {
+        self.bids.first().copied()
+    }
+
+    pub fn best_ask(&self) -> Option<PriceLevel> {
+        self.asks.first().copied()
+    }
+
+    pub fn spread(&self) -> Option<Decimal> {
+        self.best_ask()?.price.checked_sub(self.best_bid()?.price)
+    }
+
+    pub fn mid_price(&self) -> Option<Decimal> {
+        let ask = self.best_ask()?.price;
+        let sum = ask.checked_add(self.best_bid()?.price)?;
+        // Halving needs at most one more place
+        let scale = (sum.scale() + 1).min(MAX_SCALE);
+        sum.checked_div(Decimal::from(2), scale, Rounding::Nearest)
+    }
+
+    // Applies the levels of `diff` without checking its sequence numbers
+    pub fn apply(&mut self, diff: &OrderBookDiff) {
+        update_levels(&mut self.bids, &diff.bids, true);
+        update_levels(&mut self.asks, &diff.asks, false);
+        self.last_update_id = diff.final_update_id;
+    }
+}
+
+fn update_levels(levels: &mut Vec<PriceLevel>, changes: &[PriceLevel], descending: bool) {
+    for change in changes {
+        let position = levels.binary_search_by(|level| {
+            let ordering = level.price.cmp(&change.price);
+            if descending {
+                ordering.reverse()
+            } else {
+                ordering
+            }
+        });
+        match position {
+            Ok(index) if change.quantity.is_positive() => levels[index].quantity = change.quantity,
+            Ok(index) => {
+                levels.remove(index);
+            }
+            Err(index) if change.quantity.is_positive() => levels.insert(index, *change),
+            Err(_) => {}
+        }
+    }
+}
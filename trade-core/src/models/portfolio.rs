@@ -0,0 +1,293 @@
+use super::candle::Candle;
+use super::decimal::Decimal;
+use super::order::Fill;
+use super::trade::Side;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// Holdings of one symbol, valued at average cost. Short positions have a negative quantity and
+// cost.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Position {
+    pub symbol: String,
+    pub quantity: Decimal,
+    // Cost of the open quantity in the quote asset
+    pub cost: Decimal,
+    // Gross of fees
+    pub realized_pnl: Decimal,
+    pub fees: Decimal,
+    // Last known price
+    pub mark_price: Option<Decimal>,
+}
+
+impl Position {
+    pub fn new(symbol: String) -> Self {
+        Position {
+            symbol,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_flat(&self) -> bool {
+        self.quantity.is_zero()
+    }
+
+    // `None` while flat
+    pub fn average_price(&self) -> Option<Decimal> {
+        self.cost.checked_div_precise(self.quantity)
+    }
+
+    // Value at the mark price, at cost while there is none
+    pub fn market_value(&self) -> Option<Decimal> {
+        match self.mark_price {
+            Some(mark) => mark.checked_mul(self.quantity),
+            None => Some(self.cost),
+        }
+    }
+
+    pub fn unrealized_pnl(&self) -> Option<Decimal> {
+        self.market_value()?.checked_sub(self.cost)
+    }
+
+    // Adds a fill and returns the PnL it realized. Fills against the position close it first,
+    // the rest opens a position on the other side. `None` on overflow, the position is unchanged
+    // then.
+    pub fn apply(&mut self, fill: &Fill) -> Option<Decimal> {
+        let mut next = self.clone();
+        let realized = next.apply_unchecked(fill)?;
+        *self = next;
+        Some(realized)
+    }
+
+    fn apply_unchecked(&mut self, fill: &Fill) -> Option<Decimal> {
+        let mut remaining = fill.signed_quantity();
+        let mut realized = Decimal::ZERO;
+
+        let reducing = (self.quantity.is_positive() && remaining.is_negative())
+            || (self.quantity.is_negative() && remaining.is_positive());
+        if reducing {
+            // Closed quantity, signed like the position
+            let closed = if remaining.abs() >= self.quantity.abs() {
+                self.quantity
+            } else {
+                remaining.checked_neg()?
+            };
+            let closed_cost = if closed == self.quantity {
+                self.cost
+            } else {
+                self.average_price()?.checked_mul(closed)?
+            };
+            realized = fill.price.checked_mul(closed)?.checked_sub(closed_cost)?;
+
+            self.quantity = self.quantity.checked_sub(closed)?;
+            self.cost = self.cost.checked_sub(closed_cost)?;
+            remaining = remaining.checked_add(closed)?;
+        }
+        if !remaining.is_zero() {
+            self.quantity = self.quantity.checked_add(remaining)?;
+            self.cost = self.cost.checked_add(fill.price.checked_mul(remaining)?)?;
+        }
+
+        self.realized_pnl = self.realized_pnl.checked_add(realized)?;
+        self.fees = self.fees.checked_add(fill.fee)?;
+        self.mark_price = Some(fill.price);
+        Some(realized)
+    }
+}
+
+// Cash and positions of an account in one quote asset
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Portfolio {
+    cash: Decimal,
+    positions: HashMap<String, Position>,
+}
+
+impl Portfolio {
+    pub fn new(cash: Decimal) -> Self {
+        Portfolio {
+            cash,
+            positions: HashMap::new(),
+        }
+    }
+
+    pub fn cash(&self) -> Decimal {
+        self.cash
+    }
+
+    pub fn position(&self, symbol: &str) -> Option<&Position> {
+        self.positions.get(symbol)
+    }
+
+    // Held quantity, zero without position
+    pub fn quantity(&self, symbol: &str) -> Decimal {
+        self.position(symbol)
+            .map(|position| position.quantity)
+            .unwrap_or_default()
+    }
+
+    pub fn positions(&self) -> impl Iterator<Item = &Position> {
+        self.positions.values()
+    }
+
+    // Books the fill against cash and position, returns the realized PnL. `None` on overflow,
+    // the portfolio is unchanged then.
+    pub fn apply_fill(&mut self, fill: &Fill) -> Option<Decimal> {
+        let notional = fill.notional()?;
+        let cash = match fill.side {
+            Side::Buy => self.cash.checked_sub(notional)?,
+            Side::Sell => self.cash.checked_add(notional)?,
+        }
+        .checked_sub(fill.fee)?;
+
+        let realized = self
+            .positions
+            .entry(fill.symbol.clone())
+            .or_insert_with(|| Position::new(fill.symbol.clone()))
+            .apply(fill)?;
+        self.cash = cash;
+        Some(realized)
+    }
+
+    pub fn mark(&mut self, symbol: &str, price: Decimal) {
+        if let Some(position) = self.positions.get_mut(symbol) {
+            position.mark_price = Some(price);
+        }
+    }
+
+    // Marks to the close of the candle
+    pub fn mark_candle(&mut self, symbol: &str, candle: &Candle) {
+        self.mark(symbol, candle.close);
+    }
+
+    // Cash plus market value of all positions
+    pub fn equity(&self) -> Option<Decimal> {
+        self.positions().try_fold(self.cash, |equity, position| {
+            equity.checked_add(position.market_value()?)
+        })
+    }
+
+    pub fn realized_pnl(&self) -> Option<Decimal> {
+        self.sum(|position| Some(position.realized_pnl))
+    }
+
+    pub fn unrealized_pnl(&self) -> Option<Decimal> {
+        self.sum(Position::unrealized_pnl)
+    }
+
+    pub fn fees(&self) -> Option<Decimal> {
+        self.sum(|position| Some(position.fees))
+    }
+
+    // Realized plus unrealized PnL minus fees
+    pub fn net_pnl(&self) -> Option<Decimal> {
+        self.realized_pnl()?
+            .checked_add(self.unrealized_pnl()?)?
+            .checked_sub(self.fees()?)
+    }
+
+    fn sum(&self, value: impl Fn(&Position) -> Option<Decimal>) -> Option<Decimal> {
+        self.positions().try_fold(Decimal::ZERO, |sum, position| {
+            sum.checked_add(value(position)?)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Portfolio, Position};
+    use crate::models::{decimal::Decimal, order::Fill, trade::Side};
+    use crate::test_data::dec;
+    use chrono::{TimeZone, Utc};
+
+    fn fill(side: Side, quantity: &str, price: &str, fee: &str) -> Fill {
+        Fill {
+            client_order_id: "test".to_string(),
+            symbol: "BTCUSDT".to_string(),
+            side,
+            quantity: dec(quantity),
+            price: dec(price),
+            fee: dec(fee),
+            time: Utc.with_ymd_and_hms(2022, 1, 1, 0, 0, 0).unwrap(),
+        }
+    }
+
+    #[test]
+    fn positions_use_the_average_cost() {
+        let mut position = Position::new("BTCUSDT".to_string());
+
+        assert_eq!(
+            Some(Decimal::ZERO),
+            position.apply(&fill(Side::Buy, "1", "100", "0"))
+        );
+        assert_eq!(
+            Some(Decimal::ZERO),
+            position.apply(&fill(Side::Buy, "3", "120", "0"))
+        );
+        assert_eq!(Some(dec("115")), position.average_price());
+
+        // 2 of 4 sold 15 above the average
+        assert_eq!(
+            Some(dec("30")),
+            position.apply(&fill(Side::Sell, "2", "130", "0"))
+        );
+        assert_eq!((dec("2"), dec("230")), (position.quantity, position.cost));
+        assert_eq!(Some(dec("30")), position.unrealized_pnl());
+    }
+
+    #[test]
+    fn fills_beyond_the_position_flip_it() {
+        let mut position = Position::new("BTCUSDT".to_string());
+        position.apply(&fill(Side::Buy, "1", "100", "0"));
+
+        assert_eq!(
+            Some(dec("-10")),
+            position.apply(&fill(Side::Sell, "3", "90", "0"))
+        );
+        assert_eq!((dec("-2"), dec("-180")), (position.quantity, position.cost));
+        assert_eq!(Some(dec("90")), position.average_price());
+
+        // Covering the short below its entry is a gain
+        assert_eq!(
+            Some(dec("20")),
+            position.apply(&fill(Side::Buy, "2", "80", "0"))
+        );
+        assert!(position.is_flat());
+        assert_eq!(Decimal::ZERO, position.cost);
+        assert_eq!(dec("10"), position.realized_pnl);
+    }
+
+    #[test]
+    fn partial_closes_of_uneven_averages_stay_close() {
+        let mut position = Position::new("BTCUSDT".to_string());
+        position.apply(&fill(Side::Buy, "1", "100", "0"));
+        position.apply(&fill(Side::Buy, "2", "101", "0"));
+
+        // Average of 100.666...
+        position.apply(&fill(Side::Sell, "1", "110", "0"));
+        position.apply(&fill(Side::Sell, "2", "110", "0"));
+        assert!(position.is_flat());
+        // Closing the rest books the remaining cost, so nothing is lost to rounding
+        assert_eq!(dec("28"), position.realized_pnl);
+    }
+
+    #[test]
+    fn portfolio_accounts_cash_fees_and_marks() {
+        let mut portfolio = Portfolio::new(dec("1000"));
+
+        portfolio.apply_fill(&fill(Side::Buy, "2", "100", "0.2"));
+        assert_eq!(dec("799.8"), portfolio.cash());
+        portfolio.mark("BTCUSDT", dec("110"));
+        assert_eq!(Some(dec("1019.8")), portfolio.equity());
+        assert_eq!(Some(dec("20")), portfolio.unrealized_pnl());
+
+        portfolio.apply_fill(&fill(Side::Sell, "1", "120", "0.12"));
+        assert_eq!(dec("919.68"), portfolio.cash());
+        assert_eq!(Some(dec("20")), portfolio.realized_pnl());
+        assert_eq!(Some(dec("20")), portfolio.unrealized_pnl());
+        assert_eq!(Some(dec("0.32")), portfolio.fees());
+        assert_eq!(Some(dec("39.68")), portfolio.net_pnl());
+        assert_eq!(Some(dec("1039.68")), portfolio.equity());
+        assert_eq!(dec("1"), portfolio.quantity("BTCUSDT"));
+        assert_eq!(Decimal::ZERO, portfolio.quantity("ETHUSDT"));
+    }
+}
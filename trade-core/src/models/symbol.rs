@@ -0,0 +1,92 @@
+use super::decimal::{Decimal, Rounding};
+use serde::{Deserialize, Serialize};
+
+// Exchange metadata of a tradable symbol
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SymbolInfo {
+    pub symbol: String,
+    pub base_asset: String,
+    pub quote_asset: String,
+    // Smallest price increment
+    pub tick_size: Decimal,
+    // Smallest quantity increment
+    pub lot_size: Decimal,
+    pub min_quantity: Decimal,
+}
+
+impl SymbolInfo {
+    // Rounds to the tick size, prices are left as they are if the symbol has none
+    pub fn round_price(&self, price: Decimal, rounding: Rounding) -> Option<Decimal> {
+        round_to(price, self.tick_size, rounding)
+    }
+
+    // Rounds to the lot size, quantities are left as they are if the symbol has none
+    pub fn round_quantity(&self, quantity: Decimal, rounding: Rounding) -> Option<Decimal> {
+        round_to(quantity, self.lot_size, rounding)
+    }
+
+    // Places of the tick size
+    pub fn price_precision(&self) -> u8 {
+        self.tick_size.scale()
+    }
+
+    // Places of the lot size
+    pub fn quantity_precision(&self) -> u8 {
+        self.lot_size.scale()
+    }
+}
+
+fn round_to(value: Decimal, step: Decimal, rounding: Rounding) -> Option<Decimal> {
+    if step.is_zero() {
+        return Some(value);
+    }
+    value.round_to_step(step, rounding)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SymbolInfo;
+    use crate::models::decimal::Rounding;
+    use crate::test_data::dec;
+
+    fn info(tick_size: &str, lot_size: &str) -> SymbolInfo {
+        SymbolInfo {
+            symbol: "BTCUSDT".to_string(),
+            base_asset: "BTC".to_string(),
+            quote_asset: "USDT".to_string(),
+            tick_size: dec(tick_size),
+            lot_size: dec(lot_size),
+            min_quantity: dec(lot_size),
+        }
+    }
+
+    #[test]
+    fn values_are_rounded_to_the_symbol_precision() {
+        let info = info("0.01000000", "0.00001000");
+
+        assert_eq!(2, info.price_precision());
+        assert_eq!(5, info.quantity_precision());
+        assert_eq!(
+            Some(dec("20000.12")),
+            info.round_price(dec("20000.123"), Rounding::Floor)
+        );
+        assert_eq!(
+            Some(dec("20000.13")),
+            info.round_price(dec("20000.123"), Rounding::Ceil)
+        );
+        assert_eq!(
+            Some(dec("0.12345")),
+            info.round_quantity(dec("0.123456"), Rounding::Floor)
+        );
+    }
+
+    #[test]
+    fn symbols_without_steps_keep_values() {
+        let info = info("0", "0");
+
+        assert_eq!(
+            Some(dec("1.23456")),
+            info.round_price(dec("1.23456"), Rounding::Nearest)
+        );
+    }
+}
@@ -0,0 +1,91 @@
+use super::decimal::Decimal;
+use chrono::{DateTime, Utc};
+use influxdb::{InfluxDbWriteable, Type};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum Side {
+    Buy,
+    Sell,
+}
+
+impl Side {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Side::Buy => "buy",
+            Side::Sell => "sell",
+        }
+    }
+
+    pub fn opposite(&self) -> Side {
+        match self {
+            Side::Buy => Side::Sell,
+            Side::Sell => Side::Buy,
+        }
+    }
+}
+
+// Stored as tag
+impl From<Side> for Type {
+    fn from(side: Side) -> Self {
+        Type::Text(side.as_str().to_string())
+    }
+}
+
+// A single executed trade, `side` is the side of the taker
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, InfluxDbWriteable)]
+pub struct Trade {
+    pub id: u64,
+    pub price: Decimal,
+    pub quantity: Decimal,
+    #[influxdb(tag)]
+    pub side: Side,
+    pub time: DateTime<Utc>,
+}
+
+impl Trade {
+    // `None` on overflow
+    pub fn notional(&self) -> Option<Decimal> {
+        self.price.checked_mul(self.quantity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Side, Trade};
+    use chrono::{TimeZone, Utc};
+    use influxdb::{InfluxDbWriteable, Query};
+
+    fn trade() -> Trade {
+        Trade {
+            id: 42,
+            price: "20000.5".parse().unwrap(),
+            quantity: "0.25".parse().unwrap(),
+            side: Side::Sell,
+            time: Utc.with_ymd_and_hms(2022, 1, 1, 0, 0, 0).unwrap(),
+        }
+    }
+
+    #[test]
+    fn trades_are_written_with_side_tag() {
+        let line = trade().into_query("trade").build().unwrap().get();
+
+        assert!(line.starts_with("trade,side=sell "));
+        assert!(line.contains("price=20000.5"));
+        assert!(line.contains("quantity=0.25"));
+        assert!(line.contains("id=42"));
+        assert!(line.ends_with(" 1640995200000000000"));
+    }
+
+    #[test]
+    fn notional_is_exact() {
+        assert_eq!(Some("5000.125".parse().unwrap()), trade().notional());
+    }
+
+    #[test]
+    fn trades_round_trip_through_serde() {
+        let json = serde_json::to_string(&trade()).unwrap();
+
+        assert_eq!(trade(), serde_json::from_str(&json).unwrap());
+    }
+}
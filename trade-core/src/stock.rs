@@ -1,16 +1,176 @@
-use crate::models::candle::Candle;
+use crate::data::database::SharedStockDataCache;
+use crate::data::providers::{CandleStream, MarketDataProvider};
+use crate::models::{candle::Candle, interval::Interval};
+use crate::Error;
 use chrono::{DateTime, Utc};
-use futures::stream::Stream;
+use futures::stream::{self, Stream, StreamExt};
+use std::sync::Arc;
 
 pub trait Stock {
-    type Error;
+    type Error: std::error::Error + Send + Sync + 'static;
     type Stream: Stream<Item = Result<Candle, Self::Error>>;
+    // Candles opened before `before`, ordered by time
     fn get_candles_before(&mut self, before: DateTime<Utc>) -> Self::Stream;
+    // Candles opened at or after `start` and before `end`, ordered by time. Fails if `start` is
+    // after `end`
     fn get_candles_between(&mut self, start: DateTime<Utc>, end: DateTime<Utc>) -> Self::Stream;
 
+    // All known candles followed by live ones as they close
     fn stream_candles(&mut self) -> Self::Stream;
 }
 
+// Stock of a single candle series, history is read from the cache and live candles come from the
+// market data provider
+pub struct CachedStock {
+    symbol: String,
+    interval: Interval,
+    cache: SharedStockDataCache,
+    provider: Arc<dyn MarketDataProvider>,
+}
+
+impl CachedStock {
+    pub fn new(
+        symbol: String,
+        interval: Interval,
+        cache: SharedStockDataCache,
+        provider: Arc<dyn MarketDataProvider>,
+    ) -> Self {
+        CachedStock {
+            symbol,
+            interval,
+            cache,
+            provider,
+        }
+    }
+
+    fn query(&self, start: Option<DateTime<Utc>>, end: DateTime<Utc>) -> CandleStream {
+        let symbol = self.symbol.clone();
+        let interval = self.interval;
+        let cache = Arc::clone(&self.cache);
+
+        Box::pin(
+            stream::once(async move {
+                let start = match start {
+                    Some(start) => {
+                        Error::check_range(&(start..end))?;
+                        start
+                    }
+                    None => match cache.get_first_candle(symbol.clone(), interval).await? {
+                        Some(first) => first.time,
+                        None => return Ok(Vec::new()),
+                    },
+                };
+                if start >= end {
+                    return Ok(Vec::new());
+                }
+                cache.get_candles(symbol, interval, start..end).await
+            })
+            .flat_map(candles_or_error),
+        )
+    }
+}
+
+impl Stock for CachedStock {
+    type Error = Error;
+    type Stream = CandleStream;
+
+    fn get_candles_before(&mut self, before: DateTime<Utc>) -> CandleStream {
+        self.query(None, before)
+    }
+
+    fn get_candles_between(&mut self, start: DateTime<Utc>, end: DateTime<Utc>) -> CandleStream {
+        self.query(Some(start), end)
+    }
+
+    fn stream_candles(&mut self) -> CandleStream {
+        let symbol = self.symbol.clone();
+        let interval = self.interval;
+        let cache = Arc::clone(&self.cache);
+        let provider = Arc::clone(&self.provider);
+
+        Box::pin(
+            stream::once(async move {
+                // Subscribe before reading the history, so no candle closes in between unnoticed
+                let live = provider.stream_candles(symbol.clone(), interval).await?;
+
+                let history = match (
+                    cache.get_first_candle(symbol.clone(), interval).await?,
+                    cache.get_last_candle(symbol.clone(), interval).await?,
+                ) {
+                    (Some(first), Some(last)) => {
+                        cache
+                            .get_candles(
+                                symbol.clone(),
+                                interval,
+                                first.time..last.time + interval.duration(),
+                            )
+                            .await?
+                    }
+                    _ => Vec::new(),
+                };
+                let last_time = history.last().map(|candle| candle.time);
+
+                let history: CandleStream = Box::pin(stream::iter(history.into_iter().map(Ok)));
+                Ok(history.chain(live_after(live, last_time, provider, symbol, interval)))
+            })
+            .flat_map(|result| match result {
+                Ok(candles) => candles.left_stream(),
+                Err(err) => stream::once(async { Err(err) }).right_stream(),
+            }),
+        )
+    }
+}
+
+// Skips live candles that are already part of the history and fetches the ones missed between
+// the history and the first live candle
+fn live_after(
+    live: CandleStream,
+    last_time: Option<DateTime<Utc>>,
+    provider: Arc<dyn MarketDataProvider>,
+    symbol: String,
+    interval: Interval,
+) -> impl Stream<Item = Result<Candle, Error>> + Send {
+    stream::unfold((live, last_time), move |(mut live, mut last_time)| {
+        let provider = Arc::clone(&provider);
+        let symbol = symbol.clone();
+        async move {
+            let candle = match live.next().await? {
+                Ok(candle) => candle,
+                Err(err) => return Some((vec![Err(err)], (live, last_time))),
+            };
+
+            let mut items = Vec::new();
+            match last_time {
+                Some(last) if candle.time <= last => {}
+                Some(last) if candle.time > last + interval.duration() => {
+                    let gap = last + interval.duration()..candle.time;
+                    match provider.get_candles(symbol, interval, gap).await {
+                        Ok(missed) => items.extend(missed.into_iter().map(Ok)),
+                        Err(err) => items.push(Err(err)),
+                    }
+                    items.push(Ok(candle));
+                    last_time = Some(candle.time);
+                }
+                _ => {
+                    items.push(Ok(candle));
+                    last_time = Some(candle.time);
+                }
+            }
+            Some((items, (live, last_time)))
+        }
+    })
+    .flat_map(stream::iter)
+}
+
+fn candles_or_error(
+    result: Result<Vec<Candle>, Error>,
+) -> impl Stream<Item = Result<Candle, Error>> {
+    match result {
+        Ok(candles) => stream::iter(candles.into_iter().map(Ok)).left_stream(),
+        Err(err) => stream::once(async { Err(err) }).right_stream(),
+    }
+}
+
 #[cfg(test)]
 mod test_infrastructure {
     use chrono::{DateTime, Utc};
@@ -18,44 +178,44 @@ mod test_infrastructure {
     use std::task::Poll;
 
     use crate::models::candle::Candle;
+    use crate::Error;
 
     use super::Stock;
+
+    // Reference implementation over a plain vector of candles
     pub struct TestStock {
         pub candles: Vec<Candle>,
     }
 
+    impl TestStock {
+        fn sorted(&self, predicate: impl Fn(&Candle) -> bool) -> TestStream {
+            let mut candles: Vec<_> = self.candles.iter().copied().filter(predicate).collect();
+            candles.sort_by_key(|candle| candle.time);
+            candles.into()
+        }
+    }
+
     impl Stock for TestStock {
-        type Error = ();
+        type Error = Error;
         type Stream = TestStream;
         fn get_candles_before(&mut self, before: DateTime<Utc>) -> TestStream {
-            self.candles
-                .iter()
-                .take_while(|c| c.time > before)
-                .copied()
-                .collect::<Vec<_>>()
-                .into()
+            self.sorted(|c| c.time < before)
         }
 
         fn get_candles_between(&mut self, start: DateTime<Utc>, end: DateTime<Utc>) -> TestStream {
-            self.candles
-                .iter()
-                .take_while(|c| c.time >= start && c.time <= end)
-                .copied()
-                .collect::<Vec<_>>()
-                .into()
+            self.sorted(|c| c.time >= start && c.time < end)
         }
 
         fn stream_candles(&mut self) -> TestStream {
-            unimplemented!()
+            self.sorted(|_| true)
         }
     }
     pub struct TestStream {
         iter: Box<dyn Iterator<Item = Candle>>,
     }
 
-    #[cfg(test)]
     impl Stream for TestStream {
-        type Item = Result<Candle, ()>;
+        type Item = Result<Candle, Error>;
         fn poll_next(
             mut self: std::pin::Pin<&mut Self>,
             _cx: &mut std::task::Context<'_>,
@@ -67,7 +227,6 @@ mod test_infrastructure {
         }
     }
 
-    #[cfg(test)]
     impl From<Vec<Candle>> for TestStream {
         fn from(vec: Vec<Candle>) -> Self {
             TestStream {
@@ -76,3 +235,199 @@ mod test_infrastructure {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::test_infrastructure::TestStock;
+    use super::{CachedStock, Stock};
+    use crate::data::database::{in_memory::InMemoryStockDataCache, StockDataCache};
+    use crate::data::providers::{CandleStream, MarketDataProvider};
+    use crate::models::{candle::Candle, decimal::Decimal, interval::Interval, symbol::SymbolInfo};
+    use crate::test_data::minute;
+    use crate::Error;
+    use async_trait::async_trait;
+    use chrono::{DateTime, Utc};
+    use futures::executor::block_on;
+    use futures::stream::{self, StreamExt};
+    use proptest::prelude::*;
+    use std::collections::BTreeSet;
+    use std::ops::Range;
+    use std::sync::Arc;
+
+    fn candle(time: DateTime<Utc>) -> Candle {
+        Candle {
+            open: Decimal::from(1),
+            high: Decimal::from(2),
+            low: Decimal::new(5, 1),
+            close: Decimal::new(15, 1),
+            volume: Decimal::from(10),
+            time,
+        }
+    }
+
+    // Serves history from a fixed series and streams only the given live candles
+    struct FeedProvider {
+        series: Vec<Candle>,
+        live: Vec<Candle>,
+    }
+
+    #[async_trait]
+    impl MarketDataProvider for FeedProvider {
+        async fn get_symbols(&self) -> Result<Vec<SymbolInfo>, Error> {
+            Ok(Vec::new())
+        }
+        async fn get_candles(
+            &self,
+            _symbol: String,
+            _interval: Interval,
+            range: Range<DateTime<Utc>>,
+        ) -> Result<Vec<Candle>, Error> {
+            Ok(self
+                .series
+                .iter()
+                .filter(|candle| range.contains(&candle.time))
+                .copied()
+                .collect())
+        }
+        async fn stream_candles(
+            &self,
+            _symbol: String,
+            _interval: Interval,
+        ) -> Result<CandleStream, Error> {
+            Ok(Box::pin(stream::iter(
+                self.live.clone().into_iter().map(Ok),
+            )))
+        }
+    }
+
+    fn cached_stock(cached: &[Candle], provider: FeedProvider) -> CachedStock {
+        let cache = InMemoryStockDataCache::new();
+        block_on(cache.write_candles("BTCUSDT".to_string(), Interval::OneMinute, cached.to_vec()))
+            .unwrap();
+        CachedStock::new(
+            "BTCUSDT".to_string(),
+            Interval::OneMinute,
+            Arc::new(cache),
+            Arc::new(provider),
+        )
+    }
+
+    fn collect_times<S, E>(stream: S) -> Vec<DateTime<Utc>>
+    where
+        S: futures::Stream<Item = Result<Candle, E>>,
+        E: std::fmt::Debug,
+    {
+        block_on(stream.map(|candle| candle.unwrap().time).collect())
+    }
+
+    #[test]
+    fn stream_continues_history_with_live_candles() {
+        let cached: Vec<_> = (0..10).map(minute).map(candle).collect();
+        let series: Vec<_> = (0..20).map(minute).map(candle).collect();
+        // Live feed starts with candles that are already cached
+        let live: Vec<_> = (8..12).map(minute).map(candle).collect();
+        let mut stock = cached_stock(&cached, FeedProvider { series, live });
+
+        let times = collect_times(stock.stream_candles());
+
+        assert_eq!((0..12).map(minute).collect::<Vec<_>>(), times);
+    }
+
+    #[test]
+    fn stream_fills_gap_before_live_candles() {
+        let cached: Vec<_> = (0..10).map(minute).map(candle).collect();
+        let series: Vec<_> = (0..20).map(minute).map(candle).collect();
+        let live: Vec<_> = (15..17).map(minute).map(candle).collect();
+        let mut stock = cached_stock(&cached, FeedProvider { series, live });
+
+        let times = collect_times(stock.stream_candles());
+
+        assert_eq!((0..17).map(minute).collect::<Vec<_>>(), times);
+    }
+
+    #[test]
+    fn stream_without_history_is_live() {
+        let live: Vec<_> = (3..5).map(minute).map(candle).collect();
+        let mut stock = cached_stock(
+            &[],
+            FeedProvider {
+                series: Vec::new(),
+                live,
+            },
+        );
+
+        let times = collect_times(stock.stream_candles());
+
+        assert_eq!(vec![minute(3), minute(4)], times);
+    }
+
+    #[test]
+    fn between_rejects_inverted_range() {
+        let cached: Vec<_> = (0..10).map(minute).map(candle).collect();
+        let mut stock = cached_stock(
+            &cached,
+            FeedProvider {
+                series: Vec::new(),
+                live: Vec::new(),
+            },
+        );
+
+        let results: Vec<_> = block_on(
+            stock
+                .get_candles_between(minute(5), minute(2))
+                .collect::<Vec<_>>(),
+        );
+
+        assert_eq!(1, results.len());
+        assert!(matches!(results[0], Err(Error::InvalidRange { .. })));
+    }
+
+    fn expected(minutes: &BTreeSet<i64>, range: Range<i64>) -> Vec<DateTime<Utc>> {
+        minutes.range(range).copied().map(minute).collect()
+    }
+
+    proptest! {
+        #[test]
+        fn between_returns_exactly_the_range(
+            minutes in prop::collection::btree_set(0i64..500, 0..100),
+            start in 0i64..500,
+            length in 0i64..500,
+        ) {
+            let end = start + length;
+            let candles: Vec<_> = minutes.iter().rev().copied().map(minute).map(candle).collect();
+            let mut cached = cached_stock(&candles, FeedProvider { series: Vec::new(), live: Vec::new() });
+            let mut reference = TestStock { candles };
+
+            let expected = expected(&minutes, start..end);
+            prop_assert_eq!(&expected, &collect_times(cached.get_candles_between(minute(start), minute(end))));
+            prop_assert_eq!(&expected, &collect_times(reference.get_candles_between(minute(start), minute(end))));
+        }
+
+        #[test]
+        fn before_returns_everything_earlier(
+            minutes in prop::collection::btree_set(0i64..500, 0..100),
+            before in 0i64..600,
+        ) {
+            let candles: Vec<_> = minutes.iter().rev().copied().map(minute).map(candle).collect();
+            let mut cached = cached_stock(&candles, FeedProvider { series: Vec::new(), live: Vec::new() });
+            let mut reference = TestStock { candles };
+
+            let expected = expected(&minutes, 0..before);
+            prop_assert_eq!(&expected, &collect_times(cached.get_candles_before(minute(before))));
+            prop_assert_eq!(&expected, &collect_times(reference.get_candles_before(minute(before))));
+        }
+
+        #[test]
+        fn adjacent_ranges_partition_the_series(
+            minutes in prop::collection::btree_set(0i64..500, 0..100),
+            split in 0i64..500,
+        ) {
+            let candles: Vec<_> = minutes.iter().copied().map(minute).map(candle).collect();
+            let mut cached = cached_stock(&candles, FeedProvider { series: Vec::new(), live: Vec::new() });
+
+            let mut combined = collect_times(cached.get_candles_between(minute(0), minute(split)));
+            combined.extend(collect_times(cached.get_candles_between(minute(split), minute(500))));
+            prop_assert_eq!(expected(&minutes, 0..500), combined);
+        }
+    }
+}
@@ -0,0 +1,432 @@
+use std::{io::ErrorKind, os::unix::fs::PermissionsExt, path::Path};
+
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, CertificateSigningRequest, DistinguishedName,
+    DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair, KeyUsagePurpose,
+};
+use time::{Duration, OffsetDateTime};
+use tokio::io::AsyncWriteExt;
+use tracing::info;
+use trade_core::Error;
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate, X509CertificationRequest};
+
+// The authority is never rotated, nodes pin its certificate
+const CA_VALIDITY_DAYS: i64 = 10 * 365;
+
+// Certificate and key in DER
+#[derive(Debug, Clone)]
+pub struct IssuedCertificate {
+    pub certificate: Vec<u8>,
+    pub private_key: Vec<u8>,
+}
+
+impl IssuedCertificate {
+    pub fn certificate_pem(&self) -> String {
+        encode_pem("CERTIFICATE", &self.certificate)
+    }
+
+    pub fn private_key_pem(&self) -> String {
+        encode_pem("PRIVATE KEY", &self.private_key)
+    }
+}
+
+// Internal certificate authority of the host. It signs the certificate the host presents to
+// nodes and the client certificates identifying nodes, so both sides only trust each other.
+pub struct CertificateAuthority {
+    certificate: Certificate,
+    // As stored on disk, the serialization of `certificate` is not deterministic
+    certificate_der: Vec<u8>,
+}
+
+impl CertificateAuthority {
+    // Loads the authority from `directory`, creating it on first use
+    pub async fn load_or_create(directory: &Path) -> Result<Self, Error> {
+        let certificate_path = directory.join("ca_certificate.der");
+        let private_key_path = directory.join("ca_private_key.der");
+        match (
+            tokio::fs::read(&certificate_path).await,
+            tokio::fs::read(&private_key_path).await,
+        ) {
+            (Ok(certificate_der), Ok(private_key_der)) => {
+                let key_pair = KeyPair::from_der(&private_key_der).map_err(Error::decode)?;
+                let params = CertificateParams::from_ca_cert_der(&certificate_der, key_pair)
+                    .map_err(Error::decode)?;
+                let certificate = Certificate::from_params(params).map_err(Error::backend)?;
+                Ok(CertificateAuthority {
+                    certificate,
+                    certificate_der,
+                })
+            }
+            (Err(certificate), Err(private_key))
+                if certificate.kind() == ErrorKind::NotFound
+                    && private_key.kind() == ErrorKind::NotFound =>
+            {
+                info!("Creating certificate authority in {}", directory.display());
+                let authority = Self::generate()?;
+                write(&certificate_path, &authority.certificate_der).await?;
+                write_private_key(
+                    &private_key_path,
+                    &authority.certificate.serialize_private_key_der(),
+                )
+                .await?;
+                Ok(authority)
+            }
+            // Replacing the authority would lock out every node trusting it
+            (Err(err), _) | (_, Err(err)) => Err(Error::backend(format!(
+                "Incomplete certificate authority in {}: {}",
+                directory.display(),
+                err
+            ))),
+        }
+    }
+
+    pub fn generate() -> Result<Self, Error> {
+        let mut params = CertificateParams::default();
+        params.distinguished_name = distinguished_name("deeptrading host CA");
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+        params.not_before = OffsetDateTime::now_utc();
+        params.not_after = params.not_before + Duration::days(CA_VALIDITY_DAYS);
+        let certificate = Certificate::from_params(params).map_err(Error::backend)?;
+        let certificate_der = certificate.serialize_der().map_err(Error::backend)?;
+        Ok(CertificateAuthority {
+            certificate,
+            certificate_der,
+        })
+    }
+
+    // Root nodes and the host verify their peers against
+    pub fn certificate_der(&self) -> &[u8] {
+        &self.certificate_der
+    }
+
+    // Whether `certificate` was signed by this authority
+    pub fn has_issued(&self, certificate: &[u8]) -> bool {
+        let parsed = (
+            X509Certificate::from_der(&self.certificate_der),
+            X509Certificate::from_der(certificate),
+        );
+        match parsed {
+            (Ok((_, root)), Ok((_, leaf))) => {
+                root.subject() == leaf.issuer()
+                    && leaf.verify_signature(Some(root.public_key())).is_ok()
+            }
+            _ => false,
+        }
+    }
+
+    // Certificate of the host for the key of `request`, valid for `names`
+    pub fn sign_server_request(
+        &self,
+        names: &[String],
+        request: &[u8],
+        validity: Duration,
+    ) -> Result<Vec<u8>, Error> {
+        let mut request = CertificateSigningRequest::from_der(request).map_err(Error::decode)?;
+        let mut params = CertificateParams::new(names.to_vec());
+        params.distinguished_name = request.params.distinguished_name;
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        params.not_before = OffsetDateTime::now_utc();
+        params.not_after = params.not_before + validity;
+        request.params = params;
+        request
+            .serialize_der_with_signer(&self.certificate)
+            .map_err(Error::backend)
+    }
+
+    // Client certificate identifying a node, `name` becomes its common name
+    pub fn issue_node_certificate(
+        &self,
+        name: &str,
+        validity: Duration,
+    ) -> Result<IssuedCertificate, Error> {
+        self.issue(node_params(name, validity)?)
+    }
+
+    // Client certificate for the key of `request`, a DER certificate signing request. Only the
+    // key is taken from the request, the authority decides on everything else.
+    pub fn sign_node_request(
+        &self,
+        name: &str,
+        request: &[u8],
+        validity: Duration,
+    ) -> Result<Vec<u8>, Error> {
+        let mut request = CertificateSigningRequest::from_der(request).map_err(Error::decode)?;
+        request.params = node_params(name, validity)?;
+        request
+            .serialize_der_with_signer(&self.certificate)
+            .map_err(Error::backend)
+    }
+
+    fn issue(&self, params: CertificateParams) -> Result<IssuedCertificate, Error> {
+        let certificate = Certificate::from_params(params).map_err(Error::backend)?;
+        Ok(IssuedCertificate {
+            certificate: certificate
+                .serialize_der_with_signer(&self.certificate)
+                .map_err(Error::backend)?,
+            private_key: certificate.serialize_private_key_der(),
+        })
+    }
+}
+
+fn node_params(name: &str, validity: Duration) -> Result<CertificateParams, Error> {
+    if !is_valid_node_name(name) {
+        return Err(Error::backend(format!("Invalid node name {:?}", name)));
+    }
+    let mut params = CertificateParams::new(vec![name.to_string()]);
+    params.distinguished_name = distinguished_name(name);
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+    params.not_before = OffsetDateTime::now_utc();
+    params.not_after = params.not_before + validity;
+    Ok(params)
+}
+
+// Common name a DER certificate signing request asks for
+pub fn requested_name(request: &[u8]) -> Option<String> {
+    let (_, request) = X509CertificationRequest::from_der(request).ok()?;
+    let subject = &request.certification_request_info.subject;
+    let common_name = subject.iter_common_name().next()?;
+    common_name.as_str().ok().map(str::to_string)
+}
+
+// DNS names a DER certificate is valid for
+pub fn dns_names(certificate: &[u8]) -> Vec<String> {
+    let certificate = match X509Certificate::from_der(certificate) {
+        Ok((_, certificate)) => certificate,
+        Err(_) => return Vec::new(),
+    };
+    match certificate.subject_alternative_name() {
+        Ok(Some(extension)) => extension
+            .value
+            .general_names
+            .iter()
+            .filter_map(|name| match name {
+                GeneralName::DNSName(name) => Some(name.to_string()),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+// Letters, digits, '-', '_' and '.', so names are safe to use as directory names
+pub fn is_valid_node_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+// Files handed to a node, read by `trade_node` from its certificate directory
+pub async fn write_node_files(
+    directory: &Path,
+    authority: &CertificateAuthority,
+    issued: &IssuedCertificate,
+) -> Result<(), Error> {
+    tokio::fs::create_dir_all(directory)
+        .await
+        .map_err(Error::backend)?;
+    write(
+        &directory.join("ca_certificate.der"),
+        authority.certificate_der(),
+    )
+    .await?;
+    write(&directory.join("certificate.der"), &issued.certificate).await?;
+    write_private_key(&directory.join("private_key.der"), &issued.private_key).await
+}
+
+// Whether the DER certificate is within its validity period
+pub fn is_valid(certificate: &[u8]) -> bool {
+    X509Certificate::from_der(certificate)
+        .is_ok_and(|(_, certificate)| certificate.validity().is_valid())
+}
+
+pub fn encode_pem(tag: &str, contents: &[u8]) -> String {
+    pem::encode(&pem::Pem {
+        tag: tag.to_string(),
+        contents: contents.to_vec(),
+    })
+}
+
+fn distinguished_name(common_name: &str) -> DistinguishedName {
+    let mut name = DistinguishedName::new();
+    name.push(DnType::CommonName, common_name);
+    name
+}
+
+async fn write(path: &Path, data: &[u8]) -> Result<(), Error> {
+    tokio::fs::write(path, data).await.map_err(Error::backend)
+}
+
+// Only the owner may read private keys, also when an existing file is overwritten
+pub async fn write_private_key(path: &Path, data: &[u8]) -> Result<(), Error> {
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .await
+        .map_err(Error::backend)?;
+    file.set_permissions(std::fs::Permissions::from_mode(0o600))
+        .await
+        .map_err(Error::backend)?;
+    file.write_all(data).await.map_err(Error::backend)?;
+    file.sync_all().await.map_err(Error::backend)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        dns_names, is_valid, is_valid_node_name, requested_name, write_node_files,
+        CertificateAuthority,
+    };
+    use rcgen::{Certificate, CertificateParams, DistinguishedName, DnType};
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+    use time::Duration;
+    use x509_parser::prelude::{FromDer, X509Certificate};
+
+    #[tokio::test]
+    async fn authority_is_persisted() {
+        let directory = tempfile::tempdir().unwrap();
+        let created = CertificateAuthority::load_or_create(directory.path())
+            .await
+            .unwrap();
+        let loaded = CertificateAuthority::load_or_create(directory.path())
+            .await
+            .unwrap();
+        assert_eq!(created.certificate_der(), loaded.certificate_der());
+        assert_eq!(0o600, mode(&directory.path().join("ca_private_key.der")));
+
+        // Certificates issued after a restart still chain to the stored root
+        let issued = loaded
+            .issue_node_certificate("node-1", Duration::days(30))
+            .unwrap();
+        assert!(created.has_issued(&issued.certificate));
+        assert!(!CertificateAuthority::generate()
+            .unwrap()
+            .has_issued(&issued.certificate));
+        let leaf = X509Certificate::from_der(&issued.certificate).unwrap().1;
+        let common_name = leaf.subject().iter_common_name().next().unwrap();
+        assert_eq!("node-1", common_name.as_str().unwrap());
+    }
+
+    fn mode(path: &Path) -> u32 {
+        std::fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    #[tokio::test]
+    async fn node_keys_are_private() {
+        let directory = tempfile::tempdir().unwrap();
+        let authority = CertificateAuthority::generate().unwrap();
+        let issued = authority
+            .issue_node_certificate("node-1", Duration::days(30))
+            .unwrap();
+        let key_path = directory.path().join("private_key.der");
+        std::fs::write(&key_path, b"old").unwrap();
+        std::fs::set_permissions(&key_path, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        write_node_files(directory.path(), &authority, &issued)
+            .await
+            .unwrap();
+        assert_eq!(0o600, mode(&key_path));
+        assert_eq!(issued.private_key, std::fs::read(&key_path).unwrap());
+    }
+
+    #[test]
+    fn expired_certificates_are_not_valid() {
+        let authority = CertificateAuthority::generate().unwrap();
+        let valid = authority
+            .issue_node_certificate("node-1", Duration::days(30))
+            .unwrap();
+        let expired = authority
+            .issue_node_certificate("node-1", Duration::seconds(-1))
+            .unwrap();
+        assert!(is_valid(&valid.certificate));
+        assert!(authority.has_issued(&expired.certificate));
+        assert!(!is_valid(&expired.certificate));
+        assert!(!is_valid(&[1, 2, 3]));
+    }
+
+    #[tokio::test]
+    async fn incomplete_authority_is_not_replaced() {
+        let directory = tempfile::tempdir().unwrap();
+        CertificateAuthority::load_or_create(directory.path())
+            .await
+            .unwrap();
+        let key_path = directory.path().join("ca_private_key.der");
+        std::fs::remove_file(&key_path).unwrap();
+        assert!(CertificateAuthority::load_or_create(directory.path())
+            .await
+            .is_err());
+        assert!(!key_path.exists());
+    }
+
+    #[test]
+    fn server_requests_are_signed_for_the_names() {
+        let authority = CertificateAuthority::generate().unwrap();
+        let key =
+            Certificate::from_params(CertificateParams::new(vec!["attacker.example".to_string()]))
+                .unwrap();
+        let request = key.serialize_request_der().unwrap();
+        let names = vec!["localhost".to_string(), "host".to_string()];
+
+        let signed = authority
+            .sign_server_request(&names, &request, Duration::days(365))
+            .unwrap();
+        assert!(authority.has_issued(&signed));
+        assert_eq!(names, dns_names(&signed));
+        let leaf = X509Certificate::from_der(&signed).unwrap().1;
+        let root = X509Certificate::from_der(authority.certificate_der())
+            .unwrap()
+            .1;
+        // Rotating the leaf never outlives the root
+        assert!(leaf.validity().not_after < root.validity().not_after);
+        assert!(dns_names(&[1, 2, 3]).is_empty());
+    }
+
+    #[test]
+    fn node_names_are_restricted() {
+        assert!(is_valid_node_name("node-1.eu_west"));
+        assert!(!is_valid_node_name(""));
+        assert!(!is_valid_node_name(".."));
+        assert!(!is_valid_node_name("node/1"));
+        assert!(CertificateAuthority::generate()
+            .unwrap()
+            .issue_node_certificate("../node", Duration::days(30))
+            .is_err());
+    }
+
+    #[test]
+    fn requests_are_signed_with_the_given_name() {
+        let authority = CertificateAuthority::generate().unwrap();
+        let mut params = CertificateParams::new(vec!["example.com".to_string()]);
+        params.distinguished_name = DistinguishedName::new();
+        params.distinguished_name.push(DnType::CommonName, "node-2");
+        let key = Certificate::from_params(params).unwrap();
+        let request = key.serialize_request_der().unwrap();
+        assert_eq!(Some("node-2".to_string()), requested_name(&request));
+
+        let signed = authority
+            .sign_node_request("node-1", &request, Duration::days(30))
+            .unwrap();
+        assert!(authority.has_issued(&signed));
+        let leaf = X509Certificate::from_der(&signed).unwrap().1;
+        assert_eq!(
+            leaf.public_key().raw,
+            X509Certificate::from_der(&key.serialize_der().unwrap())
+                .unwrap()
+                .1
+                .public_key()
+                .raw
+        );
+        let common_name = leaf.subject().iter_common_name().next().unwrap();
+        assert_eq!("node-1", common_name.as_str().unwrap());
+        assert!(authority
+            .sign_node_request("node-1", &[1, 2, 3], Duration::days(30))
+            .is_err());
+    }
+}
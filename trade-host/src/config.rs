@@ -1,6 +1,8 @@
 use std::path::PathBuf;
 
 use serde::Deserialize;
+use trade_core::models::{decimal::Decimal, interval::Interval};
+use trade_protocol::version;
 
 #[derive(Deserialize, Debug, Clone, Copy)]
 pub enum TracingMode {
@@ -14,9 +16,21 @@ pub struct HostConfig {
     pub environment: HostEnvironment,
     pub cert_path: PathBuf,
     pub misc_path: PathBuf,
+    // Used for candles when no InfluxDB is configured
+    pub candle_path: PathBuf,
     pub cert_names: Vec<String>,
+    // Nodes getting a client certificate issued into `cert_path/nodes/<name>`
+    pub node_names: Vec<String>,
+    // One-time tokens nodes can enroll with, see `enrollment`
+    pub enrollment_tokens: Vec<String>,
+    // Validity of the certificate the host presents, signed by its CA for `cert_names`
+    pub host_certificate_days: i64,
+    // Validity of the client certificates of nodes
+    pub node_certificate_days: i64,
     pub host: String,
     pub port: u16,
+    // Nodes missing any of these in their handshake are disconnected
+    pub required_node_capabilities: Vec<String>,
     pub binance_api_key: Option<String>,
     pub binance_secret_key: Option<String>,
     pub tracing_mode: Option<TracingMode>,
@@ -25,6 +39,33 @@ pub struct HostConfig {
     pub influx_url: Option<String>,
     pub influx_username: Option<String>,
     pub influx_password: Option<String>,
+    pub influx_database: String,
+    pub influx_batch_size: usize,
+    pub influx_write_retries: u32,
+    pub influx_max_in_flight: usize,
+    // Candles kept in memory in front of InfluxDB
+    pub memory_cache_capacity: usize,
+    pub backfill_interval: Interval,
+    pub backfill_days: i64,
+    pub binance_requests_per_minute: u32,
+    pub binance_ws_endpoint: String,
+    // Per symbol, in the quote asset
+    pub risk_max_position_notional: Decimal,
+    pub risk_max_order_notional: Decimal,
+    pub risk_daily_loss_limit: Decimal,
+    // Per node
+    pub risk_max_orders_per_minute: u32,
+    // Allowed deviation of limit and stop prices from the last close
+    pub risk_price_band_bps: u32,
+    // Rejects all orders from the start, creating `kill_switch` in `misc_path` does so at runtime
+    pub risk_kill_switch: bool,
+    // Simulates orders instead of sending them to Binance, unset all but production hosts do so.
+    // Staging hosts never trade live.
+    pub paper_trading: Option<bool>,
+    pub paper_initial_cash: Decimal,
+    // Fraction of the notional
+    pub paper_fee_rate: Decimal,
+    pub paper_latency_ms: u64,
 }
 
 impl Default for HostConfig {
@@ -33,9 +74,18 @@ impl Default for HostConfig {
             environment: HostEnvironment::Development,
             cert_path: PathBuf::from("./data/certs"),
             misc_path: PathBuf::from("./data/misc"),
+            candle_path: PathBuf::from("./data/candles"),
             cert_names: vec!["localhost".to_string(), "host".to_string()],
+            node_names: Vec::new(),
+            enrollment_tokens: Vec::new(),
+            host_certificate_days: 365,
+            node_certificate_days: 30,
             host: "0.0.0.0".to_string(),
             port: 4001,
+            required_node_capabilities: vec![
+                version::CAPABILITY_CANDLES.to_string(),
+                version::CAPABILITY_ORDERS.to_string(),
+            ],
             binance_api_key: None,
             binance_secret_key: None,
             tracing_mode: None,
@@ -44,6 +94,25 @@ impl Default for HostConfig {
             influx_url: None,
             influx_username: None,
             influx_password: None,
+            influx_database: "deeptrading".to_string(),
+            influx_batch_size: 5000,
+            influx_write_retries: 3,
+            influx_max_in_flight: 4,
+            memory_cache_capacity: 1_000_000,
+            backfill_interval: Interval::OneMinute,
+            backfill_days: 30,
+            binance_requests_per_minute: 600,
+            binance_ws_endpoint: "wss://stream.binance.com:9443".to_string(),
+            risk_max_position_notional: Decimal::from(10_000),
+            risk_max_order_notional: Decimal::from(1_000),
+            risk_daily_loss_limit: Decimal::from(500),
+            risk_max_orders_per_minute: 30,
+            risk_price_band_bps: 500,
+            risk_kill_switch: false,
+            paper_trading: None,
+            paper_initial_cash: Decimal::from(10_000),
+            paper_fee_rate: Decimal::new(1, 3),
+            paper_latency_ms: 50,
         }
     }
 }
@@ -0,0 +1,228 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use time::Duration;
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
+use trade_core::Error;
+use trade_protocol::packets::{CertificateBundle, EnrollmentRejection};
+
+use crate::authority::{self, CertificateAuthority};
+use crate::execution::ORDER_ID_SEPARATOR;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct EnrollmentState {
+    used_tokens: BTreeSet<String>,
+    // Enrolled node names and when they were enrolled
+    nodes: BTreeMap<String, DateTime<Utc>>,
+}
+
+// Issues client certificates to new nodes in exchange for one-time tokens, and renews them for
+// nodes that are already enrolled. Used tokens and enrolled names are stored in `path`, so a
+// token never enrolls a second node, not even after a restart.
+pub struct Enrollment {
+    authority: Arc<CertificateAuthority>,
+    tokens: BTreeSet<String>,
+    // Names of nodes certified by configuration, which cannot be enrolled
+    reserved_names: BTreeSet<String>,
+    validity: Duration,
+    path: PathBuf,
+    state: Mutex<EnrollmentState>,
+}
+
+impl Enrollment {
+    pub async fn open(
+        path: PathBuf,
+        authority: Arc<CertificateAuthority>,
+        tokens: Vec<String>,
+        reserved_names: Vec<String>,
+        validity: Duration,
+    ) -> Result<Self, Error> {
+        let state = match tokio::fs::read(&path).await {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(Error::decode)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => EnrollmentState::default(),
+            Err(err) => return Err(Error::backend(err)),
+        };
+        Ok(Enrollment {
+            authority,
+            tokens: tokens.into_iter().collect(),
+            reserved_names: reserved_names.into_iter().collect(),
+            validity,
+            path,
+            state: Mutex::new(state),
+        })
+    }
+
+    // Certifies the node named in `request` and uses up `token`
+    pub async fn enroll(
+        &self,
+        token: &str,
+        request: &[u8],
+    ) -> Result<CertificateBundle, EnrollmentRejection> {
+        let mut state = self.state.lock().await;
+        if !self.tokens.contains(token) || state.used_tokens.contains(token) {
+            return Err(EnrollmentRejection::InvalidToken);
+        }
+        let name = authority::requested_name(request).ok_or_else(|| {
+            EnrollmentRejection::InvalidRequest {
+                reason: "no common name".to_string(),
+            }
+        })?;
+        // The name qualifies the client order ids of the node
+        if name.contains(ORDER_ID_SEPARATOR) {
+            return Err(EnrollmentRejection::InvalidRequest {
+                reason: format!("name contains '{}'", ORDER_ID_SEPARATOR),
+            });
+        }
+        if state.nodes.contains_key(&name) || self.reserved_names.contains(&name) {
+            return Err(EnrollmentRejection::NameTaken { name });
+        }
+
+        let bundle = self.sign(&name, request)?;
+        state.used_tokens.insert(token.to_string());
+        state.nodes.insert(name.clone(), Utc::now());
+        if let Err(err) = self.persist(&state).await {
+            // Without a record the token could be used again
+            error!("Cannot store enrollment of node {}: {}", name, err);
+            state.used_tokens.remove(token);
+            state.nodes.remove(&name);
+            return Err(EnrollmentRejection::Unavailable);
+        }
+        info!("Enrolled node {}", name);
+        Ok(bundle)
+    }
+
+    // New certificate for the key of `request`, `name` is the identity of the requesting node
+    pub fn renew(
+        &self,
+        name: &str,
+        request: &[u8],
+    ) -> Result<CertificateBundle, EnrollmentRejection> {
+        let bundle = self.sign(name, request)?;
+        info!("Renewed certificate of node {}", name);
+        Ok(bundle)
+    }
+
+    fn sign(&self, name: &str, request: &[u8]) -> Result<CertificateBundle, EnrollmentRejection> {
+        let certificate = self
+            .authority
+            .sign_node_request(name, request, self.validity)
+            .map_err(|err| {
+                warn!("Cannot sign certificate request of node {}: {}", name, err);
+                EnrollmentRejection::InvalidRequest {
+                    reason: err.to_string(),
+                }
+            })?;
+        Ok(CertificateBundle {
+            certificate,
+            ca_chain: vec![self.authority.certificate_der().to_vec()],
+        })
+    }
+
+    async fn persist(&self, state: &EnrollmentState) -> Result<(), Error> {
+        let bytes = serde_json::to_vec_pretty(state).map_err(Error::decode)?;
+        let temporary = self.path.with_extension("tmp");
+        tokio::fs::write(&temporary, bytes)
+            .await
+            .map_err(Error::backend)?;
+        tokio::fs::rename(&temporary, &self.path)
+            .await
+            .map_err(Error::backend)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use super::Enrollment;
+    use crate::authority::CertificateAuthority;
+    use rcgen::{Certificate, CertificateParams, DistinguishedName, DnType};
+    use time::Duration;
+    use trade_protocol::packets::EnrollmentRejection;
+
+    fn request(name: &str) -> Vec<u8> {
+        let mut params = CertificateParams::default();
+        params.distinguished_name = DistinguishedName::new();
+        params.distinguished_name.push(DnType::CommonName, name);
+        Certificate::from_params(params)
+            .unwrap()
+            .serialize_request_der()
+            .unwrap()
+    }
+
+    async fn enrollment(directory: &Path, authority: &Arc<CertificateAuthority>) -> Enrollment {
+        Enrollment::open(
+            directory.join("enrollment.json"),
+            Arc::clone(authority),
+            vec!["token-1".to_string(), "token-2".to_string()],
+            vec!["configured".to_string()],
+            Duration::days(30),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn tokens_enroll_one_node() {
+        let directory = tempfile::tempdir().unwrap();
+        let authority = Arc::new(CertificateAuthority::generate().unwrap());
+        let enrollment = enrollment(directory.path(), &authority).await;
+
+        let bundle = enrollment
+            .enroll("token-1", &request("node-1"))
+            .await
+            .unwrap();
+        assert!(authority.has_issued(&bundle.certificate));
+        assert_eq!(vec![authority.certificate_der().to_vec()], bundle.ca_chain);
+        assert_eq!(
+            Err(EnrollmentRejection::InvalidToken),
+            enrollment.enroll("token-1", &request("node-2")).await
+        );
+        assert_eq!(
+            Err(EnrollmentRejection::InvalidToken),
+            enrollment.enroll("unknown", &request("node-2")).await
+        );
+        assert!(matches!(
+            enrollment.enroll("token-2", &request("node-1")).await,
+            Err(EnrollmentRejection::NameTaken { .. })
+        ));
+        assert!(matches!(
+            enrollment.enroll("token-2", &request("configured")).await,
+            Err(EnrollmentRejection::NameTaken { .. })
+        ));
+        assert!(matches!(
+            enrollment.enroll("token-2", &[1, 2, 3]).await,
+            Err(EnrollmentRejection::InvalidRequest { .. })
+        ));
+        assert!(matches!(
+            enrollment.enroll("token-2", &request("node:4")).await,
+            Err(EnrollmentRejection::InvalidRequest { .. })
+        ));
+
+        // Refused requests leave the token usable
+        let reopened = self::enrollment(directory.path(), &authority).await;
+        assert_eq!(
+            Err(EnrollmentRejection::InvalidToken),
+            reopened.enroll("token-1", &request("node-3")).await
+        );
+        assert!(reopened.enroll("token-2", &request("node-3")).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn renewal_keeps_the_identity() {
+        let directory = tempfile::tempdir().unwrap();
+        let authority = Arc::new(CertificateAuthority::generate().unwrap());
+        let enrollment = enrollment(directory.path(), &authority).await;
+
+        let bundle = enrollment.renew("node-1", &request("node-2")).unwrap();
+        assert_eq!(
+            Some("node-1".to_string()),
+            trade_protocol::certificates::common_name(&bundle.certificate)
+        );
+    }
+}
@@ -0,0 +1,670 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use binance::account::{Account, OrderCancellation, OrderRequest, OrderStatusRequest};
+use binance::api::Binance;
+use binance::config::Config;
+use binance::market::Market;
+use binance::rest_model::{
+    Order as BinanceOrder, OrderResponse, OrderSide, OrderStatus as BinanceOrderStatus,
+    OrderType as BinanceOrderType, Prices, TimeInForce as BinanceTimeInForce,
+};
+use binance::userstream::UserStream;
+use binance::ws_model::{OrderUpdate, WebsocketEvent};
+use chrono::{DateTime, TimeZone, Utc};
+use futures_util::{SinkExt, StreamExt};
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{error, warn};
+use trade_core::models::{
+    decimal::Decimal,
+    order::{Fill, Order, OrderType, TimeInForce},
+    portfolio::Portfolio,
+    trade::Side,
+};
+use trade_core::Error;
+
+use super::{AccountState, Exchange, ExecutionReport, OpenOrder, OrderSnapshot, OrderStatus};
+
+// Listen keys expire after an hour without keep-alive
+const LISTEN_KEY_KEEP_ALIVE: Duration = Duration::from_secs(30 * 60);
+// Binance error code of unknown orders
+const NO_SUCH_ORDER: i32 = -2013;
+// Binance error codes that may go away when retried: unknown and server errors, disconnects,
+// timeouts, rate limits and clock skew. All others, e.g. -2010 for refused orders or -1013 for
+// filter failures, are about the request itself.
+const TRANSIENT_ERRORS: &[i32] = &[
+    -1000, -1001, -1003, -1006, -1007, -1008, -1015, -1016, -1021,
+];
+
+// Spot trading on Binance, execution reports come from the user data stream
+pub struct BinanceExchange {
+    account: Account,
+    market: Market,
+    user_stream: UserStream,
+    ws_endpoint: String,
+    // Positions are tracked in pairs with this asset
+    quote_asset: String,
+}
+
+impl BinanceExchange {
+    pub fn new(api_key: Option<String>, secret_key: Option<String>, ws_endpoint: String) -> Self {
+        Self::new_with_config(api_key, secret_key, ws_endpoint, Config::default())
+    }
+
+    pub fn new_with_config(
+        api_key: Option<String>,
+        secret_key: Option<String>,
+        ws_endpoint: String,
+        config: Config,
+    ) -> Self {
+        BinanceExchange {
+            account: Account::new_with_config(api_key.clone(), secret_key.clone(), &config),
+            market: Market::new_with_config(api_key.clone(), secret_key.clone(), &config),
+            user_stream: UserStream::new_with_config(api_key, secret_key, &config),
+            ws_endpoint,
+            quote_asset: "USDT".to_string(),
+        }
+    }
+
+    pub fn with_quote_asset(mut self, quote_asset: String) -> Self {
+        self.quote_asset = quote_asset;
+        self
+    }
+}
+
+#[async_trait]
+impl Exchange for BinanceExchange {
+    async fn submit_order(&self, order: &Order) -> Result<(), Error> {
+        self.account
+            .place_order(order_request(order))
+            .await
+            .map_err(binance_error)?;
+        Ok(())
+    }
+
+    async fn cancel_order(&self, symbol: &str, client_order_id: &str) -> Result<(), Error> {
+        self.account
+            .cancel_order(OrderCancellation {
+                symbol: symbol.to_string(),
+                orig_client_order_id: Some(client_order_id.to_string()),
+                ..OrderCancellation::default()
+            })
+            .await
+            .map_err(binance_error)?;
+        Ok(())
+    }
+
+    async fn open_orders(&self) -> Result<Vec<OpenOrder>, Error> {
+        let orders = self
+            .account
+            .get_all_open_orders()
+            .await
+            .map_err(binance_error)?;
+        let mut open_orders = Vec::new();
+        for order in &orders {
+            if let Some(open_order) = open_order(order)? {
+                open_orders.push(open_order);
+            }
+        }
+        Ok(open_orders)
+    }
+
+    async fn order_snapshot(
+        &self,
+        symbol: &str,
+        client_order_id: &str,
+    ) -> Result<OrderSnapshot, Error> {
+        let order = self
+            .account
+            .order_status(OrderStatusRequest {
+                symbol: symbol.to_string(),
+                orig_client_order_id: Some(client_order_id.to_string()),
+                ..OrderStatusRequest::default()
+            })
+            .await
+            .map_err(binance_error)?;
+        snapshot_from_order(&order)
+    }
+
+    // Binance keeps no PnL, so the day starts when asked
+    async fn account(&self) -> Result<AccountState, Error> {
+        let account = self.account.get_account().await.map_err(binance_error)?;
+        let Prices::AllPrices(prices) =
+            self.market.get_all_prices().await.map_err(binance_error)?;
+        let balances = account
+            .balances
+            .iter()
+            .map(|balance| {
+                let total = decimal(balance.free)?
+                    .checked_add(decimal(balance.locked)?)
+                    .ok_or_else(|| Error::decode("Balance overflows"))?;
+                Ok((balance.asset.clone(), total))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        let prices = prices
+            .iter()
+            .map(|price| Ok((price.symbol.clone(), decimal(price.price)?)))
+            .collect::<Result<HashMap<_, _>, Error>>()?;
+        let portfolio = portfolio_from_balances(&self.quote_asset, &balances, &prices, Utc::now())
+            .ok_or_else(|| Error::decode("Balances overflow"))?;
+        Ok(AccountState {
+            portfolio,
+            day_start_pnl: None,
+        })
+    }
+
+    async fn execution_reports(&self) -> Result<mpsc::UnboundedReceiver<ExecutionReport>, Error> {
+        let listen_key = self
+            .user_stream
+            .start()
+            .await
+            .map_err(binance_error)?
+            .listen_key;
+        let url = format!("{}/ws/{}", self.ws_endpoint, listen_key);
+        let (mut socket, _) = tokio_tungstenite::connect_async(url.as_str())
+            .await
+            .map_err(Error::backend)?;
+
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let user_stream = self.user_stream.clone();
+        tokio::spawn(async move {
+            let mut keep_alive = tokio::time::interval(LISTEN_KEY_KEEP_ALIVE);
+            // The first tick completes right away
+            keep_alive.tick().await;
+            loop {
+                tokio::select! {
+                    _ = sender.closed() => break,
+                    _ = keep_alive.tick() => {
+                        if let Err(err) = user_stream.keep_alive(&listen_key).await {
+                            error!("Cannot keep the user data stream alive: {}", err);
+                            break;
+                        }
+                    }
+                    message = socket.next() => match message {
+                        Some(Ok(Message::Text(text))) => {
+                            if !forward_report(&text, &sender) {
+                                break;
+                            }
+                        }
+                        Some(Ok(Message::Ping(payload))) => {
+                            socket.send(Message::Pong(payload)).await.ok();
+                        }
+                        Some(Ok(Message::Close(frame))) => {
+                            warn!("User data stream closed: {:?}", frame);
+                            break;
+                        }
+                        Some(Ok(_)) => {}
+                        Some(Err(err)) => {
+                            warn!("User data stream failed: {}", err);
+                            break;
+                        }
+                        None => break,
+                    }
+                }
+            }
+            user_stream.close(&listen_key).await.ok();
+        });
+        Ok(receiver)
+    }
+}
+
+// Returns false once nobody receives the reports anymore
+fn forward_report(text: &str, sender: &mpsc::UnboundedSender<ExecutionReport>) -> bool {
+    match serde_json::from_str::<WebsocketEvent>(text) {
+        Ok(WebsocketEvent::OrderUpdate(update)) => {
+            match execution_report_from_order_update(&update) {
+                Ok(report) => return sender.send(report).is_ok(),
+                Err(err) => warn!("Cannot convert execution report: {}", err),
+            }
+        }
+        // Balance and account updates are not tracked
+        Ok(_) => {}
+        Err(err) => warn!("Cannot parse user data stream message: {}", err),
+    }
+    true
+}
+
+fn binance_error(err: binance::errors::Error) -> Error {
+    use binance::errors::Error as BinanceError;
+    match err {
+        BinanceError::BinanceError { response } if response.code == NO_SUCH_ORDER => {
+            Error::NotFound(response.msg)
+        }
+        BinanceError::BinanceError { response } if !TRANSIENT_ERRORS.contains(&response.code) => {
+            Error::rejected(response)
+        }
+        err @ (BinanceError::InvalidPrice
+        | BinanceError::InvalidOrderError { .. }
+        | BinanceError::InvalidListenKey(_)
+        | BinanceError::Unauthorized) => Error::rejected(err),
+        BinanceError::Json(err) => Error::decode(err),
+        err => Error::backend(err),
+    }
+}
+
+fn timestamp(unix_ms: u64) -> DateTime<Utc> {
+    Utc.timestamp_millis_opt(unix_ms as i64).unwrap()
+}
+
+fn decimal(value: f64) -> Result<Decimal, Error> {
+    Decimal::try_from(value)
+}
+
+fn side(side: &OrderSide) -> Side {
+    match side {
+        OrderSide::Buy => Side::Buy,
+        OrderSide::Sell => Side::Sell,
+    }
+}
+
+fn order_status(status: &BinanceOrderStatus) -> OrderStatus {
+    match status {
+        BinanceOrderStatus::New | BinanceOrderStatus::PendingCancel => OrderStatus::New,
+        BinanceOrderStatus::PartiallyFilled | BinanceOrderStatus::Trade => {
+            OrderStatus::PartiallyFilled
+        }
+        BinanceOrderStatus::Filled => OrderStatus::Filled,
+        BinanceOrderStatus::Canceled => OrderStatus::Canceled,
+        BinanceOrderStatus::Rejected => OrderStatus::Rejected,
+        BinanceOrderStatus::Expired => OrderStatus::Expired,
+    }
+}
+
+fn order_request(order: &Order) -> OrderRequest {
+    let time_in_force = Some(match order.time_in_force {
+        TimeInForce::Gtc => BinanceTimeInForce::GTC,
+        TimeInForce::Ioc => BinanceTimeInForce::IOC,
+        TimeInForce::Fok => BinanceTimeInForce::FOK,
+    });
+    let (order_type, price, stop_price, time_in_force) = match order.order_type {
+        OrderType::Market => (BinanceOrderType::Market, None, None, None),
+        OrderType::Limit { price } => (BinanceOrderType::Limit, Some(price), None, time_in_force),
+        OrderType::Stop { stop_price } => {
+            (BinanceOrderType::StopLoss, None, Some(stop_price), None)
+        }
+        OrderType::StopLimit { stop_price, price } => (
+            BinanceOrderType::StopLossLimit,
+            Some(price),
+            Some(stop_price),
+            time_in_force,
+        ),
+    };
+    OrderRequest {
+        symbol: order.symbol.clone(),
+        side: match order.side {
+            Side::Buy => OrderSide::Buy,
+            Side::Sell => OrderSide::Sell,
+        },
+        order_type,
+        time_in_force,
+        quantity: Some(order.quantity.to_f64()),
+        price: price.map(|price| price.to_f64()),
+        new_client_order_id: Some(order.client_order_id.clone()),
+        stop_price: stop_price.map(|price| price.to_f64()),
+        // Only full responses parse for every order type
+        new_order_resp_type: Some(OrderResponse::Full),
+        ..OrderRequest::default()
+    }
+}
+
+// Fees are paid in the quote or the base asset, or in BNB. Fees in other assets than the traded
+// ones are not part of the PnL.
+fn fee_in_quote(symbol: &str, asset: Option<&str>, commission: Decimal, price: Decimal) -> Decimal {
+    match asset {
+        Some(asset) if !asset.is_empty() && symbol.ends_with(asset) => commission,
+        Some(asset) if !asset.is_empty() && symbol.starts_with(asset) => {
+            commission.checked_mul(price).unwrap_or(Decimal::ZERO)
+        }
+        _ => Decimal::ZERO,
+    }
+}
+
+pub fn execution_report_from_order_update(update: &OrderUpdate) -> Result<ExecutionReport, Error> {
+    // Cancels carry the id of the cancel request, the order's id is the original one
+    let client_order_id = match update.origin_client_id.as_deref() {
+        Some(original) if !original.is_empty() => original.to_string(),
+        _ => update.client_order_id.clone().unwrap_or_default(),
+    };
+    let side = side(&update.side);
+    let time = timestamp(update.event_time);
+
+    let fill = if update.execution_type == BinanceOrderStatus::Trade {
+        let price = decimal(update.last_executed_price)?;
+        Some(Fill {
+            client_order_id: client_order_id.clone(),
+            symbol: update.symbol.clone(),
+            side,
+            quantity: decimal(update.qty_last_executed)?,
+            price,
+            fee: fee_in_quote(
+                &update.symbol,
+                update.commission_asset.as_deref(),
+                decimal(update.commission)?,
+                price,
+            ),
+            time: timestamp(update.trade_order_time),
+        })
+    } else {
+        None
+    };
+
+    Ok(ExecutionReport {
+        client_order_id,
+        symbol: update.symbol.clone(),
+        side,
+        status: order_status(&update.current_order_status),
+        fill,
+        filled_quantity: decimal(update.cumulative_filled_qty)?,
+        filled_quote: decimal(update.cumulative_quote_asset_transacted_qty)?,
+        reason: Some(update.order_reject_reason.clone()).filter(|reason| reason != "NONE"),
+        time,
+    })
+}
+
+// Balances of other assets become positions in their pair with `quote_asset`. Their cost is
+// unknown, so they are valued at the current price. Assets without such a pair are left out.
+fn portfolio_from_balances(
+    quote_asset: &str,
+    balances: &[(String, Decimal)],
+    prices: &HashMap<String, Decimal>,
+    time: DateTime<Utc>,
+) -> Option<Portfolio> {
+    let mut cash = Decimal::ZERO;
+    let mut fills = Vec::new();
+    for (asset, quantity) in balances {
+        if asset == quote_asset {
+            cash = *quantity;
+            continue;
+        }
+        let symbol = format!("{}{}", asset, quote_asset);
+        if let (true, Some(price)) = (quantity.is_positive(), prices.get(&symbol)) {
+            fills.push(Fill {
+                client_order_id: String::new(),
+                symbol,
+                side: Side::Buy,
+                quantity: *quantity,
+                price: *price,
+                fee: Decimal::ZERO,
+                time,
+            });
+        }
+    }
+
+    // Bought from the cash, so the quote balance is left afterwards
+    let bought = fills
+        .iter()
+        .try_fold(Decimal::ZERO, |sum, fill| sum.checked_add(fill.notional()?))?;
+    let mut portfolio = Portfolio::new(cash.checked_add(bought)?);
+    for fill in &fills {
+        portfolio.apply_fill(fill)?;
+    }
+    Some(portfolio)
+}
+
+fn snapshot_from_order(order: &BinanceOrder) -> Result<OrderSnapshot, Error> {
+    Ok(OrderSnapshot {
+        client_order_id: order.client_order_id.clone(),
+        symbol: order.symbol.clone(),
+        status: order_status(&order.status),
+        filled_quantity: decimal(order.executed_qty)?,
+        filled_quote: decimal(order.cummulative_quote_qty)?,
+    })
+}
+
+// The inverse of `order_request`, orders of other types were not sent by the host
+fn open_order(order: &BinanceOrder) -> Result<Option<OpenOrder>, Error> {
+    let order_type = match order.order_type {
+        BinanceOrderType::Market => OrderType::Market,
+        BinanceOrderType::Limit => OrderType::Limit {
+            price: decimal(order.price)?,
+        },
+        BinanceOrderType::StopLoss => OrderType::Stop {
+            stop_price: decimal(order.stop_price)?,
+        },
+        BinanceOrderType::StopLossLimit => OrderType::StopLimit {
+            stop_price: decimal(order.stop_price)?,
+            price: decimal(order.price)?,
+        },
+        _ => return Ok(None),
+    };
+    let time_in_force = match order.time_in_force {
+        BinanceTimeInForce::IOC => TimeInForce::Ioc,
+        BinanceTimeInForce::FOK => TimeInForce::Fok,
+        _ => TimeInForce::Gtc,
+    };
+    Ok(Some(OpenOrder {
+        order: Order {
+            client_order_id: order.client_order_id.clone(),
+            symbol: order.symbol.clone(),
+            side: side(&order.side),
+            quantity: decimal(order.orig_qty)?,
+            order_type,
+            time_in_force,
+        },
+        snapshot: snapshot_from_order(order)?,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        binance_error, execution_report_from_order_update, open_order, order_request,
+        portfolio_from_balances, BinanceOrder,
+    };
+    use crate::exchange::OrderStatus;
+    use crate::test_data::dec;
+    use binance::rest_model::{OrderType as BinanceOrderType, TimeInForce as BinanceTimeInForce};
+    use binance::ws_model::WebsocketEvent;
+    use serde_json::json;
+    use trade_core::models::{
+        order::{Order, OrderType, TimeInForce},
+        trade::Side,
+    };
+    use trade_core::Error;
+
+    fn execution_report(
+        execution_type: &str,
+        status: &str,
+        client_order_id: &str,
+        original_client_order_id: &str,
+    ) -> serde_json::Value {
+        json!({
+            "e": "executionReport",
+            "E": 1_640_995_200_000u64,
+            "s": "BTCUSDT",
+            "c": client_order_id,
+            "S": "BUY",
+            "o": "LIMIT",
+            "f": "GTC",
+            "q": "1.00000000",
+            "p": "20000.00000000",
+            "P": "0.00000000",
+            "F": "0.00000000",
+            "g": -1,
+            "C": original_client_order_id,
+            "x": execution_type,
+            "X": status,
+            "r": "NONE",
+            "i": 4293153,
+            "l": "0.40000000",
+            "z": "0.40000000",
+            "L": "19999.50000000",
+            "n": "0.00040000",
+            "N": "BTC",
+            "T": 1_640_995_200_001u64,
+            "t": 12,
+            "I": 8641984,
+            "w": true,
+            "m": false,
+            "M": false,
+            "O": 1_640_995_199_000u64,
+            "Z": "7999.80000000",
+            "Y": "7999.80000000",
+            "Q": "0.00000000"
+        })
+    }
+
+    fn report(value: serde_json::Value) -> crate::exchange::ExecutionReport {
+        match serde_json::from_value::<WebsocketEvent>(value).unwrap() {
+            WebsocketEvent::OrderUpdate(update) => {
+                execution_report_from_order_update(&update).unwrap()
+            }
+            event => panic!("Unexpected event {:?}", event),
+        }
+    }
+
+    #[test]
+    fn trades_become_fills() {
+        let report = report(execution_report("TRADE", "PARTIALLY_FILLED", "node-1", ""));
+
+        assert_eq!("node-1", report.client_order_id);
+        assert_eq!(OrderStatus::PartiallyFilled, report.status);
+        assert_eq!(
+            (dec("0.4"), dec("7999.8")),
+            (report.filled_quantity, report.filled_quote)
+        );
+        assert_eq!(None, report.reason);
+        let fill = report.fill.unwrap();
+        assert_eq!((dec("0.4"), dec("19999.5")), (fill.quantity, fill.price));
+        // Paid in BTC, converted at the fill price
+        assert_eq!(dec("7.9998"), fill.fee);
+    }
+
+    #[test]
+    fn cancels_refer_to_the_original_order() {
+        let report = report(execution_report(
+            "CANCELED", "CANCELED", "cancel-7", "node-1",
+        ));
+
+        assert_eq!("node-1", report.client_order_id);
+        assert_eq!(OrderStatus::Canceled, report.status);
+        assert_eq!(None, report.fill);
+    }
+
+    #[test]
+    fn orders_map_to_requests() {
+        let order = Order {
+            order_type: OrderType::StopLimit {
+                stop_price: dec("19000"),
+                price: dec("18900.5"),
+            },
+            ..Order::market(
+                "node-1".to_string(),
+                "BTCUSDT".to_string(),
+                Side::Sell,
+                dec("0.25"),
+            )
+        }
+        .with_time_in_force(TimeInForce::Ioc);
+
+        let request = order_request(&order);
+        assert_eq!(BinanceOrderType::StopLossLimit, request.order_type);
+        assert_eq!(Some(BinanceTimeInForce::IOC), request.time_in_force);
+        assert_eq!(Some(0.25), request.quantity);
+        assert_eq!(Some(18900.5), request.price);
+        assert_eq!(Some(19000.0), request.stop_price);
+        assert_eq!(Some("node-1".to_string()), request.new_client_order_id);
+
+        let market = order_request(&Order::market(
+            "node-2".to_string(),
+            "BTCUSDT".to_string(),
+            Side::Buy,
+            dec("1"),
+        ));
+        assert_eq!(None, market.time_in_force);
+        assert_eq!(None, market.price);
+    }
+
+    fn binance_order(order_type: &str) -> BinanceOrder {
+        serde_json::from_value(json!({
+            "symbol": "BTCUSDT",
+            "orderId": 7,
+            "orderListId": -1,
+            "clientOrderId": "node:1",
+            "price": "18900.50000000",
+            "origQty": "0.25000000",
+            "executedQty": "0.10000000",
+            "cummulativeQuoteQty": "1890.05000000",
+            "status": "PARTIALLY_FILLED",
+            "timeInForce": "IOC",
+            "type": order_type,
+            "side": "SELL",
+            "stopPrice": "19000.00000000",
+            "icebergQty": "0.00000000",
+            "time": 1640995200000u64,
+            "updateTime": 1640995260000u64,
+            "isWorking": true,
+            "origQuoteOrderQty": "0.00000000"
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn open_orders_map_back_to_orders() {
+        let open = open_order(&binance_order("STOP_LOSS_LIMIT"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            Order {
+                order_type: OrderType::StopLimit {
+                    stop_price: dec("19000"),
+                    price: dec("18900.5"),
+                },
+                ..Order::market(
+                    "node:1".to_string(),
+                    "BTCUSDT".to_string(),
+                    Side::Sell,
+                    dec("0.25"),
+                )
+            }
+            .with_time_in_force(TimeInForce::Ioc),
+            open.order
+        );
+        assert_eq!(OrderStatus::PartiallyFilled, open.snapshot.status);
+        assert_eq!(dec("0.1"), open.snapshot.filled_quantity);
+
+        // Never sent by the host
+        assert_eq!(None, open_order(&binance_order("TAKE_PROFIT")).unwrap());
+    }
+
+    #[test]
+    fn balances_become_positions_at_the_current_price() {
+        let balances = vec![
+            ("USDT".to_string(), dec("500")),
+            ("BTC".to_string(), dec("0.5")),
+            ("ETH".to_string(), dec("0")),
+            // No pair with the quote asset
+            ("XYZ".to_string(), dec("3")),
+        ];
+        let prices = [("BTCUSDT", "20000"), ("ETHUSDT", "1500")]
+            .into_iter()
+            .map(|(symbol, price)| (symbol.to_string(), dec(price)))
+            .collect();
+
+        let portfolio =
+            portfolio_from_balances("USDT", &balances, &prices, chrono::Utc::now()).unwrap();
+        assert_eq!(dec("500"), portfolio.cash());
+        assert_eq!(dec("0.5"), portfolio.quantity("BTCUSDT"));
+        assert_eq!(1, portfolio.positions().count());
+        assert_eq!(Some(dec("0")), portfolio.net_pnl());
+    }
+
+    #[test]
+    fn refused_orders_are_not_retried() {
+        let error = |code: i32| {
+            binance_error(binance::errors::Error::BinanceError {
+                response: serde_json::from_value(json!({ "code": code, "msg": "refused" }))
+                    .unwrap(),
+            })
+        };
+
+        assert!(matches!(error(-2010), Error::Rejected(_)));
+        // Filter failure, e.g. a price off the tick size
+        assert!(matches!(error(-1013), Error::Rejected(_)));
+        assert!(matches!(error(-2013), Error::NotFound(_)));
+        // Timeout of the matching engine
+        assert!(error(-1007).is_transient());
+    }
+}
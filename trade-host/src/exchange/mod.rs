@@ -0,0 +1,105 @@
+pub mod binance;
+pub mod paper;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use trade_core::models::{
+    decimal::Decimal,
+    order::{Fill, Order},
+    portfolio::Portfolio,
+    trade::Side,
+};
+use trade_core::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum OrderStatus {
+    // Sent, but not acknowledged by the exchange yet
+    PendingNew,
+    New,
+    PartiallyFilled,
+    Filled,
+    Canceled,
+    Rejected,
+    Expired,
+}
+
+impl OrderStatus {
+    // Final orders never change again
+    pub fn is_final(&self) -> bool {
+        matches!(
+            self,
+            OrderStatus::Filled
+                | OrderStatus::Canceled
+                | OrderStatus::Rejected
+                | OrderStatus::Expired
+        )
+    }
+}
+
+// Change of an order as reported by the exchange
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExecutionReport {
+    pub client_order_id: String,
+    pub symbol: String,
+    pub side: Side,
+    pub status: OrderStatus,
+    // Set if the report is about an execution
+    pub fill: Option<Fill>,
+    // Cumulative over all executions of the order
+    pub filled_quantity: Decimal,
+    pub filled_quote: Decimal,
+    pub reason: Option<String>,
+    pub time: DateTime<Utc>,
+}
+
+// State of an order as queried from the exchange
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OrderSnapshot {
+    pub client_order_id: String,
+    pub symbol: String,
+    pub status: OrderStatus,
+    pub filled_quantity: Decimal,
+    pub filled_quote: Decimal,
+}
+
+// Order resting at the exchange, as sent to it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpenOrder {
+    pub order: Order,
+    pub snapshot: OrderSnapshot,
+}
+
+// Holdings at the exchange, the risk checks continue from them after a restart
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccountState {
+    pub portfolio: Portfolio,
+    // Net PnL of the portfolio at the start of the current UTC day, if the exchange knows it
+    pub day_start_pnl: Option<Decimal>,
+}
+
+// Venue orders of the host are executed at
+#[async_trait]
+pub trait Exchange: Send + Sync {
+    // Acknowledgement and executions arrive as execution reports
+    async fn submit_order(&self, order: &Order) -> Result<(), Error>;
+
+    async fn cancel_order(&self, symbol: &str, client_order_id: &str) -> Result<(), Error>;
+
+    // Orders of types the host never sends are left out
+    async fn open_orders(&self) -> Result<Vec<OpenOrder>, Error>;
+
+    // `Error::NotFound` for orders the exchange does not know
+    async fn order_snapshot(
+        &self,
+        symbol: &str,
+        client_order_id: &str,
+    ) -> Result<OrderSnapshot, Error>;
+
+    async fn account(&self) -> Result<AccountState, Error>;
+
+    // Reports from now on, the receiver closes when the connection drops. Reports missed in
+    // between have to be reconciled with `open_orders` and `order_snapshot`.
+    async fn execution_reports(&self) -> Result<mpsc::UnboundedReceiver<ExecutionReport>, Error>;
+}
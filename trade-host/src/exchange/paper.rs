@@ -0,0 +1,590 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, Mutex};
+use tracing::{error, info};
+use trade_core::backtest::matching::PendingOrder;
+use trade_core::models::{
+    candle::Candle,
+    decimal::Decimal,
+    interval::Interval,
+    order::{Fill, Order, TimeInForce},
+    portfolio::Portfolio,
+    trade::{Side, Trade},
+};
+use trade_core::Error;
+
+use super::{AccountState, Exchange, ExecutionReport, OpenOrder, OrderSnapshot, OrderStatus};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PaperState {
+    portfolio: Portfolio,
+    // Time of the latest market data, so replayed data runs on its own clock
+    clock: Option<DateTime<Utc>>,
+    open: Vec<PendingOrder>,
+    orders: HashMap<String, OrderSnapshot>,
+    // Net PnL at the start of the latest day on the clock
+    #[serde(default)]
+    day_start: Option<(NaiveDate, Decimal)>,
+}
+
+// Simulated spot exchange matching orders against candles and trades, without touching real
+// funds. Orders fill completely, like in a backtest:
+// - market orders at the next price, the close of a candle they arrived during
+// - limit orders once the market trades at their price
+// - stop orders at their stop price or the next price once that is reached
+// - stop limit orders rest as limit orders after their stop price traded
+// IOC and FOK orders expire if the first candle or trade they see does not fill them. Cash is
+// kept in a single quote asset, sells need an open position. Balances and orders are stored in
+// `path` after every change, so they survive restarts.
+pub struct PaperExchange {
+    path: PathBuf,
+    // Fraction of the notional, e.g. 0.001 for 0.1%
+    fee_rate: Decimal,
+    latency: Duration,
+    state: Mutex<PaperState>,
+    reports: Mutex<Option<mpsc::UnboundedSender<ExecutionReport>>>,
+}
+
+impl PaperExchange {
+    // Continues with the state stored in `path`, starts with `initial_cash` without one
+    pub async fn open(path: PathBuf, initial_cash: Decimal) -> Result<Self, Error> {
+        let state = match tokio::fs::read(&path).await {
+            Ok(bytes) => {
+                info!("Continuing paper trading from {}", path.display());
+                serde_json::from_slice(&bytes).map_err(Error::decode)?
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => PaperState {
+                portfolio: Portfolio::new(initial_cash),
+                clock: None,
+                open: Vec::new(),
+                orders: HashMap::new(),
+                day_start: None,
+            },
+            Err(err) => return Err(Error::backend(err)),
+        };
+        Ok(PaperExchange {
+            path,
+            fee_rate: Decimal::ZERO,
+            latency: Duration::zero(),
+            state: Mutex::new(state),
+            reports: Mutex::new(None),
+        })
+    }
+
+    pub fn with_fee_rate(mut self, fee_rate: Decimal) -> Self {
+        self.fee_rate = fee_rate;
+        self
+    }
+
+    // Orders take part in matching once `latency` passed on the clock of the market data
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    pub async fn portfolio(&self) -> Portfolio {
+        self.state.lock().await.portfolio.clone()
+    }
+
+    // `candle` closed, orders active before its close take part
+    pub async fn on_candle(&self, symbol: &str, interval: Interval, candle: &Candle) {
+        self.on_market_data(symbol, candle, candle.time + interval.duration())
+            .await;
+    }
+
+    pub async fn on_trade(&self, symbol: &str, trade: &Trade) {
+        let candle = Candle {
+            open: trade.price,
+            high: trade.price,
+            low: trade.price,
+            close: trade.price,
+            volume: trade.quantity,
+            time: trade.time,
+        };
+        self.on_market_data(symbol, &candle, trade.time).await;
+    }
+
+    async fn on_market_data(&self, symbol: &str, candle: &Candle, end: DateTime<Utc>) {
+        let mut state = self.state.lock().await;
+        state.clock = Some(state.clock.map_or(end, |clock| clock.max(end)));
+        let day = state.clock.unwrap_or(end).date_naive();
+        let new_day = state.day_start.map(|(start, _)| start) != Some(day);
+        if new_day {
+            let pnl = state.portfolio.net_pnl().unwrap_or(Decimal::ZERO);
+            state.day_start = Some((day, pnl));
+        }
+        state.portfolio.mark_candle(symbol, candle);
+
+        let mut reports = Vec::new();
+        let mut index = 0;
+        while index < state.open.len() {
+            let pending = &mut state.open[index];
+            if pending.order.symbol != symbol || pending.active_at > end {
+                index += 1;
+                continue;
+            }
+            // Arrived after the open, only the close is known to trade afterwards
+            let price = if pending.active_at > candle.time {
+                let close = Candle {
+                    open: candle.close,
+                    high: candle.close,
+                    low: candle.close,
+                    ..*candle
+                };
+                pending.fill_price(&close, unslipped)
+            } else {
+                pending.fill_price(candle, unslipped)
+            };
+            let gtc = pending.order.time_in_force == TimeInForce::Gtc;
+            if price.is_none() && gtc {
+                index += 1;
+                continue;
+            }
+
+            let pending = state.open.remove(index);
+            let report = match price {
+                Some(price) => self.execute(&mut state, pending.order, price, candle.time),
+                None => finish(
+                    &mut state,
+                    &pending.order,
+                    OrderStatus::Expired,
+                    candle.time,
+                ),
+            };
+            reports.push(report);
+        }
+
+        if new_day || !reports.is_empty() {
+            if let Err(err) = self.persist(&state).await {
+                error!("Cannot store paper trading state: {}", err);
+            }
+        }
+        drop(state);
+        for report in reports {
+            self.send(report).await;
+        }
+    }
+
+    fn execute(
+        &self,
+        state: &mut PaperState,
+        order: Order,
+        price: Decimal,
+        time: DateTime<Utc>,
+    ) -> ExecutionReport {
+        let fill = Fill {
+            client_order_id: order.client_order_id.clone(),
+            symbol: order.symbol.clone(),
+            side: order.side,
+            quantity: order.quantity,
+            price,
+            fee: Decimal::ZERO,
+            time,
+        };
+        let notional = fill.notional();
+        let fee = notional.and_then(|notional| notional.checked_mul(self.fee_rate));
+        let covered = match (order.side, notional, fee) {
+            (Side::Buy, Some(notional), Some(fee)) => notional
+                .checked_add(fee)
+                .is_some_and(|cost| state.portfolio.cash() >= cost),
+            (Side::Sell, Some(_), Some(_)) => {
+                state.portfolio.quantity(&order.symbol) >= order.quantity
+            }
+            _ => false,
+        };
+        let fill = Fill {
+            fee: fee.unwrap_or(Decimal::ZERO),
+            ..fill
+        };
+        if !covered || state.portfolio.apply_fill(&fill).is_none() {
+            let mut report = finish(state, &order, OrderStatus::Rejected, time);
+            report.reason = Some("Insufficient balance".to_string());
+            return report;
+        }
+
+        let snapshot = OrderSnapshot {
+            client_order_id: order.client_order_id.clone(),
+            symbol: order.symbol.clone(),
+            status: OrderStatus::Filled,
+            filled_quantity: fill.quantity,
+            filled_quote: notional.unwrap_or(Decimal::ZERO),
+        };
+        state
+            .orders
+            .insert(order.client_order_id.clone(), snapshot.clone());
+        ExecutionReport {
+            client_order_id: snapshot.client_order_id,
+            symbol: snapshot.symbol,
+            side: order.side,
+            status: snapshot.status,
+            fill: Some(fill),
+            filled_quantity: snapshot.filled_quantity,
+            filled_quote: snapshot.filled_quote,
+            reason: None,
+            time,
+        }
+    }
+
+    async fn persist(&self, state: &PaperState) -> Result<(), Error> {
+        let bytes = serde_json::to_vec_pretty(state).map_err(Error::decode)?;
+        // Replaced atomically, so a crash never leaves a truncated file
+        let temporary = self.path.with_extension("tmp");
+        tokio::fs::write(&temporary, bytes)
+            .await
+            .map_err(Error::backend)?;
+        tokio::fs::rename(&temporary, &self.path)
+            .await
+            .map_err(Error::backend)
+    }
+
+    async fn send(&self, report: ExecutionReport) {
+        if let Some(sender) = self.reports.lock().await.as_ref() {
+            // Missed reports are reconciled by the receiver
+            sender.send(report).ok();
+        }
+    }
+}
+
+// Without an order book there is nothing to slip through
+fn unslipped(_side: Side, price: Decimal) -> Option<Decimal> {
+    Some(price)
+}
+
+// Records the state of an order without executions
+fn finish(
+    state: &mut PaperState,
+    order: &Order,
+    status: OrderStatus,
+    time: DateTime<Utc>,
+) -> ExecutionReport {
+    state.orders.insert(
+        order.client_order_id.clone(),
+        OrderSnapshot {
+            client_order_id: order.client_order_id.clone(),
+            symbol: order.symbol.clone(),
+            status,
+            filled_quantity: Decimal::ZERO,
+            filled_quote: Decimal::ZERO,
+        },
+    );
+    ExecutionReport {
+        client_order_id: order.client_order_id.clone(),
+        symbol: order.symbol.clone(),
+        side: order.side,
+        status,
+        fill: None,
+        filled_quantity: Decimal::ZERO,
+        filled_quote: Decimal::ZERO,
+        reason: None,
+        time,
+    }
+}
+
+#[async_trait]
+impl Exchange for PaperExchange {
+    async fn submit_order(&self, order: &Order) -> Result<(), Error> {
+        let mut state = self.state.lock().await;
+        if state.orders.contains_key(&order.client_order_id) {
+            return Err(Error::backend(format!(
+                "Duplicate client order id {}",
+                order.client_order_id
+            )));
+        }
+        if !order.quantity.is_positive() {
+            return Err(Error::backend("Quantity is not positive"));
+        }
+
+        let now = state.clock.unwrap_or_else(Utc::now);
+        state
+            .open
+            .push(PendingOrder::new(order.clone(), now + self.latency));
+        let report = finish(&mut state, order, OrderStatus::New, now);
+        self.persist(&state).await?;
+        drop(state);
+        self.send(report).await;
+        Ok(())
+    }
+
+    async fn cancel_order(&self, _symbol: &str, client_order_id: &str) -> Result<(), Error> {
+        let mut state = self.state.lock().await;
+        let index = state
+            .open
+            .iter()
+            .position(|pending| pending.order.client_order_id == client_order_id)
+            .ok_or_else(|| Error::NotFound(client_order_id.to_string()))?;
+        let pending = state.open.remove(index);
+        let now = state.clock.unwrap_or_else(Utc::now);
+        let report = finish(&mut state, &pending.order, OrderStatus::Canceled, now);
+        self.persist(&state).await?;
+        drop(state);
+        self.send(report).await;
+        Ok(())
+    }
+
+    async fn open_orders(&self) -> Result<Vec<OpenOrder>, Error> {
+        let state = self.state.lock().await;
+        Ok(state
+            .open
+            .iter()
+            .filter_map(|pending| {
+                Some(OpenOrder {
+                    order: pending.order.clone(),
+                    snapshot: state.orders.get(&pending.order.client_order_id)?.clone(),
+                })
+            })
+            .collect())
+    }
+
+    async fn order_snapshot(
+        &self,
+        _symbol: &str,
+        client_order_id: &str,
+    ) -> Result<OrderSnapshot, Error> {
+        self.state
+            .lock()
+            .await
+            .orders
+            .get(client_order_id)
+            .cloned()
+            .ok_or_else(|| Error::NotFound(client_order_id.to_string()))
+    }
+
+    async fn account(&self) -> Result<AccountState, Error> {
+        let state = self.state.lock().await;
+        let today = state.clock.unwrap_or_else(Utc::now).date_naive();
+        Ok(AccountState {
+            portfolio: state.portfolio.clone(),
+            day_start_pnl: state
+                .day_start
+                .filter(|(day, _)| *day == today)
+                .map(|(_, pnl)| pnl),
+        })
+    }
+
+    async fn execution_reports(&self) -> Result<mpsc::UnboundedReceiver<ExecutionReport>, Error> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        *self.reports.lock().await = Some(sender);
+        Ok(receiver)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PaperExchange;
+    use crate::exchange::{Exchange, OrderStatus};
+    use crate::test_data::{dec, minute};
+    use chrono::Duration;
+    use trade_core::models::{
+        candle::Candle,
+        interval::Interval,
+        order::{Order, TimeInForce},
+        trade::{Side, Trade},
+    };
+
+    fn candle(minute_index: i64, open: &str, high: &str, low: &str, close: &str) -> Candle {
+        Candle {
+            open: dec(open),
+            high: dec(high),
+            low: dec(low),
+            close: dec(close),
+            volume: dec("10"),
+            time: minute(minute_index),
+        }
+    }
+
+    fn order(id: &str, side: Side, quantity: &str) -> Order {
+        Order::market(id.to_string(), "BTCUSDT".to_string(), side, dec(quantity))
+    }
+
+    async fn exchange(path: &std::path::Path) -> PaperExchange {
+        PaperExchange::open(path.join("paper.json"), dec("1000"))
+            .await
+            .unwrap()
+            .with_fee_rate(dec("0.001"))
+            .with_latency(Duration::seconds(30))
+    }
+
+    #[tokio::test]
+    async fn orders_match_against_later_candles() {
+        let directory = tempfile::tempdir().unwrap();
+        let exchange = exchange(directory.path()).await;
+        let mut reports = exchange.execution_reports().await.unwrap();
+        let candle_0 = candle(0, "100", "101", "99", "100");
+        exchange
+            .on_candle("BTCUSDT", Interval::OneMinute, &candle_0)
+            .await;
+
+        exchange
+            .submit_order(&order("a", Side::Buy, "2"))
+            .await
+            .unwrap();
+        let limit = Order::limit(
+            "b".to_string(),
+            "BTCUSDT".to_string(),
+            Side::Sell,
+            dec("1"),
+            dec("110"),
+        );
+        exchange.submit_order(&limit).await.unwrap();
+        assert_eq!(OrderStatus::New, reports.recv().await.unwrap().status);
+        assert_eq!(OrderStatus::New, reports.recv().await.unwrap().status);
+
+        // Arrived at 1:30, so the market order fills at the close
+        let candle_1 = candle(1, "102", "111", "101", "104");
+        exchange
+            .on_candle("BTCUSDT", Interval::OneMinute, &candle_1)
+            .await;
+        let filled = reports.recv().await.unwrap();
+        assert_eq!(OrderStatus::Filled, filled.status);
+        let fill = filled.fill.unwrap();
+        assert_eq!((dec("104"), dec("0.208")), (fill.price, fill.fee));
+        assert_eq!(dec("791.792"), exchange.portfolio().await.cash());
+        assert_eq!(1, exchange.open_orders().await.unwrap().len());
+
+        // Traded through the limit
+        exchange
+            .on_trade(
+                "BTCUSDT",
+                &Trade {
+                    id: 1,
+                    price: dec("111"),
+                    quantity: dec("1"),
+                    side: Side::Buy,
+                    time: minute(3),
+                },
+            )
+            .await;
+        let filled = reports.recv().await.unwrap();
+        assert_eq!(Some(dec("111")), filled.fill.map(|fill| fill.price));
+        assert_eq!(dec("1"), exchange.portfolio().await.quantity("BTCUSDT"));
+    }
+
+    #[tokio::test]
+    async fn latency_and_time_in_force_apply() {
+        let directory = tempfile::tempdir().unwrap();
+        let exchange = exchange(directory.path()).await;
+        let mut reports = exchange.execution_reports().await.unwrap();
+        let candle_0 = candle(0, "100", "101", "99", "100");
+        exchange
+            .on_candle("BTCUSDT", Interval::OneMinute, &candle_0)
+            .await;
+
+        let ioc = Order::limit(
+            "a".to_string(),
+            "BTCUSDT".to_string(),
+            Side::Buy,
+            dec("1"),
+            dec("90"),
+        )
+        .with_time_in_force(TimeInForce::Ioc);
+        exchange.submit_order(&ioc).await.unwrap();
+        reports.recv().await.unwrap();
+
+        // Traded at 85 before the order arrived at 1:30
+        let candle_1 = candle(1, "95", "96", "85", "92");
+        exchange
+            .on_candle("BTCUSDT", Interval::OneMinute, &candle_1)
+            .await;
+        assert_eq!(OrderStatus::Expired, reports.recv().await.unwrap().status);
+        assert_eq!(
+            OrderStatus::Expired,
+            exchange
+                .order_snapshot("BTCUSDT", "a")
+                .await
+                .unwrap()
+                .status
+        );
+    }
+
+    #[tokio::test]
+    async fn uncovered_orders_are_rejected() {
+        let directory = tempfile::tempdir().unwrap();
+        let exchange = exchange(directory.path()).await;
+        let mut reports = exchange.execution_reports().await.unwrap();
+        let candle_0 = candle(0, "100", "101", "99", "100");
+        exchange
+            .on_candle("BTCUSDT", Interval::OneMinute, &candle_0)
+            .await;
+
+        exchange
+            .submit_order(&order("a", Side::Sell, "1"))
+            .await
+            .unwrap();
+        exchange
+            .submit_order(&order("b", Side::Buy, "100"))
+            .await
+            .unwrap();
+        reports.recv().await.unwrap();
+        reports.recv().await.unwrap();
+        let candle_2 = candle(2, "100", "101", "99", "100");
+        exchange
+            .on_candle("BTCUSDT", Interval::OneMinute, &candle_2)
+            .await;
+
+        let rejected = reports.recv().await.unwrap();
+        assert_eq!(OrderStatus::Rejected, rejected.status);
+        assert!(rejected.reason.is_some());
+        assert_eq!(OrderStatus::Rejected, reports.recv().await.unwrap().status);
+        assert!(exchange
+            .submit_order(&order("a", Side::Buy, "1"))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn state_survives_restarts() {
+        let directory = tempfile::tempdir().unwrap();
+        {
+            let exchange = exchange(directory.path()).await;
+            let candle_0 = candle(0, "100", "101", "99", "100");
+            exchange
+                .on_candle("BTCUSDT", Interval::OneMinute, &candle_0)
+                .await;
+            exchange
+                .submit_order(&order("a", Side::Buy, "1"))
+                .await
+                .unwrap();
+            let candle_2 = candle(2, "100", "101", "99", "100");
+            exchange
+                .on_candle("BTCUSDT", Interval::OneMinute, &candle_2)
+                .await;
+            exchange
+                .submit_order(&order("b", Side::Sell, "1"))
+                .await
+                .unwrap();
+        }
+
+        let exchange = exchange(directory.path()).await;
+        assert_eq!(dec("1"), exchange.portfolio().await.quantity("BTCUSDT"));
+        assert_eq!(dec("899.9"), exchange.portfolio().await.cash());
+        let open = exchange.open_orders().await.unwrap();
+        assert_eq!(
+            vec!["b".to_string()],
+            open.into_iter()
+                .map(|open| open.snapshot.client_order_id)
+                .collect::<Vec<_>>()
+        );
+
+        // The risk checks continue from the PnL at the start of the day
+        let account = exchange.account().await.unwrap();
+        assert_eq!(exchange.portfolio().await, account.portfolio);
+        assert_eq!(Some(dec("0")), account.day_start_pnl);
+
+        exchange.cancel_order("BTCUSDT", "b").await.unwrap();
+        assert!(exchange.open_orders().await.unwrap().is_empty());
+        assert!(exchange.cancel_order("BTCUSDT", "b").await.is_err());
+
+        // Marked at 100 with the fee paid when the next day starts
+        let next_day = candle(24 * 60, "90", "90", "90", "90");
+        exchange
+            .on_candle("BTCUSDT", Interval::OneMinute, &next_day)
+            .await;
+        let account = exchange.account().await.unwrap();
+        assert_eq!(Some(dec("-0.1")), account.day_start_pnl);
+        assert_eq!(Some(dec("-10.1")), account.portfolio.net_pnl());
+    }
+}
@@ -0,0 +1,519 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use tokio::sync::{broadcast, Mutex, RwLock};
+use tracing::{error, info, warn};
+use trade_core::models::{
+    decimal::{Decimal, Rounding},
+    order::{Fill, Order, OrderType},
+    symbol::SymbolInfo,
+    trade::Side,
+};
+use trade_core::Error;
+use trade_protocol::packets::OrderRejection;
+
+use crate::exchange::{Exchange, ExecutionReport, OpenOrder, OrderSnapshot, OrderStatus};
+use crate::risk::RiskManager;
+
+// Between the node name and the client order id of the node in the ids sent to the exchange
+pub const ORDER_ID_SEPARATOR: char = ':';
+
+// Nodes choose their client order ids independently, so the exchange and the risk checks see
+// them qualified by the node name. Names never contain the separator, ids of nodes might.
+pub fn exchange_order_id(node: &str, client_order_id: &str) -> String {
+    format!("{}{}{}", node, ORDER_ID_SEPARATOR, client_order_id)
+}
+
+// Node name and client order id of the node, `None` for orders not sent for a node
+pub fn split_exchange_order_id(exchange_order_id: &str) -> Option<(&str, &str)> {
+    exchange_order_id.split_once(ORDER_ID_SEPARATOR)
+}
+
+// An order of a node and what the exchange reported about it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackedOrder {
+    // With the client order id of the node
+    pub order: Order,
+    pub node: String,
+    pub status: OrderStatus,
+    pub filled_quantity: Decimal,
+    pub filled_quote: Decimal,
+    pub reason: Option<String>,
+}
+
+impl TrackedOrder {
+    // Returns the fill the report adds, reports of known executions add none
+    fn apply(&mut self, report: &ExecutionReport) -> Option<Fill> {
+        let fill = report
+            .fill
+            .clone()
+            .filter(|_| report.filled_quantity > self.filled_quantity);
+        if report.filled_quantity >= self.filled_quantity {
+            self.filled_quantity = report.filled_quantity;
+            self.filled_quote = report.filled_quote;
+        }
+        if !self.status.is_final() {
+            self.status = report.status;
+        }
+        if report.reason.is_some() {
+            self.reason = report.reason.clone();
+        }
+        fill
+    }
+
+    // Executions missed while disconnected become one fill at their average price. Their fees
+    // are unknown.
+    fn reconcile(&mut self, snapshot: &OrderSnapshot, time: DateTime<Utc>) -> Option<Fill> {
+        let missed = snapshot.filled_quantity.checked_sub(self.filled_quantity)?;
+        let fill = if missed.is_positive() {
+            let price = snapshot
+                .filled_quote
+                .checked_sub(self.filled_quote)?
+                .checked_div_precise(missed)?;
+            self.filled_quantity = snapshot.filled_quantity;
+            self.filled_quote = snapshot.filled_quote;
+            Some(Fill {
+                client_order_id: snapshot.client_order_id.clone(),
+                symbol: self.order.symbol.clone(),
+                side: self.order.side,
+                quantity: missed,
+                price,
+                fee: Decimal::ZERO,
+                time,
+            })
+        } else {
+            None
+        };
+        self.status = snapshot.status;
+        fill
+    }
+}
+
+// Routes node orders through the risk checks to an exchange and tracks their lifecycle from the
+// execution reports of the exchange. Fills update the risk manager, which stops counting orders
+// towards its limits once they finished. Finished orders are forgotten, open ones are taken over
+// from the exchange after a restart.
+pub struct Execution {
+    exchange: Arc<dyn Exchange>,
+    risk: Arc<RiskManager>,
+    // Unfinished orders by exchange order id
+    orders: Mutex<HashMap<String, TrackedOrder>>,
+    // Tick and lot sizes orders are rounded to, by symbol. Orders are refused until known.
+    symbols: RwLock<Option<HashMap<String, SymbolInfo>>>,
+    // Fills by node, with the client order ids of the node
+    fill_sender: broadcast::Sender<(String, Fill)>,
+    reconnect_delay: Duration,
+    max_reconnect_delay: Duration,
+}
+
+impl Execution {
+    pub fn new(exchange: Arc<dyn Exchange>, risk: Arc<RiskManager>) -> Self {
+        let (fill_sender, _) = broadcast::channel(1024);
+        Execution {
+            exchange,
+            risk,
+            orders: Mutex::new(HashMap::new()),
+            symbols: RwLock::new(None),
+            fill_sender,
+            reconnect_delay: Duration::from_secs(1),
+            max_reconnect_delay: Duration::from_secs(60),
+        }
+    }
+
+    // Orders of other symbols are rejected
+    pub fn with_symbols(mut self, symbols: Vec<SymbolInfo>) -> Self {
+        *self.symbols.get_mut() = Some(index_symbols(symbols));
+        self
+    }
+
+    pub async fn set_symbols(&self, symbols: Vec<SymbolInfo>) {
+        *self.symbols.write().await = Some(index_symbols(symbols));
+    }
+
+    pub fn with_reconnect_delay(mut self, initial: Duration, max: Duration) -> Self {
+        self.reconnect_delay = initial;
+        self.max_reconnect_delay = max;
+        self
+    }
+
+    pub fn subscribe_fills(&self) -> broadcast::Receiver<(String, Fill)> {
+        self.fill_sender.subscribe()
+    }
+
+    // `None` once the order finished
+    pub async fn order(&self, node: &str, client_order_id: &str) -> Option<TrackedOrder> {
+        self.orders
+            .lock()
+            .await
+            .get(&exchange_order_id(node, client_order_id))
+            .cloned()
+    }
+
+    // Prices and quantities are rounded to the tick and lot size first, never to the node's
+    // disadvantage
+    pub async fn submit(
+        &self,
+        node_id: usize,
+        node: &str,
+        order: Order,
+        now: DateTime<Utc>,
+    ) -> Result<(), OrderRejection> {
+        let order = match self.symbols.read().await.as_ref() {
+            Some(symbols) => match symbols.get(&order.symbol) {
+                Some(info) => round_order(order, info)?,
+                None => {
+                    return Err(OrderRejection::UnknownSymbol {
+                        symbol: order.symbol,
+                    })
+                }
+            },
+            None => return Err(OrderRejection::Starting),
+        };
+        let client_order_id = exchange_order_id(node, &order.client_order_id);
+        let exchange_order = Order {
+            client_order_id: client_order_id.clone(),
+            ..order.clone()
+        };
+        {
+            // Held during the risk check, so ids cannot be used twice concurrently
+            let mut orders = self.orders.lock().await;
+            if orders.contains_key(&client_order_id) {
+                return Err(OrderRejection::DuplicateOrderId);
+            }
+            self.risk.check(node_id, &exchange_order, now).await?;
+            orders.insert(
+                client_order_id.clone(),
+                TrackedOrder {
+                    order,
+                    node: node.to_string(),
+                    status: OrderStatus::PendingNew,
+                    filled_quantity: Decimal::ZERO,
+                    filled_quote: Decimal::ZERO,
+                    reason: None,
+                },
+            );
+        }
+
+        match self.exchange.submit_order(&exchange_order).await {
+            Ok(()) => Ok(()),
+            Err(err) if err.is_transient() => {
+                // E.g. a timeout, the order might have reached the exchange nevertheless
+                warn!("Submitting order {} failed: {}", client_order_id, err);
+                self.settle_submission(&exchange_order, now, err.to_string())
+                    .await
+            }
+            Err(err) => {
+                self.reject(&client_order_id, err.to_string()).await;
+                Err(OrderRejection::Exchange {
+                    reason: err.to_string(),
+                })
+            }
+        }
+    }
+
+    // Asks the exchange what became of an order whose submission failed. Unless the exchange
+    // answers, the order stays pending until the next reconciliation.
+    async fn settle_submission(
+        &self,
+        order: &Order,
+        now: DateTime<Utc>,
+        reason: String,
+    ) -> Result<(), OrderRejection> {
+        let client_order_id = &order.client_order_id;
+        match self
+            .exchange
+            .order_snapshot(&order.symbol, client_order_id)
+            .await
+        {
+            Ok(snapshot) => {
+                self.apply_snapshots(&[snapshot], now).await;
+                Ok(())
+            }
+            Err(Error::NotFound(_)) => {
+                self.reject(client_order_id, reason.clone()).await;
+                Err(OrderRejection::Exchange { reason })
+            }
+            Err(err) => {
+                warn!(
+                    "Order {} is pending until the next reconciliation: {}",
+                    client_order_id, err
+                );
+                Ok(())
+            }
+        }
+    }
+
+    async fn reject(&self, client_order_id: &str, reason: String) {
+        if let Some(mut tracked) = self.orders.lock().await.remove(client_order_id) {
+            tracked.status = OrderStatus::Rejected;
+            tracked.reason = Some(reason);
+            log_status(&tracked);
+        }
+        self.risk.release(client_order_id).await;
+    }
+
+    // Returns false for unknown or finished orders of the node
+    pub async fn cancel(&self, node: &str, client_order_id: &str) -> Result<bool, Error> {
+        let client_order_id = exchange_order_id(node, client_order_id);
+        let symbol = match self.orders.lock().await.get(&client_order_id) {
+            Some(tracked) => tracked.order.symbol.clone(),
+            None => return Ok(false),
+        };
+        // The cancel itself is reported by the exchange
+        self.exchange
+            .cancel_order(&symbol, &client_order_id)
+            .await?;
+        Ok(true)
+    }
+
+    pub async fn handle_report(&self, report: ExecutionReport) {
+        let (fill, finished) = {
+            let mut orders = self.orders.lock().await;
+            let tracked = match orders.get_mut(&report.client_order_id) {
+                Some(tracked) => tracked,
+                None => {
+                    warn!(
+                        "Execution report of unknown order {} ({:?})",
+                        report.client_order_id, report.status
+                    );
+                    return;
+                }
+            };
+            let previous = tracked.status;
+            let fill = tracked.apply(&report);
+            if tracked.status != previous {
+                log_status(tracked);
+            }
+            let finished = tracked.status.is_final();
+            if finished {
+                orders.remove(&report.client_order_id);
+            }
+            (fill, finished)
+        };
+        if let Some(fill) = fill {
+            self.book_fill(fill).await;
+        }
+        // After the fill, so the quantity is counted by either the position or the open order
+        if finished {
+            self.risk.release(&report.client_order_id).await;
+        }
+    }
+
+    // Catches up with everything missed while no execution reports arrived
+    pub async fn reconcile(&self, now: DateTime<Utc>) -> Result<(), Error> {
+        let open_orders = self.exchange.open_orders().await?;
+        self.restore(&open_orders).await;
+        let open_ids: HashSet<&str> = open_orders
+            .iter()
+            .map(|open| open.snapshot.client_order_id.as_str())
+            .collect();
+
+        let mut snapshots = Vec::new();
+        let unsettled: Vec<(String, String)> = self
+            .orders
+            .lock()
+            .await
+            .iter()
+            .filter(|(client_order_id, _)| !open_ids.contains(client_order_id.as_str()))
+            .map(|(client_order_id, tracked)| {
+                (tracked.order.symbol.clone(), client_order_id.clone())
+            })
+            .collect();
+        // No longer open, so they finished while disconnected
+        for (symbol, client_order_id) in unsettled {
+            match self
+                .exchange
+                .order_snapshot(&symbol, &client_order_id)
+                .await
+            {
+                Ok(snapshot) => snapshots.push(snapshot),
+                // E.g. submitted right now and not known to the exchange yet
+                Err(Error::NotFound(_)) => {
+                    warn!("Order {} is unknown to the exchange", client_order_id)
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        snapshots.extend(open_orders.into_iter().map(|open| open.snapshot));
+        self.apply_snapshots(&snapshots, now).await;
+        Ok(())
+    }
+
+    // Tracks the open orders of nodes again, e.g. after a restart. Their executions so far are
+    // part of the account already.
+    async fn restore(&self, open_orders: &[OpenOrder]) {
+        let mut orders = self.orders.lock().await;
+        for OpenOrder { order, snapshot } in open_orders {
+            if orders.contains_key(&snapshot.client_order_id) {
+                continue;
+            }
+            let (node, client_order_id) = match split_exchange_order_id(&snapshot.client_order_id) {
+                Some(ids) => ids,
+                // Reported as not tracked
+                None => continue,
+            };
+            let remaining = order
+                .quantity
+                .checked_sub(snapshot.filled_quantity)
+                .unwrap_or(Decimal::ZERO);
+            self.risk.restore(order, remaining).await;
+            let tracked = TrackedOrder {
+                order: Order {
+                    client_order_id: client_order_id.to_string(),
+                    ..order.clone()
+                },
+                node: node.to_string(),
+                status: snapshot.status,
+                filled_quantity: snapshot.filled_quantity,
+                filled_quote: snapshot.filled_quote,
+                reason: None,
+            };
+            info!(
+                "Restored order {} of node {}, filled {} of {}",
+                client_order_id, node, tracked.filled_quantity, order.quantity
+            );
+            orders.insert(snapshot.client_order_id.clone(), tracked);
+        }
+    }
+
+    async fn apply_snapshots(&self, snapshots: &[OrderSnapshot], now: DateTime<Utc>) {
+        let mut fills = Vec::new();
+        let mut finished = Vec::new();
+        {
+            let mut orders = self.orders.lock().await;
+            for snapshot in snapshots {
+                match orders.get_mut(&snapshot.client_order_id) {
+                    Some(tracked) => {
+                        let previous = tracked.status;
+                        fills.extend(tracked.reconcile(snapshot, now));
+                        if tracked.status != previous {
+                            log_status(tracked);
+                        }
+                        if tracked.status.is_final() {
+                            orders.remove(&snapshot.client_order_id);
+                            finished.push(snapshot.client_order_id.clone());
+                        }
+                    }
+                    None => warn!(
+                        "Open order {} of {} is not tracked",
+                        snapshot.client_order_id, snapshot.symbol
+                    ),
+                }
+            }
+        }
+        for fill in fills {
+            warn!(
+                "Order {} was filled by {} at {} while disconnected",
+                fill.client_order_id, fill.quantity, fill.price
+            );
+            self.book_fill(fill).await;
+        }
+        for client_order_id in finished {
+            self.risk.release(&client_order_id).await;
+        }
+    }
+
+    // Follows the execution reports, reconnecting and reconciling whenever they stop
+    pub async fn run(&self) {
+        let mut reconnect_delay = self.reconnect_delay;
+        loop {
+            match self.exchange.execution_reports().await {
+                Ok(mut reports) => {
+                    info!("Following execution reports");
+                    match self.reconcile(Utc::now()).await {
+                        Ok(()) => reconnect_delay = self.reconnect_delay,
+                        Err(err) => error!("Cannot reconcile orders: {}", err),
+                    }
+                    while let Some(report) = reports.recv().await {
+                        self.handle_report(report).await;
+                    }
+                    warn!("Execution reports ended");
+                }
+                Err(err) => error!("Cannot follow execution reports: {}", err),
+            }
+
+            tokio::time::sleep(reconnect_delay).await;
+            reconnect_delay = (reconnect_delay * 2).min(self.max_reconnect_delay);
+        }
+    }
+
+    async fn book_fill(&self, fill: Fill) {
+        self.risk.apply_fill(&fill).await;
+        if let Some((node, client_order_id)) = split_exchange_order_id(&fill.client_order_id) {
+            let fill = Fill {
+                client_order_id: client_order_id.to_string(),
+                ..fill.clone()
+            };
+            // Nobody might be listening
+            self.fill_sender.send((node.to_string(), fill)).ok();
+        }
+    }
+}
+
+fn index_symbols(symbols: Vec<SymbolInfo>) -> HashMap<String, SymbolInfo> {
+    symbols
+        .into_iter()
+        .map(|info| (info.symbol.clone(), info))
+        .collect()
+}
+
+fn round_order(order: Order, info: &SymbolInfo) -> Result<Order, OrderRejection> {
+    if !order.quantity.is_positive() {
+        return Err(OrderRejection::InvalidQuantity);
+    }
+    let quantity = info
+        .round_quantity(order.quantity, Rounding::Floor)
+        .ok_or(OrderRejection::InvalidQuantity)?;
+    if quantity < info.min_quantity || !quantity.is_positive() {
+        return Err(OrderRejection::BelowMinQuantity {
+            quantity,
+            min_quantity: info.min_quantity,
+        });
+    }
+
+    // Limits never buy higher or sell lower than asked
+    let limit_rounding = match order.side {
+        Side::Buy => Rounding::Floor,
+        Side::Sell => Rounding::Ceil,
+    };
+    let round = |price: Decimal, rounding: Rounding| {
+        info.round_price(price, rounding)
+            .ok_or(OrderRejection::InvalidQuantity)
+    };
+    let order_type = match order.order_type {
+        OrderType::Market => OrderType::Market,
+        OrderType::Limit { price } => OrderType::Limit {
+            price: round(price, limit_rounding)?,
+        },
+        OrderType::Stop { stop_price } => OrderType::Stop {
+            stop_price: round(stop_price, Rounding::Nearest)?,
+        },
+        OrderType::StopLimit { stop_price, price } => OrderType::StopLimit {
+            stop_price: round(stop_price, Rounding::Nearest)?,
+            price: round(price, limit_rounding)?,
+        },
+    };
+    Ok(Order {
+        quantity,
+        order_type,
+        ..order
+    })
+}
+
+fn log_status(tracked: &TrackedOrder) {
+    let id = &tracked.order.client_order_id;
+    match tracked.status {
+        OrderStatus::Rejected => warn!(
+            "Order {} of node {} rejected: {}",
+            id,
+            tracked.node,
+            tracked.reason.as_deref().unwrap_or("no reason given")
+        ),
+        status => info!(
+            "Order {} of node {} is {:?}, filled {} of {}",
+            id, tracked.node, status, tracked.filled_quantity, tracked.order.quantity
+        ),
+    }
+}
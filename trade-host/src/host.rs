@@ -9,23 +9,42 @@ use std::{
     time::Duration,
 };
 use tokio::sync::broadcast::{self, Receiver, Sender};
+use trade_core::data::database::{
+    disk::DiskStockDataCache,
+    in_memory::InMemoryStockDataCache,
+    influx::{InfluxLineWriter, InfluxStockDataCache, InfluxWriteMetrics, InfluxWriteOptions},
+    tiered::{TieredStockDataCache, WriteBehindMetrics},
+    SharedStockDataCache,
+};
 
 use tracing::{error, info, info_span, trace, warn};
 use tracing_futures::Instrument;
 
 use crate::services::{
-    binance::BinanceService, certificate_check::CertificateCheckService, k8s::KubernetesService,
-    recoverer::RecovererService, start_service, trade_protocol::TradeProtocolService, Service,
+    backfill::BackfillService, binance::BinanceService, certificate_check::CertificateCheckService,
+    execution::ExecutionService, k8s::KubernetesService, kline_stream::KlineStreamService,
+    recoverer::RecovererService, risk::RiskService, start_service,
+    trade_protocol::TradeProtocolService, Service,
 };
 
 pub struct Host {
     pub config: HostConfig,
+    pub stock_data_cache: SharedStockDataCache,
+    // Only available when writing to InfluxDB
+    pub influx_metrics: Option<Arc<InfluxWriteMetrics>>,
+    // Writes from memory to InfluxDB
+    pub write_behind_metrics: Option<Arc<WriteBehindMetrics>>,
 }
 
 impl Host {
     pub fn new(config: HostConfig) -> Arc<Host> {
+        let (stock_data_cache, influx_metrics, write_behind_metrics) =
+            create_stock_data_cache(&config);
         Arc::new(Host {
-            config: config.clone(),
+            stock_data_cache,
+            influx_metrics,
+            write_behind_metrics,
+            config,
         })
     }
 
@@ -40,23 +59,58 @@ impl Host {
         let this = Arc::clone(&self);
 
         let cert_service = try_init::<CertificateCheckService>(Arc::clone(&this), ()).await;
-        let binance_service = try_init::<BinanceService>(Arc::clone(&this), (None, None)).await;
+        let binance_service = try_init::<BinanceService>(
+            Arc::clone(&this),
+            (
+                this.config.binance_api_key.clone(),
+                this.config.binance_secret_key.clone(),
+            ),
+        )
+        .await;
+        let backfill_service =
+            try_init::<BackfillService>(Arc::clone(&this), Arc::clone(&binance_service)).await;
+        let kline_stream_service = try_init::<KlineStreamService>(
+            Arc::clone(&this),
+            (Arc::clone(&binance_service), Arc::clone(&backfill_service)),
+        )
+        .await;
+        let risk_service =
+            try_init::<RiskService>(Arc::clone(&this), Arc::clone(&kline_stream_service)).await;
+        let execution_service = try_init::<ExecutionService>(
+            Arc::clone(&this),
+            (
+                Arc::clone(&risk_service),
+                Arc::clone(&kline_stream_service),
+                Arc::clone(&binance_service),
+            ),
+        )
+        .await;
+
         let protocol_service = try_init::<TradeProtocolService>(
             Arc::clone(&this),
-            (Arc::clone(&binance_service), Arc::clone(&cert_service)),
+            (
+                Arc::clone(&binance_service),
+                Arc::clone(&cert_service),
+                Arc::clone(&kline_stream_service),
+                Arc::clone(&execution_service),
+            ),
         )
         .await;
 
         let recoverer_service = try_init::<RecovererService>(Arc::clone(&this), ()).await;
         let k8s_service = try_init::<KubernetesService>(Arc::clone(&this), ()).await;
 
-        let mut services = Vec::with_capacity(5);
-
-        services.push(start_service_guarded(protocol_service, &shutdown_sender));
-        services.push(start_service_guarded(recoverer_service, &shutdown_sender));
-        services.push(start_service_guarded(binance_service, &shutdown_sender));
-        services.push(start_service_guarded(cert_service, &shutdown_sender));
-        services.push(start_service_guarded(k8s_service, &shutdown_sender));
+        let services = vec![
+            start_service_guarded(protocol_service, &shutdown_sender),
+            start_service_guarded(recoverer_service, &shutdown_sender),
+            start_service_guarded(binance_service, &shutdown_sender),
+            start_service_guarded(backfill_service, &shutdown_sender),
+            start_service_guarded(kline_stream_service, &shutdown_sender),
+            start_service_guarded(risk_service, &shutdown_sender),
+            start_service_guarded(execution_service, &shutdown_sender),
+            start_service_guarded(cert_service, &shutdown_sender),
+            start_service_guarded(k8s_service, &shutdown_sender),
+        ];
 
         shutdown_recv
             .recv()
@@ -97,6 +151,55 @@ impl Host {
     }
 }
 
+fn create_stock_data_cache(
+    config: &HostConfig,
+) -> (
+    SharedStockDataCache,
+    Option<Arc<InfluxWriteMetrics>>,
+    Option<Arc<WriteBehindMetrics>>,
+) {
+    match config.influx_url {
+        Some(ref influx_url) => {
+            let mut client = influxdb::Client::new(influx_url, &config.influx_database);
+            let mut writer = InfluxLineWriter::new(influx_url, &config.influx_database)
+                .with_options(InfluxWriteOptions {
+                    batch_size: config.influx_batch_size,
+                    max_retries: config.influx_write_retries,
+                    max_in_flight: config.influx_max_in_flight,
+                    ..InfluxWriteOptions::default()
+                });
+            if let (Some(username), Some(password)) =
+                (&config.influx_username, &config.influx_password)
+            {
+                client = client.with_auth(username, password);
+                writer = writer.with_auth(username, password);
+            }
+            info!("Using InfluxDB at {}", influx_url);
+            let cache = InfluxStockDataCache::new(client, writer);
+            let metrics = cache.metrics();
+            let memory =
+                InMemoryStockDataCache::new().with_capacity_limit(config.memory_cache_capacity);
+            let cache = TieredStockDataCache::new(memory, Arc::new(cache));
+            let write_behind_metrics = cache.metrics();
+            (Arc::new(cache), Some(metrics), Some(write_behind_metrics))
+        }
+        None => match DiskStockDataCache::open(&config.candle_path) {
+            Ok(cache) => {
+                info!("Storing candles in {}", config.candle_path.display());
+                (Arc::new(cache), None, None)
+            }
+            Err(err) => {
+                warn!(
+                    "Cannot store candles in {} ({}), keeping them only in memory",
+                    config.candle_path.display(),
+                    err
+                );
+                (Arc::new(InMemoryStockDataCache::new()), None, None)
+            }
+        },
+    }
+}
+
 async fn ensure_directory<P: AsRef<Path> + Clone>(directory: P) {
     if !tokio::fs::metadata(directory.clone()).await.is_ok() {
         match tokio::fs::create_dir_all(&directory).await {
@@ -189,7 +292,7 @@ fn start_service_guarded<S: 'static + Service>(
 
     tokio::spawn(async move {
         start_service(service, &cloned_shutdown_sender, cloned_status_sender)
-            .expect(&format!("Failed to start service {}", service_name));
+            .unwrap_or_else(|_| panic!("Failed to start service {}", service_name));
     });
 
     ServiceHandle {
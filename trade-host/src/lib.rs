@@ -1,3 +1,24 @@
+pub mod authority;
 pub mod config;
+pub mod enrollment;
+pub mod exchange;
+pub mod execution;
 pub mod host;
+pub mod rate_limiter;
+pub mod risk;
 pub mod services;
+
+#[cfg(test)]
+pub(crate) mod test_data {
+    use chrono::{DateTime, Duration, TimeZone, Utc};
+    use trade_core::models::decimal::Decimal;
+
+    pub fn dec(value: &str) -> Decimal {
+        value.parse().unwrap()
+    }
+
+    // Start of minute `minute` since 2022-01-01
+    pub fn minute(minute: i64) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2022, 1, 1, 0, 0, 0).unwrap() + Duration::minutes(minute)
+    }
+}
@@ -0,0 +1,45 @@
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+// Spaces out requests evenly so that at most `requests_per_minute` are issued per minute
+pub struct RateLimiter {
+    spacing: Duration,
+    next_slot: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(requests_per_minute: u32) -> Self {
+        RateLimiter {
+            spacing: Duration::from_secs(60) / requests_per_minute.max(1),
+            next_slot: Mutex::new(Instant::now()),
+        }
+    }
+
+    // Waits until the next request may be issued
+    pub async fn acquire(&self) {
+        let mut next_slot = self.next_slot.lock().await;
+        let now = Instant::now();
+        if *next_slot > now {
+            tokio::time::sleep_until(*next_slot).await;
+        }
+        *next_slot = (*next_slot).max(now) + self.spacing;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RateLimiter;
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    #[tokio::test(start_paused = true)]
+    async fn requests_are_spaced() {
+        let rate_limiter = RateLimiter::new(60);
+        let start = Instant::now();
+        for _ in 0..3 {
+            rate_limiter.acquire().await;
+        }
+        assert_eq!(Duration::from_secs(2), start.elapsed());
+    }
+}
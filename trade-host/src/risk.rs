@@ -0,0 +1,599 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use tokio::sync::Mutex;
+use trade_core::models::{
+    candle::Candle,
+    decimal::Decimal,
+    order::{Fill, Order},
+    portfolio::Portfolio,
+    trade::Side,
+};
+use trade_protocol::packets::OrderRejection;
+
+use crate::config::HostConfig;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RiskLimits {
+    // Per symbol, in the quote asset
+    pub max_position_notional: Decimal,
+    pub max_order_notional: Decimal,
+    // Net PnL that may be lost since the start of the UTC day
+    pub daily_loss_limit: Decimal,
+    // Per node
+    pub max_orders_per_minute: u32,
+    // Allowed deviation of limit and stop prices from the last close
+    pub price_band_bps: u32,
+}
+
+impl From<&HostConfig> for RiskLimits {
+    fn from(config: &HostConfig) -> Self {
+        RiskLimits {
+            max_position_notional: config.risk_max_position_notional,
+            max_order_notional: config.risk_max_order_notional,
+            daily_loss_limit: config.risk_daily_loss_limit,
+            max_orders_per_minute: config.risk_max_orders_per_minute,
+            price_band_bps: config.risk_price_band_bps,
+        }
+    }
+}
+
+struct RiskState {
+    // Only the PnL of the portfolio is used, so it starts without cash
+    portfolio: Portfolio,
+    // Last close per symbol
+    prices: HashMap<String, Decimal>,
+    day: NaiveDate,
+    day_start_pnl: Decimal,
+    // Recent order times per node
+    orders: HashMap<usize, VecDeque<DateTime<Utc>>>,
+    // Accepted orders that may still fill, by client order id
+    open_orders: HashMap<String, OpenOrder>,
+}
+
+struct OpenOrder {
+    symbol: String,
+    side: Side,
+    // Not filled yet
+    remaining: Decimal,
+}
+
+// Pre-trade checks every order of a node has to pass before it reaches an exchange.
+//
+// Orders are valued at their limit or stop price, market orders at the last close. Once the daily
+// loss limit is reached only orders reducing a position pass, the position limit likewise does not
+// stop orders reducing a position. Accepted orders count as if filled on their side until they are
+// released, so neither limit can be bypassed with several orders in flight.
+pub struct RiskManager {
+    limits: RiskLimits,
+    kill_switch: AtomicBool,
+    state: Mutex<RiskState>,
+}
+
+impl RiskManager {
+    pub fn new(limits: RiskLimits) -> Self {
+        RiskManager {
+            limits,
+            kill_switch: AtomicBool::new(false),
+            state: Mutex::new(RiskState {
+                portfolio: Portfolio::new(Decimal::ZERO),
+                prices: HashMap::new(),
+                day: NaiveDate::MIN,
+                day_start_pnl: Decimal::ZERO,
+                orders: HashMap::new(),
+                open_orders: HashMap::new(),
+            }),
+        }
+    }
+
+    pub fn limits(&self) -> &RiskLimits {
+        &self.limits
+    }
+
+    // Rejects every order while engaged
+    pub fn set_kill_switch(&self, engaged: bool) {
+        self.kill_switch.store(engaged, Ordering::SeqCst);
+    }
+
+    pub fn kill_switch_engaged(&self) -> bool {
+        self.kill_switch.load(Ordering::SeqCst)
+    }
+
+    // Marks the positions to the close of the candle and uses it as reference price
+    pub async fn update_price(&self, symbol: &str, candle: &Candle) {
+        let mut state = self.state.lock().await;
+        state.prices.insert(symbol.to_string(), candle.close);
+        state.portfolio.mark_candle(symbol, candle);
+    }
+
+    // Continues from the holdings at the exchange after a restart. Without `day_start_pnl` the
+    // losses of the day before the restart are unknown and counting starts now.
+    pub async fn seed(
+        &self,
+        portfolio: Portfolio,
+        day_start_pnl: Option<Decimal>,
+        now: DateTime<Utc>,
+    ) {
+        let mut state = self.state.lock().await;
+        state.portfolio = portfolio;
+        state.day = now.date_naive();
+        state.day_start_pnl = day_start_pnl.unwrap_or_else(|| state.net_pnl());
+    }
+
+    // The filled quantity moves from the open order to the position
+    pub async fn apply_fill(&self, fill: &Fill) {
+        let mut state = self.state.lock().await;
+        state.portfolio.apply_fill(fill);
+        if let Some(open) = state.open_orders.get_mut(&fill.client_order_id) {
+            open.remaining = open
+                .remaining
+                .checked_sub(fill.quantity)
+                .unwrap_or(Decimal::ZERO);
+            if !open.remaining.is_positive() {
+                state.open_orders.remove(&fill.client_order_id);
+            }
+        }
+    }
+
+    // Counts an order accepted before a restart that is still open at the exchange
+    pub async fn restore(&self, order: &Order, remaining: Decimal) {
+        self.state.lock().await.open_orders.insert(
+            order.client_order_id.clone(),
+            OpenOrder {
+                symbol: order.symbol.clone(),
+                side: order.side,
+                remaining,
+            },
+        );
+    }
+
+    // Stops counting an accepted order, once it was canceled, rejected or expired
+    pub async fn release(&self, client_order_id: &str) {
+        self.state.lock().await.open_orders.remove(client_order_id);
+    }
+
+    // Unfilled quantity of the accepted orders, negative if sells outweigh buys
+    pub async fn open_quantity(&self, symbol: &str) -> Decimal {
+        let state = self.state.lock().await;
+        let buys = state.open_quantity(symbol, Side::Buy);
+        let sells = state.open_quantity(symbol, Side::Sell);
+        buys.zip(sells)
+            .and_then(|(buys, sells)| buys.checked_sub(sells))
+            .unwrap_or(Decimal::ZERO)
+    }
+
+    // Quantity held according to the applied fills, negative for short positions
+    pub async fn position(&self, symbol: &str) -> Decimal {
+        self.state.lock().await.portfolio.quantity(symbol)
+    }
+
+    // Net PnL lost since the start of the UTC day of `now`, zero while in profit
+    pub async fn daily_loss(&self, now: DateTime<Utc>) -> Decimal {
+        let mut state = self.state.lock().await;
+        state.roll_day(now);
+        daily_loss(&state)
+    }
+
+    // Accepted orders count towards the limits until filled or released
+    pub async fn check(
+        &self,
+        node_id: usize,
+        order: &Order,
+        now: DateTime<Utc>,
+    ) -> Result<(), OrderRejection> {
+        if self.kill_switch_engaged() {
+            return Err(OrderRejection::KillSwitch);
+        }
+        if !order.quantity.is_positive() {
+            return Err(OrderRejection::InvalidQuantity);
+        }
+
+        let mut state = self.state.lock().await;
+        state.roll_day(now);
+        self.check_limits(&mut state, node_id, order, now)?;
+        state.open_orders.insert(
+            order.client_order_id.clone(),
+            OpenOrder {
+                symbol: order.symbol.clone(),
+                side: order.side,
+                remaining: order.quantity,
+            },
+        );
+        Ok(())
+    }
+
+    fn check_limits(
+        &self,
+        state: &mut RiskState,
+        node_id: usize,
+        order: &Order,
+        now: DateTime<Utc>,
+    ) -> Result<(), OrderRejection> {
+        // Every attempt counts, so rejected orders cannot be spammed either
+        let window_start = now - Duration::minutes(1);
+        let recent = state.orders.entry(node_id).or_default();
+        while recent.front().is_some_and(|time| *time <= window_start) {
+            recent.pop_front();
+        }
+        if recent.len() >= self.limits.max_orders_per_minute as usize {
+            return Err(OrderRejection::RateLimit {
+                max_orders_per_minute: self.limits.max_orders_per_minute,
+            });
+        }
+        recent.push_back(now);
+
+        let reference =
+            *state
+                .prices
+                .get(&order.symbol)
+                .ok_or_else(|| OrderRejection::NoReferencePrice {
+                    symbol: order.symbol.clone(),
+                })?;
+        for price in [order.limit_price(), order.stop_price()]
+            .into_iter()
+            .flatten()
+        {
+            if !self.within_band(price, reference) {
+                return Err(OrderRejection::PriceBand {
+                    price,
+                    reference,
+                    max_deviation_bps: self.limits.price_band_bps,
+                });
+            }
+        }
+
+        // Overflows only for absurd quantities
+        let price = order
+            .limit_price()
+            .or_else(|| order.stop_price())
+            .unwrap_or(reference);
+        let notional = price
+            .checked_mul(order.quantity)
+            .ok_or(OrderRejection::InvalidQuantity)?;
+        if notional > self.limits.max_order_notional {
+            return Err(OrderRejection::OrderNotional {
+                notional,
+                limit: self.limits.max_order_notional,
+            });
+        }
+
+        // As if the open orders on the same side filled first
+        let current = state.portfolio.quantity(&order.symbol);
+        let open = state
+            .open_quantity(&order.symbol, order.side)
+            .ok_or(OrderRejection::InvalidQuantity)?;
+        let resulting = match order.side {
+            Side::Buy => current.checked_add(open),
+            Side::Sell => current.checked_sub(open),
+        }
+        .and_then(|quantity| quantity.checked_add(order.signed_quantity()))
+        .ok_or(OrderRejection::InvalidQuantity)?;
+        let reduces = resulting.abs() <= current.abs()
+            && !(resulting.is_positive() && current.is_negative())
+            && !(resulting.is_negative() && current.is_positive());
+        if reduces {
+            return Ok(());
+        }
+
+        let loss = daily_loss(state);
+        if loss >= self.limits.daily_loss_limit {
+            return Err(OrderRejection::DailyLossLimit {
+                loss,
+                limit: self.limits.daily_loss_limit,
+            });
+        }
+        let position_notional = resulting
+            .abs()
+            .checked_mul(reference)
+            .ok_or(OrderRejection::InvalidQuantity)?;
+        if position_notional > self.limits.max_position_notional {
+            return Err(OrderRejection::PositionLimit {
+                notional: position_notional,
+                limit: self.limits.max_position_notional,
+            });
+        }
+        Ok(())
+    }
+
+    fn within_band(&self, price: Decimal, reference: Decimal) -> bool {
+        let band = Decimal::new(self.limits.price_band_bps as i64, 4);
+        let deviation = price
+            .checked_sub(reference)
+            .map(|deviation| deviation.abs());
+        let allowed = reference.abs().checked_mul(band);
+        matches!((deviation, allowed), (Some(deviation), Some(allowed)) if deviation <= allowed)
+    }
+}
+
+impl RiskState {
+    fn net_pnl(&self) -> Decimal {
+        self.portfolio.net_pnl().unwrap_or(Decimal::ZERO)
+    }
+
+    // Unfilled quantity of the accepted orders on `side`, `None` on overflow
+    fn open_quantity(&self, symbol: &str, side: Side) -> Option<Decimal> {
+        self.open_orders
+            .values()
+            .filter(|open| open.symbol == symbol && open.side == side)
+            .try_fold(Decimal::ZERO, |sum, open| sum.checked_add(open.remaining))
+    }
+
+    fn roll_day(&mut self, now: DateTime<Utc>) {
+        let day = now.date_naive();
+        if day != self.day {
+            self.day = day;
+            self.day_start_pnl = self.net_pnl();
+        }
+    }
+}
+
+fn daily_loss(state: &RiskState) -> Decimal {
+    (state.day_start_pnl - state.net_pnl()).max(Decimal::ZERO)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RiskLimits, RiskManager};
+    use crate::test_data::dec;
+    use chrono::{DateTime, Duration, TimeZone, Utc};
+    use trade_core::models::{
+        candle::Candle,
+        decimal::Decimal,
+        order::{Fill, Order},
+        portfolio::Portfolio,
+        trade::Side,
+    };
+    use trade_protocol::packets::OrderRejection;
+
+    fn time(seconds: i64) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2022, 1, 1, 12, 0, 0).unwrap() + Duration::seconds(seconds)
+    }
+
+    fn candle(close: &str) -> Candle {
+        Candle {
+            open: dec(close),
+            high: dec(close),
+            low: dec(close),
+            close: dec(close),
+            volume: dec("1"),
+            time: time(0),
+        }
+    }
+
+    fn market(side: Side, quantity: &str) -> Order {
+        Order::market("a".to_string(), "BTCUSDT".to_string(), side, dec(quantity))
+    }
+
+    fn fill(side: Side, quantity: &str, price: &str) -> Fill {
+        Fill {
+            client_order_id: "a".to_string(),
+            symbol: "BTCUSDT".to_string(),
+            side,
+            quantity: dec(quantity),
+            price: dec(price),
+            fee: Decimal::ZERO,
+            time: time(0),
+        }
+    }
+
+    async fn manager() -> RiskManager {
+        let manager = RiskManager::new(RiskLimits {
+            max_position_notional: dec("1000"),
+            max_order_notional: dec("500"),
+            daily_loss_limit: dec("100"),
+            max_orders_per_minute: 3,
+            price_band_bps: 500,
+        });
+        manager.update_price("BTCUSDT", &candle("100")).await;
+        manager
+    }
+
+    #[tokio::test]
+    async fn orders_need_a_reference_price() {
+        let manager = manager().await;
+        let order = Order::market("a".to_string(), "ETHUSDT".to_string(), Side::Buy, dec("1"));
+
+        assert_eq!(
+            Err(OrderRejection::NoReferencePrice {
+                symbol: "ETHUSDT".to_string()
+            }),
+            manager.check(1, &order, time(0)).await
+        );
+        assert_eq!(
+            Ok(()),
+            manager.check(1, &market(Side::Buy, "1"), time(0)).await
+        );
+        assert_eq!(
+            Err(OrderRejection::InvalidQuantity),
+            manager.check(1, &market(Side::Buy, "0"), time(0)).await
+        );
+    }
+
+    #[tokio::test]
+    async fn prices_outside_the_band_are_rejected() {
+        let manager = manager().await;
+        let limit = |price: &str| {
+            Order::limit(
+                "a".to_string(),
+                "BTCUSDT".to_string(),
+                Side::Buy,
+                dec("1"),
+                dec(price),
+            )
+        };
+
+        assert_eq!(Ok(()), manager.check(1, &limit("95"), time(0)).await);
+        assert_eq!(
+            Err(OrderRejection::PriceBand {
+                price: dec("94.9"),
+                reference: dec("100"),
+                max_deviation_bps: 500,
+            }),
+            manager.check(1, &limit("94.9"), time(0)).await
+        );
+    }
+
+    #[tokio::test]
+    async fn order_and_position_notionals_are_limited() {
+        let manager = manager().await;
+
+        assert_eq!(
+            Err(OrderRejection::OrderNotional {
+                notional: dec("600"),
+                limit: dec("500"),
+            }),
+            manager.check(1, &market(Side::Buy, "6"), time(0)).await
+        );
+
+        manager.apply_fill(&fill(Side::Buy, "8", "100")).await;
+        assert_eq!(
+            Err(OrderRejection::PositionLimit {
+                notional: dec("1100"),
+                limit: dec("1000"),
+            }),
+            manager.check(1, &market(Side::Buy, "3"), time(0)).await
+        );
+        // Reducing the position is always fine
+        assert_eq!(
+            Ok(()),
+            manager.check(1, &market(Side::Sell, "3"), time(0)).await
+        );
+    }
+
+    #[tokio::test]
+    async fn daily_losses_stop_new_exposure_until_the_next_day() {
+        let manager = manager().await;
+        manager.apply_fill(&fill(Side::Buy, "4", "100")).await;
+        manager.update_price("BTCUSDT", &candle("70")).await;
+
+        assert_eq!(dec("0"), manager.daily_loss(time(0)).await);
+        manager.update_price("BTCUSDT", &candle("45")).await;
+        assert_eq!(dec("100"), manager.daily_loss(time(0)).await);
+        assert_eq!(
+            Err(OrderRejection::DailyLossLimit {
+                loss: dec("100"),
+                limit: dec("100"),
+            }),
+            manager.check(1, &market(Side::Buy, "1"), time(0)).await
+        );
+        assert_eq!(
+            Ok(()),
+            manager.check(1, &market(Side::Sell, "1"), time(1)).await
+        );
+
+        let next_day = time(0) + Duration::days(1);
+        assert_eq!(Decimal::ZERO, manager.daily_loss(next_day).await);
+        assert_eq!(
+            Ok(()),
+            manager.check(1, &market(Side::Buy, "1"), next_day).await
+        );
+    }
+
+    #[tokio::test]
+    async fn open_orders_reduce_a_position_only_once() {
+        let manager = manager().await;
+        manager.apply_fill(&fill(Side::Buy, "4", "100")).await;
+        assert_eq!(Decimal::ZERO, manager.daily_loss(time(0)).await);
+        manager.update_price("BTCUSDT", &candle("75")).await;
+        let sell =
+            |id: &str| Order::market(id.to_string(), "BTCUSDT".to_string(), Side::Sell, dec("4"));
+
+        // Past the daily loss limit, only the first sell still reduces the position
+        assert_eq!(Ok(()), manager.check(1, &sell("b"), time(0)).await);
+        assert_eq!(dec("-4"), manager.open_quantity("BTCUSDT").await);
+        assert_eq!(
+            Err(OrderRejection::DailyLossLimit {
+                loss: dec("100"),
+                limit: dec("100"),
+            }),
+            manager.check(1, &sell("c"), time(1)).await
+        );
+
+        manager.release("b").await;
+        assert_eq!(Decimal::ZERO, manager.open_quantity("BTCUSDT").await);
+        assert_eq!(Ok(()), manager.check(1, &sell("c"), time(2)).await);
+    }
+
+    #[tokio::test]
+    async fn fills_move_open_quantity_to_the_position() {
+        let manager = manager().await;
+        let buy = Order::market("b".to_string(), "BTCUSDT".to_string(), Side::Buy, dec("3"));
+        assert_eq!(Ok(()), manager.check(1, &buy, time(0)).await);
+
+        let partial = Fill {
+            client_order_id: "b".to_string(),
+            ..fill(Side::Buy, "1", "100")
+        };
+        manager.apply_fill(&partial).await;
+        assert_eq!(dec("2"), manager.open_quantity("BTCUSDT").await);
+        manager.apply_fill(&partial).await;
+        manager.apply_fill(&partial).await;
+        assert_eq!(Decimal::ZERO, manager.open_quantity("BTCUSDT").await);
+        assert_eq!(dec("3"), manager.position("BTCUSDT").await);
+    }
+
+    #[tokio::test]
+    async fn seeded_state_survives_restarts() {
+        let manager = manager().await;
+        let mut portfolio = Portfolio::new(Decimal::ZERO);
+        portfolio.apply_fill(&fill(Side::Buy, "9", "100"));
+        // Lost 90 before the restart
+        manager.seed(portfolio, Some(dec("90")), time(0)).await;
+
+        assert_eq!(dec("9"), manager.position("BTCUSDT").await);
+        assert_eq!(dec("90"), manager.daily_loss(time(0)).await);
+        assert_eq!(
+            Err(OrderRejection::PositionLimit {
+                notional: dec("1100"),
+                limit: dec("1000"),
+            }),
+            manager.check(1, &market(Side::Buy, "2"), time(0)).await
+        );
+    }
+
+    #[tokio::test]
+    async fn order_rate_is_limited_per_node() {
+        let manager = manager().await;
+        for second in 0..3 {
+            assert_eq!(
+                Ok(()),
+                manager
+                    .check(1, &market(Side::Buy, "1"), time(second))
+                    .await
+            );
+        }
+
+        assert_eq!(
+            Err(OrderRejection::RateLimit {
+                max_orders_per_minute: 3
+            }),
+            manager.check(1, &market(Side::Buy, "1"), time(30)).await
+        );
+        assert_eq!(
+            Ok(()),
+            manager.check(2, &market(Side::Buy, "1"), time(30)).await
+        );
+        // The first order left the window
+        assert_eq!(
+            Ok(()),
+            manager.check(1, &market(Side::Buy, "1"), time(60)).await
+        );
+    }
+
+    #[tokio::test]
+    async fn kill_switch_rejects_everything() {
+        let manager = manager().await;
+        manager.set_kill_switch(true);
+
+        assert_eq!(
+            Err(OrderRejection::KillSwitch),
+            manager.check(1, &market(Side::Sell, "1"), time(0)).await
+        );
+        manager.set_kill_switch(false);
+        assert_eq!(
+            Ok(()),
+            manager.check(1, &market(Side::Buy, "1"), time(0)).await
+        );
+    }
+}
@@ -0,0 +1,234 @@
+use std::{ops::Range, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use tokio::sync::broadcast::Receiver;
+use tracing::{error, info, warn};
+use trade_core::{
+    data::{database::StockDataCache, providers::MarketDataProvider},
+    models::{candle::Candle, interval::Interval},
+    Error,
+};
+
+use crate::{host::Host, rate_limiter::RateLimiter};
+
+use super::{binance::BinanceService, Service};
+
+// Candles requested per page, the maximum Binance returns at once
+const PAGE_SIZE: i32 = 1000;
+
+pub struct KlineBackfill {
+    provider: Arc<dyn MarketDataProvider>,
+    interval: Interval,
+    // How far back to fetch for symbols without any candles
+    history: chrono::Duration,
+    rate_limiter: RateLimiter,
+    max_retries: u32,
+    retry_delay: Duration,
+}
+
+impl KlineBackfill {
+    pub fn new(
+        provider: Arc<dyn MarketDataProvider>,
+        interval: Interval,
+        history: chrono::Duration,
+        rate_limiter: RateLimiter,
+    ) -> Self {
+        KlineBackfill {
+            provider,
+            interval,
+            history,
+            rate_limiter,
+            max_retries: 5,
+            retry_delay: Duration::from_secs(1),
+        }
+    }
+
+    pub fn with_retries(mut self, max_retries: u32, retry_delay: Duration) -> Self {
+        self.max_retries = max_retries;
+        self.retry_delay = retry_delay;
+        self
+    }
+
+    pub fn interval(&self) -> Interval {
+        self.interval
+    }
+
+    // Fetches all closed candles after the last cached one up to `until`.
+    // Returns the amount of candles written.
+    pub async fn backfill_symbol(
+        &self,
+        cache: &(dyn StockDataCache + Send + Sync),
+        symbol: String,
+        until: DateTime<Utc>,
+    ) -> Result<usize, Error> {
+        let last_candle = cache.get_last_candle(symbol.clone(), self.interval).await?;
+        let start = match last_candle {
+            Some(candle) => candle.time + self.interval.duration(),
+            None => self.interval.floor(until - self.history),
+        };
+        // The candle containing `until` is still open
+        let end = self.interval.floor(until);
+
+        self.backfill_range(cache, symbol, start..end).await
+    }
+
+    // Fetches and writes the candles of `range` page by page, so an interrupted backfill resumes
+    // at the last written page
+    pub async fn backfill_range(
+        &self,
+        cache: &(dyn StockDataCache + Send + Sync),
+        symbol: String,
+        range: Range<DateTime<Utc>>,
+    ) -> Result<usize, Error> {
+        let page_duration = self.interval.duration() * PAGE_SIZE;
+        let mut written = 0;
+        let mut page_start = range.start;
+
+        while page_start < range.end {
+            let page_end = (page_start + page_duration).min(range.end);
+            let candles = self
+                .fetch_page(symbol.clone(), page_start..page_end)
+                .await?;
+
+            if !candles.is_empty() {
+                written += candles.len();
+                cache
+                    .write_candles(symbol.clone(), self.interval, candles)
+                    .await?;
+            }
+            page_start = page_end;
+        }
+
+        if written > 0 {
+            info!(
+                "Backfilled {} {} candles of {}",
+                written, self.interval, symbol
+            );
+        }
+        Ok(written)
+    }
+
+    async fn fetch_page(
+        &self,
+        symbol: String,
+        range: Range<DateTime<Utc>>,
+    ) -> Result<Vec<Candle>, Error> {
+        let mut attempt = 0;
+        loop {
+            self.rate_limiter.acquire().await;
+            match self
+                .provider
+                .get_candles(symbol.clone(), self.interval, range.clone())
+                .await
+            {
+                Ok(candles) => return Ok(candles),
+                Err(err) if err.is_transient() && attempt < self.max_retries => {
+                    let delay = self.retry_delay * 2u32.pow(attempt);
+                    warn!(
+                        "Failed to fetch candles of {} ({}), retrying in {:?}",
+                        symbol, err, delay
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+pub struct BackfillService {
+    host: Arc<Host>,
+    binance_service: Arc<BinanceService>,
+    pub backfill: Arc<KlineBackfill>,
+}
+
+impl BackfillService {
+    async fn backfill_all(&self) {
+        let symbols: Vec<String> = self
+            .binance_service
+            .symbol_node_map
+            .lock()
+            .await
+            .iter()
+            .map(|(symbol, _)| symbol.clone())
+            .collect();
+
+        for symbol in symbols {
+            if let Err(err) = self
+                .backfill
+                .backfill_symbol(
+                    self.host.stock_data_cache.as_ref(),
+                    symbol.clone(),
+                    Utc::now(),
+                )
+                .await
+            {
+                error!("Failed to backfill {}: {}", symbol, err);
+            }
+        }
+
+        if let Some(metrics) = &self.host.influx_metrics {
+            let stats = metrics.snapshot();
+            info!(
+                candles_written = stats.candles_written,
+                batches_written = stats.batches_written,
+                batches_failed = stats.batches_failed,
+                retries = stats.retries,
+                "InfluxDB writes at {:.0} candles/s",
+                stats.candles_per_second()
+            );
+        }
+        if let Some(metrics) = &self.host.write_behind_metrics {
+            let stats = metrics.snapshot();
+            if stats.retries > 0 || stats.batches_dropped > 0 {
+                warn!(
+                    batches_written = stats.batches_written,
+                    retries = stats.retries,
+                    batches_dropped = stats.batches_dropped,
+                    "Candle writes to InfluxDB failed: {}",
+                    stats.last_error.as_deref().unwrap_or("unknown error")
+                );
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Service for BackfillService {
+    type Params = Arc<BinanceService>;
+    async fn try_init(
+        host: Arc<Host>,
+        params: Self::Params,
+    ) -> Result<Arc<Self>, Box<dyn std::error::Error + Send + Sync>> {
+        let backfill = KlineBackfill::new(
+            Arc::clone(&params.market_data) as Arc<dyn MarketDataProvider>,
+            host.config.backfill_interval,
+            chrono::Duration::days(host.config.backfill_days),
+            RateLimiter::new(host.config.binance_requests_per_minute),
+        );
+        Ok(Arc::new(BackfillService {
+            host,
+            binance_service: params,
+            backfill: Arc::new(backfill),
+        }))
+    }
+    async fn run(
+        self: Arc<Self>,
+        mut shutdown_recv: Receiver<()>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        loop {
+            tokio::select! {
+                _ = shutdown_recv.recv() => return Ok(()),
+                _ = self.backfill_all() => {}
+            }
+
+            // Catch up with the candles closed in the meantime
+            tokio::select! {
+                _ = shutdown_recv.recv() => return Ok(()),
+                _ = tokio::time::sleep(self.backfill.interval().duration().to_std()?) => {}
+            }
+        }
+    }
+}
@@ -5,16 +5,20 @@ use binance::{api::Binance, general::General};
 use tokio::sync::broadcast::Receiver;
 use tokio::sync::Mutex;
 use tracing::{error, info};
+use trade_core::data::providers::{binance::BinanceMarketDataProvider, MarketDataProvider};
 
 use crate::host::Host;
 
 use super::Service;
 
+// Symbol, Node Id
+pub type SymbolNodeMap = Vec<(String, Option<usize>)>;
+
 pub struct BinanceService {
     api_key: Option<String>,
     secret_key: Option<String>,
-    // Symbol, Node Id
-    pub symbol_node_map: Arc<Mutex<Vec<(String, Option<usize>)>>>,
+    pub market_data: Arc<BinanceMarketDataProvider>,
+    pub symbol_node_map: Arc<Mutex<SymbolNodeMap>>,
 }
 
 impl BinanceService {
@@ -33,6 +37,10 @@ impl Service for BinanceService {
         params: Self::Params,
     ) -> Result<Arc<Self>, Box<dyn std::error::Error + Send + Sync>> {
         Ok(Arc::new(BinanceService {
+            market_data: Arc::new(BinanceMarketDataProvider::new(
+                params.0.clone(),
+                params.1.clone(),
+            )),
             api_key: params.0,
             secret_key: params.1,
             symbol_node_map: Arc::new(Mutex::new(vec![])),
@@ -40,7 +48,7 @@ impl Service for BinanceService {
     }
     async fn run(
         self: Arc<Self>,
-        _shutdown_recv: Receiver<()>,
+        mut shutdown_recv: Receiver<()>,
     ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         let this = Arc::clone(&self);
         let general_api = this.get_binance::<General>();
@@ -52,24 +60,23 @@ impl Service for BinanceService {
             }
         }
 
-        let exchange_info = general_api
-            .exchange_info()
+        let this = Arc::clone(&self);
+        let symbols = this
+            .market_data
+            .get_symbols()
             .await
             .expect("Failed to fetch exchange info");
 
         let this = Arc::clone(&self);
         let mut symbol_node_map = this.symbol_node_map.lock().await;
-        for symbol in exchange_info.clone().symbols {
-            symbol_node_map.push((symbol.symbol, None));
+        for symbol in &symbols {
+            symbol_node_map.push((symbol.symbol.clone(), None));
         }
-        info!("Loaded {} symbols", exchange_info.symbols.len());
-
-        let this = Arc::clone(&self);
-        let _market = this.get_binance::<binance::market::Market>();
-
-        let this = Arc::clone(&self);
-        let _margin = this.get_binance::<binance::margin::Margin>();
+        drop(symbol_node_map);
+        info!("Loaded {} symbols", symbols.len());
 
-        loop {}
+        // Ignore failure, we're shutting down anyway
+        shutdown_recv.recv().await.ok();
+        Ok(())
     }
 }
@@ -1,19 +1,93 @@
-use crate::{config::HostConfig, host::Host};
+use crate::{
+    authority::{self, CertificateAuthority},
+    config::HostConfig,
+    enrollment::Enrollment,
+    host::Host,
+};
 use async_trait::async_trait;
+use rcgen::{CertificateParams, DistinguishedName, DnType};
 use rustls::{Certificate, PrivateKey};
-use tokio::sync::broadcast::Receiver;
+use tokio::sync::broadcast::{self, Receiver};
 
 use std::{path::PathBuf, sync::Arc, time::Duration};
-use tracing::{info, warn};
+use tracing::{error, info, warn};
+use trade_protocol::listener::TradeListener;
 use x509_parser::{prelude::X509Certificate, traits::FromDer};
 
 use super::Service;
 
 pub struct CertificateCheckService {
     config: HostConfig,
+    authority: Arc<CertificateAuthority>,
+    pub enrollment: Arc<Enrollment>,
+    // Host certificate and key after each regeneration
+    rotations: broadcast::Sender<(Vec<Certificate>, PrivateKey)>,
 }
 
 impl CertificateCheckService {
+    // Roots node certificates have to chain to
+    pub fn ca_certificates(&self) -> Vec<Certificate> {
+        vec![Certificate(self.authority.certificate_der().to_vec())]
+    }
+
+    // Notified with the new certificate and key whenever the host certificate is regenerated
+    pub fn subscribe(&self) -> broadcast::Receiver<(Vec<Certificate>, PrivateKey)> {
+        self.rotations.subscribe()
+    }
+
+    // Listener presenting the host certificate, it presents each regenerated one to new
+    // connections
+    pub async fn listener(self: &Arc<Self>) -> Result<TradeListener, Box<dyn std::error::Error>> {
+        // Subscribed first, so no rotation is missed after reading the current certificate
+        let mut rotations = self.subscribe();
+        let certs = Arc::clone(self).get_certs().await;
+        let listener = TradeListener::new(certs.0, certs.1, self.ca_certificates())?;
+        let resolver = listener.resolver();
+        tokio::spawn(async move {
+            loop {
+                match rotations.recv().await {
+                    Ok((certs, key)) => match resolver.update(certs, key) {
+                        Ok(()) => info!("Presenting the regenerated host certificate"),
+                        Err(err) => error!("Cannot use regenerated host certificate: {}", err),
+                    },
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!("Missed {} host certificate rotations", skipped)
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+        Ok(listener)
+    }
+
+    // Issues certificates of configured nodes that have no valid one. Nodes renew them like
+    // enrolled nodes.
+    pub async fn issue_node_certificates(&self) {
+        for name in &self.config.node_names {
+            let directory = self.config.cert_path.join("nodes").join(name);
+            let issued = tokio::fs::read(directory.join("certificate.der"))
+                .await
+                .is_ok_and(|certificate| {
+                    self.authority.has_issued(&certificate) && authority::is_valid(&certificate)
+                });
+            if issued {
+                continue;
+            }
+
+            info!("Issuing certificate of node {}", name);
+            let validity = time::Duration::days(self.config.node_certificate_days);
+            let result = match self.authority.issue_node_certificate(name, validity) {
+                Ok(certificate) => {
+                    authority::write_node_files(&directory, &self.authority, &certificate).await
+                }
+                Err(err) => Err(err),
+            };
+            if let Err(err) = result {
+                warn!("Cannot issue certificate of node {}: {}", name, err);
+            }
+        }
+    }
+
     pub async fn get_certs(self: Arc<Self>) -> (Vec<Certificate>, PrivateKey) {
         let certificate_der_path = self.config.cert_path.clone().join("certificate.der");
         let certificate_der_data = tokio::fs::read(certificate_der_path)
@@ -27,7 +101,7 @@ impl CertificateCheckService {
             .expect("Failed to read private_key.der");
 
         let key = PrivateKey(private_key_der_data);
-        return (vec![certificate_der], key);
+        (vec![certificate_der], key)
     }
 
     // returns recommended duration for next check
@@ -58,7 +132,7 @@ impl CertificateCheckService {
             .await;
 
             // Check certificate after generation
-            return Duration::ZERO;
+            Duration::ZERO
         } else {
             info!("Certificates found, checking for validity");
             let certificate_data = tokio::fs::read(certificate_der_path.clone())
@@ -67,22 +141,63 @@ impl CertificateCheckService {
             let certificate = X509Certificate::from_der(&certificate_data)
                 .expect("Failed to read X509 certificate")
                 .1;
-            if certificate.validity.is_valid() {
-                info!(
-                    "Current certificate is valid until {until}",
-                    until = certificate.validity.not_after.to_rfc2822()
-                );
-                let duration_until_expiration = certificate.validity.time_to_expiration().unwrap();
-                let mut duration_until_regeneration = duration_until_expiration;
-
+            if !self.authority.has_issued(&certificate_data) {
+                warn!("Current certificate is not issued by the host CA, generating new ones");
+                self.generate(
+                    request_pem_path,
+                    request_der_path,
+                    private_key_pem_path,
+                    private_key_der_path,
+                    certificate_pem_path,
+                    certificate_der_path,
+                )
+                .await;
+                // Check certificate after generation
+                Duration::ZERO
+            } else if authority::dns_names(&certificate_data) != self.config.cert_names {
+                warn!("Current certificate is not valid for the configured names, generating new ones");
+                self.generate(
+                    request_pem_path,
+                    request_der_path,
+                    private_key_pem_path,
+                    private_key_der_path,
+                    certificate_pem_path,
+                    certificate_der_path,
+                )
+                .await;
+                // Check certificate after generation
+                Duration::ZERO
+            } else if certificate.validity.is_valid() {
                 // Regenerate 3 days before expiration
-                duration_until_regeneration = duration_until_regeneration
-                    .checked_sub(time::Duration::seconds(60 * 60 * 24 * 3))
-                    .unwrap_or(time::Duration::ZERO);
-                let sys_duration = std::time::Duration::from_millis(
-                    duration_until_regeneration.whole_milliseconds() as u64,
-                );
-                return sys_duration;
+                let until_regeneration = certificate
+                    .validity
+                    .time_to_expiration()
+                    .unwrap_or(time::Duration::ZERO)
+                    - time::Duration::days(3);
+                // Negative once within those 3 days
+                match Duration::try_from(until_regeneration) {
+                    Ok(duration) if !duration.is_zero() => {
+                        info!(
+                            "Current certificate is valid until {until}",
+                            until = certificate.validity.not_after.to_rfc2822()
+                        );
+                        duration
+                    }
+                    _ => {
+                        info!("Current certificate expires soon, generating new ones");
+                        self.generate(
+                            request_pem_path,
+                            request_der_path,
+                            private_key_pem_path,
+                            private_key_der_path,
+                            certificate_pem_path,
+                            certificate_der_path,
+                        )
+                        .await;
+                        // Check certificate after generation
+                        Duration::ZERO
+                    }
+                }
             } else {
                 warn!("Current certificates are not valid, generating new ones");
                 self.generate(
@@ -95,7 +210,7 @@ impl CertificateCheckService {
                 )
                 .await;
                 // Check certificate after generation
-                return Duration::ZERO;
+                Duration::ZERO
             }
         }
     }
@@ -108,54 +223,67 @@ impl CertificateCheckService {
         certificate_pem: PathBuf,
         certificate_der: PathBuf,
     ) {
-        let mut params = rcgen::CertificateParams::new(self.config.cert_names.clone());
-        params.not_after = time::OffsetDateTime::now_utc() + time::Duration::days(365 * 10);
-        let certificate = rcgen::generate_simple_self_signed(self.config.cert_names.clone())
-            .expect("Failed to generate self-signed certificate");
+        // A fresh key on every rotation, the CA stays the same so nodes keep trusting the host
+        let mut params = CertificateParams::new(self.config.cert_names.clone());
+        params.distinguished_name = DistinguishedName::new();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "deeptrading host");
+        let key = rcgen::Certificate::from_params(params).expect("Failed to generate key");
+        let request = key
+            .serialize_request_der()
+            .expect("Failed to create certificate request");
+        let private_key = key.serialize_private_key_der();
+        let certificate = self
+            .authority
+            .sign_server_request(
+                &self.config.cert_names,
+                &request,
+                time::Duration::days(self.config.host_certificate_days),
+            )
+            .expect("Failed to sign certificate request");
 
         tokio::fs::write(
             request_pem,
-            certificate
-                .serialize_pem()
-                .expect("Failed to create request.pem"),
+            authority::encode_pem("CERTIFICATE REQUEST", &request),
         )
         .await
         .expect("Failed to write request.pem");
 
-        tokio::fs::write(
-            request_der,
-            certificate
-                .serialize_der()
-                .expect("Failed to create request.der"),
+        tokio::fs::write(request_der, &request)
+            .await
+            .expect("Failed to write request.der");
+
+        authority::write_private_key(
+            &private_key_pem,
+            authority::encode_pem("PRIVATE KEY", &private_key).as_bytes(),
         )
         .await
-        .expect("Failed to write request.der");
+        .expect("Failed to write private_key.pem");
+
+        authority::write_private_key(&private_key_der, &private_key)
+            .await
+            .expect("Failed to write private_key.der");
 
         tokio::fs::write(
             certificate_pem,
-            certificate
-                .serialize_pem()
-                .expect("Failed to create certificate.pem"),
+            authority::encode_pem("CERTIFICATE", &certificate),
         )
         .await
         .expect("Failed to write certificate.pem");
 
-        tokio::fs::write(
-            certificate_der,
-            certificate
-                .serialize_der()
-                .expect("Failed to create certificate.der"),
-        )
-        .await
-        .expect("Failed to write certificate.der");
-
-        tokio::fs::write(private_key_pem, certificate.serialize_private_key_pem())
+        tokio::fs::write(certificate_der, &certificate)
             .await
-            .expect("Failed to write private_key.pem");
+            .expect("Failed to write certificate.der");
 
-        tokio::fs::write(private_key_der, certificate.serialize_private_key_der())
-            .await
-            .expect("Failed to write private_key.der");
+        info!(
+            "Generated host certificate for {:?}",
+            self.config.cert_names
+        );
+        // Nobody listens yet on startup
+        let _ = self
+            .rotations
+            .send((vec![Certificate(certificate)], PrivateKey(private_key)));
     }
 }
 
@@ -166,12 +294,26 @@ impl Service for CertificateCheckService {
         host: Arc<Host>,
         _params: Self::Params,
     ) -> Result<Arc<Self>, Box<dyn std::error::Error + Send + Sync>> {
+        let config = &host.config;
+        let authority = Arc::new(CertificateAuthority::load_or_create(&config.cert_path).await?);
+        let enrollment = Enrollment::open(
+            config.cert_path.join("enrollment.json"),
+            Arc::clone(&authority),
+            config.enrollment_tokens.clone(),
+            config.node_names.clone(),
+            time::Duration::days(config.node_certificate_days),
+        )
+        .await?;
         let arc = Arc::new(CertificateCheckService {
-            config: host.config.clone(),
+            config: config.clone(),
+            authority,
+            enrollment: Arc::new(enrollment),
+            rotations: broadcast::channel(4).0,
         });
 
         info!("Checking certificates");
         Arc::clone(&arc).check_and_regenerate().await;
+        arc.issue_node_certificates().await;
 
         Ok(arc)
     }
@@ -182,14 +324,140 @@ impl Service for CertificateCheckService {
         loop {
             let this = Arc::clone(&self);
 
-            // Check certificates, each minute
             let result = this.check_and_regenerate().await;
-            if shutdown_recv.try_recv().is_ok() {
-                return Ok(());
+            tokio::select! {
+                _ = shutdown_recv.recv() => return Ok(()),
+                _ = tokio::time::sleep(result) => {}
             }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CertificateCheckService;
+    use crate::{authority::CertificateAuthority, config::HostConfig, enrollment::Enrollment};
+    use rcgen::CertificateParams;
+    use rustls::{
+        server::ResolvesServerCert, ClientConfig, ClientConnection, Connection, RootCertStore,
+        ServerConfig, ServerConnection,
+    };
+    use std::{convert::TryInto, path::Path, sync::Arc, time::Duration};
+    use tokio::sync::broadcast;
 
-            std::thread::sleep(result);
-            tokio::task::yield_now().await;
+    async fn service(directory: &Path) -> Arc<CertificateCheckService> {
+        let config = HostConfig {
+            cert_path: directory.to_path_buf(),
+            cert_names: vec!["localhost".to_string()],
+            host_certificate_days: 30,
+            ..HostConfig::default()
+        };
+        let authority = Arc::new(
+            CertificateAuthority::load_or_create(directory)
+                .await
+                .unwrap(),
+        );
+        let enrollment = Enrollment::open(
+            directory.join("enrollment.json"),
+            Arc::clone(&authority),
+            Vec::new(),
+            Vec::new(),
+            time::Duration::days(30),
+        )
+        .await
+        .unwrap();
+        Arc::new(CertificateCheckService {
+            config,
+            authority,
+            enrollment: Arc::new(enrollment),
+            rotations: broadcast::channel(4).0,
+        })
+    }
+
+    // Certificate a client trusting `root` is presented in a handshake with `resolver`
+    fn presented(resolver: Arc<dyn ResolvesServerCert>, root: &[u8]) -> Vec<u8> {
+        let server_config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(resolver);
+        let mut roots = RootCertStore::empty();
+        roots.add(&rustls::Certificate(root.to_vec())).unwrap();
+        let client_config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        let mut server = Connection::from(ServerConnection::new(Arc::new(server_config)).unwrap());
+        let mut client = Connection::from(
+            ClientConnection::new(Arc::new(client_config), "localhost".try_into().unwrap())
+                .unwrap(),
+        );
+        while client.is_handshaking() || server.is_handshaking() {
+            transfer(&mut client, &mut server);
+            transfer(&mut server, &mut client);
+        }
+        client.peer_certificates().unwrap()[0].0.clone()
+    }
+
+    fn transfer(from: &mut Connection, to: &mut Connection) {
+        let mut records = Vec::new();
+        while from.wants_write() {
+            from.write_tls(&mut records).unwrap();
         }
+        let mut records = records.as_slice();
+        while !records.is_empty() {
+            to.read_tls(&mut records).unwrap();
+        }
+        to.process_new_packets().unwrap();
+    }
+
+    #[tokio::test]
+    async fn certificates_near_expiry_are_regenerated_and_presented() {
+        let directory = tempfile::tempdir().unwrap();
+        let service = service(directory.path()).await;
+        assert_eq!(
+            Duration::ZERO,
+            Arc::clone(&service).check_and_regenerate().await
+        );
+        let listener = service.listener().await.unwrap();
+        let root = service.authority.certificate_der().to_vec();
+        let certificate_path = directory.path().join("certificate.der");
+        assert_eq!(
+            std::fs::read(&certificate_path).unwrap(),
+            presented(listener.resolver(), &root)
+        );
+
+        // Expires within the 3 days certificates are regenerated before
+        let key =
+            rcgen::Certificate::from_params(CertificateParams::new(vec!["localhost".to_string()]))
+                .unwrap();
+        let expiring = service
+            .authority
+            .sign_server_request(
+                &service.config.cert_names,
+                &key.serialize_request_der().unwrap(),
+                time::Duration::days(1),
+            )
+            .unwrap();
+        std::fs::write(&certificate_path, &expiring).unwrap();
+        std::fs::write(
+            directory.path().join("private_key.der"),
+            key.serialize_private_key_der(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            Duration::ZERO,
+            Arc::clone(&service).check_and_regenerate().await
+        );
+        let regenerated = std::fs::read(&certificate_path).unwrap();
+        assert_ne!(expiring, regenerated);
+        assert!(
+            Arc::clone(&service).check_and_regenerate().await > Duration::from_secs(60 * 60 * 24)
+        );
+
+        // Handed to the listener in the background
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(regenerated, presented(listener.resolver(), &root));
     }
 }
@@ -0,0 +1,172 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::sync::broadcast::{self, Receiver};
+use tracing::{error, info, warn};
+use trade_core::data::providers::{binance::BinanceMarketDataProvider, MarketDataProvider};
+use trade_core::models::interval::Interval;
+use trade_core::Error;
+
+use crate::config::HostEnvironment;
+use crate::exchange::{binance::BinanceExchange, paper::PaperExchange, Exchange};
+use crate::execution::Execution;
+use crate::host::Host;
+use crate::risk::RiskManager;
+
+use super::{
+    binance::BinanceService, kline_stream::KlineStreamService, risk::RiskService, Service,
+};
+
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+// Executes the orders of the nodes that passed the risk checks
+pub struct ExecutionService {
+    pub execution: Arc<Execution>,
+    exchange: Arc<dyn Exchange>,
+    risk: Arc<RiskManager>,
+    market_data: Arc<BinanceMarketDataProvider>,
+    // Without keys nothing can be traded, and the account cannot be read either
+    read_account: bool,
+    // Set when orders are simulated instead of sent to Binance
+    paper: Option<Arc<PaperExchange>>,
+    kline_stream_service: Arc<KlineStreamService>,
+    // Of the streamed candles
+    interval: Interval,
+}
+
+impl ExecutionService {
+    // Reads what the exchange knows before orders are taken, until it answers
+    async fn prepare(&self) {
+        if self.read_account {
+            let account = retry("read the account", || self.exchange.account()).await;
+            if account.day_start_pnl.is_none() {
+                warn!("Losses of the day before the start are not known to the risk checks");
+            }
+            self.risk
+                .seed(account.portfolio, account.day_start_pnl, chrono::Utc::now())
+                .await;
+        }
+
+        // Orders are rounded to the tick and lot sizes of the symbols
+        let symbols = retry("read the symbols", || self.market_data.get_symbols()).await;
+        self.execution.set_symbols(symbols).await;
+        info!("Taking orders");
+    }
+
+    // Feeds the streamed candles to the simulated exchange
+    async fn simulate(&self, paper: &PaperExchange) {
+        let mut candle_receiver = self.kline_stream_service.ingestion.subscribe();
+        loop {
+            match candle_receiver.recv().await {
+                Ok((symbol, candle)) => paper.on_candle(&symbol, self.interval, &candle).await,
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!("Paper trading missed {} candles", skipped);
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Service for ExecutionService {
+    type Params = (
+        Arc<RiskService>,
+        Arc<KlineStreamService>,
+        Arc<BinanceService>,
+    );
+    async fn try_init(
+        host: Arc<Host>,
+        params: Self::Params,
+    ) -> Result<Arc<Self>, Box<dyn std::error::Error + Send + Sync>> {
+        let config = &host.config;
+        let staging = config.environment == HostEnvironment::Staging;
+        let paper_trading = match config.paper_trading {
+            Some(false) if staging => return Err("Staging hosts never trade live".into()),
+            Some(paper_trading) => paper_trading,
+            // Live trading has to be asked for outside production, API keys are not enough
+            None => config.environment != HostEnvironment::Production,
+        };
+
+        let (exchange, paper): (Arc<dyn Exchange>, _) = if paper_trading {
+            info!("Paper trading, orders are simulated");
+            tokio::fs::create_dir_all(&config.misc_path).await?;
+            let paper = PaperExchange::open(
+                config.misc_path.join("paper_exchange.json"),
+                config.paper_initial_cash,
+            )
+            .await?
+            .with_fee_rate(config.paper_fee_rate)
+            .with_latency(chrono::Duration::milliseconds(
+                config.paper_latency_ms as i64,
+            ));
+            let paper = Arc::new(paper);
+            (Arc::clone(&paper) as Arc<dyn Exchange>, Some(paper))
+        } else {
+            if config.binance_api_key.is_none() || config.binance_secret_key.is_none() {
+                warn!("No Binance API keys configured, orders will be rejected by the exchange");
+            }
+            let exchange = BinanceExchange::new(
+                config.binance_api_key.clone(),
+                config.binance_secret_key.clone(),
+                config.binance_ws_endpoint.clone(),
+            );
+            (Arc::new(exchange), None)
+        };
+        let keys_configured =
+            config.binance_api_key.is_some() && config.binance_secret_key.is_some();
+        let execution = Execution::new(Arc::clone(&exchange), Arc::clone(&params.0.manager));
+
+        Ok(Arc::new(ExecutionService {
+            execution: Arc::new(execution),
+            exchange,
+            risk: Arc::clone(&params.0.manager),
+            market_data: Arc::clone(&params.2.market_data),
+            read_account: paper_trading || keys_configured,
+            paper,
+            kline_stream_service: params.1,
+            interval: config.backfill_interval,
+        }))
+    }
+    async fn run(
+        self: Arc<Self>,
+        mut shutdown_recv: Receiver<()>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let simulation = async {
+            match &self.paper {
+                Some(paper) => self.simulate(paper).await,
+                None => std::future::pending().await,
+            }
+        };
+        let execution = async {
+            self.prepare().await;
+            self.execution.run().await
+        };
+        tokio::select! {
+            _ = shutdown_recv.recv() => {},
+            _ = execution => {},
+            _ = simulation => {},
+        }
+        Ok(())
+    }
+}
+
+// E.g. while Binance is unavailable for a moment
+async fn retry<T, F, Fut>(what: &str, mut attempt: F) -> T
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, Error>>,
+{
+    let mut delay = INITIAL_RETRY_DELAY;
+    loop {
+        match attempt().await {
+            Ok(value) => return value,
+            Err(err) => error!("Cannot {}, retrying in {:?}: {}", what, delay, err),
+        }
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_RETRY_DELAY);
+    }
+}
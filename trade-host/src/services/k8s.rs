@@ -41,8 +41,10 @@ impl Service for KubernetesService {
     }
     async fn run(
         self: Arc<Self>,
-        _shutdown_recv: Receiver<()>,
+        mut shutdown_recv: Receiver<()>,
     ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        loop {}
+        // Ignore failure, we're shutting down anyway
+        shutdown_recv.recv().await.ok();
+        Ok(())
     }
 }
@@ -0,0 +1,357 @@
+use std::{
+    collections::BTreeSet,
+    collections::HashMap,
+    ops::Range,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use binance::ws_model::CombinedStreamEvent;
+use chrono::{DateTime, Utc};
+use futures_util::StreamExt;
+use tokio::sync::broadcast::{self, Receiver};
+use tokio::sync::watch;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{error, info, warn};
+use trade_core::{
+    data::{
+        database::SharedStockDataCache,
+        providers::binance::{candle_from_ws_kline, trade_from_ws_trade, KlineEvent, MarketEvent},
+    },
+    models::{candle::Candle, interval::Interval, trade::Trade},
+};
+
+use crate::host::Host;
+
+use super::{
+    backfill::{BackfillService, KlineBackfill},
+    binance::BinanceService,
+    Service,
+};
+
+// How often the allocated symbols are checked for changes
+const ALLOCATION_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+// Missing candles of a symbol
+type Gap = (String, Range<DateTime<Utc>>);
+
+// Streams closed klines of a set of symbols into the cache and to the subscribers, and their
+// trades to the subscribers
+pub struct KlineIngestion {
+    ws_endpoint: String,
+    interval: Interval,
+    backfill: Arc<KlineBackfill>,
+    cache: SharedStockDataCache,
+    candle_sender: broadcast::Sender<(String, Candle)>,
+    trade_sender: broadcast::Sender<(String, Trade)>,
+    reconnect_delay: Duration,
+    max_reconnect_delay: Duration,
+    // Gaps whose backfill failed transiently, retried every `gap_retry_interval`
+    gaps: Arc<Mutex<Vec<Gap>>>,
+    gap_retry_interval: Duration,
+}
+
+impl KlineIngestion {
+    pub fn new(
+        ws_endpoint: String,
+        backfill: Arc<KlineBackfill>,
+        cache: SharedStockDataCache,
+    ) -> Self {
+        let (candle_sender, _) = broadcast::channel(1024);
+        let (trade_sender, _) = broadcast::channel(1024);
+        KlineIngestion {
+            ws_endpoint,
+            interval: backfill.interval(),
+            backfill,
+            cache,
+            candle_sender,
+            trade_sender,
+            reconnect_delay: Duration::from_secs(1),
+            max_reconnect_delay: Duration::from_secs(60),
+            gaps: Arc::new(Mutex::new(Vec::new())),
+            gap_retry_interval: Duration::from_secs(60),
+        }
+    }
+
+    pub fn with_reconnect_delay(mut self, initial: Duration, max: Duration) -> Self {
+        self.reconnect_delay = initial;
+        self.max_reconnect_delay = max;
+        self
+    }
+
+    pub fn with_gap_retry_interval(mut self, gap_retry_interval: Duration) -> Self {
+        self.gap_retry_interval = gap_retry_interval;
+        self
+    }
+
+    // Closed candles of all streamed symbols
+    pub fn subscribe(&self) -> broadcast::Receiver<(String, Candle)> {
+        self.candle_sender.subscribe()
+    }
+
+    // Trades of all streamed symbols
+    pub fn subscribe_trades(&self) -> broadcast::Receiver<(String, Trade)> {
+        self.trade_sender.subscribe()
+    }
+
+    // Streams the symbols of `symbols`, reconnecting whenever they change or the connection drops.
+    // Gaps that failed to backfill are retried meanwhile, also while disconnected.
+    pub async fn run(&self, symbols: watch::Receiver<BTreeSet<String>>) {
+        let mut gap_retry = tokio::time::interval_at(
+            tokio::time::Instant::now() + self.gap_retry_interval,
+            self.gap_retry_interval,
+        );
+        let retry_gaps = async {
+            loop {
+                gap_retry.tick().await;
+                self.retry_gaps();
+            }
+        };
+        tokio::select! {
+            _ = self.stream(symbols) => {}
+            _ = retry_gaps => {}
+        }
+    }
+
+    async fn stream(&self, mut symbols: watch::Receiver<BTreeSet<String>>) {
+        // Open time of the next expected candle per symbol
+        let mut expected: HashMap<String, DateTime<Utc>> = HashMap::new();
+        let mut reconnect_delay = self.reconnect_delay;
+
+        loop {
+            let current = symbols.borrow_and_update().clone();
+            if current.is_empty() {
+                if symbols.changed().await.is_err() {
+                    return;
+                }
+                continue;
+            }
+
+            let streams: Vec<String> = current
+                .iter()
+                .flat_map(|symbol| {
+                    let symbol = symbol.to_lowercase();
+                    [
+                        format!("{}@kline_{}", symbol, self.interval),
+                        format!("{}@trade", symbol),
+                    ]
+                })
+                .collect();
+            let url = format!("{}/stream?streams={}", self.ws_endpoint, streams.join("/"));
+
+            match tokio_tungstenite::connect_async(url.as_str()).await {
+                Ok((mut socket, _)) => {
+                    info!(
+                        "Streaming {} klines of {} symbols",
+                        self.interval,
+                        current.len()
+                    );
+                    loop {
+                        tokio::select! {
+                            changed = symbols.changed() => {
+                                if changed.is_err() {
+                                    return;
+                                }
+                                info!("Allocated symbols changed, resubscribing");
+                                break;
+                            }
+                            message = socket.next() => match message {
+                                Some(Ok(Message::Text(text))) => {
+                                    // Connection is healthy again
+                                    reconnect_delay = self.reconnect_delay;
+                                    self.handle_message(&text, &mut expected).await;
+                                }
+                                Some(Ok(Message::Ping(payload))) => {
+                                    futures_util::SinkExt::send(&mut socket, Message::Pong(payload))
+                                        .await
+                                        .ok();
+                                }
+                                Some(Ok(Message::Close(frame))) => {
+                                    warn!("Kline stream closed: {:?}", frame);
+                                    break;
+                                }
+                                Some(Ok(_)) => {}
+                                Some(Err(err)) => {
+                                    warn!("Kline stream failed: {}", err);
+                                    break;
+                                }
+                                None => {
+                                    warn!("Kline stream ended");
+                                    break;
+                                }
+                            }
+                        }
+                    }
+                }
+                Err(err) => error!("Cannot connect to kline stream: {}", err),
+            }
+
+            tokio::time::sleep(reconnect_delay).await;
+            reconnect_delay = (reconnect_delay * 2).min(self.max_reconnect_delay);
+        }
+    }
+
+    async fn handle_message(&self, text: &str, expected: &mut HashMap<String, DateTime<Utc>>) {
+        let event = match serde_json::from_str::<CombinedStreamEvent<MarketEvent>>(text) {
+            Ok(event) => event,
+            Err(err) => {
+                warn!("Cannot parse kline stream message: {}", err);
+                return;
+            }
+        };
+        let KlineEvent { symbol, kline } = match event.data {
+            MarketEvent::Kline(event) if event.kline.is_final_bar => event,
+            MarketEvent::Trade(event) => {
+                match trade_from_ws_trade(&event) {
+                    // Nobody might be listening
+                    Ok(trade) => {
+                        self.trade_sender.send((event.symbol, trade)).ok();
+                    }
+                    Err(err) => warn!("Cannot convert trade of {}: {}", event.symbol, err),
+                }
+                return;
+            }
+            _ => return,
+        };
+        let candle = match candle_from_ws_kline(&kline) {
+            Ok(candle) => candle,
+            Err(err) => {
+                warn!("Cannot convert kline of {}: {}", symbol, err);
+                return;
+            }
+        };
+
+        let next = match expected.get(&symbol) {
+            Some(next) => Some(*next),
+            None => match self
+                .cache
+                .get_last_candle(symbol.clone(), self.interval)
+                .await
+            {
+                Ok(last_candle) => last_candle.map(|candle| candle.time + self.interval.duration()),
+                Err(err) => {
+                    error!("Cannot read last candle of {}: {}", symbol, err);
+                    None
+                }
+            },
+        };
+
+        if let Some(next) = next {
+            if candle.time < next {
+                // Already known, e.g. replayed after a reconnect
+                return;
+            }
+            if candle.time > next {
+                warn!(
+                    "Detected gap in {} klines from {} to {}",
+                    symbol, next, candle.time
+                );
+                self.spawn_backfill(symbol.clone(), next..candle.time);
+            }
+        }
+
+        if let Err(err) = self
+            .cache
+            .write_candles(symbol.clone(), self.interval, vec![candle])
+            .await
+        {
+            error!("Failed to write candle of {}: {}", symbol, err);
+        }
+        expected.insert(symbol.clone(), candle.time + self.interval.duration());
+
+        // Nobody might be listening
+        self.candle_sender.send((symbol, candle)).ok();
+    }
+
+    // Off the read loop, a long backfill would stall pings and Binance drops the stream
+    fn spawn_backfill(&self, symbol: String, range: Range<DateTime<Utc>>) {
+        let backfill = Arc::clone(&self.backfill);
+        let cache = Arc::clone(&self.cache);
+        let gaps = Arc::clone(&self.gaps);
+        tokio::spawn(async move {
+            match backfill
+                .backfill_range(cache.as_ref(), symbol.clone(), range.clone())
+                .await
+            {
+                Ok(_) => info!("Backfilled gap of {} from {}", symbol, range.start),
+                Err(err) if err.is_transient() => {
+                    warn!("Failed to backfill gap of {}, retrying: {}", symbol, err);
+                    gaps.lock().unwrap().push((symbol, range));
+                }
+                Err(err) => error!("Failed to backfill gap of {}: {}", symbol, err),
+            }
+        });
+    }
+
+    fn retry_gaps(&self) {
+        let gaps = std::mem::take(&mut *self.gaps.lock().unwrap());
+        for (symbol, range) in gaps {
+            self.spawn_backfill(symbol, range);
+        }
+    }
+}
+
+pub struct KlineStreamService {
+    binance_service: Arc<BinanceService>,
+    pub ingestion: Arc<KlineIngestion>,
+}
+
+impl KlineStreamService {
+    async fn allocated_symbols(&self) -> BTreeSet<String> {
+        self.binance_service
+            .symbol_node_map
+            .lock()
+            .await
+            .iter()
+            .filter(|(_, node_id)| node_id.is_some())
+            .map(|(symbol, _)| symbol.clone())
+            .collect()
+    }
+}
+
+#[async_trait]
+impl Service for KlineStreamService {
+    type Params = (Arc<BinanceService>, Arc<BackfillService>);
+    async fn try_init(
+        host: Arc<Host>,
+        params: Self::Params,
+    ) -> Result<Arc<Self>, Box<dyn std::error::Error + Send + Sync>> {
+        let ingestion = KlineIngestion::new(
+            host.config.binance_ws_endpoint.clone(),
+            Arc::clone(&params.1.backfill),
+            Arc::clone(&host.stock_data_cache),
+        );
+        Ok(Arc::new(KlineStreamService {
+            binance_service: params.0,
+            ingestion: Arc::new(ingestion),
+        }))
+    }
+    async fn run(
+        self: Arc<Self>,
+        mut shutdown_recv: Receiver<()>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let (symbols_sender, symbols_receiver) = watch::channel(BTreeSet::new());
+        let ingestion = Arc::clone(&self.ingestion);
+        let ingestion_task = ingestion.run(symbols_receiver);
+        tokio::pin!(ingestion_task);
+
+        loop {
+            let symbols = self.allocated_symbols().await;
+            symbols_sender.send_if_modified(|current| {
+                if *current != symbols {
+                    *current = symbols;
+                    true
+                } else {
+                    false
+                }
+            });
+
+            tokio::select! {
+                _ = shutdown_recv.recv() => return Ok(()),
+                _ = &mut ingestion_task => return Ok(()),
+                _ = tokio::time::sleep(ALLOCATION_POLL_INTERVAL) => {}
+            }
+        }
+    }
+}
@@ -6,10 +6,14 @@ use tokio::sync::broadcast::{Receiver, Sender};
 use tracing::{error, error_span, info, info_span, trace_span};
 use tracing_futures::Instrument;
 
+pub mod backfill;
 pub mod binance;
 pub mod certificate_check;
+pub mod execution;
 pub mod k8s;
+pub mod kline_stream;
 pub mod recoverer;
+pub mod risk;
 pub mod trade_protocol;
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -82,7 +86,7 @@ pub(crate) fn start_service<S: 'static + Service>(
     let mut cloned_receiver = shutdown_sender.subscribe();
     let cloned_status_sender = status_sender.clone();
     tokio::spawn(async move {
-        let _ = cloned_receiver
+        cloned_receiver
             .recv()
             .await
             .expect("Failed to receive shutdown signal");
@@ -35,6 +35,7 @@ impl Service for RecovererService {
         let host_status_file_path = self.host.config.misc_path.clone().join("~deeptrading.lock");
         let host_status_file = tokio::fs::OpenOptions::new()
             .create(true)
+            .truncate(false)
             .read(true)
             .write(true)
             .append(false)
@@ -43,13 +44,13 @@ impl Service for RecovererService {
             .expect("Failed to open or create ~deeptrading.lock");
         let mut host_status = HostStatus::new(host_status_file);
 
-        if host_status.get_value().await == true {
+        if host_status.get_value().await {
             warn!("The host was not terminated gracefully before.")
         }
 
         let host_status_arc = Arc::clone(&this.host_status);
         let mut host_status_lock = host_status_arc.lock().await;
-        let _ = std::mem::replace(&mut *host_status_lock, Some(host_status));
+        *host_status_lock = Some(host_status);
 
         loop {
             tokio::task::yield_now().await;
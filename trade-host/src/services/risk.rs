@@ -0,0 +1,76 @@
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use tokio::sync::broadcast::{self, Receiver};
+use tracing::{error, info, warn};
+
+use crate::host::Host;
+use crate::risk::{RiskLimits, RiskManager};
+
+use super::{kline_stream::KlineStreamService, Service};
+
+// How often the kill switch file is looked for
+const KILL_SWITCH_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Keeps the reference prices of the risk manager up to date and controls its kill switch
+pub struct RiskService {
+    pub manager: Arc<RiskManager>,
+    kline_stream_service: Arc<KlineStreamService>,
+    kill_switch_path: PathBuf,
+    kill_switch_configured: bool,
+}
+
+impl RiskService {
+    async fn update_kill_switch(&self) {
+        let engaged = self.kill_switch_configured
+            || tokio::fs::metadata(&self.kill_switch_path).await.is_ok();
+        if engaged != self.manager.kill_switch_engaged() {
+            if engaged {
+                error!("Kill switch engaged, rejecting all orders");
+            } else {
+                warn!("Kill switch released");
+            }
+            self.manager.set_kill_switch(engaged);
+        }
+    }
+}
+
+#[async_trait]
+impl Service for RiskService {
+    type Params = Arc<KlineStreamService>;
+    async fn try_init(
+        host: Arc<Host>,
+        params: Self::Params,
+    ) -> Result<Arc<Self>, Box<dyn std::error::Error + Send + Sync>> {
+        let manager = RiskManager::new(RiskLimits::from(&host.config));
+        manager.set_kill_switch(host.config.risk_kill_switch);
+        Ok(Arc::new(RiskService {
+            manager: Arc::new(manager),
+            kline_stream_service: params,
+            kill_switch_path: host.config.misc_path.join("kill_switch"),
+            kill_switch_configured: host.config.risk_kill_switch,
+        }))
+    }
+    async fn run(
+        self: Arc<Self>,
+        mut shutdown_recv: Receiver<()>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        info!("Risk limits: {:?}", self.manager.limits());
+        let mut candle_receiver = self.kline_stream_service.ingestion.subscribe();
+        let mut kill_switch_poll = tokio::time::interval(KILL_SWITCH_POLL_INTERVAL);
+
+        loop {
+            tokio::select! {
+                _ = shutdown_recv.recv() => return Ok(()),
+                _ = kill_switch_poll.tick() => self.update_kill_switch().await,
+                received = candle_receiver.recv() => match received {
+                    Ok((symbol, candle)) => self.manager.update_price(&symbol, &candle).await,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!("Risk checks missed {} candles", skipped);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                },
+            }
+        }
+    }
+}
@@ -1,25 +1,53 @@
 use crate::host::Host;
 use async_trait::async_trait;
-use std::{net::SocketAddr, str::FromStr, sync::Arc};
-use tokio::sync::broadcast::Receiver;
-use tracing::info;
+use chrono::Utc;
+use std::{collections::HashMap, net::SocketAddr, str::FromStr, sync::Arc, time::Duration};
+use tokio::sync::{broadcast, broadcast::Receiver, Mutex};
+use tracing::{info, warn};
+use trade_core::models::{candle::Candle, order::Order};
 use trade_protocol::{
-    listener::{TradeListener, TradeListenerHandler},
-    packets::AllocationResponsePacketData,
-    services::FinancialServiceHandler,
+    packets::{
+        CertificateBundle, EnrollmentRejection, HandshakeRejection, HostHello, NodeHello,
+        OrderRejection,
+    },
+    services::{EnrollmentServiceHandler, FinancialServiceHandler},
+    version,
 };
 
-use super::{binance::BinanceService, certificate_check::CertificateCheckService, Service};
+use super::{
+    binance::BinanceService, certificate_check::CertificateCheckService,
+    execution::ExecutionService, kline_stream::KlineStreamService, Service,
+};
+
+// Shorter than the default tarpc deadline, so nodes can simply poll again
+const CANDLE_POLL_TIMEOUT: Duration = Duration::from_secs(5);
 
 pub struct TradeProtocolService {
     host: Arc<Host>,
     binance_service: Arc<BinanceService>,
     certificate_service: Arc<CertificateCheckService>,
+    kline_stream_service: Arc<KlineStreamService>,
+    execution_service: Arc<ExecutionService>,
+    // Node ids by certificate name, stable across reconnects of a node
+    node_ids: std::sync::Mutex<HashMap<String, usize>>,
+}
+
+impl TradeProtocolService {
+    fn node_id(&self, node_name: &str) -> usize {
+        let mut node_ids = self.node_ids.lock().unwrap();
+        let next_id = node_ids.len() + 1;
+        *node_ids.entry(node_name.to_string()).or_insert(next_id)
+    }
 }
 
 #[async_trait]
 impl Service for TradeProtocolService {
-    type Params = (Arc<BinanceService>, Arc<CertificateCheckService>);
+    type Params = (
+        Arc<BinanceService>,
+        Arc<CertificateCheckService>,
+        Arc<KlineStreamService>,
+        Arc<ExecutionService>,
+    );
     async fn try_init(
         host: Arc<Host>,
         params: Self::Params,
@@ -28,6 +56,9 @@ impl Service for TradeProtocolService {
             host,
             binance_service: params.0,
             certificate_service: params.1,
+            kline_stream_service: params.2,
+            execution_service: params.3,
+            node_ids: std::sync::Mutex::new(HashMap::new()),
         }))
     }
     async fn run(
@@ -35,14 +66,32 @@ impl Service for TradeProtocolService {
         _shutdown_recv: Receiver<()>,
     ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         let this = Arc::clone(&self);
-        let cert_handler = Arc::clone(&this.certificate_service);
-        let certs = cert_handler.get_certs().await;
-        let mut listener = TradeListener::new(certs.0, certs.1).expect("Failed to create listener");
+        let mut listener = this
+            .certificate_service
+            .listener()
+            .await
+            .expect("Failed to create listener");
 
         let address_value = format!("{}:{}", self.host.config.host, self.host.config.port);
         let address = SocketAddr::from_str(&address_value).expect("Failed to parse address");
 
-        let listen_task = listener.listen(address, Arc::new(|_| Arc::new(FinancialServiceImpl)));
+        let enrollment_handler = Arc::new(EnrollmentServiceImpl {
+            service: Arc::clone(&this),
+        });
+        let listen_task = listener.listen(
+            address,
+            Arc::new(move |connection, node_name: String| {
+                Arc::new(FinancialServiceImpl {
+                    service: Arc::clone(&this),
+                    connection,
+                    node_id: this.node_id(&node_name),
+                    node_name,
+                    // Subscribed right away, so no candle is missed between two polls
+                    candle_receiver: Mutex::new(this.kline_stream_service.ingestion.subscribe()),
+                })
+            }),
+            enrollment_handler,
+        );
         tokio::join!(
             async move {
                 listen_task.await.expect("Cannot listen on address");
@@ -55,50 +104,77 @@ impl Service for TradeProtocolService {
     }
 }
 
-struct FinancialServiceImpl;
+struct FinancialServiceImpl {
+    service: Arc<TradeProtocolService>,
+    connection: Arc<quinn::Connection>,
+    // From the client certificate of the node
+    node_id: usize,
+    node_name: String,
+    candle_receiver: Mutex<broadcast::Receiver<(String, Candle)>>,
+}
 
-#[async_trait::async_trait]
-impl FinancialServiceHandler for FinancialServiceImpl {
-    async fn hello(self: Arc<Self>, name: String) -> String {
-        "Hello".to_string()
-    }
-    async fn send_heartbeat(self: Arc<Self>) {}
-    async fn request_allocation(self: Arc<Self>) -> String {
-        "AAPL".to_string()
+impl FinancialServiceImpl {
+    async fn is_allocated(&self, symbol: &str) -> bool {
+        let node_id = self.node_id;
+        self.service
+            .binance_service
+            .symbol_node_map
+            .lock()
+            .await
+            .iter()
+            .any(|(allocated, allocated_node_id)| {
+                allocated == symbol && *allocated_node_id == Some(node_id)
+            })
     }
 }
 
-struct TradeProtocolHandler {
-    service: Arc<TradeProtocolService>,
-}
+#[async_trait::async_trait]
+impl FinancialServiceHandler for FinancialServiceImpl {
+    async fn handshake(self: Arc<Self>, hello: NodeHello) -> Result<HostHello, HandshakeRejection> {
+        let missing: Vec<String> = self
+            .service
+            .host
+            .config
+            .required_node_capabilities
+            .iter()
+            .filter(|capability| !hello.capabilities.contains(capability))
+            .cloned()
+            .collect();
+        if !missing.is_empty() {
+            let rejection = HandshakeRejection::MissingCapabilities { missing };
+            warn!(
+                "Rejected node {} of build {}: {}",
+                self.node_name, hello.build_version, rejection
+            );
+            return Err(rejection);
+        }
 
-#[async_trait]
-impl TradeListenerHandler for TradeProtocolHandler {
-    async fn on_connect(
-        &mut self,
-        connection: Arc<quinn::Connection>,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         info!(
-            "Established connection from {}",
-            connection.remote_address()
+            "Node {} of build {} connected with capabilities {:?}",
+            self.node_name, hello.build_version, hello.capabilities
         );
-        Ok(())
+        Ok(HostHello {
+            build_version: env!("CARGO_PKG_VERSION").to_string(),
+            protocol_version: version::negotiated_version(&self.connection)
+                .unwrap_or(version::PROTOCOL_VERSION),
+            capabilities: version::capabilities(),
+        })
     }
-    async fn handle_heartbeat(
-        &mut self,
-        connection: Arc<quinn::Connection>,
-        _packet: trade_protocol::packets::HeartbeatPacketData,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        info!("Heartbeat received from {}", connection.remote_address());
-        Ok(())
+    async fn hello(self: Arc<Self>, _name: String) -> String {
+        "Hello".to_string()
     }
-    async fn handle_allocation_request(
-        &mut self,
-        connection: Arc<quinn::Connection>,
-    ) -> Result<AllocationResponsePacketData, Box<dyn std::error::Error + Send + Sync>> {
+    async fn send_heartbeat(self: Arc<Self>) {
         info!(
-            "Allocation request received from {}",
-            connection.remote_address()
+            "Heartbeat received from node {} at {}",
+            self.node_name,
+            self.connection.remote_address()
+        );
+    }
+    async fn request_allocation(self: Arc<Self>) -> Option<String> {
+        info!(
+            "Allocation request received from node {} at {}",
+            self.node_name,
+            self.connection.remote_address()
         );
         let binance_service = Arc::clone(&self.service.binance_service);
         let mut symbol_node_map = binance_service.symbol_node_map.lock().await;
@@ -108,18 +184,123 @@ impl TradeListenerHandler for TradeProtocolHandler {
             .iter_mut()
             .find(|(_, node_id)| node_id.is_none())
         {
-            *node_id = Some(connection.stable_id());
-            info!(
-                "Allocated symbol {} to node {}",
-                symbol,
-                connection.stable_id()
-            );
-            Ok(AllocationResponsePacketData {
-                symbol: Some(symbol.clone()),
-            })
+            *node_id = Some(self.node_id);
+            info!("Allocated symbol {} to node {}", symbol, self.node_id);
+            Some(symbol.clone())
+        } else {
+            info!("Cannot allocate symbol to node {}", self.node_id);
+            None
+        }
+    }
+    async fn next_candles(self: Arc<Self>) -> Vec<(String, Candle)> {
+        let mut candle_receiver = self.candle_receiver.lock().await;
+        let mut candles = Vec::new();
+
+        let deadline = tokio::time::sleep(CANDLE_POLL_TIMEOUT);
+        tokio::pin!(deadline);
+        while candles.is_empty() {
+            let received = tokio::select! {
+                _ = &mut deadline => break,
+                received = candle_receiver.recv() => received,
+            };
+            match received {
+                Ok((symbol, candle)) => {
+                    if self.is_allocated(&symbol).await {
+                        candles.push((symbol, candle));
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!("Node {} missed {} candles", self.node_id, skipped);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+
+        // Hand out everything else that is already there
+        while let Ok((symbol, candle)) = candle_receiver.try_recv() {
+            if self.is_allocated(&symbol).await {
+                candles.push((symbol, candle));
+            }
+        }
+        candles
+    }
+    async fn submit_order(self: Arc<Self>, order: Order) -> Result<(), OrderRejection> {
+        let node_id = self.node_id;
+        let result = if self.is_allocated(&order.symbol).await {
+            self.service
+                .execution_service
+                .execution
+                .submit(node_id, &self.node_name, order.clone(), Utc::now())
+                .await
         } else {
-            info!("Cannot allocate symbol to node {}", connection.stable_id());
-            Ok(AllocationResponsePacketData { symbol: None })
+            Err(OrderRejection::NotAllocated {
+                symbol: order.symbol.clone(),
+            })
+        };
+
+        match &result {
+            Ok(()) => info!(
+                "Submitted order {} of node {}",
+                order.client_order_id, node_id
+            ),
+            Err(rejection) => warn!(
+                "Rejected order {} of node {}: {}",
+                order.client_order_id, node_id, rejection
+            ),
+        }
+        result
+    }
+    async fn cancel_order(self: Arc<Self>, client_order_id: String) -> bool {
+        let node_id = self.node_id;
+        match self
+            .service
+            .execution_service
+            .execution
+            .cancel(&self.node_name, &client_order_id)
+            .await
+        {
+            Ok(requested) => requested,
+            Err(err) => {
+                warn!(
+                    "Cannot cancel order {} of node {}: {}",
+                    client_order_id, node_id, err
+                );
+                false
+            }
+        }
+    }
+    async fn renew_certificate(
+        self: Arc<Self>,
+        request: Vec<u8>,
+    ) -> Result<CertificateBundle, EnrollmentRejection> {
+        self.service
+            .certificate_service
+            .enrollment
+            .renew(&self.node_name, &request)
+    }
+}
+
+// Serves nodes connecting without a client certificate
+struct EnrollmentServiceImpl {
+    service: Arc<TradeProtocolService>,
+}
+
+#[async_trait::async_trait]
+impl EnrollmentServiceHandler for EnrollmentServiceImpl {
+    async fn enroll(
+        self: Arc<Self>,
+        token: String,
+        request: Vec<u8>,
+    ) -> Result<CertificateBundle, EnrollmentRejection> {
+        let result = self
+            .service
+            .certificate_service
+            .enrollment
+            .enroll(&token, &request)
+            .await;
+        if let Err(rejection) = &result {
+            warn!("Rejected enrollment: {}", rejection);
         }
+        result
     }
 }
@@ -0,0 +1,276 @@
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::Duration;
+
+use binance::config::Config;
+use chrono::{DateTime, TimeZone, Utc};
+use serde_json::json;
+use trade_core::data::database::{in_memory::InMemoryStockDataCache, StockDataCache};
+use trade_core::data::providers::binance::BinanceMarketDataProvider;
+use trade_core::models::interval::Interval;
+use trade_core::Error;
+use trade_host::rate_limiter::RateLimiter;
+use trade_host::services::backfill::KlineBackfill;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, Request, Respond, ResponseTemplate};
+
+const MINUTE_MS: i64 = 60 * 1000;
+
+// Serves 1m klines of the given listing period like the Binance REST API
+struct KlineResponder {
+    listed: Range<i64>,
+}
+
+impl Respond for KlineResponder {
+    fn respond(&self, request: &Request) -> ResponseTemplate {
+        let param = |name: &str| {
+            request
+                .url
+                .query_pairs()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.parse::<i64>().unwrap())
+        };
+        let start = param("startTime").unwrap().max(self.listed.start);
+        let end = param("endTime").unwrap().min(self.listed.end - 1);
+        let limit = param("limit").unwrap_or(500);
+
+        // Round up to the next open time
+        let first = (start + MINUTE_MS - 1) / MINUTE_MS * MINUTE_MS;
+        let klines: Vec<_> = (0..)
+            .map(|i| first + i * MINUTE_MS)
+            .take_while(|open_time| *open_time <= end)
+            .take(limit as usize)
+            .map(|open_time| {
+                json!([
+                    open_time,
+                    "1.0",
+                    "2.0",
+                    "0.5",
+                    "1.5",
+                    "10.0",
+                    open_time + MINUTE_MS - 1,
+                    "15.0",
+                    3,
+                    "5.0",
+                    "7.5",
+                    "0"
+                ])
+            })
+            .collect();
+        ResponseTemplate::new(200).set_body_json(klines)
+    }
+}
+
+fn time(minute: i64) -> DateTime<Utc> {
+    Utc.timestamp_millis_opt(minute * MINUTE_MS).unwrap()
+}
+
+async fn mock_server(listed: Range<i64>) -> MockServer {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/api/v3/klines"))
+        .respond_with(KlineResponder {
+            listed: listed.start * MINUTE_MS..listed.end * MINUTE_MS,
+        })
+        .mount(&server)
+        .await;
+    server
+}
+
+fn backfill(server: &MockServer) -> KlineBackfill {
+    let config = Config::default().set_rest_api_endpoint(server.uri());
+    let provider = BinanceMarketDataProvider::new_with_config(None, None, config);
+    KlineBackfill::new(
+        Arc::new(provider),
+        Interval::OneMinute,
+        chrono::Duration::minutes(5000),
+        RateLimiter::new(60_000),
+    )
+    .with_retries(3, Duration::from_millis(10))
+}
+
+async fn kline_requests(server: &MockServer) -> usize {
+    server
+        .received_requests()
+        .await
+        .unwrap()
+        .iter()
+        .filter(|request| request.url.path() == "/api/v3/klines")
+        .count()
+}
+
+#[tokio::test]
+async fn backfill_paginates() {
+    let server = mock_server(1_000_000..1_002_500).await;
+    let backfill = backfill(&server);
+    let cache = InMemoryStockDataCache::new();
+
+    let written = backfill
+        .backfill_symbol(&cache, "BTCUSDT".to_string(), time(1_002_500))
+        .await
+        .expect("Failed to backfill");
+
+    assert_eq!(2500, written);
+    let candles = cache
+        .get_candles(
+            "BTCUSDT".to_string(),
+            Interval::OneMinute,
+            time(0)..time(2_000_000),
+        )
+        .await
+        .unwrap();
+    assert_eq!(2500, candles.len());
+    assert_eq!(time(1_000_000), candles.first().unwrap().time);
+    assert_eq!(time(1_002_499), candles.last().unwrap().time);
+    assert!(candles.windows(2).all(|pair| pair[0].time < pair[1].time));
+}
+
+#[tokio::test]
+async fn backfill_resumes_without_duplicates() {
+    let server = mock_server(1_000_000..1_003_000).await;
+    let backfill = backfill(&server);
+    let cache = InMemoryStockDataCache::new();
+
+    let first = backfill
+        .backfill_symbol(&cache, "BTCUSDT".to_string(), time(1_002_000))
+        .await
+        .unwrap();
+    let requests_before = kline_requests(&server).await;
+
+    // Restart after half an hour, including a still open candle
+    let second = backfill
+        .backfill_symbol(
+            &cache,
+            "BTCUSDT".to_string(),
+            time(1_002_030) + chrono::Duration::seconds(30),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(2000, first);
+    assert_eq!(30, second);
+    assert_eq!(1, kline_requests(&server).await - requests_before);
+
+    let candles = cache
+        .get_candles(
+            "BTCUSDT".to_string(),
+            Interval::OneMinute,
+            time(0)..time(2_000_000),
+        )
+        .await
+        .unwrap();
+    assert_eq!(2030, candles.len());
+    assert!(candles.windows(2).all(|pair| pair[0].time < pair[1].time));
+}
+
+#[tokio::test]
+async fn backfill_retries_transient_errors() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/api/v3/klines"))
+        .respond_with(ResponseTemplate::new(503))
+        .up_to_n_times(2)
+        .with_priority(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/api/v3/klines"))
+        .respond_with(KlineResponder {
+            listed: 1_000_000 * MINUTE_MS..1_000_100 * MINUTE_MS,
+        })
+        .mount(&server)
+        .await;
+
+    let backfill = backfill(&server);
+    let cache = InMemoryStockDataCache::new();
+
+    let written = backfill
+        .backfill_range(
+            &cache,
+            "BTCUSDT".to_string(),
+            time(1_000_000)..time(1_000_100),
+        )
+        .await
+        .expect("Failed to backfill");
+
+    assert_eq!(100, written);
+    assert_eq!(3, kline_requests(&server).await);
+}
+
+#[tokio::test]
+async fn backfill_gives_up_after_retries() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/api/v3/klines"))
+        .respond_with(ResponseTemplate::new(503))
+        .mount(&server)
+        .await;
+
+    let backfill = backfill(&server);
+    let cache = InMemoryStockDataCache::new();
+
+    let result = backfill
+        .backfill_range(
+            &cache,
+            "BTCUSDT".to_string(),
+            time(1_000_000)..time(1_000_100),
+        )
+        .await;
+
+    assert!(result.is_err());
+    assert_eq!(4, kline_requests(&server).await);
+}
+
+#[tokio::test]
+async fn backfill_does_not_retry_unknown_symbols() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/api/v3/klines"))
+        .respond_with(ResponseTemplate::new(400).set_body_raw(
+            r#"{"code":-1121,"msg":"Invalid symbol."}"#,
+            "application/json",
+        ))
+        .mount(&server)
+        .await;
+
+    let backfill = backfill(&server);
+    let cache = InMemoryStockDataCache::new();
+
+    let result = backfill
+        .backfill_range(
+            &cache,
+            "NOPEUSDT".to_string(),
+            time(1_000_000)..time(1_000_100),
+        )
+        .await;
+
+    assert!(matches!(result, Err(Error::NotFound(_))));
+    assert_eq!(1, kline_requests(&server).await);
+}
+
+#[tokio::test]
+async fn backfill_does_not_retry_rejected_requests() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/api/v3/klines"))
+        .respond_with(ResponseTemplate::new(400).set_body_raw(
+            r#"{"code":-1100,"msg":"Illegal characters found in parameter 'symbol'."}"#,
+            "application/json",
+        ))
+        .mount(&server)
+        .await;
+
+    let backfill = backfill(&server);
+    let cache = InMemoryStockDataCache::new();
+
+    let result = backfill
+        .backfill_range(
+            &cache,
+            "BTC USDT".to_string(),
+            time(1_000_000)..time(1_000_100),
+        )
+        .await;
+
+    assert!(matches!(result, Err(Error::Rejected(_))));
+    assert_eq!(1, kline_requests(&server).await);
+}
@@ -0,0 +1,623 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, TimeZone, Utc};
+use tokio::sync::{mpsc, Mutex};
+use trade_core::models::{
+    candle::Candle,
+    decimal::Decimal,
+    order::{Fill, Order, OrderType},
+    portfolio::Portfolio,
+    symbol::SymbolInfo,
+    trade::Side,
+};
+use trade_core::Error;
+use trade_host::exchange::{
+    AccountState, Exchange, ExecutionReport, OpenOrder, OrderSnapshot, OrderStatus,
+};
+use trade_host::execution::{exchange_order_id, Execution};
+use trade_host::risk::{RiskLimits, RiskManager};
+use trade_protocol::packets::OrderRejection;
+
+// Node the orders of the tests belong to
+const NODE: &str = "node";
+
+// Exchange whose execution reports and order states are scripted by the tests
+#[derive(Default)]
+struct MockExchange {
+    // Also the orders `open_orders` reports with their snapshots
+    submitted: Mutex<Vec<Order>>,
+    canceled: Mutex<Vec<String>>,
+    reject_submissions: Mutex<Option<String>>,
+    // Submissions fail as if the response got lost
+    time_out_submissions: Mutex<bool>,
+    // Answers of `open_orders` and `order_snapshot`
+    snapshots: Mutex<HashMap<String, OrderSnapshot>>,
+    reports: Mutex<Option<mpsc::UnboundedSender<ExecutionReport>>>,
+    connections: Mutex<usize>,
+}
+
+impl MockExchange {
+    async fn send(&self, report: ExecutionReport) {
+        self.reports
+            .lock()
+            .await
+            .as_ref()
+            .expect("Not connected")
+            .send(report)
+            .unwrap();
+    }
+
+    async fn disconnect(&self) {
+        self.reports.lock().await.take();
+    }
+
+    async fn connections(&self) -> usize {
+        *self.connections.lock().await
+    }
+}
+
+#[async_trait]
+impl Exchange for MockExchange {
+    async fn submit_order(&self, order: &Order) -> Result<(), Error> {
+        if let Some(reason) = self.reject_submissions.lock().await.clone() {
+            return Err(Error::rejected(reason));
+        }
+        if *self.time_out_submissions.lock().await {
+            return Err(Error::backend("Timed out"));
+        }
+        self.submitted.lock().await.push(order.clone());
+        Ok(())
+    }
+
+    async fn cancel_order(&self, _symbol: &str, client_order_id: &str) -> Result<(), Error> {
+        self.canceled.lock().await.push(client_order_id.to_string());
+        Ok(())
+    }
+
+    async fn open_orders(&self) -> Result<Vec<OpenOrder>, Error> {
+        let submitted = self.submitted.lock().await;
+        Ok(self
+            .snapshots
+            .lock()
+            .await
+            .values()
+            .filter(|snapshot| !snapshot.status.is_final())
+            .filter_map(|snapshot| {
+                let order = submitted
+                    .iter()
+                    .find(|order| order.client_order_id == snapshot.client_order_id)?;
+                Some(OpenOrder {
+                    order: order.clone(),
+                    snapshot: snapshot.clone(),
+                })
+            })
+            .collect())
+    }
+
+    async fn order_snapshot(
+        &self,
+        _symbol: &str,
+        client_order_id: &str,
+    ) -> Result<OrderSnapshot, Error> {
+        self.snapshots
+            .lock()
+            .await
+            .get(client_order_id)
+            .cloned()
+            .ok_or_else(|| Error::NotFound(client_order_id.to_string()))
+    }
+
+    async fn account(&self) -> Result<AccountState, Error> {
+        Ok(AccountState {
+            portfolio: Portfolio::new(Decimal::ZERO),
+            day_start_pnl: None,
+        })
+    }
+
+    async fn execution_reports(&self) -> Result<mpsc::UnboundedReceiver<ExecutionReport>, Error> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        *self.reports.lock().await = Some(sender);
+        *self.connections.lock().await += 1;
+        Ok(receiver)
+    }
+}
+
+fn dec(value: &str) -> Decimal {
+    value.parse().unwrap()
+}
+
+fn time() -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(2022, 1, 1, 12, 0, 0).unwrap()
+}
+
+fn buy(client_order_id: &str, quantity: &str) -> Order {
+    Order::market(
+        client_order_id.to_string(),
+        "BTCUSDT".to_string(),
+        Side::Buy,
+        dec(quantity),
+    )
+}
+
+// Of the exchange about order `client_order_id` of `NODE`
+fn report(
+    client_order_id: &str,
+    status: OrderStatus,
+    fill: Option<(&str, &str)>,
+    filled: (&str, &str),
+) -> ExecutionReport {
+    let client_order_id = &exchange_order_id(NODE, client_order_id);
+    ExecutionReport {
+        client_order_id: client_order_id.to_string(),
+        symbol: "BTCUSDT".to_string(),
+        side: Side::Buy,
+        status,
+        fill: fill.map(|(quantity, price)| Fill {
+            client_order_id: client_order_id.to_string(),
+            symbol: "BTCUSDT".to_string(),
+            side: Side::Buy,
+            quantity: dec(quantity),
+            price: dec(price),
+            fee: dec("0.1"),
+            time: time(),
+        }),
+        filled_quantity: dec(filled.0),
+        filled_quote: dec(filled.1),
+        reason: None,
+        time: time(),
+    }
+}
+
+// Of the exchange about order `client_order_id` of `NODE`
+fn snapshot(client_order_id: &str, status: OrderStatus, filled: (&str, &str)) -> OrderSnapshot {
+    OrderSnapshot {
+        client_order_id: exchange_order_id(NODE, client_order_id),
+        symbol: "BTCUSDT".to_string(),
+        status,
+        filled_quantity: dec(filled.0),
+        filled_quote: dec(filled.1),
+    }
+}
+
+fn btcusdt() -> SymbolInfo {
+    SymbolInfo {
+        symbol: "BTCUSDT".to_string(),
+        base_asset: "BTC".to_string(),
+        quote_asset: "USDT".to_string(),
+        tick_size: dec("0.01"),
+        lot_size: dec("0.001"),
+        min_quantity: dec("0.001"),
+    }
+}
+
+async fn setup() -> (Arc<MockExchange>, Arc<RiskManager>, Arc<Execution>) {
+    let exchange = Arc::new(MockExchange::default());
+    let risk = Arc::new(RiskManager::new(RiskLimits {
+        max_position_notional: dec("10000"),
+        max_order_notional: dec("1000"),
+        daily_loss_limit: dec("500"),
+        max_orders_per_minute: 100,
+        price_band_bps: 500,
+    }));
+    let candle = Candle {
+        open: dec("100"),
+        high: dec("100"),
+        low: dec("100"),
+        close: dec("100"),
+        volume: dec("1"),
+        time: time(),
+    };
+    risk.update_price("BTCUSDT", &candle).await;
+
+    let execution = Execution::new(
+        Arc::clone(&exchange) as Arc<dyn Exchange>,
+        Arc::clone(&risk),
+    )
+    .with_symbols(vec![btcusdt()])
+    .with_reconnect_delay(Duration::from_millis(10), Duration::from_millis(10));
+    (exchange, risk, Arc::new(execution))
+}
+
+// Polls until `condition` holds
+async fn eventually<F, Fut>(mut condition: F)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = bool>,
+{
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while !condition().await {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    })
+    .await
+    .expect("Condition not met in time");
+}
+
+async fn status(execution: &Execution, client_order_id: &str) -> OrderStatus {
+    execution.order(NODE, client_order_id).await.unwrap().status
+}
+
+// Finished orders are no longer tracked
+async fn finished(execution: &Execution, client_order_id: &str) -> bool {
+    execution.order(NODE, client_order_id).await.is_none()
+}
+
+#[tokio::test]
+async fn order_lifecycle_is_tracked() {
+    let (exchange, risk, execution) = setup().await;
+    let mut fills = execution.subscribe_fills();
+
+    execution
+        .submit(1, NODE, buy("a", "2"), time())
+        .await
+        .unwrap();
+    assert_eq!(1, exchange.submitted.lock().await.len());
+    assert_eq!(OrderStatus::PendingNew, status(&execution, "a").await);
+    assert_eq!(dec("2"), risk.open_quantity("BTCUSDT").await);
+
+    execution
+        .handle_report(report("a", OrderStatus::New, None, ("0", "0")))
+        .await;
+    assert_eq!(OrderStatus::New, status(&execution, "a").await);
+
+    let partial = report(
+        "a",
+        OrderStatus::PartiallyFilled,
+        Some(("0.5", "100")),
+        ("0.5", "50"),
+    );
+    execution.handle_report(partial.clone()).await;
+    // Delivered twice, booked once
+    execution.handle_report(partial).await;
+    execution
+        .handle_report(report(
+            "a",
+            OrderStatus::Filled,
+            Some(("1.5", "101")),
+            ("2", "201.5"),
+        ))
+        .await;
+
+    assert!(finished(&execution, "a").await);
+    assert_eq!(dec("2"), risk.position("BTCUSDT").await);
+    assert_eq!(Decimal::ZERO, risk.open_quantity("BTCUSDT").await);
+    for quantity in ["0.5", "1.5"] {
+        let (node, fill) = fills.recv().await.unwrap();
+        assert_eq!((NODE, "a"), (node.as_str(), fill.client_order_id.as_str()));
+        assert_eq!(dec(quantity), fill.quantity);
+    }
+    assert!(fills.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn rejections_are_returned() {
+    let (exchange, _risk, execution) = setup().await;
+
+    // Over the order notional, never reaches the exchange
+    assert!(matches!(
+        execution.submit(1, NODE, buy("a", "20"), time()).await,
+        Err(OrderRejection::OrderNotional { .. })
+    ));
+    assert!(exchange.submitted.lock().await.is_empty());
+    assert!(execution.order(NODE, "a").await.is_none());
+
+    *exchange.reject_submissions.lock().await = Some("Insufficient balance".to_string());
+    assert!(matches!(
+        execution.submit(1, NODE, buy("b", "1"), time()).await,
+        Err(OrderRejection::Exchange { .. })
+    ));
+    assert!(finished(&execution, "b").await);
+
+    *exchange.reject_submissions.lock().await = None;
+    execution
+        .submit(1, NODE, buy("c", "1"), time())
+        .await
+        .unwrap();
+    assert_eq!(
+        Err(OrderRejection::DuplicateOrderId),
+        execution.submit(1, NODE, buy("c", "1"), time()).await
+    );
+}
+
+#[tokio::test]
+async fn orders_are_rounded_to_the_symbol_filters() {
+    let (exchange, _risk, execution) = setup().await;
+    let limit = |id: &str, side: Side| {
+        Order::limit(
+            id.to_string(),
+            "BTCUSDT".to_string(),
+            side,
+            dec("1.23456"),
+            dec("100.005"),
+        )
+    };
+
+    execution
+        .submit(1, NODE, limit("a", Side::Buy), time())
+        .await
+        .unwrap();
+    execution
+        .submit(1, NODE, limit("b", Side::Sell), time())
+        .await
+        .unwrap();
+    let submitted = exchange.submitted.lock().await.clone();
+    assert_eq!(dec("1.234"), submitted[0].quantity);
+    // Never pays more or receives less than asked
+    assert_eq!(
+        OrderType::Limit {
+            price: dec("100.00")
+        },
+        submitted[0].order_type
+    );
+    assert_eq!(
+        OrderType::Limit {
+            price: dec("100.01")
+        },
+        submitted[1].order_type
+    );
+    assert_eq!(
+        dec("1.234"),
+        execution.order(NODE, "a").await.unwrap().order.quantity
+    );
+
+    assert_eq!(
+        Err(OrderRejection::BelowMinQuantity {
+            quantity: dec("0"),
+            min_quantity: dec("0.001"),
+        }),
+        execution.submit(1, NODE, buy("c", "0.0004"), time()).await
+    );
+    let other = Order::market("d".to_string(), "ETHUSDT".to_string(), Side::Buy, dec("1"));
+    assert_eq!(
+        Err(OrderRejection::UnknownSymbol {
+            symbol: "ETHUSDT".to_string()
+        }),
+        execution.submit(1, NODE, other, time()).await
+    );
+    assert_eq!(2, exchange.submitted.lock().await.len());
+}
+
+#[tokio::test]
+async fn failed_submissions_are_settled_with_the_exchange() {
+    let (exchange, risk, execution) = setup().await;
+    *exchange.time_out_submissions.lock().await = true;
+
+    // Reached the exchange although the response got lost
+    exchange.snapshots.lock().await.insert(
+        exchange_order_id(NODE, "a"),
+        snapshot("a", OrderStatus::New, ("0", "0")),
+    );
+    execution
+        .submit(1, NODE, buy("a", "1"), time())
+        .await
+        .unwrap();
+    assert_eq!(OrderStatus::New, status(&execution, "a").await);
+    assert_eq!(dec("1"), risk.open_quantity("BTCUSDT").await);
+
+    assert!(matches!(
+        execution.submit(1, NODE, buy("b", "1"), time()).await,
+        Err(OrderRejection::Exchange { .. })
+    ));
+    assert!(finished(&execution, "b").await);
+    assert_eq!(dec("1"), risk.open_quantity("BTCUSDT").await);
+}
+
+#[tokio::test]
+async fn cancels_are_tracked() {
+    let (exchange, risk, execution) = setup().await;
+    execution
+        .submit(1, NODE, buy("a", "1"), time())
+        .await
+        .unwrap();
+
+    // Only the node owning the order may cancel it
+    assert!(!execution.cancel("other", "a").await.unwrap());
+    assert!(execution.cancel(NODE, "a").await.unwrap());
+    assert_eq!(
+        vec![exchange_order_id(NODE, "a")],
+        *exchange.canceled.lock().await
+    );
+
+    execution
+        .handle_report(report("a", OrderStatus::Canceled, None, ("0", "0")))
+        .await;
+    assert!(finished(&execution, "a").await);
+    assert!(!execution.cancel(NODE, "a").await.unwrap());
+    // No longer counts towards the risk limits
+    assert_eq!(Decimal::ZERO, risk.open_quantity("BTCUSDT").await);
+}
+
+#[tokio::test]
+async fn open_orders_are_reconciled_after_reconnect() {
+    let (exchange, risk, execution) = setup().await;
+    let task = tokio::spawn({
+        let execution = Arc::clone(&execution);
+        async move { execution.run().await }
+    });
+    eventually(|| async { exchange.connections().await == 1 }).await;
+
+    execution
+        .submit(1, NODE, buy("a", "2"), time())
+        .await
+        .unwrap();
+    execution
+        .submit(1, NODE, buy("b", "2"), time())
+        .await
+        .unwrap();
+    exchange
+        .send(report("a", OrderStatus::New, None, ("0", "0")))
+        .await;
+    exchange
+        .send(report(
+            "b",
+            OrderStatus::PartiallyFilled,
+            Some(("0.5", "100")),
+            ("0.5", "50"),
+        ))
+        .await;
+    eventually(|| async { status(&execution, "b").await == OrderStatus::PartiallyFilled }).await;
+
+    // While disconnected "a" filled completely and "b" by another 1 at 104
+    exchange.disconnect().await;
+    {
+        let mut snapshots = exchange.snapshots.lock().await;
+        snapshots.insert(
+            exchange_order_id(NODE, "a"),
+            snapshot("a", OrderStatus::Filled, ("2", "204")),
+        );
+        snapshots.insert(
+            exchange_order_id(NODE, "b"),
+            snapshot("b", OrderStatus::PartiallyFilled, ("1.5", "154")),
+        );
+    }
+    eventually(|| async { exchange.connections().await == 2 }).await;
+    eventually(|| finished(&execution, "a")).await;
+    task.abort();
+
+    let b = execution.order(NODE, "b").await.unwrap();
+    assert_eq!(OrderStatus::PartiallyFilled, b.status);
+    assert_eq!(
+        (dec("1.5"), dec("154")),
+        (b.filled_quantity, b.filled_quote)
+    );
+    assert_eq!(dec("3.5"), risk.position("BTCUSDT").await);
+    assert_eq!(dec("0.5"), risk.open_quantity("BTCUSDT").await);
+}
+
+#[tokio::test]
+async fn open_orders_count_towards_the_position_limit() {
+    let (exchange, risk, execution) = setup().await;
+
+    // 10000 in the quote asset, at most 1000 per order
+    for index in 0..10 {
+        execution
+            .submit(1, NODE, buy(&index.to_string(), "10"), time())
+            .await
+            .unwrap();
+    }
+    assert!(matches!(
+        execution.submit(1, NODE, buy("10", "1"), time()).await,
+        Err(OrderRejection::PositionLimit { .. })
+    ));
+
+    execution
+        .handle_report(report("0", OrderStatus::Expired, None, ("0", "0")))
+        .await;
+    execution
+        .submit(1, NODE, buy("10", "1"), time())
+        .await
+        .unwrap();
+    assert_eq!(dec("91"), risk.open_quantity("BTCUSDT").await);
+    assert_eq!(11, exchange.submitted.lock().await.len());
+}
+
+#[tokio::test]
+async fn orders_are_refused_until_the_symbols_are_known() {
+    let (exchange, risk, _) = setup().await;
+    let execution = Execution::new(Arc::clone(&exchange) as Arc<dyn Exchange>, risk);
+
+    assert_eq!(
+        Err(OrderRejection::Starting),
+        execution.submit(1, NODE, buy("a", "1"), time()).await
+    );
+    assert!(exchange.submitted.lock().await.is_empty());
+
+    execution.set_symbols(vec![btcusdt()]).await;
+    execution
+        .submit(1, NODE, buy("a", "1"), time())
+        .await
+        .unwrap();
+    assert_eq!(1, exchange.submitted.lock().await.len());
+}
+
+#[tokio::test]
+async fn client_order_ids_are_per_node() {
+    let (exchange, risk, execution) = setup().await;
+    let mut fills = execution.subscribe_fills();
+
+    execution
+        .submit(1, NODE, buy("1", "1"), time())
+        .await
+        .unwrap();
+    execution
+        .submit(2, "other", buy("1", "2"), time())
+        .await
+        .unwrap();
+    let submitted: Vec<String> = exchange
+        .submitted
+        .lock()
+        .await
+        .iter()
+        .map(|order| order.client_order_id.clone())
+        .collect();
+    assert_eq!(vec!["node:1", "other:1"], submitted);
+    assert_eq!(dec("3"), risk.open_quantity("BTCUSDT").await);
+
+    execution
+        .handle_report(report(
+            "1",
+            OrderStatus::Filled,
+            Some(("1", "100")),
+            ("1", "100"),
+        ))
+        .await;
+    assert!(finished(&execution, "1").await);
+    assert_eq!(
+        dec("2"),
+        execution.order("other", "1").await.unwrap().order.quantity
+    );
+    assert_eq!(dec("2"), risk.open_quantity("BTCUSDT").await);
+    let (node, fill) = fills.recv().await.unwrap();
+    assert_eq!((NODE, "1"), (node.as_str(), fill.client_order_id.as_str()));
+}
+
+#[tokio::test]
+async fn open_orders_are_restored_after_a_restart() {
+    let (exchange, risk, execution) = setup().await;
+    // Left open by the previous run, next to an order placed by hand
+    {
+        let mut submitted = exchange.submitted.lock().await;
+        submitted.push(buy(&exchange_order_id(NODE, "a"), "2"));
+        submitted.push(buy("manual", "5"));
+        let mut snapshots = exchange.snapshots.lock().await;
+        snapshots.insert(
+            exchange_order_id(NODE, "a"),
+            snapshot("a", OrderStatus::PartiallyFilled, ("0.5", "50")),
+        );
+        snapshots.insert(
+            "manual".to_string(),
+            OrderSnapshot {
+                client_order_id: "manual".to_string(),
+                ..snapshot("a", OrderStatus::New, ("0", "0"))
+            },
+        );
+    }
+
+    execution.reconcile(time()).await.unwrap();
+    let restored = execution.order(NODE, "a").await.unwrap();
+    assert_eq!(buy("a", "2"), restored.order);
+    assert_eq!(OrderStatus::PartiallyFilled, restored.status);
+    assert_eq!(dec("1.5"), risk.open_quantity("BTCUSDT").await);
+    // Part of the account already
+    assert_eq!(Decimal::ZERO, risk.position("BTCUSDT").await);
+    assert_eq!(
+        Err(OrderRejection::DuplicateOrderId),
+        execution.submit(1, NODE, buy("a", "1"), time()).await
+    );
+
+    execution
+        .handle_report(report(
+            "a",
+            OrderStatus::Filled,
+            Some(("1.5", "100")),
+            ("2", "200"),
+        ))
+        .await;
+    assert!(finished(&execution, "a").await);
+    assert_eq!(dec("1.5"), risk.position("BTCUSDT").await);
+    assert_eq!(Decimal::ZERO, risk.open_quantity("BTCUSDT").await);
+}
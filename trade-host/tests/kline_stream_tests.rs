@@ -0,0 +1,449 @@
+use std::collections::BTreeSet;
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::Duration;
+
+use binance::config::Config;
+use chrono::{DateTime, TimeZone, Utc};
+use futures_util::SinkExt;
+use serde_json::json;
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, watch};
+use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+use tokio_tungstenite::tungstenite::Message;
+use trade_core::data::database::in_memory::InMemoryStockDataCache;
+use trade_core::data::database::SharedStockDataCache;
+use trade_core::data::providers::binance::BinanceMarketDataProvider;
+use trade_core::models::decimal::Decimal;
+use trade_core::models::interval::Interval;
+use trade_host::rate_limiter::RateLimiter;
+use trade_host::services::backfill::KlineBackfill;
+use trade_host::services::kline_stream::KlineIngestion;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, Respond, ResponseTemplate};
+
+const MINUTE_MS: i64 = 60 * 1000;
+
+// Serves 1m klines of the given period like the Binance REST API
+struct KlineResponder {
+    listed: Range<i64>,
+    delay: Duration,
+}
+
+impl Respond for KlineResponder {
+    fn respond(&self, request: &wiremock::Request) -> ResponseTemplate {
+        let param = |name: &str| {
+            request
+                .url
+                .query_pairs()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.parse::<i64>().unwrap())
+        };
+        let start = param("startTime").unwrap().max(self.listed.start);
+        let end = param("endTime").unwrap().min(self.listed.end - 1);
+
+        let first = (start + MINUTE_MS - 1) / MINUTE_MS * MINUTE_MS;
+        let klines: Vec<_> = (0..)
+            .map(|i| first + i * MINUTE_MS)
+            .take_while(|open_time| *open_time <= end)
+            .map(|open_time| {
+                json!([
+                    open_time,
+                    "1.0",
+                    "2.0",
+                    "0.5",
+                    "1.5",
+                    "10.0",
+                    open_time + MINUTE_MS - 1,
+                    "15.0",
+                    3,
+                    "5.0",
+                    "7.5",
+                    "0"
+                ])
+            })
+            .collect();
+        ResponseTemplate::new(200)
+            .set_body_json(klines)
+            .set_delay(self.delay)
+    }
+}
+
+fn time(minute: i64) -> DateTime<Utc> {
+    Utc.timestamp_millis_opt(minute * MINUTE_MS).unwrap()
+}
+
+fn kline_message(symbol: &str, minute: i64, closed: bool) -> Message {
+    let open_time = minute * MINUTE_MS;
+    Message::Text(
+        json!({
+            "stream": format!("{}@kline_1m", symbol.to_lowercase()),
+            "data": {
+                "e": "kline",
+                "E": open_time + MINUTE_MS,
+                "s": symbol,
+                "k": {
+                    "t": open_time,
+                    "T": open_time + MINUTE_MS - 1,
+                    "s": symbol,
+                    "i": "1m",
+                    "f": 100,
+                    "L": 200,
+                    "o": "1.0",
+                    "c": "1.5",
+                    "h": "2.0",
+                    "l": "0.5",
+                    "v": "10.0",
+                    "n": 100,
+                    "x": closed,
+                    "q": "15.0",
+                    "V": "5.0",
+                    "Q": "7.5",
+                    "B": "0"
+                }
+            }
+        })
+        .to_string(),
+    )
+}
+
+fn trade_message(symbol: &str, id: u64, price: &str) -> Message {
+    Message::Text(
+        json!({
+            "stream": format!("{}@trade", symbol.to_lowercase()),
+            "data": {
+                "e": "trade",
+                "E": 1_000_000 * MINUTE_MS,
+                "s": symbol,
+                "t": id,
+                "p": price,
+                "q": "0.5",
+                "b": 88,
+                "a": 50,
+                "T": 1_000_000 * MINUTE_MS,
+                "m": true,
+                "M": true
+            }
+        })
+        .to_string(),
+    )
+}
+
+// Stand-in for the Binance stream endpoint, every accepted connection gets the next batch of
+// messages and is dropped afterwards. Reports the requested path of each connection.
+#[allow(clippy::result_large_err)]
+async fn stream_server(batches: Vec<Vec<Message>>) -> (String, mpsc::UnboundedReceiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let (path_sender, path_receiver) = mpsc::unbounded_channel();
+
+    tokio::spawn(async move {
+        for batch in batches {
+            let (stream, _) = listener.accept().await.unwrap();
+            let path_sender = path_sender.clone();
+            let mut socket =
+                tokio_tungstenite::accept_hdr_async(stream, |request: &Request, response| {
+                    path_sender.send(request.uri().to_string()).ok();
+                    Ok::<Response, _>(response)
+                })
+                .await
+                .unwrap();
+            for message in batch {
+                socket.send(message).await.unwrap();
+            }
+            // Simulates a dropped connection
+            drop(socket);
+        }
+        // Keep the last connection attempt pending
+        let _pending = listener.accept().await;
+        std::future::pending::<()>().await;
+    });
+
+    (format!("ws://{}", address), path_receiver)
+}
+
+async fn ingestion(
+    ws_endpoint: String,
+    cache: SharedStockDataCache,
+    backfill_delay: Duration,
+) -> (KlineIngestion, MockServer) {
+    let server = MockServer::start().await;
+    ingestion_with_server(ws_endpoint, cache, backfill_delay, server).await
+}
+
+async fn ingestion_with_server(
+    ws_endpoint: String,
+    cache: SharedStockDataCache,
+    backfill_delay: Duration,
+    server: MockServer,
+) -> (KlineIngestion, MockServer) {
+    Mock::given(method("GET"))
+        .and(path("/api/v3/klines"))
+        .respond_with(KlineResponder {
+            listed: 0..2_000_000 * MINUTE_MS,
+            delay: backfill_delay,
+        })
+        .mount(&server)
+        .await;
+
+    let config = Config::default().set_rest_api_endpoint(server.uri());
+    let provider = BinanceMarketDataProvider::new_with_config(None, None, config);
+    let backfill = KlineBackfill::new(
+        Arc::new(provider),
+        Interval::OneMinute,
+        chrono::Duration::minutes(100),
+        RateLimiter::new(60_000),
+    )
+    .with_retries(0, Duration::from_millis(10));
+
+    let ingestion = KlineIngestion::new(ws_endpoint, Arc::new(backfill), cache)
+        .with_reconnect_delay(Duration::from_millis(10), Duration::from_millis(100))
+        .with_gap_retry_interval(Duration::from_millis(50));
+    (ingestion, server)
+}
+
+#[tokio::test]
+async fn stream_persists_and_broadcasts_closed_klines() {
+    let (ws_endpoint, mut paths) = stream_server(vec![vec![
+        kline_message("BTCUSDT", 1_000_000, false),
+        kline_message("BTCUSDT", 1_000_000, true),
+        kline_message("ETHUSDT", 1_000_000, true),
+        kline_message("BTCUSDT", 1_000_001, true),
+    ]])
+    .await;
+    let cache: SharedStockDataCache = Arc::new(InMemoryStockDataCache::new());
+    let (ingestion, _server) = ingestion(ws_endpoint, Arc::clone(&cache), Duration::ZERO).await;
+    let mut candles = ingestion.subscribe();
+
+    let symbols: BTreeSet<String> = ["BTCUSDT", "ETHUSDT"].map(String::from).into();
+    let (_symbols_sender, symbols_receiver) = watch::channel(symbols);
+    let ingestion = Arc::new(ingestion);
+    let task = tokio::spawn({
+        let ingestion = Arc::clone(&ingestion);
+        async move { ingestion.run(symbols_receiver).await }
+    });
+
+    let mut received = Vec::new();
+    for _ in 0..3 {
+        let (symbol, candle) = tokio::time::timeout(Duration::from_secs(5), candles.recv())
+            .await
+            .expect("No candle received")
+            .unwrap();
+        received.push((symbol, candle.time));
+    }
+    task.abort();
+
+    assert_eq!(
+        vec![
+            ("BTCUSDT".to_string(), time(1_000_000)),
+            ("ETHUSDT".to_string(), time(1_000_000)),
+            ("BTCUSDT".to_string(), time(1_000_001)),
+        ],
+        received
+    );
+    assert_eq!(
+        "/stream?streams=btcusdt@kline_1m/btcusdt@trade/ethusdt@kline_1m/ethusdt@trade",
+        paths.recv().await.unwrap()
+    );
+
+    let stored = cache
+        .get_candles(
+            "BTCUSDT".to_string(),
+            Interval::OneMinute,
+            time(0)..time(2_000_000),
+        )
+        .await
+        .unwrap();
+    assert_eq!(2, stored.len());
+    assert_eq!(Decimal::new(15, 1), stored[0].close);
+}
+
+#[tokio::test]
+async fn gap_after_reconnect_is_backfilled() {
+    let (ws_endpoint, mut paths) = stream_server(vec![
+        vec![kline_message("BTCUSDT", 1_000_000, true)],
+        // Reconnected after missing four candles, the first one is a replay
+        vec![
+            kline_message("BTCUSDT", 1_000_000, true),
+            kline_message("BTCUSDT", 1_000_005, true),
+        ],
+    ])
+    .await;
+    let cache: SharedStockDataCache = Arc::new(InMemoryStockDataCache::new());
+    let (ingestion, server) = ingestion(ws_endpoint, Arc::clone(&cache), Duration::ZERO).await;
+    let mut candles = ingestion.subscribe();
+
+    let symbols: BTreeSet<String> = ["BTCUSDT".to_string()].into();
+    let (_symbols_sender, symbols_receiver) = watch::channel(symbols);
+    let ingestion = Arc::new(ingestion);
+    let task = tokio::spawn({
+        let ingestion = Arc::clone(&ingestion);
+        async move { ingestion.run(symbols_receiver).await }
+    });
+
+    let mut received = Vec::new();
+    for _ in 0..2 {
+        let (_, candle) = tokio::time::timeout(Duration::from_secs(5), candles.recv())
+            .await
+            .expect("No candle received")
+            .unwrap();
+        received.push(candle.time);
+    }
+    task.abort();
+
+    assert_eq!(vec![time(1_000_000), time(1_000_005)], received);
+    assert!(paths.recv().await.is_some());
+    assert!(paths.recv().await.is_some());
+
+    // The gap is backfilled in the background
+    let times = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let times = stored_times(&cache).await;
+            if times.len() == 6 {
+                return times;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("Gap was not backfilled");
+    assert_eq!((1_000_000..=1_000_005).map(time).collect::<Vec<_>>(), times);
+    assert_eq!(1, server.received_requests().await.unwrap().len());
+}
+
+#[tokio::test]
+async fn failed_backfills_are_retried() {
+    let (ws_endpoint, _paths) = stream_server(vec![vec![
+        kline_message("BTCUSDT", 1_000_000, true),
+        kline_message("BTCUSDT", 1_000_005, true),
+    ]])
+    .await;
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/api/v3/klines"))
+        .respond_with(ResponseTemplate::new(500))
+        .up_to_n_times(1)
+        .with_priority(1)
+        .mount(&server)
+        .await;
+    let cache: SharedStockDataCache = Arc::new(InMemoryStockDataCache::new());
+    let (ingestion, server) =
+        ingestion_with_server(ws_endpoint, Arc::clone(&cache), Duration::ZERO, server).await;
+
+    let symbols: BTreeSet<String> = ["BTCUSDT".to_string()].into();
+    let (_symbols_sender, symbols_receiver) = watch::channel(symbols);
+    let task = tokio::spawn(async move { ingestion.run(symbols_receiver).await });
+
+    let times = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let times = stored_times(&cache).await;
+            if times.len() == 6 {
+                return times;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("Gap was not backfilled after the failure");
+    task.abort();
+    assert_eq!((1_000_000..=1_000_005).map(time).collect::<Vec<_>>(), times);
+    assert_eq!(2, server.received_requests().await.unwrap().len());
+}
+
+#[tokio::test]
+async fn trades_are_broadcast() {
+    let (ws_endpoint, _paths) = stream_server(vec![vec![
+        trade_message("BTCUSDT", 1, "20000.1234567890123"),
+        trade_message("ETHUSDT", 2, "1500.5"),
+    ]])
+    .await;
+    let cache: SharedStockDataCache = Arc::new(InMemoryStockDataCache::new());
+    let (ingestion, _server) = ingestion(ws_endpoint, Arc::clone(&cache), Duration::ZERO).await;
+    let mut trades = ingestion.subscribe_trades();
+
+    let symbols: BTreeSet<String> = ["BTCUSDT", "ETHUSDT"].map(String::from).into();
+    let (_symbols_sender, symbols_receiver) = watch::channel(symbols);
+    let task = tokio::spawn(async move { ingestion.run(symbols_receiver).await });
+
+    let mut received = Vec::new();
+    for _ in 0..2 {
+        let (symbol, trade) = tokio::time::timeout(Duration::from_secs(5), trades.recv())
+            .await
+            .expect("No trade received")
+            .unwrap();
+        received.push((symbol, trade.id, trade.price));
+    }
+    task.abort();
+
+    assert_eq!(
+        vec![
+            (
+                "BTCUSDT".to_string(),
+                1,
+                "20000.1234567890123".parse::<Decimal>().unwrap()
+            ),
+            ("ETHUSDT".to_string(), 2, Decimal::new(15005, 1)),
+        ],
+        received
+    );
+    assert!(stored_times(&cache).await.is_empty());
+}
+
+#[tokio::test]
+async fn pings_are_answered_during_backfill() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let ws_endpoint = format!("ws://{}", listener.local_addr().unwrap());
+    let (pong_sender, pong_receiver) = tokio::sync::oneshot::channel();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+        socket
+            .send(kline_message("BTCUSDT", 1_000_000, true))
+            .await
+            .unwrap();
+        socket
+            .send(kline_message("BTCUSDT", 1_000_005, true))
+            .await
+            .unwrap();
+        socket.send(Message::Ping(b"alive".to_vec())).await.unwrap();
+        while let Some(Ok(message)) = futures_util::StreamExt::next(&mut socket).await {
+            if let Message::Pong(payload) = message {
+                pong_sender.send(payload).ok();
+                break;
+            }
+        }
+        std::future::pending::<()>().await;
+    });
+    let cache: SharedStockDataCache = Arc::new(InMemoryStockDataCache::new());
+    let (ingestion, _server) =
+        ingestion(ws_endpoint, Arc::clone(&cache), Duration::from_secs(3)).await;
+
+    let symbols: BTreeSet<String> = ["BTCUSDT".to_string()].into();
+    let (_symbols_sender, symbols_receiver) = watch::channel(symbols);
+    let task = tokio::spawn(async move { ingestion.run(symbols_receiver).await });
+
+    let payload = tokio::time::timeout(Duration::from_secs(1), pong_receiver)
+        .await
+        .expect("Ping was not answered while backfilling")
+        .unwrap();
+    assert_eq!(b"alive".to_vec(), payload);
+    assert_eq!(
+        vec![time(1_000_000), time(1_000_005)],
+        stored_times(&cache).await
+    );
+    task.abort();
+}
+
+async fn stored_times(cache: &SharedStockDataCache) -> Vec<DateTime<Utc>> {
+    cache
+        .get_candles(
+            "BTCUSDT".to_string(),
+            Interval::OneMinute,
+            time(0)..time(2_000_000),
+        )
+        .await
+        .unwrap()
+        .iter()
+        .map(|candle| candle.time)
+        .collect()
+}
@@ -0,0 +1,95 @@
+use std::path::Path;
+
+use rcgen::{Certificate, CertificateParams, DistinguishedName, DnType};
+use tokio::io::AsyncWriteExt;
+use trade_protocol::packets::CertificateBundle;
+
+// Files of the node identity in the certificate directory, all in DER
+pub const CA_CERTIFICATE: &str = "ca_certificate.der";
+pub const CERTIFICATE: &str = "certificate.der";
+pub const PRIVATE_KEY: &str = "private_key.der";
+
+// New key pair and a certificate signing request for it, both in DER
+pub fn certificate_request(name: &str) -> Result<(Vec<u8>, Vec<u8>), rcgen::RcgenError> {
+    let mut params = CertificateParams::new(vec![name.to_string()]);
+    params.distinguished_name = DistinguishedName::new();
+    params.distinguished_name.push(DnType::CommonName, name);
+    let key = Certificate::from_params(params)?;
+    Ok((
+        key.serialize_request_der()?,
+        key.serialize_private_key_der(),
+    ))
+}
+
+// Staged while the identity is replaced, see `store`
+const PENDING_CERTIFICATE: &str = "certificate.der.pending";
+const PENDING_PRIVATE_KEY: &str = "private_key.der.pending";
+
+// Replaces the identity in `directory` with a certificate issued for `private_key`. Key and
+// certificate are staged completely and then moved in key first. `recover` finishes or undoes an
+// interrupted replacement, so the key in place always matches the certificate.
+pub async fn store(
+    directory: &Path,
+    bundle: &CertificateBundle,
+    private_key: &[u8],
+) -> Result<(), std::io::Error> {
+    tokio::fs::create_dir_all(directory).await?;
+    recover(directory).await?;
+    if let Some(root) = bundle.ca_chain.last() {
+        // Left alone if unchanged, it may be provided read-only for enrollment
+        let current = tokio::fs::read(directory.join(CA_CERTIFICATE)).await.ok();
+        if current.as_ref() != Some(root) {
+            write(&directory.join(CA_CERTIFICATE), root).await?;
+        }
+    }
+
+    let pending_private_key = directory.join(PENDING_PRIVATE_KEY);
+    let pending_certificate = directory.join(PENDING_CERTIFICATE);
+    write_synced(&pending_private_key, private_key).await?;
+    write_synced(&pending_certificate, &bundle.certificate).await?;
+    tokio::fs::rename(&pending_private_key, directory.join(PRIVATE_KEY)).await?;
+    tokio::fs::rename(&pending_certificate, directory.join(CERTIFICATE)).await
+}
+
+// Completes a `store` interrupted after the key was moved in, and discards one interrupted
+// before. Called before the identity in `directory` is used.
+pub async fn recover(directory: &Path) -> Result<(), std::io::Error> {
+    let pending_private_key = directory.join(PENDING_PRIVATE_KEY);
+    let pending_certificate = directory.join(PENDING_CERTIFICATE);
+    if pending_private_key.exists() {
+        // The identity in place is still the previous one
+        remove_if_exists(&pending_private_key).await?;
+        remove_if_exists(&pending_certificate).await
+    } else if pending_certificate.exists() {
+        tokio::fs::rename(&pending_certificate, directory.join(CERTIFICATE)).await
+    } else {
+        Ok(())
+    }
+}
+
+// Replaced atomically, so a crash never leaves a truncated file
+async fn write(path: &Path, data: &[u8]) -> Result<(), std::io::Error> {
+    let temporary = path.with_extension("tmp");
+    tokio::fs::write(&temporary, data).await?;
+    tokio::fs::rename(&temporary, path).await
+}
+
+// On disk before it is moved in, readable by the owner only as it may be a key
+async fn write_synced(path: &Path, data: &[u8]) -> Result<(), std::io::Error> {
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .await?;
+    file.write_all(data).await?;
+    file.sync_all().await
+}
+
+async fn remove_if_exists(path: &Path) -> Result<(), std::io::Error> {
+    match tokio::fs::remove_file(path).await {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
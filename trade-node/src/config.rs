@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+
 use serde::Deserialize;
 
 #[derive(Deserialize, Debug, Clone, Copy)]
@@ -12,11 +14,22 @@ pub struct NodeConfig {
     pub environment: NodeEnvironment,
     pub host_address: String,
     pub host_port: u16,
+    // Name in the certificate of the host
+    pub host_name: String,
+    // Certificate and key of the node and the host CA. Nodes without a certificate enroll with
+    // the host, which needs the host CA in there unless verification is skipped.
+    pub cert_path: PathBuf,
+    // Tried in order until one enrolls the node
+    pub enrollment_tokens: Vec<String>,
+    // Common name of the node certificate, the host name if unset
+    pub node_name: Option<String>,
     pub local_address: String,
     pub local_port: u16,
     pub tracing_mode: Option<TracingMode>,
     pub jaeger_agent_endpoint: Option<String>,
     pub jaeger_collector_endpoint: Option<String>,
+    // Accepts any host certificate, only honoured in development
+    pub insecure_skip_host_verification: bool,
 }
 
 impl Default for NodeConfig {
@@ -25,11 +38,16 @@ impl Default for NodeConfig {
             environment: NodeEnvironment::Development,
             host_address: "127.0.0.1".to_string(),
             host_port: 4001,
+            host_name: "localhost".to_string(),
+            cert_path: PathBuf::from("./data/certs"),
+            enrollment_tokens: Vec::new(),
+            node_name: None,
             local_address: "0.0.0.0".to_string(),
             local_port: 4002,
             tracing_mode: None,
             jaeger_agent_endpoint: None,
             jaeger_collector_endpoint: None,
+            insecure_skip_host_verification: false,
         }
     }
 }
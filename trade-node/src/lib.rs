@@ -5,19 +5,27 @@ use std::{
     time::Duration,
 };
 
-use config::NodeConfig;
+use config::{NodeConfig, NodeEnvironment};
 
-use rustls::{client::ServerCertVerifier, ClientConfig, RootCertStore};
+use rustls::{client::ServerCertVerifier, Certificate, ClientConfig, PrivateKey, RootCertStore};
 use tarpc::context;
 use tokio::sync::mpsc::UnboundedReceiver;
-use tracing::{error, info, trace};
-use trade_protocol::client::TradeClient;
+use tracing::{error, info, trace, warn};
+use trade_protocol::{
+    client::{TradeClient, TradeConnection},
+    packets::{EnrollmentRejection, NodeHello},
+    version,
+};
 
 use crate::pyd::PythonDaemon;
+mod certificates;
 pub mod config;
 mod interface;
 mod pyd;
 
+// How often the certificate is checked for renewal
+const CERTIFICATE_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
 pub enum NodeState {
     Initialization,
     InitialTraining,
@@ -39,10 +47,29 @@ impl Node {
     }
 
     pub async fn run(self: Arc<Self>, mut shutdown_recv: UnboundedReceiver<()>) {
-        let this = Arc::clone(&self);
-        let mut client = this.create_client().await;
+        self.ensure_certificate().await;
+        let tls_config = self.tls_config(true).await;
+        let mut client = self.create_client(self.config.local_port, tls_config).await;
 
         let connection = client.connect().await.expect("Failed to create connection");
+        let hello = NodeHello {
+            build_version: env!("CARGO_PKG_VERSION").to_string(),
+            capabilities: version::capabilities(),
+        };
+        match connection.handshake(hello).await {
+            Ok(Ok(host)) => info!(
+                "Host of build {} speaks protocol {} with capabilities {:?}",
+                host.build_version, host.protocol_version, host.capabilities
+            ),
+            Ok(Err(rejection)) => {
+                error!("Host rejected the node: {}", rejection);
+                return;
+            }
+            Err(err) => {
+                error!("Handshake with host failed: {}", err);
+                return;
+            }
+        }
         for i in 0..100 {
             connection
                 .client
@@ -85,43 +112,167 @@ impl Node {
             _ = python_daemon_task => {
                 error!("Python daemon ended prematurely");
             }
+            _ = self.renew_certificate(&connection) => {}
+        }
+    }
+
+    // Enrolls the node with the host unless it has a certificate already
+    async fn ensure_certificate(&self) {
+        let directory = &self.config.cert_path;
+        if directory.exists() {
+            certificates::recover(directory)
+                .await
+                .expect("Failed to recover certificate");
+        }
+        if directory.join(certificates::CERTIFICATE).exists() {
+            return;
+        }
+        assert!(
+            !self.config.enrollment_tokens.is_empty(),
+            "No certificate in {} and no enrollment token",
+            directory.display()
+        );
+
+        let name = self.node_name();
+        info!("Enrolling as node {}", name);
+        let (request, private_key) =
+            certificates::certificate_request(&name).expect("Failed to create certificate request");
+        // Any free port, so the port of the node is not blocked while the endpoint closes
+        let tls_config = self.tls_config(false).await;
+        let client = self.create_client(0, tls_config).await;
+        for token in &self.config.enrollment_tokens {
+            let result = client
+                .enroll(token.clone(), request.clone())
+                .await
+                .expect("Failed to reach host for enrollment");
+            match result {
+                Ok(bundle) => {
+                    certificates::store(directory, &bundle, &private_key)
+                        .await
+                        .expect("Failed to store certificate");
+                    info!("Enrolled as node {}", name);
+                    return;
+                }
+                // Another node may have used the token already
+                Err(EnrollmentRejection::InvalidToken) => continue,
+                Err(rejection) => panic!("Enrollment rejected: {}", rejection),
+            }
+        }
+        panic!("Enrollment rejected: all tokens are invalid");
+    }
+
+    // Renews the certificate over `connection` once it is close to expiry. The new certificate
+    // is used for the next connection.
+    async fn renew_certificate(&self, connection: &TradeConnection) {
+        let directory = &self.config.cert_path;
+        loop {
+            let certificate = tokio::fs::read(directory.join(certificates::CERTIFICATE))
+                .await
+                .unwrap_or_default();
+            if trade_protocol::certificates::needs_renewal(&certificate) {
+                info!("Renewing node certificate");
+                let (request, private_key) = certificates::certificate_request(&self.node_name())
+                    .expect("Failed to create certificate request");
+                let mut ctx = context::current();
+                ctx.deadline += Duration::from_secs(60);
+                match connection.client.renew_certificate(ctx, request).await {
+                    Ok(Ok(bundle)) => {
+                        match certificates::store(directory, &bundle, &private_key).await {
+                            Ok(()) => info!("Renewed node certificate"),
+                            Err(err) => error!("Failed to store renewed certificate: {}", err),
+                        }
+                    }
+                    Ok(Err(rejection)) => warn!("Certificate renewal rejected: {}", rejection),
+                    Err(err) => warn!("Failed to renew certificate: {}", err),
+                }
+            }
+            tokio::time::sleep(CERTIFICATE_CHECK_INTERVAL).await;
         }
     }
 
-    async fn create_client(self: Arc<Self>) -> TradeClient {
+    // Configured name, the host name otherwise
+    fn node_name(&self) -> String {
+        self.config
+            .node_name
+            .clone()
+            .or_else(|| std::env::var("HOSTNAME").ok())
+            .unwrap_or_else(|| "node".to_string())
+    }
+
+    async fn create_client(&self, local_port: u16, tls_config: ClientConfig) -> TradeClient {
         let host_address_value = format!("{}:{}", self.config.host_address, self.config.host_port);
         let host_address = host_address_value
             .to_socket_addrs()
             .expect("Failed to resolve address")
             .next()
             .expect("No host address provided");
-        let local_address_value =
-            format!("{}:{}", self.config.local_address, self.config.local_port);
+        let local_address_value = format!("{}:{}", self.config.local_address, local_port);
 
         let local_address =
             SocketAddr::from_str(&local_address_value).expect("Failed to parse address");
-        let mut tls_config = ClientConfig::builder()
-            .with_safe_defaults()
-            .with_root_certificates(RootCertStore::empty())
-            .with_no_client_auth();
-
-        // TODO: Only allow this in development and use in staging/production a root CA
-        tls_config
-            .dangerous()
-            .set_certificate_verifier(Arc::new(NoVerifier));
-
         info!(
             "Connecting to host {} with local QUIC end point {}",
             host_address_value, local_address_value
         );
-        let client = TradeClient::new(local_address, host_address, "trade-node", tls_config)
-            .await
-            .expect("Failed to initialize trade client");
+        let client = TradeClient::new(
+            local_address,
+            host_address,
+            &self.config.host_name,
+            tls_config,
+        )
+        .await
+        .expect("Failed to initialize trade client");
 
         client
     }
+
+    // Trusts only hosts certified by the host CA, identifies the node with its certificate
+    // if `identify`
+    async fn tls_config(&self, identify: bool) -> ClientConfig {
+        let read = |name: &str| {
+            let path = self.config.cert_path.join(name);
+            async move {
+                tokio::fs::read(&path)
+                    .await
+                    .unwrap_or_else(|err| panic!("Failed to read {}: {}", path.display(), err))
+            }
+        };
+
+        let mut roots = RootCertStore::empty();
+        if !self.config.insecure_skip_host_verification {
+            let ca_certificate = Certificate(read(certificates::CA_CERTIFICATE).await);
+            roots
+                .add(&ca_certificate)
+                .expect("Failed to add host CA certificate");
+        }
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots);
+        let mut tls_config = if identify {
+            let certificate = Certificate(read(certificates::CERTIFICATE).await);
+            let private_key = PrivateKey(read(certificates::PRIVATE_KEY).await);
+            builder
+                .with_single_cert(vec![certificate], private_key)
+                .expect("Failed to use node certificate")
+        } else {
+            builder.with_no_client_auth()
+        };
+
+        if self.config.insecure_skip_host_verification {
+            assert!(
+                self.config.environment == NodeEnvironment::Development,
+                "Host verification can only be skipped in development"
+            );
+            warn!("Not verifying the host certificate");
+            tls_config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(NoVerifier));
+        }
+        tls_config
+    }
 }
 
+// Accepts any host certificate, for development only
 pub struct NoVerifier;
 
 impl ServerCertVerifier for NoVerifier {
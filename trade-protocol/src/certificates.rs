@@ -0,0 +1,57 @@
+use chrono::Utc;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+// Common name of the subject of a DER certificate
+pub fn common_name(certificate: &[u8]) -> Option<String> {
+    let (_, certificate) = X509Certificate::from_der(certificate).ok()?;
+    let common_name = certificate.subject().iter_common_name().next()?;
+    common_name.as_str().ok().map(str::to_string)
+}
+
+// Whether less than a third of the validity of a DER certificate is left. Unreadable
+// certificates need a new one as well.
+pub fn needs_renewal(certificate: &[u8]) -> bool {
+    let validity = match X509Certificate::from_der(certificate) {
+        Ok((_, certificate)) => certificate.validity().clone(),
+        Err(_) => return true,
+    };
+    let not_before = validity.not_before.timestamp();
+    let not_after = validity.not_after.timestamp();
+    Utc::now().timestamp() >= not_after - (not_after - not_before) / 3
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{common_name, needs_renewal};
+    use rcgen::{Certificate, CertificateParams, DistinguishedName, DnType};
+    use time::{Duration, OffsetDateTime};
+
+    fn certificate(valid_since: Duration, valid_for: Duration) -> Vec<u8> {
+        let mut params = CertificateParams::new(vec!["node-1".to_string()]);
+        params.distinguished_name = DistinguishedName::new();
+        params.distinguished_name.push(DnType::CommonName, "node-1");
+        params.not_before = OffsetDateTime::now_utc() - valid_since;
+        params.not_after = params.not_before + valid_for;
+        Certificate::from_params(params)
+            .unwrap()
+            .serialize_der()
+            .unwrap()
+    }
+
+    #[test]
+    fn renewal_starts_in_the_last_third() {
+        let days = Duration::days;
+        assert!(!needs_renewal(&certificate(days(1), days(30))));
+        assert!(!needs_renewal(&certificate(days(19), days(30))));
+        assert!(needs_renewal(&certificate(days(21), days(30))));
+        assert!(needs_renewal(&certificate(days(31), days(30))));
+        assert!(needs_renewal(&[1, 2, 3]));
+    }
+
+    #[test]
+    fn common_name_is_read() {
+        let der = certificate(Duration::days(1), Duration::days(30));
+        assert_eq!(Some("node-1".to_string()), common_name(&der));
+        assert_eq!(None, common_name(&[1, 2, 3]));
+    }
+}
@@ -1,8 +1,12 @@
-use crate::services::FinancialServiceClient;
+use crate::packets::{
+    CertificateBundle, EnrollmentRejection, HandshakeRejection, HostHello, NodeHello,
+};
+use crate::services::{EnrollmentServiceClient, FinancialServiceClient};
+use crate::version;
 use crate::StreamFramer;
 use quinn::ClientConfig;
 use std::{net::SocketAddr, sync::Arc};
-use tarpc::client;
+use tarpc::{client, context};
 use tokio_serde::formats::Bincode;
 use tokio_util::codec::{Framed, LengthDelimitedCodec};
 use tracing::info;
@@ -22,8 +26,9 @@ impl TradeClient {
         local_addr: SocketAddr,
         remote_addr: SocketAddr,
         server_name: &str,
-        rustls_config: rustls::ClientConfig,
+        mut rustls_config: rustls::ClientConfig,
     ) -> Result<TradeClient, Box<dyn std::error::Error>> {
+        rustls_config.alpn_protocols = version::alpn_protocols();
         let endpoint = quinn::Endpoint::client(local_addr)?;
         Ok(TradeClient {
             endpoint,
@@ -86,12 +91,38 @@ impl TradeClient {
 
         if let Some(ref conn) = self.connection {
             info!("Using old connection");
-            Ok(Arc::clone(&conn))
+            Ok(Arc::clone(conn))
         } else {
             unreachable!()
         }
     }
 
+    // Exchanges a one-time token and a DER certificate signing request for a certificate of
+    // the node, on a connection of its own. The TLS config must not present a certificate.
+    pub async fn enroll(
+        &self,
+        token: String,
+        request: Vec<u8>,
+    ) -> Result<Result<CertificateBundle, EnrollmentRejection>, Box<dyn std::error::Error>> {
+        let client_config = ClientConfig::new(Arc::new(self.rustls_config.clone()));
+        let quinn::NewConnection {
+            connection: conn, ..
+        } = self
+            .endpoint
+            .connect_with(client_config, self.remote_addr, &self.server_name)?
+            .instrument(tracing::info_span!("Establishing enrollment connection"))
+            .await?;
+        let (send, recv) = conn.open_bi().await?;
+
+        let codec = LengthDelimitedCodec::new();
+        let framed = Framed::new(StreamFramer { write: send, recv }, codec);
+        let transport = tarpc::serde_transport::new(framed, Bincode::default());
+        let client = EnrollmentServiceClient::new(client::Config::default(), transport).spawn();
+        let result = client.enroll(context::current(), token, request).await;
+        conn.close(0u32.into(), b"Enrollment done");
+        Ok(result?)
+    }
+
     pub async fn close(&mut self) {
         self.endpoint
             .close(0u32.into(), b"Graceful connection disposal");
@@ -118,7 +149,32 @@ pub struct TradeConnection {
     //current_request_id: Arc<AtomicU64>,
 }
 
-impl TradeConnection {}
+impl TradeConnection {
+    // Certificate the host presented when the connection was established
+    pub fn host_certificate(&self) -> Option<rustls::Certificate> {
+        let certificates = self
+            .conn
+            .peer_identity()?
+            .downcast::<Vec<rustls::Certificate>>()
+            .ok()?;
+        certificates.first().cloned()
+    }
+
+    // Announces this build to the host, which may reject it and close the connection
+    pub async fn handshake(
+        &self,
+        hello: NodeHello,
+    ) -> Result<Result<HostHello, HandshakeRejection>, Box<dyn std::error::Error>> {
+        match self.client.handshake(context::current(), hello).await {
+            Ok(result) => Ok(result),
+            // The close reason of the host explains more than a failed request
+            Err(err) => match self.conn.open_bi().await {
+                Err(closed) => Err(closed.into()),
+                Ok(_) => Err(err.into()),
+            },
+        }
+    }
+}
 
 impl Drop for TradeConnection {
     fn drop(&mut self) {
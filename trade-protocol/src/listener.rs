@@ -1,18 +1,30 @@
 use crate::{
+    certificates,
     //    encoding::{self, EncodedPacket},
     packets::{AllocationResponsePacketData, HeartbeatPacketData},
-    services::{FinancialServer, FinancialService, FinancialServiceHandler},
+    services::{
+        EnrollmentServer, EnrollmentService, EnrollmentServiceHandler, FinancialServer,
+        FinancialService, FinancialServiceHandler,
+    },
+    version,
     StreamFramer,
 };
 use async_trait::async_trait;
 //use chrono::Utc;
 use futures_util::stream::StreamExt;
 use quinn::{Connection, ServerConfig};
+use rustls::{
+    server::{AllowAnyAnonymousOrAuthenticatedClient, ClientHello, ResolvesServerCert},
+    sign::{self, CertifiedKey},
+    RootCertStore,
+};
 //use quinn::{RecvStream, SendStream, ServerConfig};
 //use serde::{Deserialize, Serialize};
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    net::SocketAddr,
+    sync::{atomic::AtomicBool, Arc, RwLock},
+};
 use tarpc::server::{self, Channel};
-use tokio::sync::Mutex;
 use tokio_serde::formats::Bincode;
 use tokio_util::codec::length_delimited::LengthDelimitedCodec;
 use tokio_util::codec::Framed;
@@ -36,36 +48,99 @@ pub trait TradeListenerHandler: Send {
     ) -> Result<AllocationResponsePacketData, Box<dyn std::error::Error + Send + Sync>>;
 }
 
+// Certificate presented in new handshakes, which can be replaced while listening. Established
+// connections keep the certificate they were opened with.
+pub struct CertificateResolver {
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertificateResolver {
+    pub fn new(
+        certs: Vec<rustls::Certificate>,
+        key: rustls::PrivateKey,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(CertificateResolver {
+            current: RwLock::new(Arc::new(certified_key(certs, key)?)),
+        })
+    }
+
+    // Swaps certificate and key at once, invalid material leaves the current one in place
+    pub fn update(
+        &self,
+        certs: Vec<rustls::Certificate>,
+        key: rustls::PrivateKey,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let certified_key = Arc::new(certified_key(certs, key)?);
+        *self.current.write().unwrap() = certified_key;
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(Arc::clone(&self.current.read().unwrap()))
+    }
+}
+
+fn certified_key(
+    certs: Vec<rustls::Certificate>,
+    key: rustls::PrivateKey,
+) -> Result<CertifiedKey, Box<dyn std::error::Error>> {
+    if certs.is_empty() {
+        return Err("No certificate given".into());
+    }
+    let key = sign::any_supported_type(&key).map_err(|_| "Unsupported private key")?;
+    Ok(CertifiedKey::new(certs, key))
+}
+
 pub struct TradeListener {
     server_config: ServerConfig,
+    resolver: Arc<CertificateResolver>,
 }
 
 impl TradeListener {
+    // Nodes presenting a client certificate issued by one of `client_roots` are served, all
+    // others may only enroll
     pub fn new(
         certs: Vec<rustls::Certificate>,
         key: rustls::PrivateKey,
+        client_roots: Vec<rustls::Certificate>,
     ) -> Result<Self, Box<dyn std::error::Error>> {
-        let server_crypto = rustls::ServerConfig::builder()
+        let mut roots = RootCertStore::empty();
+        for root in &client_roots {
+            roots.add(root)?;
+        }
+        let resolver = Arc::new(CertificateResolver::new(certs, key)?);
+        let mut server_crypto = rustls::ServerConfig::builder()
             .with_safe_defaults()
-            .with_no_client_auth()
-            .with_single_cert(certs, key)?;
-        //server_crypto.alpn_protocols = common::ALPN_QUIC_HTTP.iter().map(|&x| x.into()).collect();
+            .with_client_cert_verifier(AllowAnyAnonymousOrAuthenticatedClient::new(roots))
+            .with_cert_resolver(Arc::clone(&resolver) as Arc<dyn ResolvesServerCert>);
+        server_crypto.alpn_protocols = version::alpn_protocols();
 
         let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(server_crypto));
         Arc::get_mut(&mut server_config.transport)
             .unwrap()
             .max_concurrent_uni_streams(0_u8.into());
-        Ok(TradeListener { server_config })
+        Ok(TradeListener {
+            server_config,
+            resolver,
+        })
+    }
+
+    // Replaces the certificate of the listener, also once it is listening
+    pub fn resolver(&self) -> Arc<CertificateResolver> {
+        Arc::clone(&self.resolver)
     }
 
     pub async fn listen<
-        'a,
         H: 'static + Send + FinancialServiceHandler + Sync,
-        F: 'static + Send + Fn(Arc<Connection>) -> Arc<H> + std::marker::Sync,
+        F: 'static + Send + Fn(Arc<Connection>, String) -> Arc<H> + std::marker::Sync,
+        E: 'static + Send + EnrollmentServiceHandler + Sync,
     >(
         &mut self,
         addr: SocketAddr,
         create_handler: Arc<F>,
+        enrollment_handler: Arc<E>,
     ) -> Result<(), Box<dyn std::error::Error>> {
         let (endpoint, mut incoming) = quinn::Endpoint::server(self.server_config.clone(), addr)?;
         info!("listening on {}", endpoint.local_addr()?);
@@ -73,7 +148,11 @@ impl TradeListener {
         while let Some(conn) = incoming.next().await {
             info!("Incoming connection from {}", conn.remote_address());
 
-            let fut = handle_connection(conn, Arc::clone(&create_handler));
+            let fut = handle_connection(
+                conn,
+                Arc::clone(&create_handler),
+                Arc::clone(&enrollment_handler),
+            );
             tokio::spawn(async move {
                 if let Err(e) = fut.await {
                     error!("connection failed: {reason}", reason = e.to_string())
@@ -85,29 +164,63 @@ impl TradeListener {
     }
 }
 
+// Common name of the verified client certificate, identifying the node
+pub fn peer_name(connection: &Connection) -> Option<String> {
+    let certificates = connection
+        .peer_identity()?
+        .downcast::<Vec<rustls::Certificate>>()
+        .ok()?;
+    certificates::common_name(&certificates.first()?.0)
+}
+
 async fn handle_connection<
     H: FinancialServiceHandler + Send + Sync + 'static,
-    F: Send + Fn(Arc<Connection>) -> Arc<H>,
+    F: Send + Fn(Arc<Connection>, String) -> Arc<H>,
+    E: EnrollmentServiceHandler + Send + Sync + 'static,
 >(
     conn: quinn::Connecting,
     create_handler: Arc<F>,
+    enrollment_handler: Arc<E>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let quinn::NewConnection {
         connection,
         mut bi_streams,
         ..
     } = conn.await?;
+    // QUIC requires ALPN, so clients without one of our protocols already fail in TLS. Guards
+    // against the TLS config ever accepting others.
+    let protocol_version = match version::negotiated_version(&connection) {
+        Some(protocol_version) => protocol_version,
+        None => {
+            let reason = format!(
+                "Unsupported protocol, expected one of {}",
+                version::alpn_protocols()
+                    .iter()
+                    .map(|protocol| String::from_utf8_lossy(protocol).into_owned())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+            connection.close(version::CLOSE_INCOMPATIBLE.into(), reason.as_bytes());
+            return Err(reason.into());
+        }
+    };
+    // Nodes without certificate are still enrolling
+    let node_name = peer_name(&connection);
+    if node_name.is_none() && connection.peer_identity().is_some() {
+        connection.close(
+            version::CLOSE_INVALID_CERTIFICATE.into(),
+            b"Client certificate without common name",
+        );
+        return Err("Client certificate without common name".into());
+    }
     let span = info_span!(
         "connection",
         remote = %connection.remote_address(),
-        protocol = %connection
-            .handshake_data()
-            .unwrap()
-            .downcast::<quinn::crypto::rustls::HandshakeData>().unwrap()
-            .protocol
-            .map_or_else(|| "<none>".into(), |x| String::from_utf8_lossy(&x).into_owned())
+        node = %node_name.as_deref().unwrap_or("<enrolling>"),
+        protocol = %protocol_version
     );
     let connection_arc = Arc::new(connection);
+    let handshaken = Arc::new(AtomicBool::new(false));
     //tarpc::serde_transport::new(, codec);
     async {
         info!("established");
@@ -134,17 +247,27 @@ async fn handle_connection<
                 codec,
             );
             info!("established bi-stream");
-            let transport = tarpc::serde_transport::new(framed, Bincode::default());
-            info!("Initialized transport");
-            let channel = server::BaseChannel::with_defaults(transport);
-            info!("Initialized channel");
-            let handler = create_handler(connection_arc.clone());
-            info!("Initialized handler");
-            let server = FinancialServer(connection_arc.clone(), handler);
-            info!("Serving requests");
-            tokio::spawn(async move {
-                channel.execute(server.serve());
-            });
+            match &node_name {
+                Some(node_name) => {
+                    let transport = tarpc::serde_transport::new(framed, Bincode::default());
+                    info!("Initialized transport");
+                    let channel = server::BaseChannel::with_defaults(transport);
+                    info!("Initialized channel");
+                    let handler = create_handler(connection_arc.clone(), node_name.clone());
+                    info!("Initialized handler");
+                    let server =
+                        FinancialServer(connection_arc.clone(), handler, Arc::clone(&handshaken));
+                    info!("Serving requests");
+                    tokio::spawn(channel.execute(server.serve()));
+                }
+                None => {
+                    let transport = tarpc::serde_transport::new(framed, Bincode::default());
+                    let channel = server::BaseChannel::with_defaults(transport);
+                    let server = EnrollmentServer(Arc::clone(&enrollment_handler));
+                    info!("Serving enrollment requests");
+                    tokio::spawn(channel.execute(server.serve()));
+                }
+            }
             /*
             //tokio::spawn(fut);
             let fut = handle_request(Arc::clone(&connection_arc), stream, Arc::clone(&handler));
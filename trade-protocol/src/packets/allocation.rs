@@ -54,6 +54,7 @@ mod tests {
 
         let obtained_bytes = bincode::serialize(&packet).unwrap();
         let expected_bytes: Vec<u8> = vec![
+            1, // Some
             4, 0, 0, 0, 0, 0, 0, 0,  // Length
             65, // A
             65, // A
@@ -67,6 +68,7 @@ mod tests {
     #[test]
     fn deserialization_works_resp() {
         let bytes = vec![
+            1, // Some
             4, 0, 0, 0, 0, 0, 0, 0,  // Length
             65, // A
             65, // A
@@ -0,0 +1,75 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use super::PacketData;
+
+// Client certificate issued to a node and the chain up to the host CA, all in DER
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CertificateBundle {
+    pub certificate: Vec<u8>,
+    // Ordered from the issuer of `certificate` to the root
+    pub ca_chain: Vec<Vec<u8>>,
+}
+
+impl PacketData for CertificateBundle {
+    fn id() -> u8 {
+        4
+    }
+}
+
+// Why the host refused to issue a certificate
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EnrollmentRejection {
+    // Unknown or already used enrollment token
+    InvalidToken,
+    InvalidRequest { reason: String },
+    // Another node is enrolled with the name
+    NameTaken { name: String },
+    // The host cannot issue certificates right now
+    Unavailable,
+}
+
+impl fmt::Display for EnrollmentRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EnrollmentRejection::InvalidToken => write!(f, "invalid enrollment token"),
+            EnrollmentRejection::InvalidRequest { reason } => {
+                write!(f, "invalid certificate request: {}", reason)
+            }
+            EnrollmentRejection::NameTaken { name } => {
+                write!(f, "node name {} is already taken", name)
+            }
+            EnrollmentRejection::Unavailable => write!(f, "enrollment is unavailable"),
+        }
+    }
+}
+
+impl PacketData for EnrollmentRejection {
+    fn id() -> u8 {
+        5
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CertificateBundle, EnrollmentRejection};
+
+    #[test]
+    fn round_trip_works() {
+        let bundle = CertificateBundle {
+            certificate: vec![1, 2, 3],
+            ca_chain: vec![vec![4, 5]],
+        };
+        let bytes = bincode::serialize(&bundle).unwrap();
+        assert_eq!(bundle, bincode::deserialize(&bytes).unwrap());
+
+        let rejection = EnrollmentRejection::NameTaken {
+            name: "node-1".to_string(),
+        };
+        let bytes = bincode::serialize(&rejection).unwrap();
+        let obtained: EnrollmentRejection = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(rejection, obtained);
+        assert_eq!("node name node-1 is already taken", obtained.to_string());
+    }
+}
@@ -0,0 +1,81 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use super::PacketData;
+
+// First request of a node on a new connection
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeHello {
+    pub build_version: String,
+    pub capabilities: Vec<String>,
+}
+
+impl PacketData for NodeHello {
+    fn id() -> u8 {
+        6
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HostHello {
+    pub build_version: String,
+    // Negotiated over ALPN
+    pub protocol_version: u32,
+    pub capabilities: Vec<String>,
+}
+
+impl PacketData for HostHello {
+    fn id() -> u8 {
+        7
+    }
+}
+
+// Why the host refused a node, the connection is closed afterwards
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HandshakeRejection {
+    // Capabilities the host depends on but the node lacks
+    MissingCapabilities { missing: Vec<String> },
+}
+
+impl fmt::Display for HandshakeRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeRejection::MissingCapabilities { missing } => {
+                write!(f, "node lacks capabilities {}", missing.join(", "))
+            }
+        }
+    }
+}
+
+impl PacketData for HandshakeRejection {
+    fn id() -> u8 {
+        8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HandshakeRejection, NodeHello};
+
+    #[test]
+    fn round_trip_works() {
+        let hello = NodeHello {
+            build_version: "0.1.0".to_string(),
+            capabilities: vec!["candles".to_string()],
+        };
+        let bytes = bincode::serialize(&hello).unwrap();
+        assert_eq!(hello, bincode::deserialize(&bytes).unwrap());
+
+        let rejection = HandshakeRejection::MissingCapabilities {
+            missing: vec!["orders".to_string(), "candles".to_string()],
+        };
+        let bytes = bincode::serialize(&rejection).unwrap();
+        let obtained: HandshakeRejection = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(rejection, obtained);
+        assert_eq!(
+            "node lacks capabilities orders, candles",
+            obtained.to_string()
+        );
+    }
+}
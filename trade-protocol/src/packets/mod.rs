@@ -9,3 +9,12 @@ pub use heartbeat::HeartbeatPacketData;
 
 mod allocation;
 pub use allocation::*;
+
+mod order;
+pub use order::OrderRejection;
+
+mod enrollment;
+pub use enrollment::{CertificateBundle, EnrollmentRejection};
+
+mod handshake;
+pub use handshake::{HandshakeRejection, HostHello, NodeHello};
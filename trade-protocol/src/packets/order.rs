@@ -0,0 +1,146 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use trade_core::models::decimal::Decimal;
+
+use super::PacketData;
+
+// Why the host refused an order of a node
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OrderRejection {
+    KillSwitch,
+    NotAllocated {
+        symbol: String,
+    },
+    InvalidQuantity,
+    RateLimit {
+        max_orders_per_minute: u32,
+    },
+    // No candle of the symbol seen yet, so the order cannot be valued
+    NoReferencePrice {
+        symbol: String,
+    },
+    PriceBand {
+        price: Decimal,
+        reference: Decimal,
+        max_deviation_bps: u32,
+    },
+    OrderNotional {
+        notional: Decimal,
+        limit: Decimal,
+    },
+    PositionLimit {
+        // Notional of the position after the order
+        notional: Decimal,
+        limit: Decimal,
+    },
+    DailyLossLimit {
+        loss: Decimal,
+        limit: Decimal,
+    },
+    // Another order of the host uses the client order id
+    DuplicateOrderId,
+    // The exchange refused the order
+    Exchange {
+        reason: String,
+    },
+    // The exchange does not list the symbol
+    UnknownSymbol {
+        symbol: String,
+    },
+    // Rounded down to the lot size
+    BelowMinQuantity {
+        quantity: Decimal,
+        min_quantity: Decimal,
+    },
+    // The host is still waiting for the exchange after a start
+    Starting,
+    // Sent before the handshake of the connection
+    HandshakeRequired,
+}
+
+impl fmt::Display for OrderRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OrderRejection::KillSwitch => write!(f, "kill switch engaged"),
+            OrderRejection::NotAllocated { symbol } => {
+                write!(f, "{} is not allocated to the node", symbol)
+            }
+            OrderRejection::InvalidQuantity => write!(f, "quantity is not positive"),
+            OrderRejection::RateLimit {
+                max_orders_per_minute,
+            } => write!(f, "more than {} orders per minute", max_orders_per_minute),
+            OrderRejection::NoReferencePrice { symbol } => {
+                write!(f, "no reference price for {}", symbol)
+            }
+            OrderRejection::PriceBand {
+                price,
+                reference,
+                max_deviation_bps,
+            } => write!(
+                f,
+                "price {} deviates more than {} bps from {}",
+                price, max_deviation_bps, reference
+            ),
+            OrderRejection::OrderNotional { notional, limit } => {
+                write!(f, "order notional {} exceeds {}", notional, limit)
+            }
+            OrderRejection::PositionLimit { notional, limit } => {
+                write!(f, "position notional {} would exceed {}", notional, limit)
+            }
+            OrderRejection::DailyLossLimit { loss, limit } => {
+                write!(f, "daily loss {} reached the limit of {}", loss, limit)
+            }
+            OrderRejection::DuplicateOrderId => write!(f, "client order id is already used"),
+            OrderRejection::Exchange { reason } => write!(f, "exchange rejected: {}", reason),
+            OrderRejection::UnknownSymbol { symbol } => {
+                write!(f, "{} is not listed by the exchange", symbol)
+            }
+            OrderRejection::BelowMinQuantity {
+                quantity,
+                min_quantity,
+            } => write!(
+                f,
+                "quantity {} is below the minimum of {}",
+                quantity, min_quantity
+            ),
+            OrderRejection::Starting => write!(f, "host is still starting"),
+            OrderRejection::HandshakeRequired => write!(f, "handshake required before orders"),
+        }
+    }
+}
+
+impl PacketData for OrderRejection {
+    fn id() -> u8 {
+        3
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::OrderRejection;
+    use trade_core::models::decimal::Decimal;
+
+    #[test]
+    fn round_trip_works() {
+        let rejection = OrderRejection::OrderNotional {
+            notional: Decimal::new(150_005, 1),
+            limit: Decimal::from(10_000),
+        };
+
+        let bytes = bincode::serialize(&rejection).unwrap();
+        let obtained: OrderRejection = bincode::deserialize(&bytes).unwrap();
+
+        assert_eq!(rejection, obtained);
+        assert_eq!("order notional 15000.5 exceeds 10000", obtained.to_string());
+    }
+
+    #[test]
+    fn missing_handshakes_are_told_apart() {
+        let bytes = bincode::serialize(&OrderRejection::HandshakeRequired).unwrap();
+        let obtained: OrderRejection = bincode::deserialize(&bytes).unwrap();
+
+        assert_eq!(OrderRejection::HandshakeRequired, obtained);
+        assert_eq!("handshake required before orders", obtained.to_string());
+    }
+}
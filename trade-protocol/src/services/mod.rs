@@ -1,39 +1,201 @@
-use std::sync::Arc;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use quinn::Connection;
 use tarpc::context;
+use trade_core::models::{candle::Candle, order::Order};
+
+use crate::packets::{
+    CertificateBundle, EnrollmentRejection, HandshakeRejection, HostHello, NodeHello,
+    OrderRejection,
+};
+use crate::version;
+
+// Rejected nodes are disconnected once they had the chance to receive the rejection
+const REJECTED_CLOSE_DELAY: Duration = Duration::from_secs(1);
 
 #[tarpc::service]
 pub trait FinancialService {
+    // Exchanges build versions and capabilities, sent by nodes before any other request
+    async fn handshake(hello: NodeHello) -> Result<HostHello, HandshakeRejection>;
     async fn hello(name: String) -> String;
     async fn send_heartbeat();
-    async fn request_allocation() -> String;
+    async fn request_allocation() -> Option<String>;
+    // Waits for the next closed candles of the allocated symbols, empty on timeout
+    async fn next_candles() -> Vec<(String, Candle)>;
+    // Passes the order through the risk checks of the host
+    async fn submit_order(order: Order) -> Result<(), OrderRejection>;
+    // Whether the cancel was sent to the exchange, false for unknown or finished orders
+    async fn cancel_order(client_order_id: String) -> bool;
+    // New certificate of the node for the key of `request`, a DER certificate signing request
+    async fn renew_certificate(request: Vec<u8>) -> Result<CertificateBundle, EnrollmentRejection>;
 }
 
 #[async_trait::async_trait]
 pub trait FinancialServiceHandler {
+    async fn handshake(self: Arc<Self>, hello: NodeHello) -> Result<HostHello, HandshakeRejection>;
     async fn hello(self: Arc<Self>, name: String) -> String;
     async fn send_heartbeat(self: Arc<Self>);
-    async fn request_allocation(self: Arc<Self>) -> String;
+    async fn request_allocation(self: Arc<Self>) -> Option<String>;
+    async fn next_candles(self: Arc<Self>) -> Vec<(String, Candle)>;
+    async fn submit_order(self: Arc<Self>, order: Order) -> Result<(), OrderRejection>;
+    async fn cancel_order(self: Arc<Self>, client_order_id: String) -> bool;
+    async fn renew_certificate(
+        self: Arc<Self>,
+        request: Vec<u8>,
+    ) -> Result<CertificateBundle, EnrollmentRejection>;
 }
 
-#[derive(Clone)]
+// Serves a connection with the handler, the flag tells whether the node completed the
+// handshake and is shared by all streams of the connection
 pub struct FinancialServer<H: FinancialServiceHandler + Send + 'static + std::marker::Sync>(
     pub Arc<Connection>,
     pub Arc<H>,
+    pub Arc<AtomicBool>,
 );
 
+// Derived `Clone` would require `H: Clone`, but only the `Arc`s are cloned
+impl<H: FinancialServiceHandler + Send + 'static + std::marker::Sync> Clone for FinancialServer<H> {
+    fn clone(&self) -> Self {
+        FinancialServer(
+            Arc::clone(&self.0),
+            Arc::clone(&self.1),
+            Arc::clone(&self.2),
+        )
+    }
+}
+
+impl<H: FinancialServiceHandler + Send + 'static + std::marker::Sync> FinancialServer<H> {
+    // Requests before a successful handshake are not served and close the connection, so
+    // their responses never arrive
+    fn handshaken(&self) -> bool {
+        let handshaken = self.2.load(Ordering::Acquire);
+        if !handshaken {
+            self.0.close(
+                version::CLOSE_HANDSHAKE_REQUIRED.into(),
+                b"Handshake required before other requests",
+            );
+        }
+        handshaken
+    }
+}
+
 #[tarpc::server]
 impl<H: FinancialServiceHandler + Send + 'static + std::marker::Sync> FinancialService
     for FinancialServer<H>
 {
+    async fn handshake(
+        self,
+        _: context::Context,
+        hello: NodeHello,
+    ) -> Result<HostHello, HandshakeRejection> {
+        let result = self.1.handshake(hello).await;
+        if result.is_ok() {
+            self.2.store(true, Ordering::Release);
+        } else if let Err(rejection) = &result {
+            let connection = Arc::clone(&self.0);
+            let reason = format!("Incompatible node: {}", rejection);
+            tokio::spawn(async move {
+                tokio::time::sleep(REJECTED_CLOSE_DELAY).await;
+                connection.close(version::CLOSE_INCOMPATIBLE.into(), reason.as_bytes());
+            });
+        }
+        result
+    }
     async fn hello(self, _: context::Context, name: String) -> String {
+        if !self.handshaken() {
+            return String::new();
+        }
         self.1.hello(name).await
     }
     async fn send_heartbeat(self, _: context::Context) {
+        if !self.handshaken() {
+            return;
+        }
         self.1.send_heartbeat().await
     }
-    async fn request_allocation(self, _: context::Context) -> String {
+    async fn request_allocation(self, _: context::Context) -> Option<String> {
+        if !self.handshaken() {
+            return None;
+        }
         self.1.request_allocation().await
     }
+    async fn next_candles(self, _: context::Context) -> Vec<(String, Candle)> {
+        if !self.handshaken() {
+            return Vec::new();
+        }
+        self.1.next_candles().await
+    }
+    async fn submit_order(self, _: context::Context, order: Order) -> Result<(), OrderRejection> {
+        if !self.handshaken() {
+            return Err(OrderRejection::HandshakeRequired);
+        }
+        self.1.submit_order(order).await
+    }
+    async fn cancel_order(self, _: context::Context, client_order_id: String) -> bool {
+        if !self.handshaken() {
+            return false;
+        }
+        self.1.cancel_order(client_order_id).await
+    }
+    async fn renew_certificate(
+        self,
+        _: context::Context,
+        request: Vec<u8>,
+    ) -> Result<CertificateBundle, EnrollmentRejection> {
+        if !self.handshaken() {
+            return Err(EnrollmentRejection::Unavailable);
+        }
+        self.1.renew_certificate(request).await
+    }
+}
+
+// Only service of nodes connecting without a client certificate
+#[tarpc::service]
+pub trait EnrollmentService {
+    // Exchanges a one-time token and a DER certificate signing request for a certificate
+    async fn enroll(
+        token: String,
+        request: Vec<u8>,
+    ) -> Result<CertificateBundle, EnrollmentRejection>;
+}
+
+#[async_trait::async_trait]
+pub trait EnrollmentServiceHandler {
+    async fn enroll(
+        self: Arc<Self>,
+        token: String,
+        request: Vec<u8>,
+    ) -> Result<CertificateBundle, EnrollmentRejection>;
+}
+
+pub struct EnrollmentServer<H: EnrollmentServiceHandler + Send + 'static + std::marker::Sync>(
+    pub Arc<H>,
+);
+
+impl<H: EnrollmentServiceHandler + Send + 'static + std::marker::Sync> Clone
+    for EnrollmentServer<H>
+{
+    fn clone(&self) -> Self {
+        EnrollmentServer(Arc::clone(&self.0))
+    }
+}
+
+#[tarpc::server]
+impl<H: EnrollmentServiceHandler + Send + 'static + std::marker::Sync> EnrollmentService
+    for EnrollmentServer<H>
+{
+    async fn enroll(
+        self,
+        _: context::Context,
+        token: String,
+        request: Vec<u8>,
+    ) -> Result<CertificateBundle, EnrollmentRejection> {
+        self.0.enroll(token, request).await
+    }
 }
@@ -0,0 +1,76 @@
+use quinn::Connection;
+
+// Version of the RPC interface, bumped on incompatible changes. It is negotiated over ALPN, so
+// hosts and nodes can be rolled out independently as long as they share a version.
+pub const PROTOCOL_VERSION: u32 = 1;
+// Versions this build speaks, preferred first
+pub const SUPPORTED_VERSIONS: &[u32] = &[PROTOCOL_VERSION];
+
+const ALPN_PREFIX: &str = "deeptrading/";
+
+// Optional features announced in the handshake. Peers ignore names they do not know, so adding
+// one never breaks older builds.
+pub const CAPABILITY_CANDLES: &str = "candles";
+pub const CAPABILITY_ORDERS: &str = "orders";
+pub const CAPABILITY_CERTIFICATE_RENEWAL: &str = "certificate-renewal";
+
+// Application close codes
+pub const CLOSE_INVALID_CERTIFICATE: u32 = 1;
+pub const CLOSE_INCOMPATIBLE: u32 = 2;
+pub const CLOSE_HANDSHAKE_REQUIRED: u32 = 3;
+
+// Capabilities implemented by this build of the protocol
+pub fn capabilities() -> Vec<String> {
+    [
+        CAPABILITY_CANDLES,
+        CAPABILITY_ORDERS,
+        CAPABILITY_CERTIFICATE_RENEWAL,
+    ]
+    .iter()
+    .map(|capability| capability.to_string())
+    .collect()
+}
+
+pub fn alpn_protocol(version: u32) -> Vec<u8> {
+    format!("{}{}", ALPN_PREFIX, version).into_bytes()
+}
+
+// ALPN identifiers of all supported versions, in order of preference
+pub fn alpn_protocols() -> Vec<Vec<u8>> {
+    SUPPORTED_VERSIONS
+        .iter()
+        .map(|version| alpn_protocol(*version))
+        .collect()
+}
+
+pub fn parse_alpn_protocol(protocol: &[u8]) -> Option<u32> {
+    std::str::from_utf8(protocol)
+        .ok()?
+        .strip_prefix(ALPN_PREFIX)?
+        .parse()
+        .ok()
+}
+
+// Protocol version agreed on in the TLS handshake of `connection`
+pub fn negotiated_version(connection: &Connection) -> Option<u32> {
+    let protocol = connection
+        .handshake_data()?
+        .downcast::<quinn::crypto::rustls::HandshakeData>()
+        .ok()?
+        .protocol?;
+    parse_alpn_protocol(&protocol).filter(|version| SUPPORTED_VERSIONS.contains(version))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{alpn_protocol, alpn_protocols, parse_alpn_protocol, PROTOCOL_VERSION};
+
+    #[test]
+    fn alpn_identifiers_round_trip() {
+        assert_eq!(b"deeptrading/1".to_vec(), alpn_protocol(1));
+        assert_eq!(vec![alpn_protocol(PROTOCOL_VERSION)], alpn_protocols());
+        assert_eq!(Some(12), parse_alpn_protocol(b"deeptrading/12"));
+        assert_eq!(None, parse_alpn_protocol(b"h3"));
+        assert_eq!(None, parse_alpn_protocol(b"deeptrading/"));
+    }
+}
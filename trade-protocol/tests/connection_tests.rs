@@ -9,60 +9,105 @@ use std::time::Duration;
 use async_trait::async_trait;
 use futures_util::future::AbortHandle;
 use futures_util::future::Abortable;
-use rcgen::generate_simple_self_signed;
-use rustls::client::ServerCertVerifier;
+use rcgen::{BasicConstraints, CertificateParams, DistinguishedName, DnType, IsCa};
 use rustls::RootCertStore;
 use tarpc::context;
-use tracing::{info, trace};
+use tracing::info;
 use tracing_test::traced_test;
+use trade_core::models::candle::Candle;
+use trade_core::models::order::Order;
 use trade_protocol::client::TradeClient;
-use trade_protocol::listener::TradeListener;
-use trade_protocol::listener::TradeListenerHandler;
-use trade_protocol::packets::AllocationResponsePacketData;
-use trade_protocol::services::FinancialServiceHandler;
+use trade_protocol::listener::{CertificateResolver, TradeListener};
+use trade_protocol::packets::{
+    CertificateBundle, EnrollmentRejection, HandshakeRejection, HostHello, NodeHello,
+    OrderRejection,
+};
+use trade_protocol::services::{EnrollmentServiceHandler, FinancialServiceHandler};
+use trade_protocol::version;
 
-#[tokio::test]
-#[traced_test]
-async fn connection_works() {
-    let server_name = "test-server";
-    let generated_cert = generate_simple_self_signed(vec![server_name.into()])
-        .expect("Failed to generate certificate");
-    info!("Generated cert");
+// QUIC crypto error carrying the TLS no_application_protocol alert
+const NO_APPLICATION_PROTOCOL: u64 = 0x100 + 120;
 
-    let mut cert_store = RootCertStore::empty();
+// Root, host certificate and key, node certificate and key, as issued by the host
+struct Certificates {
+    root: rustls::Certificate,
+    server: (rustls::Certificate, rustls::PrivateKey),
+    node: (rustls::Certificate, rustls::PrivateKey),
+}
 
-    let key = rustls::PrivateKey(generated_cert.serialize_private_key_der());
-    let cert = rustls::Certificate(
-        generated_cert
-            .serialize_der()
-            .expect("Failed to serialize certificate"),
-    );
-    cert_store
-        .add(&cert)
-        .expect("Failed to add certificate to store");
-    info!("Created cert store");
+fn issue_certificates(server_name: &str, node_name: &str) -> Certificates {
+    issue_certificates_by(&new_root(), server_name, node_name)
+}
 
-    let mut tls_config = rustls::ClientConfig::builder()
-        .with_safe_defaults()
-        .with_root_certificates(cert_store)
-        .with_no_client_auth();
-    tls_config
-        .dangerous()
-        .set_certificate_verifier(Arc::new(NoVerifier));
+fn new_root() -> rcgen::Certificate {
+    let mut root_params = CertificateParams::default();
+    root_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    rcgen::Certificate::from_params(root_params).expect("Failed to create root")
+}
+
+// Fresh host and node certificates, like after a rotation when given the same root
+fn issue_certificates_by(
+    root: &rcgen::Certificate,
+    server_name: &str,
+    node_name: &str,
+) -> Certificates {
+    let server = rcgen::Certificate::from_params(CertificateParams::new(vec![server_name.into()]))
+        .expect("Failed to create server certificate");
+    let mut node_params = CertificateParams::new(vec![node_name.into()]);
+    node_params.distinguished_name = DistinguishedName::new();
+    node_params
+        .distinguished_name
+        .push(DnType::CommonName, node_name);
+    let node =
+        rcgen::Certificate::from_params(node_params).expect("Failed to create node certificate");
 
+    let signed = |certificate: &rcgen::Certificate| {
+        (
+            rustls::Certificate(
+                certificate
+                    .serialize_der_with_signer(root)
+                    .expect("Failed to sign certificate"),
+            ),
+            rustls::PrivateKey(certificate.serialize_private_key_der()),
+        )
+    };
+    Certificates {
+        root: rustls::Certificate(root.serialize_der().expect("Failed to serialize root")),
+        server: signed(&server),
+        node: signed(&node),
+    }
+}
+
+// Listens on `port` until the returned handle is aborted
+fn start_listener(
+    certificates: &Certificates,
+    port: u16,
+    handler: Arc<Handler>,
+) -> (AbortHandle, Arc<CertificateResolver>) {
     let (abort_handle, abort_registration) = AbortHandle::new_pair();
-    let server_ep = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4040);
-    let mut listener = TradeListener::new(vec![cert], key).expect("Failed to create listener");
-    let handler = Arc::new(Handler {
-        heartbeat_received: AtomicBool::new(false),
-    });
-    let handler_clone = Arc::clone(&handler);
+    let server_ep = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port);
+    let mut listener = TradeListener::new(
+        vec![certificates.server.0.clone()],
+        certificates.server.1.clone(),
+        vec![certificates.root.clone()],
+    )
+    .expect("Failed to create listener");
+    let resolver = listener.resolver();
     let listener_task = Abortable::new(
         async move {
             listener
-                .listen::<Handler, _>(
+                .listen::<Handler, _, Handler>(
                     server_ep,
-                    Arc::new(move |connection| Arc::clone(&handler_clone)),
+                    Arc::new({
+                        let handler = Arc::clone(&handler);
+                        move |connection: Arc<quinn::Connection>, node_name| {
+                            *handler.node_name.lock().unwrap() = Some(node_name);
+                            *handler.protocol_version.lock().unwrap() =
+                                version::negotiated_version(&connection);
+                            Arc::clone(&handler)
+                        }
+                    }),
+                    Arc::clone(&handler),
                 )
                 .await
                 .expect("Failed to run listener");
@@ -72,8 +117,45 @@ async fn connection_works() {
     );
     tokio::spawn(listener_task);
     info!("Listener started");
+    (abort_handle, resolver)
+}
+
+fn hello(capabilities: Vec<String>) -> NodeHello {
+    NodeHello {
+        build_version: "0.0.1".to_string(),
+        capabilities,
+    }
+}
+
+fn pinned_roots(certificates: &Certificates) -> RootCertStore {
+    let mut cert_store = RootCertStore::empty();
+    cert_store
+        .add(&certificates.root)
+        .expect("Failed to add certificate to store");
+    cert_store
+}
+
+#[tokio::test]
+#[traced_test]
+async fn connection_works() {
+    let server_name = "test-server";
+    let certificates = issue_certificates(server_name, "node-1");
+    info!("Generated certs");
+
+    let tls_config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(pinned_roots(&certificates))
+        .with_single_cert(
+            vec![certificates.node.0.clone()],
+            certificates.node.1.clone(),
+        )
+        .expect("Failed to create client config");
+
+    let handler = Arc::new(Handler::default());
+    let (abort_handle, _) = start_listener(&certificates, 4040, Arc::clone(&handler));
 
     let client_ep = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4041);
+    let server_ep = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4040);
     let mut client = TradeClient::new(client_ep, server_ep, server_name, tls_config)
         .await
         .expect("Failed to create client");
@@ -83,6 +165,11 @@ async fn connection_works() {
     std::thread::sleep(Duration::from_millis(500));
 
     let connection = client.connect().await.expect("Failed to create connection");
+    connection
+        .handshake(hello(version::capabilities()))
+        .await
+        .expect("Failed to handshake")
+        .expect("Compatible node was rejected");
     connection
         .client
         .send_heartbeat(context::current())
@@ -92,48 +179,344 @@ async fn connection_works() {
 
     // Wait until heartbeat receive
     std::thread::sleep(Duration::from_millis(5000));
+    assert!(handler
+        .heartbeat_received
+        .load(std::sync::atomic::Ordering::Relaxed));
     assert_eq!(
-        true,
-        handler
-            .heartbeat_received
-            .load(std::sync::atomic::Ordering::Relaxed)
+        Some("node-1".to_string()),
+        *handler.node_name.lock().unwrap()
     );
 
     client.close().await;
     abort_handle.abort();
 }
 
+#[tokio::test]
+#[traced_test]
+async fn nodes_without_certificate_can_only_enroll() {
+    let server_name = "test-server";
+    let certificates = issue_certificates(server_name, "node-1");
+    let handler = Arc::new(Handler::default());
+    let (abort_handle, _) = start_listener(&certificates, 4042, Arc::clone(&handler));
+
+    // Trusts the host, but cannot prove its identity yet
+    let tls_config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(pinned_roots(&certificates))
+        .with_no_client_auth();
+    let client_ep = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4043);
+    let server_ep = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4042);
+    let mut client = TradeClient::new(client_ep, server_ep, server_name, tls_config)
+        .await
+        .expect("Failed to create client");
+    std::thread::sleep(Duration::from_millis(500));
+
+    let bundle = client
+        .enroll("token".to_string(), vec![1, 2, 3])
+        .await
+        .expect("Failed to enroll");
+    assert_eq!(Ok(vec![1, 2, 3]), bundle.map(|bundle| bundle.certificate));
+    let rejected = client
+        .enroll("other".to_string(), vec![1, 2, 3])
+        .await
+        .expect("Failed to enroll");
+    assert_eq!(Err(EnrollmentRejection::InvalidToken), rejected);
+
+    let result = async {
+        let connection = client.connect().await?;
+        let mut ctx = context::current();
+        ctx.deadline = std::time::SystemTime::now() + Duration::from_secs(5);
+        connection.client.send_heartbeat(ctx).await?;
+        Ok::<_, Box<dyn std::error::Error>>(())
+    }
+    .await;
+    assert!(result.is_err());
+    assert!(!handler.heartbeat_received.load(Ordering::Relaxed));
+    assert!(handler.node_name.lock().unwrap().is_none());
+
+    abort_handle.abort();
+}
+
+#[tokio::test]
+#[traced_test]
+async fn rotated_certificate_is_used_for_new_connections() {
+    let server_name = "test-server";
+    // The host CA stays the same, nodes keep trusting the host across rotations
+    let root = new_root();
+    let certificates = issue_certificates_by(&root, server_name, "node-1");
+    let rotated = issue_certificates_by(&root, server_name, "node-1");
+    let handler = Arc::new(Handler::default());
+    let (abort_handle, resolver) = start_listener(&certificates, 4044, Arc::clone(&handler));
+    let server_ep = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4044);
+
+    // Nodes keep their certificate and pinned root, only the host certificate changes
+    let client = |port: u16| {
+        let tls_config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(pinned_roots(&certificates))
+            .with_single_cert(
+                vec![certificates.node.0.clone()],
+                certificates.node.1.clone(),
+            )
+            .expect("Failed to create client config");
+        let client_ep = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port);
+        TradeClient::new(client_ep, server_ep, server_name, tls_config)
+    };
+    let mut established = client(4045).await.expect("Failed to create client");
+    std::thread::sleep(Duration::from_millis(500));
+    let connection = established
+        .connect()
+        .await
+        .expect("Failed to create connection");
+    assert_eq!(
+        Some(certificates.server.0.clone()),
+        connection.host_certificate()
+    );
+    connection
+        .handshake(hello(version::capabilities()))
+        .await
+        .expect("Failed to handshake")
+        .expect("Compatible node was rejected");
+
+    resolver
+        .update(vec![rotated.server.0.clone()], rotated.server.1.clone())
+        .expect("Failed to rotate certificate");
+    assert!(resolver
+        .update(Vec::new(), rotated.server.1.clone())
+        .is_err());
+
+    // The connection opened before the rotation stays up
+    connection
+        .client
+        .send_heartbeat(context::current())
+        .await
+        .expect("Failed to send heartbeat");
+    std::thread::sleep(Duration::from_millis(1000));
+    assert!(handler.heartbeat_received.load(Ordering::Relaxed));
+
+    let mut current = client(4046).await.expect("Failed to create client");
+    let rotated_connection = current
+        .connect()
+        .await
+        .expect("Failed to connect with the rotated certificate");
+    assert_eq!(
+        Some(rotated.server.0.clone()),
+        rotated_connection.host_certificate()
+    );
+
+    established.close().await;
+    current.close().await;
+    abort_handle.abort();
+}
+
+#[tokio::test]
+#[traced_test]
+async fn handshake_rejects_incompatible_nodes() {
+    let server_name = "test-server";
+    let certificates = issue_certificates(server_name, "node-1");
+    let handler = Arc::new(Handler::default());
+    let (abort_handle, _) = start_listener(&certificates, 4048, Arc::clone(&handler));
+    let server_ep = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4048);
+    let tls_config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(pinned_roots(&certificates))
+        .with_single_cert(
+            vec![certificates.node.0.clone()],
+            certificates.node.1.clone(),
+        )
+        .expect("Failed to create client config");
+    let client_ep = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4049);
+    let mut client = TradeClient::new(client_ep, server_ep, server_name, tls_config)
+        .await
+        .expect("Failed to create client");
+    std::thread::sleep(Duration::from_millis(500));
+
+    let connection = client.connect().await.expect("Failed to create connection");
+    let host = connection
+        .handshake(hello(version::capabilities()))
+        .await
+        .expect("Failed to handshake")
+        .expect("Compatible node was rejected");
+    assert_eq!(version::PROTOCOL_VERSION, host.protocol_version);
+    assert_eq!(
+        Some(version::PROTOCOL_VERSION),
+        *handler.protocol_version.lock().unwrap()
+    );
+
+    let rejected = connection
+        .handshake(hello(vec!["orders".to_string()]))
+        .await
+        .expect("Failed to handshake");
+    assert_eq!(
+        Err(HandshakeRejection::MissingCapabilities {
+            missing: vec!["candles".to_string()]
+        }),
+        rejected
+    );
+
+    // The host closes the connection with the reason shortly after
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    let closed = connection
+        .handshake(hello(version::capabilities()))
+        .await
+        .expect_err("Connection of rejected node is still open");
+    assert!(closed
+        .to_string()
+        .contains("Incompatible node: node lacks capabilities candles"));
+
+    abort_handle.abort();
+}
+
+#[tokio::test]
+#[traced_test]
+async fn requests_before_the_handshake_close_the_connection() {
+    let server_name = "test-server";
+    let certificates = issue_certificates(server_name, "node-1");
+    let handler = Arc::new(Handler::default());
+    let (abort_handle, _) = start_listener(&certificates, 4050, Arc::clone(&handler));
+    let server_ep = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4050);
+    let tls_config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(pinned_roots(&certificates))
+        .with_single_cert(
+            vec![certificates.node.0.clone()],
+            certificates.node.1.clone(),
+        )
+        .expect("Failed to create client config");
+    let client_ep = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4051);
+    let mut client = TradeClient::new(client_ep, server_ep, server_name, tls_config)
+        .await
+        .expect("Failed to create client");
+    std::thread::sleep(Duration::from_millis(500));
+
+    let connection = client.connect().await.expect("Failed to create connection");
+    let mut ctx = context::current();
+    ctx.deadline = std::time::SystemTime::now() + Duration::from_secs(5);
+    assert!(connection.client.send_heartbeat(ctx).await.is_err());
+    assert!(!handler.heartbeat_received.load(Ordering::Relaxed));
+
+    let closed = connection
+        .handshake(hello(version::capabilities()))
+        .await
+        .expect_err("Connection skipping the handshake is still open");
+    assert!(closed
+        .to_string()
+        .contains("Handshake required before other requests"));
+
+    abort_handle.abort();
+}
+
+#[tokio::test]
+#[traced_test]
+async fn nodes_without_protocol_are_rejected() {
+    let server_name = "test-server";
+    let certificates = issue_certificates(server_name, "node-1");
+    let handler = Arc::new(Handler::default());
+    let (abort_handle, _) = start_listener(&certificates, 4052, Arc::clone(&handler));
+    let server_ep = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4052);
+    std::thread::sleep(Duration::from_millis(500));
+
+    // Offers no ALPN protocol at all, unlike `TradeClient`. QUIC requires one, so the TLS
+    // handshake fails with the no_application_protocol alert before the listener sees the node.
+    let tls_config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(pinned_roots(&certificates))
+        .with_single_cert(
+            vec![certificates.node.0.clone()],
+            certificates.node.1.clone(),
+        )
+        .expect("Failed to create client config");
+    let client_ep = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4053);
+    let endpoint = quinn::Endpoint::client(client_ep).expect("Failed to create endpoint");
+    let result = endpoint
+        .connect_with(
+            quinn::ClientConfig::new(Arc::new(tls_config)),
+            server_ep,
+            server_name,
+        )
+        .expect("Failed to connect")
+        .await;
+
+    match result {
+        Err(quinn::ConnectionError::ConnectionClosed(close)) => {
+            assert_eq!(NO_APPLICATION_PROTOCOL, u64::from(close.error_code))
+        }
+        Err(err) => panic!("Connection failed for another reason: {}", err),
+        Ok(_) => panic!("Connection without protocol was established"),
+    }
+    assert!(handler.node_name.lock().unwrap().is_none());
+
+    endpoint.close(0u32.into(), b"Done");
+    abort_handle.abort();
+}
+
+#[derive(Default)]
 pub struct Handler {
     heartbeat_received: AtomicBool,
+    // Identity the listener resolved for the connection
+    node_name: std::sync::Mutex<Option<String>>,
+    protocol_version: std::sync::Mutex<Option<u32>>,
 }
 
 #[async_trait]
 impl FinancialServiceHandler for Handler {
-    async fn hello(self: Arc<Self>, name: String) -> String {
+    // Requires candles of the node
+    async fn handshake(self: Arc<Self>, hello: NodeHello) -> Result<HostHello, HandshakeRejection> {
+        if !hello
+            .capabilities
+            .contains(&version::CAPABILITY_CANDLES.to_string())
+        {
+            return Err(HandshakeRejection::MissingCapabilities {
+                missing: vec![version::CAPABILITY_CANDLES.to_string()],
+            });
+        }
+        Ok(HostHello {
+            build_version: "test".to_string(),
+            protocol_version: version::PROTOCOL_VERSION,
+            capabilities: version::capabilities(),
+        })
+    }
+    async fn hello(self: Arc<Self>, _name: String) -> String {
         "Hello".to_string()
     }
     async fn send_heartbeat(self: Arc<Self>) {
         println!("Received heartbeat");
         self.heartbeat_received.store(true, Ordering::Relaxed)
     }
-    async fn request_allocation(self: Arc<Self>) -> String {
-        "AAPL".to_string()
+    async fn request_allocation(self: Arc<Self>) -> Option<String> {
+        Some("AAPL".to_string())
+    }
+    async fn next_candles(self: Arc<Self>) -> Vec<(String, Candle)> {
+        Vec::new()
+    }
+    async fn submit_order(self: Arc<Self>, _order: Order) -> Result<(), OrderRejection> {
+        Err(OrderRejection::KillSwitch)
+    }
+    async fn cancel_order(self: Arc<Self>, _client_order_id: String) -> bool {
+        false
+    }
+    async fn renew_certificate(
+        self: Arc<Self>,
+        _request: Vec<u8>,
+    ) -> Result<CertificateBundle, EnrollmentRejection> {
+        Err(EnrollmentRejection::Unavailable)
     }
 }
 
-struct NoVerifier;
-
-impl ServerCertVerifier for NoVerifier {
-    fn verify_server_cert(
-        &self,
-        _end_entity: &rustls::Certificate,
-        _intermediates: &[rustls::Certificate],
-        server_name: &rustls::ServerName,
-        _scts: &mut dyn Iterator<Item = &[u8]>,
-        _ocsp_response: &[u8],
-        _now: std::time::SystemTime,
-    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
-        trace!("Insecure certificate validation for {:?}", server_name);
-        Ok(rustls::client::ServerCertVerified::assertion())
+#[async_trait]
+impl EnrollmentServiceHandler for Handler {
+    // Certifies the request itself for the right token
+    async fn enroll(
+        self: Arc<Self>,
+        token: String,
+        request: Vec<u8>,
+    ) -> Result<CertificateBundle, EnrollmentRejection> {
+        if token != "token" {
+            return Err(EnrollmentRejection::InvalidToken);
+        }
+        Ok(CertificateBundle {
+            certificate: request,
+            ca_chain: Vec::new(),
+        })
     }
 }